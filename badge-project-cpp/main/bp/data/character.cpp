@@ -1,5 +1,6 @@
 #include "character.hpp"
 
+#include <cstring>
 #include <fstream>
 #include <string>
 #include <filesystem>
@@ -19,15 +20,85 @@ namespace bp::data {
     }
 
     std::size_t StateImage::get_image_size(const Character& character) const {
-        return character.get_image_size(image_name);
+        return data.data_size;
     }
 
     void StateImage::load_image(const Character& character, const std::span<uint8_t> buffer) const {
-        character.load_image(buffer, image_name);
+        character.load_image(buffer, image_name, data);
     }
 
-    void StateAnimation::load_frame(const std::span<uint8_t> buffer, const std::size_t index) const {
-        load_image_data(buffer, frames_folder / std::format("{}.bin", index));
+    /// Replaces the changed rectangle of the previous frame in the buffer with the one of the delta frame
+    static void load_delta_frame(const std::span<uint8_t> buffer, const fs::path& path, const Animation& animation) {
+        ImageDataVec file{};
+        file.resize(fs::file_size(path));
+
+        {
+            const auto frame_file = std::make_unique<std::ifstream>(path);
+            frame_file->read(
+                reinterpret_cast<std::istream::char_type*>(file.data()),
+                static_cast<std::streamsize>(file.size())
+            );
+            frame_file->close();
+        }
+
+        bp_animation_delta_s rect{};
+
+        if (file.size() < sizeof(bp_animation_delta_s)) {
+            ESP_LOGE(TAG, "Delta frame %s is missing its rectangle", path.c_str());
+            return;
+        }
+
+        std::memcpy(&rect, file.data(), sizeof(bp_animation_delta_s));
+
+        if (rect.x + rect.width > animation.width || rect.y + rect.height > animation.height) {
+            ESP_LOGE(TAG, "Delta frame %s is outside of the animation", path.c_str());
+            return;
+        }
+
+        // Rows of the alpha plane follow the rows of pixels when frames have one
+        const std::size_t pixel_count = animation.width * animation.height;
+        const bool alpha_plane = animation.frame_data.data_size > pixel_count * ANIMATION_BYTES_PER_PIXEL;
+        const std::size_t row_size = rect.width * ANIMATION_BYTES_PER_PIXEL;
+        const std::size_t payload_size = (row_size + (alpha_plane ? rect.width : 0)) * rect.height;
+
+        // Empty rectangle repeats the previous frame
+        if (payload_size == 0) return;
+
+        ImageDataVec payload{};
+        payload.resize(payload_size);
+
+        const std::span<const uint8_t> stored{file.data() + sizeof(bp_animation_delta_s), file.size() - sizeof(bp_animation_delta_s)};
+
+        if (!decompress_image_data(payload, stored, animation.frame_data.compression)) {
+            ESP_LOGE(TAG, "Failed to decompress %s", path.c_str());
+            return;
+        }
+
+        for (std::size_t row = 0; row < rect.height; row++) {
+            const std::size_t offset = ((rect.y + row) * animation.width + rect.x) * ANIMATION_BYTES_PER_PIXEL;
+            std::memcpy(buffer.data() + offset, payload.data() + row * row_size, row_size);
+        }
+
+        // Frames are shown as RGB565, the alpha plane is only kept if the buffer has room for it
+        if (!alpha_plane || buffer.size() < pixel_count * (ANIMATION_BYTES_PER_PIXEL + 1)) return;
+
+        const auto* alpha_rows = payload.data() + row_size * rect.height;
+
+        for (std::size_t row = 0; row < rect.height; row++) {
+            const std::size_t offset = pixel_count * ANIMATION_BYTES_PER_PIXEL + (rect.y + row) * animation.width + rect.x;
+            std::memcpy(buffer.data() + offset, alpha_rows + row * rect.width, rect.width);
+        }
+    }
+
+    void Animation::load_frame(const std::span<uint8_t> buffer, const std::size_t index) const {
+        const std::size_t file_number = frame_files.empty() ? index : frame_files.at(index - 1);
+        const auto path = folder / "frames" / std::format("{}.bin", file_number);
+
+        if (delta_frames && index > 1) {
+            load_delta_frame(buffer, path, *this);
+        } else {
+            load_image_data(buffer, path, frame_data);
+        }
     }
 
     bool SequenceFrame::image_exists(const Character& character) const {
@@ -35,11 +106,11 @@ namespace bp::data {
     }
 
     std::size_t SequenceFrame::get_image_size(const Character& character) const {
-        return character.get_image_size(image_name);
+        return data.data_size;
     }
 
     void SequenceFrame::load_image(const Character& character, const std::span<uint8_t> buffer) const {
-        character.load_image(buffer, image_name);
+        character.load_image(buffer, image_name, data);
     }
 
     bool StateSequence::frame_exists(const Character& character, const std::size_t index) const {
@@ -47,7 +118,7 @@ namespace bp::data {
     }
 
     std::size_t StateSequence::get_frame_size(const Character& character, const std::size_t index) const {
-        return character.get_image_size(frames[index].image_name);
+        return frames[index].get_image_size(character);
     }
 
     void StateSequence::load_frame(
@@ -55,7 +126,7 @@ namespace bp::data {
         const std::span<uint8_t> buffer,
         const std::size_t index
     ) const {
-        character.load_image(buffer, frames[index].image_name);
+        frames[index].load_image(character, buffer);
     }
 
     std::filesystem::path Character::get_image_path(const std::string& name) const {
@@ -66,12 +137,8 @@ namespace bp::data {
         return fs::exists(get_image_path(name));
     }
 
-    std::size_t Character::get_image_size(const std::string& name) const {
-        return fs::file_size(get_image_path(name));
-    }
-
-    void Character::load_image(const std::span<uint8_t> buffer, const std::string& name) const {
-        load_image_data(buffer, get_image_path(name));
+    void Character::load_image(const std::span<uint8_t> buffer, const std::string& name, const ImageData& data) const {
+        load_image_data(buffer, get_image_path(name), data);
     }
 
     uint8_t Character::default_load_layer() const {
@@ -79,6 +146,27 @@ namespace bp::data {
         return default_state.layer;
     }
 
+    static ImageData make_image_data(const bp_image_data_s& data) {
+        ImageData image_data{
+            .stored_size = data.stored_size,
+            .data_size = data.data_size
+        };
+
+        switch (data.compression) {
+            case BP_IMAGE_COMPRESSION_NONE:
+                image_data.compression = ImageCompression::None;
+                break;
+            case BP_IMAGE_COMPRESSION_RLE:
+                image_data.compression = ImageCompression::Rle;
+                break;
+            case BP_IMAGE_COMPRESSION_LZ4:
+                image_data.compression = ImageCompression::Lz4;
+                break;
+        }
+
+        return image_data;
+    }
+
     std::vector<std::string> list_characters() {
         std::vector<std::string> names{};
 
@@ -201,13 +289,14 @@ namespace bp::data {
                         image = std::monostate{};
                         break;
                     case BP_CHARACTER_STATE_SINGLE_IMAGE: {
-                        auto& [image_name, width, height, upscale, layer_load] = state_struct.image.image;
+                        auto& [image_name, width, height, upscale, layer_load, data] = state_struct.image.image;
                         image = StateImage{
                             .image_name = image_name,
                             .width = width,
                             .height = height,
                             .upscale = upscale,
-                            .layer_load = layer_load
+                            .layer_load = layer_load,
+                            .data = make_image_data(data)
                         };
                         break;
                     }
@@ -217,8 +306,7 @@ namespace bp::data {
                             .name = name,
                             .next_state = next_state,
                             .loop_count = loop_count,
-                            .layer_load = layer_load,
-                            .frames_folder = character.animations_folder / name / "frames"
+                            .layer_load = layer_load
                         };
                         break;
                     }
@@ -263,7 +351,8 @@ namespace bp::data {
                                 frame_struct.width,
                                 frame_struct.height,
                                 frame_struct.upscale,
-                                frame_struct.duration_us
+                                frame_struct.duration_us,
+                                make_image_data(frame_struct.data)
                             );
                         }
                         break;
@@ -349,7 +438,10 @@ namespace bp::data {
                     clear_screen,
                     background_color,
                     mode,
-                    upscale
+                    upscale,
+                    delta_frames,
+                    frame_map,
+                    frame_data
                 ] = animation_struct;
 
                 Animation animation{
@@ -361,7 +453,10 @@ namespace bp::data {
                     .interval_us = interval_us,
                     .clear_screen = clear_screen,
                     .background_color = background_color,
-                    .upscale = upscale
+                    .upscale = upscale,
+                    .delta_frames = delta_frames,
+                    .frame_data = make_image_data(frame_data),
+                    .folder = animation_entry.path()
                 };
 
                 switch (mode) {
@@ -373,6 +468,24 @@ namespace bp::data {
                         break;
                 }
 
+                // Repeated frames point at the same file
+                if (frame_map) {
+                    const fs::path map_filename = animation_entry.path() / "frame_map.bin";
+
+                    if (fs::file_size(map_filename) == frame_count * sizeof(uint32_t)) {
+                        animation.frame_files.resize(frame_count);
+
+                        auto map_file = std::make_unique<std::ifstream>(map_filename);
+                        map_file->read(
+                            reinterpret_cast<std::istream::char_type*>(animation.frame_files.data()),
+                            static_cast<std::streamsize>(frame_count * sizeof(uint32_t))
+                        );
+                        map_file->close();
+                    } else {
+                        ESP_LOGE(TAG, "Frame map %s doesn't match the frame count", map_filename.c_str());
+                    }
+                }
+
                 animations.emplace(animation_entry.path().filename(), animation);
             }
         }
@@ -452,28 +565,45 @@ namespace bp::data {
         };
     }
 
-    void load_image_data(std::span<uint8_t> buffer, const std::filesystem::path& path) {
+    void load_image_data(std::span<uint8_t> buffer, const std::filesystem::path& path, const ImageData& data) {
+        // Allocations can be bigger than the image, nothing past its data size is written
+        buffer = buffer.first(std::min<std::size_t>(buffer.size(), data.data_size));
+
         const auto image_file = std::make_unique<std::ifstream>(path);
+
+        if (data.compression == ImageCompression::None) {
+            image_file->read(
+                reinterpret_cast<std::istream::char_type*>(buffer.data()),
+                static_cast<std::streamsize>(buffer.size_bytes())
+            );
+            image_file->close();
+            return;
+        }
+
+        ImageDataVec stored{};
+        stored.resize(fs::file_size(path));
+
         image_file->read(
-            reinterpret_cast<std::istream::char_type*>(buffer.data()),
-            static_cast<std::streamsize>(buffer.size_bytes())
+            reinterpret_cast<std::istream::char_type*>(stored.data()),
+            static_cast<std::streamsize>(stored.size())
         );
         image_file->close();
+
+        if (!decompress_image_data(buffer, stored, data.compression)) {
+            ESP_LOGE(TAG, "Failed to decompress %s", path.c_str());
+        }
     }
 
     void preload_image(
         LoadedLayerData& layer_data, const std::string& image_name, const fs::path& images_folder,
-        const uint32_t width, const uint32_t height
+        const uint32_t width, const uint32_t height, const ImageData& data
     ) {
         image::SharedAllocatedImageData image_data;
 
         {
-            uintmax_t file_size = 0;
             const fs::path image_filename = images_folder / std::format("{}.bin", image_name);
 
-            file_size = fs::file_size(image_filename);
-
-            if (const auto opt_image = image::allocator.allocate_image_data_sl(file_size)) {
+            if (const auto opt_image = image::allocator.allocate_image_data_sl(data.data_size)) {
                 image_data = opt_image.value();
             } else {
                 throw data_exception{Error::OutOfRAM};
@@ -481,7 +611,7 @@ namespace bp::data {
 
             ESP_LOGI(TAG, "Allocated %x-%x for %s image", image_data->start(), image_data->end(), image_name.c_str());
 
-            load_image_data(image_data->span(), image_filename);
+            load_image_data(image_data->span(), image_filename, data);
         }
 
         layer_data.image_data.emplace(
@@ -514,7 +644,12 @@ namespace bp::data {
 
             ESP_LOGI(TAG, "Allocated %x-%x for %s #%d", frame->start(), frame->end(), state_anim.name.c_str(), frame_index);
 
-            state_anim.load_frame(frame->span(), frame_index);
+            // Every frame has its own buffer, so delta frames start from a copy of the previous one
+            if (anim_desc.delta_frames && !frames.empty()) {
+                std::memcpy(frame->data(), frames.back()->data(), data_size);
+            }
+
+            anim_desc.load_frame(frame->span(), frame_index);
 
             frames.emplace_back(std::move(frame));
 
@@ -538,7 +673,7 @@ namespace bp::data {
 
                 preload_image(
                     layer_data, image->image_name, character.images_folder,
-                    image->width, image->height
+                    image->width, image->height, image->data
                 );
             } else if (const auto* sequence = std::get_if<StateSequence>(&state.image)) {
                 if (!sequence->layer_load) continue;
@@ -546,7 +681,7 @@ namespace bp::data {
                 for (const auto& frame: sequence->frames) {
                     preload_image(
                         layer_data, frame.image_name, character.images_folder,
-                        frame.width, frame.height
+                        frame.width, frame.height, frame.data
                     );
                 }
             }
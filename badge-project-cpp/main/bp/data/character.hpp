@@ -11,6 +11,7 @@
 #include <lvgl.h>
 
 #include "character.hpp"
+#include "compression.hpp"
 #include "image.hpp"
 #include "../util/allocator.hpp"
 
@@ -37,12 +38,21 @@ namespace bp::data {
         std::variant<StateTransitionElapsedTime, StateTransitionClicked, StateTransitionRandom> trigger;
     };
 
+    /// How an image file is stored on the SD card
+    struct ImageData {
+        ImageCompression compression;
+        uint32_t stored_size;
+        /// Size after decompression, what has to be allocated for the image
+        uint32_t data_size;
+    };
+
     struct StateImage {
         std::string image_name;
         uint32_t width;
         uint32_t height;
         bool upscale;
         bool layer_load;
+        ImageData data;
 
         [[nodiscard]] bool image_exists(const Character& character) const;
         [[nodiscard]] std::size_t get_image_size(const Character& character) const;
@@ -54,9 +64,6 @@ namespace bp::data {
         std::string next_state;
         uint16_t loop_count;
         bool layer_load;
-        std::filesystem::path frames_folder;
-
-        void load_frame(std::span<uint8_t> buffer, std::size_t index) const;
     };
 
     struct SequenceFrame {
@@ -65,6 +72,7 @@ namespace bp::data {
         uint32_t height;
         bool upscale;
         int64_t duration_us;
+        ImageData data;
 
         [[nodiscard]] bool image_exists(const Character& character) const;
         [[nodiscard]] std::size_t get_image_size(const Character& character) const;
@@ -113,7 +121,15 @@ namespace bp::data {
         uint16_t background_color;
         AnimationMode mode;
         bool upscale;
+        /// Frames after the first one only store what changed, see `bp_animation_delta_s`
+        bool delta_frames;
+        ImageData frame_data;
         std::filesystem::path folder;
+        /// Number of the file of every frame when repeated frames are stored once, empty otherwise
+        std::vector<uint32_t> frame_files;
+
+        /// Frames are counted from 1. Delta frames are applied onto the buffer, which has to hold the previous frame
+        void load_frame(std::span<uint8_t> buffer, std::size_t index) const;
     };
 
     struct ActionSwitchState {
@@ -139,8 +155,7 @@ namespace bp::data {
 
         std::filesystem::path get_image_path(const std::string& name) const;
         bool image_exists(const std::string& name) const;
-        std::size_t get_image_size(const std::string& name) const;
-        void load_image(std::span<uint8_t> buffer, const std::string& name) const;
+        void load_image(std::span<uint8_t> buffer, const std::string& name, const ImageData& data) const;
         uint8_t default_load_layer() const;
     };
 
@@ -161,7 +176,8 @@ namespace bp::data {
     lv_image_dsc_t make_image_dsc(uint32_t width, uint32_t height, const ImageDataVec& image_data);
     lv_image_dsc_t make_image_dsc(uint32_t width, uint32_t height, const image::SharedAllocatedImageData& image_data);
 
-    void load_image_data(std::span<uint8_t> buffer, const std::filesystem::path& path);
+    /// Decompresses the file into the buffer, which has to be as large as the data size
+    void load_image_data(std::span<uint8_t> buffer, const std::filesystem::path& path, const ImageData& data);
 
     struct LoadedLayerData {
         StrMap<std::tuple<lv_image_dsc_t, image::SharedAllocatedImageData>> image_data;
@@ -170,7 +186,7 @@ namespace bp::data {
 
     void preload_image(
         LoadedLayerData& layer_data, const std::string& image_name, const std::filesystem::path& images_folder,
-        uint32_t width, uint32_t height
+        uint32_t width, uint32_t height, const ImageData& data
     );
 
     void preload_animation(
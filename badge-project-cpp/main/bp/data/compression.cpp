@@ -0,0 +1,109 @@
+#include "compression.hpp"
+
+#include <algorithm>
+#include <cstring>
+
+namespace bp::data {
+    constexpr std::size_t LZ4_MIN_MATCH = 4;
+
+    /// First byte is the size of a pixel, then packets. Control byte below 128 is followed by control + 1 pixels,
+    /// otherwise the following pixel repeats control - 126 times
+    static bool rle_decompress(const std::span<uint8_t> buffer, const std::span<const uint8_t> data) {
+        if (data.empty() || data[0] == 0) return false;
+
+        const std::size_t pixel_size = data[0];
+        std::size_t in = 1;
+        std::size_t out = 0;
+
+        while (in < data.size() && out < buffer.size()) {
+            const uint8_t control = data[in++];
+            const bool run = control >= 128;
+            const std::size_t count = run ? control - 126 : 1;
+            const std::size_t bytes = run ? pixel_size : (control + 1) * pixel_size;
+
+            if (in + bytes > data.size()) return false;
+
+            for (std::size_t i = 0; i < count && out < buffer.size(); i++) {
+                const auto copied = std::min(bytes, buffer.size() - out);
+                std::memcpy(buffer.data() + out, data.data() + in, copied);
+                out += copied;
+            }
+
+            in += bytes;
+        }
+
+        return out == buffer.size();
+    }
+
+    /// Lengths of 15 in the token continue in following bytes, until a byte isn't 255
+    static bool lz4_length(const std::span<const uint8_t> data, std::size_t& in, std::size_t& length) {
+        if (length != 15) return true;
+
+        uint8_t extra;
+        do {
+            if (in >= data.size()) return false;
+            extra = data[in++];
+            length += extra;
+        } while (extra == 255);
+
+        return true;
+    }
+
+    /// LZ4 block, sequences of literals followed by a match in the already decompressed data
+    static bool lz4_decompress(const std::span<uint8_t> buffer, const std::span<const uint8_t> data) {
+        std::size_t in = 0;
+        std::size_t out = 0;
+
+        while (in < data.size() && out < buffer.size()) {
+            const uint8_t token = data[in++];
+
+            std::size_t literals = token >> 4;
+            if (!lz4_length(data, in, literals) || in + literals > data.size()) return false;
+
+            const auto copied = std::min(literals, buffer.size() - out);
+            std::memcpy(buffer.data() + out, data.data() + in, copied);
+            out += copied;
+            in += literals;
+
+            // Last sequence has only literals
+            if (in == data.size()) break;
+            if (in + 2 > data.size()) return false;
+
+            const std::size_t offset = data[in] | data[in + 1] << 8;
+            in += 2;
+
+            if (offset == 0 || offset > out) return false;
+
+            std::size_t length = token & 15;
+            if (!lz4_length(data, in, length)) return false;
+            length += LZ4_MIN_MATCH;
+
+            // Matches can overlap what they write, so they're copied byte by byte
+            for (std::size_t i = 0; i < length && out < buffer.size(); i++, out++) {
+                buffer[out] = buffer[out - offset];
+            }
+        }
+
+        return out == buffer.size();
+    }
+
+    bool decompress_image_data(
+        const std::span<uint8_t> buffer,
+        const std::span<const uint8_t> data,
+        const ImageCompression compression
+    ) {
+        switch (compression) {
+            case ImageCompression::None: {
+                const auto copied = std::min(buffer.size(), data.size());
+                std::memcpy(buffer.data(), data.data(), copied);
+                return copied == buffer.size();
+            }
+            case ImageCompression::Rle:
+                return rle_decompress(buffer, data);
+            case ImageCompression::Lz4:
+                return lz4_decompress(buffer, data);
+        }
+
+        return false;
+    }
+}
@@ -0,0 +1,16 @@
+#pragma once
+
+#include <cstdint>
+#include <span>
+
+namespace bp::data {
+    enum class ImageCompression {
+        None,
+        Rle,
+        Lz4
+    };
+
+    /// Fills the buffer with decompressed image data, data past the end of the buffer is skipped.
+    /// Returns false if the data is broken or ends before the buffer is full
+    bool decompress_image_data(std::span<uint8_t> buffer, std::span<const uint8_t> data, ImageCompression compression);
+}
@@ -2,7 +2,7 @@
 #include <cstdint>
 
 namespace bp::data {
-    constexpr uint16_t FORMAT_VERSION = 1;
+    constexpr uint16_t FORMAT_VERSION = 4;
     constexpr std::size_t NAME_MAX_LEN = 64;
     constexpr std::size_t SPECIES_MAX_LEN = 64;
     constexpr std::size_t IMAGE_NAME_MAX_LEN = 64;
@@ -55,6 +55,25 @@ struct bp_state_transition_file_s {
     bp_state_trigger_s trigger;
 };
 
+/// How image files are compressed, decompressed data is the same as an uncompressed file
+enum bp_image_compression_e {
+    BP_IMAGE_COMPRESSION_NONE,
+    /// First byte is the size of a pixel in bytes, packets follow. Control byte below 128 is followed by
+    /// control + 1 pixels, otherwise the following pixel repeats control - 126 times
+    BP_IMAGE_COMPRESSION_RLE,
+    /// LZ4 block, without the frame format
+    BP_IMAGE_COMPRESSION_LZ4
+};
+
+/// Describes how image data is stored
+struct bp_image_data_s {
+    bp_image_compression_e compression;
+    /// Size of the file on the SD card
+    uint32_t stored_size;
+    /// Size of the image data after decompression, what has to be allocated
+    uint32_t data_size;
+};
+
 /// Describes a character image
 struct bp_character_image_descriptor_s {
     char image_name[bp::data::IMAGE_NAME_MAX_LEN];
@@ -62,6 +81,7 @@ struct bp_character_image_descriptor_s {
     uint32_t height;
     bool upscale;
     bool layer_load;
+    bp_image_data_s data;
 };
 
 /// Describes how animation will be loaded and played
@@ -70,6 +90,17 @@ enum bp_character_animation_mode_e {
     BP_CHARACTER_ANIMATION_MODE_FROM_RAM
 };
 
+/// Starts animation frames after the first one when delta frames are used, rectangle is in pixels.
+/// Palette of indexed formats follows, then rows of the rectangle and rows of the alpha plane if frames have one,
+/// everything in the layout of a whole frame. Only data after this header is compressed.
+/// Formats with less than a byte per pixel start the rectangle on a byte boundary, empty rectangle repeats the previous frame
+struct bp_animation_delta_s {
+    uint16_t x;
+    uint16_t y;
+    uint16_t width;
+    uint16_t height;
+};
+
 /// (animation.bin) Definition of an animation file
 struct bp_character_animation_file_s {
     /// Top left corner X
@@ -86,6 +117,13 @@ struct bp_character_animation_file_s {
     bp_character_animation_mode_e mode;
     /// If 2x upscale is required
     bool upscale;
+    /// Frames after the first one only store what changed since the previous frame
+    bool delta_frames;
+    /// Repeated frames are stored once. Frame files are read through frame_map.bin next to this file,
+    /// which holds the uint32_t number of the frame file for every frame
+    bool frame_map;
+    /// Same for every frame, stored size is of the largest frame
+    bp_image_data_s frame_data;
 };
 
 /// Describes data when state is an animation
@@ -103,6 +141,7 @@ struct bp_sequence_frame_file_s {
     uint32_t height;
     bool upscale;
     int64_t duration_us;
+    bp_image_data_s data;
 };
 
 /// Loading mode for sequences, either load all frames at once when state switches,
@@ -1,5 +1,6 @@
 #include "fsm.hpp"
 
+#include <cstring>
 #include <fstream>
 #include <filesystem>
 #include <unordered_set>
@@ -279,7 +280,7 @@ void bp::layer_loader() {
     ESP_LOGI(LL_TAG, "Preparing %hu layer!", layer);
 
     std::unordered_set<std::string> images_on_new_layer;
-    std::unordered_map<std::string, std::tuple<uint32_t, uint32_t>> image_size;
+    std::unordered_map<std::string, std::tuple<uint32_t, uint32_t, data::ImageData>> image_size;
     std::unordered_set<std::string> animations_on_new_layer;
     std::unordered_map<std::string, const data::StateAnimation*> state_anims;
 
@@ -292,7 +293,7 @@ void bp::layer_loader() {
                 images_on_new_layer.emplace(single->image_name);
                 image_size.emplace(
                     single->image_name,
-                    std::make_tuple(single->width, single->height)
+                    std::make_tuple(single->width, single->height, single->data)
                 );
             }
         }
@@ -310,7 +311,7 @@ void bp::layer_loader() {
                     images_on_new_layer.emplace(frame.image_name);
                     image_size.emplace(
                         frame.image_name,
-                        std::make_tuple(frame.width, frame.height)
+                        std::make_tuple(frame.width, frame.height, frame.data)
                     );
                 }
             }
@@ -339,10 +340,10 @@ void bp::layer_loader() {
     // Load missing images and animations
     for (const auto& image_name : images_on_new_layer) {
         if (!char_fsm.loaded_layer_data.image_data.contains(image_name)) {
-            const auto& [width, height] = image_size.at(image_name);
+            const auto& [width, height, image_data] = image_size.at(image_name);
 
             data::preload_image(
-                char_fsm.loaded_layer_data, image_name, char_fsm.character_data.images_folder, width, height
+                char_fsm.loaded_layer_data, image_name, char_fsm.character_data.images_folder, width, height, image_data
             );
 
             vTaskDelay(COOKER_LOAD_DELAY);
@@ -441,7 +442,12 @@ void bp::animation_cooker(const data::StateAnimation* anim_state) {
 
         ESP_LOGI(ANIM_TAG, "Allocated at %x-%x for #%d frame", inserted_image->start(), inserted_image->end(), frame_index);
 
-        anim_state->load_frame(inserted_image->span(), frame_index);
+        // Delta frames are applied onto a copy of the previous frame
+        if (anim_desc.delta_frames && !frames.empty()) {
+            std::memcpy(inserted_image->data(), frames.back()->data(), data_size);
+        }
+
+        anim_desc.load_frame(inserted_image->span(), frame_index);
 
         frames.emplace_back(std::move(inserted_image));
 
@@ -1042,7 +1048,7 @@ void CharacterFSM::play_animation(
                         timer.frame_start();
 
                         // Load frame into memory
-                        state_desc.load_frame(image_fb, frame_index + 1);
+                        animation_desc.load_frame(image_fb, frame_index + 1);
 
                         const auto* frame_buf = &image_fb;
                         if (animation_desc.upscale) {
@@ -0,0 +1,626 @@
+use crate::character::encode::Progress;
+use crate::character::repr::{Character, StateImage, StateTransitionTrigger};
+use crate::character::sim::random::SimRng;
+use crate::character::sim::{animation_duration, SimEvent, Simulator};
+use crate::character::util::TuplePick;
+use crate::character::validation::ValidationErrors;
+use crate::target::{StorageArgs, TargetArgs, TargetProfile};
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, fs};
+use std::path::PathBuf;
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Estimates how much time the character spends in each state and how long it takes to get there, with simulated runs and a Markov model of the state machine",
+    long_about=None
+)]
+pub struct AnalyzeCli {
+    #[arg(help = "Character JSON file")]
+    input_file: PathBuf,
+    #[arg(long, help = "Number of simulated runs", default_value_t = 200)]
+    runs: u32,
+    #[arg(long, help = "Length of every simulated run in milliseconds", default_value_t = 600_000)]
+    duration: i64,
+    #[arg(long, help = "Average time between clicks in milliseconds, without it the character is never clicked")]
+    click_interval: Option<i64>,
+    #[arg(long, help = "Seed of the first run, every next run uses the next seed", default_value_t = 0)]
+    seed: u64,
+    #[command(flatten)]
+    target: TargetArgs,
+    #[command(flatten)]
+    storage: StorageArgs,
+    #[arg(long, help = "Print results as JSON", default_value_t = false)]
+    json: bool
+}
+
+/// All times are in microseconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalysisOptions {
+    pub runs: u32,
+    pub duration: i64,
+    /// Clicks come at random with this average interval
+    pub click_interval: Option<i64>,
+    pub seed: u64,
+    pub target: TargetProfile
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        Self {
+            runs: 200,
+            duration: 600_000_000,
+            click_interval: None,
+            seed: 0,
+            target: TargetProfile::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SimulatedState {
+    /// Share of the simulated time spent in the state
+    pub time_fraction: f64,
+    /// Average length of a single stay
+    pub mean_dwell: Option<f64>,
+    /// Average number of entries per run
+    pub visits: f64,
+    /// Share of runs that entered the state
+    pub reached: f64,
+    /// Average time of the first entry, over the runs that entered the state
+    pub mean_first_entry: Option<f64>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MonteCarloReport {
+    pub runs: u32,
+    pub duration: i64,
+    /// Runs that ended early because of a fatal failure, they still count up to the failure
+    pub crashed_runs: u32,
+    pub states: BTreeMap<String, SimulatedState>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MarkovState {
+    /// Expected length of a single stay
+    pub mean_dwell: f64,
+    /// Long run share of time spent in the state
+    pub time_fraction: f64,
+    /// Expected time to get to the state from the default state
+    pub mean_first_entry: Option<f64>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MarkovReport {
+    pub states: BTreeMap<String, MarkovState>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AnalysisReport {
+    pub monte_carlo: MonteCarloReport,
+    /// Error says why the state machine can't be solved exactly
+    pub markov: Result<MarkovReport, String>
+}
+
+/// Runs both analyses, the character is expected to be valid
+pub fn analyze(char: &Character, options: &AnalysisOptions) -> AnalysisReport {
+    analyze_with_progress(char, options, &|_, _| {}, &AtomicBool::new(false))
+        .expect("Analysis can't be cancelled")
+}
+
+/// Same as [analyze], but reports finished runs and stops early once cancelled
+pub fn analyze_with_progress(
+    char: &Character,
+    options: &AnalysisOptions,
+    progress: Progress,
+    cancelled: &AtomicBool
+) -> Option<AnalysisReport> {
+    Some(AnalysisReport {
+        monte_carlo: monte_carlo(char, options, progress, cancelled)?,
+        markov: markov(char, options.click_interval).map_err(|err| err.to_string()),
+    })
+}
+
+/// Exponentially distributed interval, so clicks don't depend on each other
+fn click_delay(rng: &mut SimRng, mean: i64) -> i64 {
+    let uniform = rng.next_u32() as f64 / (u32::MAX as f64 + 1.0);
+
+    (-(1.0 - uniform).ln() * mean as f64).ceil() as i64
+}
+
+#[derive(Default)]
+struct StateTotals {
+    time: i64,
+    stays: u64,
+    finished_stays: u64,
+    finished_time: i64,
+    runs_reached: u32,
+    first_entry: i64
+}
+
+/// Plays the character with the headless simulator over and over, with a different seed every run.
+/// Nothing is returned when it's cancelled
+pub fn monte_carlo(char: &Character, options: &AnalysisOptions, progress: Progress, cancelled: &AtomicBool) -> Option<MonteCarloReport> {
+    let mut totals: BTreeMap<String, StateTotals> = char.states.keys()
+        .map(|state| (state.clone(), StateTotals::default()))
+        .collect();
+    let mut crashed_runs = 0;
+
+    for run in 0..options.runs {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        progress(run as usize, options.runs as usize);
+
+        let seed = options.seed.wrapping_add(run as u64);
+        let mut sim = Simulator::with_target(char.clone(), seed, &options.target);
+
+        if let Some(interval) = options.click_interval.filter(|interval| *interval > 0) {
+            // Separate generator, so clicks don't shift the rolls of the firmware
+            let mut rng = SimRng::new(!seed);
+            let mut next_click = click_delay(&mut rng, interval);
+
+            while next_click < options.duration && !sim.is_crashed() {
+                sim.advance_to(next_click);
+                sim.click();
+                next_click += click_delay(&mut rng, interval);
+            }
+        }
+
+        sim.advance_to(options.duration);
+
+        if sim.is_crashed() {
+            crashed_runs += 1;
+        }
+
+        let entries = sim.trace().iter()
+            .filter_map(|entry| match &entry.event {
+                SimEvent::StateEntered { state } => Some((entry.time, state.as_str())),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        let mut reached = BTreeSet::new();
+
+        for (index, (time, state)) in entries.iter().enumerate() {
+            let Some(total) = totals.get_mut(*state) else {
+                continue;
+            };
+
+            let left_at = entries.get(index + 1).map(|(time, _)| *time);
+            let stay = left_at.unwrap_or(options.duration).min(options.duration) - time;
+
+            total.time += stay;
+            total.stays += 1;
+
+            // Stay cut off by the end of the run would make dwell times look shorter
+            if left_at.is_some() {
+                total.finished_stays += 1;
+                total.finished_time += stay;
+            }
+
+            if reached.insert(*state) {
+                total.runs_reached += 1;
+                total.first_entry += time;
+            }
+        }
+    }
+
+    let runs = options.runs.max(1) as f64;
+    let total_time = runs * options.duration.max(1) as f64;
+
+    Some(MonteCarloReport {
+        runs: options.runs,
+        duration: options.duration,
+        crashed_runs,
+        states: totals.into_iter()
+            .map(|(state, total)| (state, SimulatedState {
+                time_fraction: total.time as f64 / total_time,
+                mean_dwell: (total.finished_stays > 0)
+                    .then(|| total.finished_time as f64 / total.finished_stays as f64),
+                visits: total.stays as f64 / runs,
+                reached: total.runs_reached as f64 / runs,
+                mean_first_entry: (total.runs_reached > 0)
+                    .then(|| total.first_entry as f64 / total.runs_reached as f64),
+            }))
+            .collect(),
+    })
+}
+
+/// Expected length of a stay in the state and where it goes next
+struct StateExits {
+    mean_dwell: f64,
+    next: Vec<(String, f64)>
+}
+
+/// Works out how the state is left, only for states where it can be done exactly.
+/// Tick rounding, loading and cooking times are left out
+fn state_exits(char: &Character, name: &str, click_interval: Option<i64>) -> anyhow::Result<StateExits> {
+    let Some(state) = char.states.get(name) else {
+        bail!("State '{name}' doesn't exist");
+    };
+
+    if let StateImage::Animation { name: animation, next_state, loop_count, .. } = &state.image {
+        let Some(animation) = char.animations.get(animation) else {
+            bail!("Animation '{animation}' doesn't exist");
+        };
+
+        return Ok(StateExits {
+            mean_dwell: animation_duration(animation, *loop_count) as f64,
+            next: vec![(next_state.clone(), 1.0)],
+        });
+    }
+
+    // Earliest elapsed time transition always wins, same duration goes to the first one
+    let elapsed = state.transitions.iter()
+        .filter_map(|transition| match transition.trigger {
+            StateTransitionTrigger::ElapsedTime { duration } => Some((duration.max(0), &transition.to_state)),
+            _ => None
+        })
+        .min_by_key(|(duration, _)| *duration);
+
+    let randoms = state.transitions.iter()
+        .filter_map(|transition| match &transition.trigger {
+            StateTransitionTrigger::Random { duration_range, chance } => Some((duration_range, *chance, &transition.to_state)),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+
+    // Every clicked transition gets switched to, the first one starts the switch
+    let clicked = click_interval
+        .filter(|interval| *interval > 0)
+        .and_then(|interval| state.transitions.iter()
+            .find(|transition| transition.trigger.is_clicked())
+            .map(|transition| (interval as f64, &transition.to_state))
+        );
+
+    if randoms.len() > 1 {
+        bail!("State '{name}' has more than one Random transition");
+    }
+
+    if let Some((duration_range, chance, to_state)) = randoms.first() {
+        if elapsed.is_some() {
+            bail!("State '{name}' has both Random and ElapsedTime transitions");
+        }
+
+        if clicked.is_some() {
+            bail!("State '{name}' has both Random and Clicked transitions");
+        }
+
+        let (start, end) = duration_range.either(
+            |tuple| (tuple.pick_min(), tuple.pick_max()),
+            |num| (num, num)
+        );
+
+        // Failed rolls wait for another random duration
+        let attempt = (start + end) as f64 / 2.0;
+
+        return Ok(StateExits {
+            mean_dwell: attempt.max(0.0) * (*chance).max(1) as f64,
+            next: vec![((*to_state).clone(), 1.0)],
+        });
+    }
+
+    match (elapsed, clicked) {
+        (Some((duration, to_state)), None) => Ok(StateExits {
+            mean_dwell: duration as f64,
+            next: vec![(to_state.clone(), 1.0)],
+        }),
+        (None, Some((interval, to_state))) => Ok(StateExits {
+            mean_dwell: interval,
+            next: vec![(to_state.clone(), 1.0)],
+        }),
+        (Some((duration, elapsed_state)), Some((interval, clicked_state))) => {
+            let click_chance = 1.0 - (-(duration as f64) / interval).exp();
+
+            Ok(StateExits {
+                mean_dwell: interval * click_chance,
+                next: vec![
+                    (clicked_state.clone(), click_chance),
+                    (elapsed_state.clone(), 1.0 - click_chance),
+                ],
+            })
+        }
+        (None, None) => bail!("State '{name}' is never left, the character ends up stuck there")
+    }
+}
+
+/// Solves `a * x = b` with Gaussian elimination, none if there isn't exactly one solution
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+
+    for column in 0..n {
+        let pivot = (column..n).max_by(|x, y| a[*x][column].abs().total_cmp(&a[*y][column].abs()))?;
+
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+
+        let pivot_row = a[column].clone();
+
+        for row in 0..n {
+            if row == column {
+                continue;
+            }
+
+            let factor = a[row][column] / pivot_row[column];
+
+            if factor == 0.0 {
+                continue;
+            }
+
+            for (value, pivot) in a[row].iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+
+    Some((0..n).map(|i| b[i] / a[i][i]).collect())
+}
+
+/// Treats the state machine as a semi-Markov process over the states reachable from the default state
+/// and solves for its stationary distribution and mean first passage times
+pub fn markov(char: &Character, click_interval: Option<i64>) -> anyhow::Result<MarkovReport> {
+    let mut exits = BTreeMap::new();
+    let mut pending = vec![char.default_state.clone()];
+
+    while let Some(state) = pending.pop() {
+        if exits.contains_key(&state) {
+            continue;
+        }
+
+        let state_exits = state_exits(char, &state, click_interval)?;
+
+        pending.extend(state_exits.next.iter()
+            .filter(|(_, chance)| *chance > 0.0)
+            .map(|(next, _)| next.clone())
+        );
+        exits.insert(state, state_exits);
+    }
+
+    let states = exits.keys().cloned().collect::<Vec<_>>();
+    let index = |state: &str| states.binary_search_by(|other| other.as_str().cmp(state)).ok();
+    let n = states.len();
+
+    // probability[i][j] of going from i to j
+    let mut probability = vec![vec![0.0; n]; n];
+
+    for (i, state) in states.iter().enumerate() {
+        for (next, chance) in &exits[state].next {
+            if let Some(j) = index(next) {
+                probability[i][j] += chance;
+            }
+        }
+    }
+
+    // Stationary distribution of the jump chain, pi * (P - I) = 0 with the last equation swapped for sum(pi) = 1
+    let mut a = vec![vec![0.0; n]; n];
+    let mut b = vec![0.0; n];
+
+    for i in 0..n {
+        for j in 0..n {
+            a[j][i] = probability[i][j] - if i == j { 1.0 } else { 0.0 };
+        }
+    }
+
+    a[n - 1] = vec![1.0; n];
+    b[n - 1] = 1.0;
+
+    let Some(jumps) = solve(a, b) else {
+        bail!("State machine splits into parts that are never left, there is no single long run distribution");
+    };
+
+    let weighted_total = states.iter()
+        .enumerate()
+        .map(|(i, state)| jumps[i] * exits[state].mean_dwell)
+        .sum::<f64>();
+
+    if weighted_total <= 0.0 {
+        bail!("Every reachable state is left right away");
+    }
+
+    let default_index = index(&char.default_state).unwrap_or_default();
+
+    let mut report = MarkovReport {
+        states: BTreeMap::new(),
+    };
+
+    for (target, state) in states.iter().enumerate() {
+        // Expected time to hit the target, h = m + P * h with the target itself left out
+        let others = (0..n).filter(|i| *i != target).collect::<Vec<_>>();
+
+        let mean_first_entry = if target == default_index {
+            Some(0.0)
+        } else {
+            let a = others.iter()
+                .map(|i| others.iter()
+                    .map(|j| if i == j { 1.0 } else { 0.0 } - probability[*i][*j])
+                    .collect()
+                )
+                .collect();
+            let b = others.iter()
+                .map(|i| exits[&states[*i]].mean_dwell)
+                .collect();
+
+            solve(a, b).and_then(|times| {
+                let position = others.iter().position(|i| *i == default_index)?;
+
+                Some(times[position])
+            })
+        };
+
+        report.states.insert(state.clone(), MarkovState {
+            mean_dwell: exits[state].mean_dwell,
+            time_fraction: jumps[target] * exits[state].mean_dwell / weighted_total,
+            mean_first_entry,
+        });
+    }
+
+    Ok(report)
+}
+
+pub fn format_duration(time: f64) -> String {
+    format!("{:.3}s", time / 1_000_000.0)
+}
+
+pub fn process_analyze_cli(cli: AnalyzeCli) -> anyhow::Result<()> {
+    let mut char: Character = serde_json::from_str(&fs::read_to_string(&cli.input_file)?)?;
+    char.refresh_frame_counts(env::current_dir()?);
+
+    let mut profile = cli.target.profile()?;
+    cli.storage.apply(&mut profile)?;
+
+    let mut errors = char.validate();
+    errors.extend(char.validate_target(&profile));
+
+    if !errors.is_empty() {
+        return Err(ValidationErrors(errors).into());
+    }
+
+    if cli.runs == 0 || cli.duration <= 0 {
+        bail!("Nothing to simulate, --runs and --duration have to be positive");
+    }
+
+    let report = analyze(&char, &AnalysisOptions {
+        runs: cli.runs,
+        duration: cli.duration * 1000,
+        click_interval: cli.click_interval.map(|interval| interval * 1000),
+        seed: cli.seed,
+        target: profile,
+    });
+
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    let monte_carlo = &report.monte_carlo;
+
+    println!(
+        "Simulated {} runs of {}, {} crashed:",
+        monte_carlo.runs,
+        format_duration(monte_carlo.duration as f64),
+        monte_carlo.crashed_runs
+    );
+    for (state, stats) in &monte_carlo.states {
+        println!(
+            "  {state}: {:.1}% of time, {:.1} visits per run, reached in {:.0}% of runs, average stay {}, first entered after {}",
+            stats.time_fraction * 100.0,
+            stats.visits,
+            stats.reached * 100.0,
+            stats.mean_dwell.map(format_duration).unwrap_or("-".to_string()),
+            stats.mean_first_entry.map(format_duration).unwrap_or("-".to_string())
+        );
+    }
+
+    match &report.markov {
+        Ok(markov) => {
+            println!("Markov model:");
+            for (state, stats) in &markov.states {
+                println!(
+                    "  {state}: {:.1}% of time, average stay {}, first entered after {}",
+                    stats.time_fraction * 100.0,
+                    format_duration(stats.mean_dwell),
+                    stats.mean_first_entry.map(format_duration).unwrap_or("never".to_string())
+                );
+            }
+        }
+        Err(err) => println!("Markov model not available: {err}")
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::{State, StateTransition};
+    use either::Either;
+
+    fn state(transitions: &[(&str, StateTransitionTrigger)]) -> State {
+        State {
+            transitions: transitions.iter()
+                .map(|(to_state, trigger)| StateTransition {
+                    to_state: to_state.to_string(),
+                    trigger: trigger.clone(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn character() -> Character {
+        let mut char = Character::default();
+
+        char.states.insert("idle".to_string(), state(&[
+            ("sleep", StateTransitionTrigger::Random { duration_range: Either::Left((1_000_000, 3_000_000)), chance: 2 }),
+        ]));
+        char.states.insert("sleep".to_string(), state(&[
+            ("idle", StateTransitionTrigger::ElapsedTime { duration: 2_000_000 }),
+        ]));
+
+        char
+    }
+
+    #[test]
+    fn markov_matches_expected_times() {
+        let report = markov(&character(), None).unwrap();
+
+        let idle = &report.states["idle"];
+        let sleep = &report.states["sleep"];
+
+        assert_eq!(idle.mean_dwell, 4_000_000.0);
+        assert_eq!(sleep.mean_first_entry, Some(4_000_000.0));
+        assert!((idle.time_fraction - 2.0 / 3.0).abs() < 1e-9);
+        assert!((sleep.time_fraction - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn simulation_agrees_with_markov() {
+        let report = analyze(&character(), &AnalysisOptions {
+            runs: 20,
+            ..Default::default()
+        });
+
+        let markov = report.markov.unwrap();
+
+        for (state, stats) in &report.monte_carlo.states {
+            assert!((stats.time_fraction - markov.states[state].time_fraction).abs() < 0.05, "{state}");
+        }
+    }
+
+    #[test]
+    fn cancelled_analysis_stops() {
+        let report = analyze_with_progress(&character(), &AnalysisOptions::default(), &|_, _| {}, &AtomicBool::new(true));
+
+        assert!(report.is_none());
+    }
+
+    #[test]
+    fn unsolvable_states_are_explained() {
+        let mut char = character();
+
+        char.states.get_mut("sleep").unwrap().transitions.clear();
+
+        let err = markov(&char, None).unwrap_err();
+        assert_eq!(err.to_string(), "State 'sleep' is never left, the character ends up stuck there");
+
+        // Clicks compete with elapsed time, 1 - e^-1 of the time the click comes first
+        let mut char = character();
+        char.states.get_mut("sleep").unwrap().transitions.push(StateTransition {
+            to_state: "idle".to_string(),
+            trigger: StateTransitionTrigger::Clicked,
+        });
+
+        let report = markov(&char, Some(2_000_000)).unwrap();
+        let expected = 2_000_000.0 * (1.0 - (-1.0_f64).exp());
+        assert!((report.states["sleep"].mean_dwell - expected).abs() < 1e-6);
+    }
+}
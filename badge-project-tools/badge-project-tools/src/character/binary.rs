@@ -0,0 +1,163 @@
+use crate::character::error::NameError;
+use anyhow::{bail, ensure};
+
+// Sizes of the bp_*_file_s structs as laid out by the firmware compiler (Xtensa, little-endian,
+// 8 byte aligned int64_t, 4 byte enums, 1 byte bools)
+pub const CHARACTER_FILE_SIZE: usize = 194;
+pub const STATE_TRANSITION_FILE_SIZE: usize = 32;
+pub const ANIMATION_FILE_SIZE: usize = 48;
+pub const SEQUENCE_FRAME_FILE_SIZE: usize = 104;
+pub const STATE_FILE_SIZE: usize = 140;
+pub const ACTION_FILE_SIZE: usize = 132;
+
+/// Writes values in firmware byte order, every gap between fields is filled with zeroes.
+/// Invalid names are still written (truncated), but reported by [BinaryWriter::finish]
+#[derive(Default)]
+pub struct BinaryWriter {
+    data: Vec<u8>,
+    errors: Vec<NameError>
+}
+
+impl BinaryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fills the gap up to the offset with zeroes
+    pub fn pad_to(&mut self, offset: usize) -> &mut Self {
+        assert!(self.data.len() <= offset, "field at {} overlaps offset {offset}", self.data.len());
+        self.data.resize(offset, 0);
+        self
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.u8(value as u8)
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u16_be(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn i64(&mut self, value: i64) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Writes NUL terminated string into a fixed size char array
+    pub fn c_string(&mut self, field: &'static str, value: &str, len: usize) -> &mut Self {
+        if let Some(error) = NameError::check(field, value, len) {
+            self.errors.push(error);
+        }
+
+        let bytes = value.as_bytes();
+        let taken = bytes.iter()
+            .take(len - 1)
+            .take_while(|c| **c != 0)
+            .count();
+
+        self.data.extend_from_slice(&bytes[..taken]);
+        self.data.resize(self.data.len() + len - taken, 0);
+
+        self
+    }
+
+    /// Pads the data to the full size of the struct
+    pub fn finish(&mut self, size: usize) -> Result<Vec<u8>, Vec<NameError>> {
+        self.pad_to(size);
+
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+
+        Ok(std::mem::take(&mut self.data))
+    }
+}
+
+/// Reads values in firmware byte order, counterpart of [BinaryWriter]
+pub struct BinaryReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> BinaryReader<'a> {
+    /// Fails if the data is shorter than the struct size
+    pub fn new(data: &'a [u8], size: usize) -> anyhow::Result<Self> {
+        ensure!(data.len() >= size, "Expected at least {size} bytes, got {}", data.len());
+
+        Ok(Self {
+            data,
+            position: 0,
+        })
+    }
+
+    pub fn skip_to(&mut self, offset: usize) -> &mut Self {
+        assert!(self.position <= offset, "field at {} overlaps offset {offset}", self.position);
+        self.position = offset;
+        self
+    }
+
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let Some(bytes) = self.data.get(self.position..self.position + N) else {
+            bail!("Unexpected end of data at {}", self.position);
+        };
+
+        self.position += N;
+        Ok(bytes.try_into()?)
+    }
+
+    pub fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> anyhow::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => bail!("Invalid bool value {other} at {}", self.position - 1)
+        }
+    }
+
+    pub fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u16_be(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn i64(&mut self) -> anyhow::Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    /// Reads fixed size char array, stopping at the first NUL
+    pub fn c_string(&mut self, len: usize) -> anyhow::Result<String> {
+        let Some(bytes) = self.data.get(self.position..self.position + len) else {
+            bail!("Unexpected end of data at {}", self.position);
+        };
+
+        self.position += len;
+
+        let end = bytes.iter().position(|c| *c == 0).unwrap_or(len);
+        Ok(String::from_utf8_lossy(&bytes[..end]).to_string())
+    }
+}
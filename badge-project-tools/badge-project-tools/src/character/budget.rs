@@ -0,0 +1,253 @@
+use crate::character::repr::{ActionType, Character, StateImage};
+use crate::character::sim::{SimFailure, Simulator};
+use crate::target::TargetProfile;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+
+/// Exploring stops after this many distinct memory layouts, big characters still get checked in reasonable time
+const MAX_EXPLORED_LAYOUTS: usize = 5000;
+
+/// Memory running out on the way through the states
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct BudgetFailure {
+    /// States switched to from the default state, in order
+    pub path: Vec<String>,
+    pub failure: SimFailure,
+    /// There was enough free memory in total, just not in one block
+    pub fragmented: bool
+}
+
+impl Display for BudgetFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "Loading the character runs out of memory: {}", self.failure)?;
+        } else {
+            write!(f, "Switching to {} runs out of memory: {}", self.path_string(), self.failure)?;
+        }
+
+        if self.fragmented {
+            write!(f, " (enough memory is free in total, but it's fragmented)")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BudgetFailure {
+    fn path_string(&self) -> String {
+        self.path.iter()
+            .map(|state| format!("'{state}'"))
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LayerBudget {
+    /// Highest memory use while in or switching to a state of the layer
+    pub peak: u64,
+    /// State the peak was reached for
+    pub peak_state: String,
+    /// Worst share of free memory that isn't in the largest free block, after a switch finished
+    pub fragmentation: f64
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BudgetReport {
+    pub storage_size: u64,
+    pub layers: BTreeMap<u8, LayerBudget>,
+    pub failures: Vec<BudgetFailure>,
+    /// Not every memory layout was checked
+    pub truncated: bool
+}
+
+/// States that can be switched to from the state, actions can switch from anywhere
+fn next_states<'a>(char: &'a Character, state: &str) -> BTreeSet<&'a str> {
+    let mut next = char.actions.values()
+        .map(|action| {
+            let ActionType::SwitchState(state) = &action.ty;
+            state.as_str()
+        })
+        .collect::<BTreeSet<_>>();
+
+    if let Some(state) = char.states.get(state) {
+        next.extend(state.transitions.iter().map(|transition| transition.to_state.as_str()));
+
+        if let StateImage::Animation { next_state, .. } = &state.image {
+            next.insert(next_state);
+        }
+    }
+
+    next
+}
+
+/// Switches through the states, waiting for every cooker and layer loader to finish
+fn replay(char: &Character, target: &TargetProfile, path: &[String]) -> Simulator {
+    let mut sim = Simulator::with_target(char.clone(), 0, target);
+
+    for state in path {
+        if sim.is_crashed() || sim.failures().count() > 0 {
+            break;
+        }
+
+        sim.request_switch(state);
+
+        while sim.task().is_some() && !sim.is_crashed() {
+            sim.complete_task();
+        }
+    }
+
+    sim
+}
+
+/// Goes through every state that can be switched to, the same way the firmware allocates images,
+/// until every memory layout that can happen has been seen.
+/// Time based behaviour is left out, switches are done one at a time after the previous one finished
+pub fn check_memory_budget(char: &Character, target: &TargetProfile) -> BudgetReport {
+    let storage_size = target.storage_size;
+    let mut report = BudgetReport {
+        storage_size,
+        layers: BTreeMap::new(),
+        failures: vec![],
+        truncated: false,
+    };
+
+    let mut seen_layouts = HashSet::new();
+    let mut seen_failures = HashSet::new();
+    let mut pending = VecDeque::from([vec![]]);
+
+    while let Some(path) = pending.pop_front() {
+        let sim = replay(char, target, &path);
+        let allocator = &sim.memory().allocator;
+
+        for (state, bytes) in sim.state_peaks() {
+            let Some(layer) = char.states.get(state).map(|state| state.layer) else {
+                continue;
+            };
+
+            let budget = report.layers.entry(layer).or_default();
+
+            if *bytes > budget.peak || budget.peak_state.is_empty() {
+                budget.peak = *bytes;
+                budget.peak_state = state.clone();
+            }
+        }
+
+        if let Some((_, failure)) = sim.failures().next() {
+            let (requested, free) = match failure {
+                SimFailure::CookingOutOfMemory { requested, .. } => (*requested, storage_size.saturating_sub(allocator.used())),
+                SimFailure::LayerOutOfMemory { requested, .. } => (*requested, storage_size.saturating_sub(allocator.used())),
+                _ => (0, 0)
+            };
+
+            // Same failure is found along many paths, the shortest one is enough
+            if seen_failures.insert(failure.to_string()) {
+                report.failures.push(BudgetFailure {
+                    path,
+                    failure: failure.clone(),
+                    fragmented: requested > 0 && requested <= free,
+                });
+            }
+
+            continue;
+        }
+
+        let free = storage_size.saturating_sub(allocator.used());
+
+        if free > 0 {
+            let fragmentation = 1.0 - allocator.largest_free_block() as f64 / free as f64;
+            let budget = report.layers.entry(sim.current_layer()).or_default();
+
+            budget.fragmentation = budget.fragmentation.max(fragmentation);
+        }
+
+        let mut layout = allocator.existing_allocations()
+            .into_iter()
+            .map(|allocation| (allocation.start, allocation.end))
+            .collect::<Vec<_>>();
+        layout.sort_unstable();
+
+        if !seen_layouts.insert((sim.current_state().to_string(), sim.current_layer(), layout)) {
+            continue;
+        }
+
+        if seen_layouts.len() >= MAX_EXPLORED_LAYOUTS {
+            report.truncated = true;
+            break;
+        }
+
+        for next in next_states(char, sim.current_state()) {
+            let mut next_path = path.clone();
+            next_path.push(next.to_string());
+
+            pending.push_back(next_path);
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::{State, StateTransition, StateTransitionTrigger};
+    use crate::character::sim::allocator::required_space;
+    use crate::image::PixelFormat;
+    use std::path::PathBuf;
+
+    fn single(name: &str, size: u32, layer: u8, layer_load: bool, to_state: &str) -> State {
+        State {
+            layer,
+            image: StateImage::Single {
+                name: name.to_string(),
+                path: PathBuf::new(),
+                width: size,
+                height: size,
+                upscale: false,
+                layer_load,
+                conversion: Default::default(),
+                data: Default::default(),
+            },
+            transitions: vec![StateTransition {
+                to_state: to_state.to_string(),
+                trigger: StateTransitionTrigger::Clicked,
+            }],
+            node_pos: None,
+        }
+    }
+
+    #[test]
+    fn layer_switch_that_doesnt_fit_fails() {
+        let mut char = Character::default();
+
+        // Both layers are loaded at once while switching
+        char.states.insert("idle".to_string(), single("idle", 1000, 0, true, "party"));
+        char.states.insert("party".to_string(), single("party", 1000, 1, true, "idle"));
+
+        let storage_size = required_space(PixelFormat::Rgb565, &Default::default(), 1000, 1000, false) * 2;
+        let report = check_memory_budget(&char, &TargetProfile {
+            storage_size,
+            ..Default::default()
+        });
+
+        assert_eq!(report.failures, vec![]);
+        assert_eq!(report.layers[&1].peak, storage_size);
+        assert!(!report.truncated);
+
+        let report = check_memory_budget(&char, &TargetProfile {
+            storage_size: storage_size - 1,
+            ..Default::default()
+        });
+
+        assert_eq!(report.failures, vec![BudgetFailure {
+            path: vec!["party".to_string()],
+            failure: SimFailure::LayerOutOfMemory {
+                layer: 1,
+                requested: storage_size / 2,
+                largest_free: storage_size / 2 - 1,
+            },
+            fragmented: false,
+        }]);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::character::repr::Character;
+use crate::character::sdcard::{min_card_size, sd_usage, write_disk_image};
+use crate::character::util::is_file_name;
+use crate::character::validation::ValidationErrors;
+use crate::character::{append_vec, build_character_files, ArchiveEntry, BuildSummary};
+use crate::image::PixelFormat;
+use crate::target::{TargetArgs, TargetProfile};
+use anyhow::{bail, ensure, Context};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use tar::Builder;
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Builds several character projects into one archive or SD card folder, optionally as FAT32 disk image",
+    long_about=None
+)]
+pub struct BundleCli {
+    #[arg(required = true, help = "Character JSON files, images are relative to the folder of each file")]
+    inputs: Vec<PathBuf>,
+    #[arg(short, long, help = "Archive (.tar) or SD card folder to write the characters into")]
+    output: Option<PathBuf>,
+    #[arg(long, help = "ID of the character that is selected on the badge, defaults to the first one")]
+    selected: Option<String>,
+    #[arg(long, help = "Size of the SD card in MB, the bundle has to fit onto it")]
+    sd_size: Option<u64>,
+    #[arg(long, help = "FAT32 disk image to write, ready for dd. It's as large as --sd-size, or as small as the bundle allows")]
+    disk_image: Option<PathBuf>,
+    #[arg(long, help = "Format version of the badge firmware to target, overrides the target profile")]
+    format_version: Option<u16>,
+    #[arg(long, help = "Pixel format of images and animation frames, overrides the target profile")]
+    pixel_format: Option<PixelFormat>,
+    #[arg(long, help = "Build the bundle even if characters have validation errors", default_value_t = false)]
+    skip_validation: bool,
+    #[command(flatten)]
+    target: TargetArgs
+}
+
+/// Character project and the folder its images are relative to
+pub struct BundleInput {
+    pub char: Character,
+    pub location: PathBuf
+}
+
+impl BundleInput {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut char: Character = serde_json::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("Failed to read character {}", path.display()))?;
+
+        let location = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from(".")
+        };
+
+        char.refresh_frame_counts(&location);
+
+        Ok(Self {
+            char,
+            location,
+        })
+    }
+}
+
+pub fn process_bundle_cli(cli: BundleCli) -> anyhow::Result<()> {
+    ensure!(cli.output.is_some() || cli.disk_image.is_some(), "Nothing to write, pass --output or --disk-image");
+
+    let mut profile = cli.target.profile()?;
+
+    if let Some(version) = cli.format_version {
+        profile.format_version = version;
+    }
+
+    if let Some(format) = cli.pixel_format {
+        profile.pixel_format = format;
+    }
+
+    profile.check()?;
+
+    let inputs = cli.inputs.iter()
+        .map(BundleInput::load)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if !cli.skip_validation {
+        for input in &inputs {
+            let mut errors = input.char.validate();
+            errors.extend(input.char.validate_target(&profile));
+
+            if !errors.is_empty() {
+                return Err(anyhow::Error::from(ValidationErrors(errors)).context(format!("Character '{}' isn't valid", input.char.id)));
+            }
+        }
+    }
+
+    let files = build_bundle(&inputs, cli.selected.as_deref(), &profile)?;
+
+    let card_size = cli.sd_size.map(|size| size * 1_000_000);
+
+    if let Some(card_size) = card_size {
+        let usage = sd_usage(&files, card_size);
+
+        ensure!(
+            usage.fits(),
+            "Bundle needs {} bytes on the SD card, but only {} bytes fit onto {} MB",
+            usage.used,
+            usage.available,
+            card_size / 1_000_000
+        );
+
+        eprintln!(
+            "Bundle takes {} of {} bytes on the SD card, in clusters of {} bytes",
+            usage.used,
+            usage.available,
+            usage.cluster_size
+        );
+    }
+
+    if let Some(output) = &cli.output {
+        if output.extension().is_some_and(|ext| ext == "tar") {
+            let mut archive = Builder::new(File::create(output)?);
+
+            for (path, data) in &files {
+                append_vec(&mut archive, path, data)?;
+            }
+
+            archive.finish()?;
+        } else {
+            write_bundle_folder(&files, output)?;
+        }
+    }
+
+    if let Some(path) = &cli.disk_image {
+        let size = card_size.unwrap_or_else(|| min_card_size(&files));
+
+        // Only the written parts take space, the rest of the file stays sparse
+        let mut disk = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        disk.set_len(size)?;
+        write_disk_image(&mut disk, size, &files)?;
+
+        eprintln!("Wrote disk image of {size} bytes to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Files of all characters in one SD card layout, only the selected character gets `selected.lock`
+pub fn build_bundle(inputs: &[BundleInput], selected: Option<&str>, profile: &TargetProfile) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut ids = HashSet::new();
+
+    for input in inputs {
+        ensure!(is_file_name(&input.char.id), "Character ID '{}' can't be used as a folder name", input.char.id);
+        ensure!(ids.insert(&input.char.id), "Character '{}' is in the bundle more than once", input.char.id);
+    }
+
+    let selected = match selected {
+        Some(selected) if !inputs.iter().any(|input| input.char.id == selected) => bail!("Selected character '{selected}' isn't in the bundle"),
+        Some(selected) => Some(selected),
+        None => inputs.first().map(|input| input.char.id.as_str())
+    };
+
+    let mut files = vec![];
+
+    for input in inputs {
+        let id = &input.char.id;
+
+        let bar = ProgressBar::new(0).with_style(ProgressStyle::with_template(&format!("Encoding '{id}' {{bar:40}} {{pos}}/{{len}}"))?);
+        let progress = |done, total| {
+            bar.set_length(total as u64);
+            bar.set_position(done as u64);
+        };
+
+        let result = build_character_files(&input.char, &input.location, selected == Some(id), true, profile, &progress);
+        bar.finish_and_clear();
+
+        let (char_files, summary) = result.with_context(|| format!("Failed to build character '{id}'"))?;
+
+        eprintln!("Built '{id}'");
+        BuildSummary {
+            lost_features: profile.codec()?.lost_features(&input.char),
+            ..summary
+        }.print();
+
+        files.extend(char_files);
+    }
+
+    Ok(files)
+}
+
+/// Writes the bundle into an SD card folder. Folders of the bundled characters are replaced,
+/// other characters stay but lose their selection when the bundle has a selected character
+fn write_bundle_folder(files: &[ArchiveEntry], output: &Path) -> anyhow::Result<()> {
+    let characters = output.join("characters");
+    let bundled: HashSet<_> = files.iter()
+        .filter_map(|(path, _)| path.strip_prefix("characters").ok()?.iter().next())
+        .collect();
+
+    // Nothing is removed or written outside of the output folder
+    for (path, _) in files {
+        ensure!(
+            path.components().all(|component| matches!(component, Component::Normal(_))),
+            "Bundle file {} would leave the output folder",
+            path.display()
+        );
+    }
+
+    for id in &bundled {
+        match fs::remove_dir_all(characters.join(id)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+
+    if files.iter().any(|(path, _)| path.ends_with("selected.lock")) && characters.is_dir() {
+        for entry in fs::read_dir(&characters)? {
+            let lock = entry?.path().join("selected.lock");
+
+            if lock.is_file() {
+                fs::remove_file(lock)?;
+            }
+        }
+    }
+
+    for (path, data) in files {
+        let path = output.join(path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, data)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use crate::character::util::is_file_name;
+use anyhow::ensure;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Folder next to the character JSON that keeps encoded images between builds
+pub const CACHE_FOLDER: &str = ".bp-cache";
+
+/// Part of every key. Bump it with every change that encodes the same sources and parameters into different bytes,
+/// like fixes of quantization or compression. `encoders_match_cache_version` fails until it's bumped
+const CACHE_VERSION: u32 = 1;
+
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Hash of the sources of an image and of everything that affects how it's encoded.
+/// FNV-1a, which unlike the hashers of std stays the same between builds of the tools
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheKey(u128);
+
+impl Default for CacheKey {
+    fn default() -> Self {
+        let mut key = Self(FNV_OFFSET);
+        key.add(&CACHE_VERSION.to_le_bytes());
+
+        key
+    }
+}
+
+impl CacheKey {
+    /// Parts are prefixed by their length, so they can't run into each other
+    pub fn add(&mut self, data: &[u8]) -> &mut Self {
+        for &byte in (data.len() as u64).to_le_bytes().iter().chain(data) {
+            self.0 ^= byte as u128;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+
+        self
+    }
+
+    /// Encoding parameters as JSON, the same way they are stored in the character.
+    /// Only maps with non-string keys fail to serialize, which parameters don't have
+    pub fn add_params(&mut self, params: &impl Serialize) -> &mut Self {
+        self.add(&serde_json::to_vec(params).unwrap_or_default())
+    }
+
+    fn file_name(&self) -> String {
+        format!("{:032x}.bin", self.0)
+    }
+}
+
+/// Encoded images of earlier builds of a character, one entry can hold every frame of an animation.
+/// Shared by the encoding threads, entries are written under a unique name first and then moved into place
+pub struct BuildCache {
+    /// Nothing is read or written without a folder
+    folder: Option<PathBuf>,
+    partial_files: AtomicUsize,
+    /// Entries read or written by this build, the rest is removed by `prune`
+    used: Mutex<HashSet<String>>
+}
+
+impl BuildCache {
+    /// Every character has its own folder, so building one character doesn't prune the others next to it.
+    /// The ID has to be a folder name, pruning never reaches outside of the cache
+    pub fn new(location: impl AsRef<Path>, id: &str) -> anyhow::Result<Self> {
+        ensure!(is_file_name(id), "Character ID '{id}' can't be used as a folder name");
+
+        Ok(Self {
+            folder: Some(location.as_ref().join(CACHE_FOLDER).join(id)),
+            partial_files: AtomicUsize::new(0),
+            used: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Every image gets encoded and nothing touches the disk, for checks that only look at the character
+    pub fn disabled() -> Self {
+        Self {
+            folder: None,
+            partial_files: AtomicUsize::new(0),
+            used: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn mark_used(&self, key: &CacheKey) {
+        if let Ok(mut used) = self.used.lock() {
+            used.insert(key.file_name());
+        }
+    }
+
+    /// Images stored under the key, broken entries are treated as missing
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<Vec<u8>>> {
+        let images = fs::read(self.folder.as_ref()?.join(key.file_name()))
+            .ok()
+            .and_then(|entry| decode_entry(&entry))?;
+
+        self.mark_used(key);
+        Some(images)
+    }
+
+    /// Failing to write the cache only makes the next build slower, so errors are ignored
+    pub fn put(&self, key: &CacheKey, images: &[Vec<u8>]) {
+        let Some(folder) = &self.folder else {
+            return;
+        };
+
+        let path = folder.join(key.file_name());
+        let index = self.partial_files.fetch_add(1, Ordering::Relaxed);
+        let partial = path.with_extension(format!("{}-{index}.partial", process::id()));
+
+        // Written aside first, so an interrupted build or another thread with the same images doesn't leave a broken entry
+        let _ = fs::create_dir_all(folder)
+            .and_then(|_| fs::write(&partial, encode_entry(images)))
+            .and_then(|_| fs::rename(&partial, &path));
+
+        self.mark_used(key);
+    }
+
+    /// Removes entries this build didn't use, so images that are gone from the character or encoded differently
+    /// don't pile up. Partial files are left to the builds that are writing them
+    pub fn prune(&self) -> anyhow::Result<()> {
+        let Ok(used) = self.used.lock() else {
+            return Ok(());
+        };
+
+        let Some(folder) = &self.folder else {
+            return Ok(());
+        };
+
+        let entries = match fs::read_dir(folder) {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            entries => entries?
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+
+            if name.ends_with(".bin") && !used.contains(&name) {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Image count, then every image prefixed by its size
+fn encode_entry(images: &[Vec<u8>]) -> Vec<u8> {
+    let mut entry = (images.len() as u32).to_le_bytes().to_vec();
+
+    for image in images {
+        entry.extend((image.len() as u32).to_le_bytes());
+        entry.extend(image);
+    }
+
+    entry
+}
+
+fn decode_entry(mut entry: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let read_u32 = |entry: &mut &[u8]| {
+        let (value, rest) = entry.split_first_chunk::<4>()?;
+        *entry = rest;
+
+        Some(u32::from_le_bytes(*value) as usize)
+    };
+
+    let count = read_u32(&mut entry)?;
+    let mut images = vec![];
+
+    for _ in 0..count {
+        let size = read_u32(&mut entry)?;
+        let (image, rest) = entry.split_at_checked(size)?;
+
+        images.push(image.to_vec());
+        entry = rest;
+    }
+
+    entry.is_empty().then_some(images)
+}
+
+/// Removes every cached image of the characters in the project, for builds that encode everything again
+pub fn clear_build_cache(location: impl AsRef<Path>) -> anyhow::Result<()> {
+    match fs::remove_dir_all(location.as_ref().join(CACHE_FOLDER)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::alpha::AlphaMode;
+    use crate::image::compress::Compression;
+    use crate::image::quantize::Quantization;
+    use crate::image::{encode_pixels, ByteOrder, ConversionOptions, PixelFormat};
+    use image::{Rgba, RgbaImage};
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn entries_keep_every_image() {
+        let images = vec![vec![1, 2, 3], vec![], vec![4]];
+        let entry = encode_entry(&images);
+
+        assert_eq!(decode_entry(&entry), Some(images));
+        assert_eq!(decode_entry(&entry[..entry.len() - 1]), None);
+
+        let mut key = CacheKey::default();
+        let first = *key.add(b"ab").add(b"c");
+        let second = *CacheKey::default().add(b"a").add(b"bc");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn unused_entries_are_pruned() {
+        let location = std::env::temp_dir().join(format!("bp-cache-{}", process::id()));
+        let (old, new) = (*CacheKey::default().add(b"old"), *CacheKey::default().add(b"new"));
+
+        BuildCache::new(&location, "cat").unwrap().put(&old, &[vec![1]]);
+        BuildCache::new(&location, "dog").unwrap().put(&old, &[vec![1]]);
+
+        let cache = BuildCache::new(&location, "cat").unwrap();
+        cache.put(&new, &[vec![2]]);
+        cache.prune().unwrap();
+
+        assert_eq!(cache.get(&old), None);
+        assert_eq!(cache.get(&new), Some(vec![vec![2]]));
+        assert_eq!(BuildCache::new(&location, "dog").unwrap().get(&old), Some(vec![vec![1]]));
+
+        clear_build_cache(&location).unwrap();
+        assert!(BuildCache::new(&location, "..").is_err());
+    }
+
+    #[test]
+    fn disabled_cache_keeps_nothing() {
+        let cache = BuildCache::disabled();
+        let key = *CacheKey::default().add(b"image");
+
+        cache.put(&key, &[vec![1]]);
+        assert_eq!(cache.get(&key), None);
+        assert!(cache.prune().is_ok());
+    }
+
+    #[test]
+    fn encoders_match_cache_version() {
+        let image = RgbaImage::from_fn(7, 5, |x, y| Rgba([(x * 40) as u8, (y * 60) as u8, ((x + y) * 20) as u8, (x * y * 10) as u8]));
+        let mut fingerprint = CacheKey::default();
+
+        for format in PixelFormat::iter() {
+            for alpha in AlphaMode::iter() {
+                for quantization in Quantization::iter() {
+                    let options = ConversionOptions {
+                        alpha,
+                        quantization,
+                        ..Default::default()
+                    };
+
+                    let data = encode_pixels(&image, format, ByteOrder::Little, &options);
+                    fingerprint.add(&data);
+
+                    for compression in Compression::iter() {
+                        fingerprint.add(&compression.compress(&data, format.pixel_size()));
+                    }
+                }
+            }
+        }
+
+        // Changed encoders have to bump CACHE_VERSION, then this is updated to the new fingerprint
+        assert_eq!(fingerprint, CacheKey(0x51cd634591e246d05d855f3769b3af5c), "Encoded images changed, bump CACHE_VERSION");
+    }
+}
@@ -0,0 +1,353 @@
+use crate::character::error::Resource;
+use crate::character::repr::Character;
+use crate::character::unpack::{read_archive_files, read_characters, read_folder_files, UnpackedCharacter};
+use crate::image::PixelFormat;
+use crate::target::{TargetArgs, TargetProfile};
+use image::RgbaImage;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Compares two character archives per state, image, transition, animation and action",
+    long_about=None
+)]
+pub struct DiffArchiveCli {
+    #[arg(help = "Older character archive (.tar) or folder that contains 'characters' folder")]
+    old: PathBuf,
+    #[arg(help = "Newer character archive (.tar) or folder that contains 'characters' folder")]
+    new: PathBuf,
+    #[arg(long, help = "Pixel format the archives were built with, overrides the target profile")]
+    pixel_format: Option<PixelFormat>,
+    #[command(flatten)]
+    target: TargetArgs
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// Descriptions of what changed, like `layer: 0 -> 1`
+    Changed(Vec<String>)
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added => write!(f, "added"),
+            Change::Removed => write!(f, "removed"),
+            Change::Changed(changes) => write!(f, "changed {}", changes.join(", ")),
+        }
+    }
+}
+
+/// Change of a part of a character between two archives
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveChange {
+    pub character: String,
+    pub resource: Resource,
+    pub change: Change
+}
+
+impl Display for ArchiveChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of '{}': {}", self.resource, self.character, self.change)
+    }
+}
+
+pub fn process_diff_archive_cli(cli: DiffArchiveCli) -> anyhow::Result<()> {
+    let mut profile = cli.target.profile()?;
+
+    if let Some(format) = cli.pixel_format {
+        profile.pixel_format = format;
+    }
+
+    profile.check()?;
+
+    let old = read_unpacked(&cli.old, &profile)?;
+    let new = read_unpacked(&cli.new, &profile)?;
+    let changes = diff_archives(&old, &new);
+
+    if changes.is_empty() {
+        println!("No differences");
+    }
+
+    for change in changes {
+        println!("{change}");
+    }
+
+    Ok(())
+}
+
+fn read_unpacked(input: &Path, profile: &TargetProfile) -> anyhow::Result<Vec<UnpackedCharacter>> {
+    let files = if input.is_dir() {
+        read_folder_files(input)?
+    } else {
+        read_archive_files(File::open(input)?)?
+    };
+
+    read_characters(&files, profile)
+}
+
+/// Changes of every character, characters are matched by their ID
+pub fn diff_archives(old: &[UnpackedCharacter], new: &[UnpackedCharacter]) -> Vec<ArchiveChange> {
+    let old = BTreeMap::from_iter(old.iter().map(|unpacked| (&unpacked.character.id, unpacked)));
+    let new = BTreeMap::from_iter(new.iter().map(|unpacked| (&unpacked.character.id, unpacked)));
+
+    let mut changes = vec![];
+
+    for id in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        match (old.get(id), new.get(id)) {
+            (Some(old), Some(new)) => changes.extend(diff_characters(old, new)),
+            (old, _) => changes.push(ArchiveChange {
+                character: id.to_string(),
+                resource: Resource::Character,
+                change: if old.is_some() { Change::Removed } else { Change::Added },
+            })
+        }
+    }
+
+    changes
+}
+
+/// Leaf values of the JSON that differ, nested fields are joined with dots
+fn value_changes(field: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for key in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+                let nested = if field.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{field}.{key}")
+                };
+
+                value_changes(&nested, old.get(key).unwrap_or(&Value::Null), new.get(key).unwrap_or(&Value::Null), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(format!("{field}: {old} -> {new}")),
+        _ => {}
+    }
+}
+
+struct Differ<'a> {
+    character: &'a str,
+    changes: Vec<ArchiveChange>
+}
+
+impl Differ<'_> {
+    fn push(&mut self, resource: Resource, change: Change) {
+        self.changes.push(ArchiveChange {
+            character: self.character.to_string(),
+            resource,
+            change,
+        });
+    }
+
+    /// Compares items of both maps by their JSON, `strip` removes fields that are compared separately
+    fn diff_maps<T: Serialize>(
+        &mut self,
+        old: &HashMap<String, T>,
+        new: &HashMap<String, T>,
+        resource: impl Fn(&String) -> Resource,
+        strip: impl Fn(&mut Value)
+    ) {
+        for name in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+            let value = |item: Option<&T>| item.map(|item| {
+                let mut value = serde_json::to_value(item).unwrap_or_default();
+                strip(&mut value);
+                value
+            });
+
+            match (value(old.get(name)), value(new.get(name))) {
+                (Some(old), Some(new)) => {
+                    let mut changes = vec![];
+                    value_changes("", &old, &new, &mut changes);
+
+                    if !changes.is_empty() {
+                        self.push(resource(name), Change::Changed(changes));
+                    }
+                }
+                (Some(_), None) => self.push(resource(name), Change::Removed),
+                (None, Some(_)) => self.push(resource(name), Change::Added),
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Resource of an unpacked image, images of states are in `images` and animation frames in `animations/<name>`
+fn image_resource(path: &Path) -> Resource {
+    let parts = path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+
+    let stem = |file: &str| file.strip_suffix(".png").unwrap_or(file).to_string();
+
+    match parts.as_slice() {
+        ["animations", animation, frame] => Resource::AnimationFrame {
+            animation: animation.to_string(),
+            index: stem(frame).parse().unwrap_or_default(),
+        },
+        [.., file] => Resource::Image(stem(file)),
+        [] => Resource::Image(String::new())
+    }
+}
+
+fn image_changes(old: &RgbaImage, new: &RgbaImage) -> Vec<String> {
+    if old.dimensions() != new.dimensions() {
+        return vec![format!("size: {}x{} -> {}x{}", old.width(), old.height(), new.width(), new.height())];
+    }
+
+    let changed = old.pixels()
+        .zip(new.pixels())
+        .filter(|(old, new)| old != new)
+        .count();
+
+    if changed == 0 {
+        return vec![];
+    }
+
+    vec![format!("{changed} of {} pixels", old.width() * old.height())]
+}
+
+/// Changes between two versions of the same character
+pub fn diff_characters(old: &UnpackedCharacter, new: &UnpackedCharacter) -> Vec<ArchiveChange> {
+    let mut differ = Differ {
+        character: &new.character.id,
+        changes: vec![],
+    };
+
+    let (old_char, new_char) = (&old.character, &new.character);
+    let mut changes = vec![];
+
+    for (field, old, new) in [
+        ("name", &old_char.name, &new_char.name),
+        ("species", &old_char.species, &new_char.species),
+        ("default_state", &old_char.default_state, &new_char.default_state)
+    ] {
+        value_changes(field, &Value::from(old.as_str()), &Value::from(new.as_str()), &mut changes);
+    }
+
+    if old.format_version != new.format_version {
+        changes.push(format!("format version: {} -> {}", old.format_version, new.format_version));
+    }
+
+    if old.selected != new.selected {
+        changes.push(format!("selected: {} -> {}", old.selected, new.selected));
+    }
+
+    if !changes.is_empty() {
+        differ.push(Resource::Character, Change::Changed(changes));
+    }
+
+    // Unpacking lays out nodes by position of the state, so they aren't part of the archive
+    differ.diff_maps(&old_char.states, &new_char.states, |name| Resource::State(name.clone()), |state| {
+        if let Value::Object(state) = state {
+            state.remove("transitions");
+            state.remove("node_pos");
+        }
+    });
+
+    // Transitions of added or removed states are part of the state
+    for state in old_char.states.keys().filter(|name| new_char.states.contains_key(*name)).collect::<BTreeSet<_>>() {
+        let transitions = |char: &Character| char.states[state].transitions.iter()
+            .map(|transition| (transition.to_state.clone(), transition.clone()))
+            .collect::<HashMap<_, _>>();
+
+        differ.diff_maps(&transitions(old_char), &transitions(new_char), |to_state| Resource::Transition {
+            state: state.clone(),
+            to_state: to_state.clone(),
+        }, |_| {});
+    }
+
+    differ.diff_maps(&old_char.animations, &new_char.animations, |name| Resource::Animation(name.clone()), |_| {});
+    differ.diff_maps(&old_char.actions, &new_char.actions, |name| Resource::Action(name.clone()), |_| {});
+
+    let old_images = BTreeMap::from_iter(old.images.iter().map(|(path, image)| (path, image)));
+    let new_images = BTreeMap::from_iter(new.images.iter().map(|(path, image)| (path, image)));
+
+    for path in old_images.keys().chain(new_images.keys()).collect::<BTreeSet<_>>() {
+        let resource = image_resource(path);
+
+        match (old_images.get(path), new_images.get(path)) {
+            (Some(old), Some(new)) => {
+                let changes = image_changes(old, new);
+
+                if !changes.is_empty() {
+                    differ.push(resource, Change::Changed(changes));
+                }
+            }
+            (Some(_), None) => differ.push(resource, Change::Removed),
+            (None, Some(_)) => differ.push(resource, Change::Added),
+            (None, None) => {}
+        }
+    }
+
+    differ.changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::{StateTransition, StateTransitionTrigger};
+    use image::Rgba;
+
+    fn unpacked(character: Character, images: Vec<(PathBuf, RgbaImage)>) -> UnpackedCharacter {
+        UnpackedCharacter {
+            character,
+            images,
+            selected: false,
+            format_version: 4,
+        }
+    }
+
+    #[test]
+    fn changes_are_attributed_to_resources() {
+        let old = Character::default();
+        let mut new = Character::default();
+
+        let idle = new.states.get_mut("idle").unwrap();
+        idle.layer = 2;
+        idle.node_pos = Some((10.0, 20.0));
+        idle.transitions.push(StateTransition {
+            to_state: "idle".to_string(),
+            trigger: StateTransitionTrigger::Clicked,
+        });
+
+        let image = RgbaImage::new(2, 2);
+        let mut edited = image.clone();
+        edited.put_pixel(1, 1, Rgba([255, 0, 0, 255]));
+
+        let changes = diff_characters(
+            &unpacked(old, vec![(PathBuf::from("images/logo.png"), image.clone()), (PathBuf::from("animations/wave/1.png"), image)]),
+            &unpacked(new, vec![(PathBuf::from("images/logo.png"), edited)])
+        );
+
+        let character = Character::default().id;
+        let change = |resource, change| ArchiveChange {
+            character: character.clone(),
+            resource,
+            change,
+        };
+
+        assert_eq!(changes, vec![
+            change(Resource::State("idle".to_string()), Change::Changed(vec!["layer: 0 -> 2".to_string()])),
+            change(Resource::Transition {
+                state: "idle".to_string(),
+                to_state: "idle".to_string(),
+            }, Change::Added),
+            change(Resource::AnimationFrame {
+                animation: "wave".to_string(),
+                index: 1,
+            }, Change::Removed),
+            change(Resource::Image("logo".to_string()), Change::Changed(vec!["1 of 4 pixels".to_string()])),
+        ]);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::character::cache::{BuildCache, CacheKey};
+use crate::character::error::{CharacterBuildError, Resource};
+use crate::character::repr::{AnimationFrameSource, Character, StateImage};
+use crate::image::animated::{decode_animated, trim_frames};
+use crate::image::sheet::SheetLayout;
+use crate::image::{encode_image, encode_image_data, ByteOrder, ConversionOptions, PixelFormat};
+use crate::target::TargetProfile;
+use image::DynamicImage;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Called with the number of finished and of all encode jobs
+pub type Progress<'a> = &'a (dyn Fn(usize, usize) + Sync);
+
+/// Size of the image data, upscaled images are stored at half the size
+fn real_size((width, height): (u32, u32), upscale: bool) -> (u32, u32) {
+    if upscale {
+        (width / 2, height / 2)
+    } else {
+        (width, height)
+    }
+}
+
+pub enum EncodeSource {
+    Image(PathBuf),
+    /// Animated file or sprite sheet that gives all frames of the animation
+    Frames(AnimationFrameSource)
+}
+
+/// Source image of the archive and how it's encoded, one per resource
+pub struct EncodeJob {
+    pub resource: Resource,
+    pub source: EncodeSource,
+    pub size: (u32, u32),
+    pub byte_order: ByteOrder,
+    pub conversion: ConversionOptions
+}
+
+pub struct Encoded {
+    pub images: Vec<Vec<u8>>,
+    /// Reused from the build cache instead of encoded
+    pub cached: bool
+}
+
+fn read_source(location: &Path, resource: &Resource, path: &Path) -> Result<Vec<u8>, CharacterBuildError> {
+    fs::read(location.join(path)).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => CharacterBuildError::MissingImage {
+            resource: resource.clone(),
+            path: path.to_path_buf(),
+        },
+        _ => CharacterBuildError::UndecodableImage {
+            resource: resource.clone(),
+            path: path.to_path_buf(),
+            reason: err.to_string(),
+        }
+    })
+}
+
+impl EncodeJob {
+    fn path(&self) -> &Path {
+        match &self.source {
+            EncodeSource::Image(path) => path,
+            EncodeSource::Frames(AnimationFrameSource::Sheet { sheet, .. }) => &sheet.image,
+            EncodeSource::Frames(AnimationFrameSource::File { path, .. }) => path,
+            EncodeSource::Frames(AnimationFrameSource::Indexed { .. } | AnimationFrameSource::List(_)) => Path::new("")
+        }
+    }
+
+    /// Encodes the images of the job, reused from the build cache if neither the sources nor the encoding changed
+    pub fn encode(&self, location: &Path, format: PixelFormat, cache: &BuildCache) -> Result<Encoded, CharacterBuildError> {
+        let path = self.path();
+        let data = read_source(location, &self.resource, path)?;
+
+        let mut key = CacheKey::default();
+        key.add(&data)
+            .add_params(&(self.size, format, self.byte_order, &self.conversion));
+
+        match &self.source {
+            EncodeSource::Frames(AnimationFrameSource::File { first, last, .. }) => {
+                key.add_params(&(first, last));
+            }
+            EncodeSource::Frames(AnimationFrameSource::Sheet { sheet, .. }) => {
+                key.add_params(&(&sheet.layout, &sheet.frames));
+
+                // Missing atlas fails the slicing below, nothing gets cached then
+                if let SheetLayout::Atlas(atlas) = &sheet.layout {
+                    key.add(&fs::read(location.join(atlas)).unwrap_or_default());
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(images) = cache.get(&key) {
+            return Ok(Encoded {
+                images,
+                cached: true,
+            });
+        }
+
+        let (width, height) = self.size;
+
+        let images = match &self.source {
+            EncodeSource::Image(_) => encode_image_data(&data, width, height, format, self.byte_order, &self.conversion)
+                .map(|image| vec![image])
+                .map_err(anyhow::Error::from),
+            EncodeSource::Frames(frames) => {
+                let decoded = match frames {
+                    AnimationFrameSource::File { first, last, .. } => decode_animated(&data).and_then(|frames| trim_frames(frames, *first, *last)),
+                    AnimationFrameSource::Sheet { sheet, .. } => sheet.slice_frames(&data, location),
+                    AnimationFrameSource::Indexed { .. } | AnimationFrameSource::List(_) => Ok(vec![])
+                };
+
+                decoded.map(|frames| frames.into_iter()
+                    .map(|frame| encode_image(&DynamicImage::ImageRgba8(frame.image), width, height, format, self.byte_order, &self.conversion))
+                    .collect())
+            }
+        };
+
+        let images = images.map_err(|err| CharacterBuildError::UndecodableImage {
+            resource: self.resource.clone(),
+            path: path.to_path_buf(),
+            reason: err.to_string(),
+        })?;
+
+        cache.put(&key, &images);
+
+        Ok(Encoded {
+            images,
+            cached: false,
+        })
+    }
+}
+
+/// Every image the archive needs, in the order the archive is built. Images used by several states are encoded once
+pub fn encode_jobs(char: &Character, profile: &TargetProfile) -> Vec<EncodeJob> {
+    let mut jobs = vec![];
+    let mut seen_images = HashSet::new();
+
+    let mut add_image = |name: &String, path: &PathBuf, size: (u32, u32), upscale: bool, conversion: &ConversionOptions| {
+        if seen_images.insert(name.clone()) {
+            jobs.push(EncodeJob {
+                resource: Resource::Image(name.clone()),
+                source: EncodeSource::Image(path.clone()),
+                size: real_size(size, upscale),
+                byte_order: profile.image_byte_order,
+                conversion: conversion.clone(),
+            });
+        }
+    };
+
+    for state in BTreeMap::from_iter(&char.states).into_values() {
+        match &state.image {
+            StateImage::Single { name, path, width, height, upscale, conversion, .. } => {
+                add_image(name, path, (*width, *height), *upscale, conversion);
+            }
+            StateImage::Sequence { frames, .. } => {
+                for frame in frames {
+                    add_image(&frame.name, &frame.path, (frame.width, frame.height), frame.upscale, &frame.conversion);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (anim_name, anim) in BTreeMap::from_iter(&char.animations) {
+        let size = (anim.real_width(), anim.real_height());
+
+        let job = |resource, source| EncodeJob {
+            resource,
+            source,
+            size,
+            byte_order: profile.frame_byte_order,
+            conversion: anim.conversion.clone(),
+        };
+
+        if let AnimationFrameSource::File { .. } | AnimationFrameSource::Sheet { .. } = &anim.frames {
+            jobs.push(job(Resource::Animation(anim_name.clone()), EncodeSource::Frames(anim.frames.clone())));
+        }
+
+        for (index, path) in anim.frames.paths() {
+            let resource = Resource::AnimationFrame {
+                animation: anim_name.clone(),
+                index,
+            };
+
+            jobs.push(job(resource, EncodeSource::Image(path)));
+        }
+    }
+
+    jobs
+}
+
+/// Encodes the jobs across all CPU cores, results are looked up by the resource of the job
+pub fn encode_all(
+    jobs: Vec<EncodeJob>,
+    location: &Path,
+    cache: &BuildCache,
+    format: PixelFormat,
+    progress: Progress
+) -> HashMap<Resource, Result<Encoded, CharacterBuildError>> {
+    let total = jobs.len();
+    let done = AtomicUsize::new(0);
+
+    progress(0, total);
+
+    jobs.into_par_iter()
+        .map(|job| {
+            let encoded = job.encode(location, format, cache);
+            progress(done.fetch_add(1, Ordering::Relaxed) + 1, total);
+
+            (job.resource, encoded)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::State;
+
+    #[test]
+    fn shared_images_are_encoded_once() {
+        let mut char = Character::default();
+
+        for state_name in ["idle", "wave"] {
+            char.states.insert(state_name.to_string(), State {
+                image: StateImage::Single {
+                    name: "logo".to_string(),
+                    path: PathBuf::from("logo.png"),
+                    width: 100,
+                    height: 80,
+                    upscale: true,
+                    layer_load: false,
+                    conversion: Default::default(),
+                    data: Default::default(),
+                },
+                ..Default::default()
+            });
+        }
+
+        let jobs = encode_jobs(&char, &TargetProfile::default());
+
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].resource, Resource::Image("logo".to_string()));
+        assert_eq!(jobs[0].size, (50, 40));
+    }
+}
@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// Part of the character that a build error is attributed to
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum Resource {
+    Character,
+    State(String),
+    Transition {
+        state: String,
+        to_state: String
+    },
+    Image(String),
+    Animation(String),
+    AnimationFrame {
+        animation: String,
+        index: usize
+    },
+    Action(String)
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resource::Character => write!(f, "Character"),
+            Resource::State(name) => write!(f, "State '{name}'"),
+            Resource::Transition { state, to_state } => write!(f, "Transition '{state}' -> '{to_state}'"),
+            Resource::Image(name) => write!(f, "Image '{name}'"),
+            Resource::Animation(name) => write!(f, "Animation '{name}'"),
+            Resource::AnimationFrame { animation, index } => write!(f, "Frame #{index} of animation '{animation}'"),
+            Resource::Action(name) => write!(f, "Action '{name}'"),
+        }
+    }
+}
+
+/// Problem with a fixed size name field, before it's attributed to a resource
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameError {
+    TooLong {
+        field: &'static str,
+        length: usize,
+        max_len: usize
+    },
+    ContainsNul {
+        field: &'static str
+    }
+}
+
+impl NameError {
+    /// Checks if the value fits into char array of the size, including NUL terminator
+    pub fn check(field: &'static str, value: &str, size: usize) -> Option<NameError> {
+        if value.contains('\0') {
+            return Some(NameError::ContainsNul { field });
+        }
+
+        if value.len() >= size {
+            return Some(NameError::TooLong {
+                field,
+                length: value.len(),
+                max_len: size - 1,
+            });
+        }
+
+        None
+    }
+
+    pub fn at(self, resource: Resource) -> CharacterBuildError {
+        match self {
+            NameError::TooLong { field, length, max_len } => CharacterBuildError::NameTooLong {
+                resource,
+                field,
+                length,
+                max_len,
+            },
+            NameError::ContainsNul { field } => CharacterBuildError::NameContainsNul {
+                resource,
+                field,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum CharacterBuildError {
+    NameTooLong {
+        resource: Resource,
+        field: &'static str,
+        length: usize,
+        max_len: usize
+    },
+    NameContainsNul {
+        resource: Resource,
+        field: &'static str
+    },
+    MissingImage {
+        resource: Resource,
+        path: PathBuf
+    },
+    UndecodableImage {
+        resource: Resource,
+        path: PathBuf,
+        reason: String
+    },
+    SizeMismatch {
+        resource: Resource,
+        expected: (u32, u32),
+        actual: (u32, u32)
+    }
+}
+
+impl CharacterBuildError {
+    pub fn resource(&self) -> &Resource {
+        match self {
+            CharacterBuildError::NameTooLong { resource, .. } => resource,
+            CharacterBuildError::NameContainsNul { resource, .. } => resource,
+            CharacterBuildError::MissingImage { resource, .. } => resource,
+            CharacterBuildError::UndecodableImage { resource, .. } => resource,
+            CharacterBuildError::SizeMismatch { resource, .. } => resource,
+        }
+    }
+
+    /// Description of the error without the resource
+    pub fn message(&self) -> String {
+        match self {
+            CharacterBuildError::NameTooLong { field, length, max_len, .. } =>
+                format!("{field} is {length} bytes long, but only {max_len} bytes fit"),
+            CharacterBuildError::NameContainsNul { field, .. } =>
+                format!("{field} contains NUL character"),
+            CharacterBuildError::MissingImage { path, .. } =>
+                format!("image file {} doesn't exist", path.display()),
+            CharacterBuildError::UndecodableImage { path, reason, .. } =>
+                format!("image file {} can't be decoded: {reason}", path.display()),
+            CharacterBuildError::SizeMismatch { expected, actual, .. } =>
+                format!(
+                    "used with size {}x{}, but it was already used with size {}x{}",
+                    actual.0, actual.1, expected.0, expected.1
+                ),
+        }
+    }
+}
+
+impl Display for CharacterBuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.resource(), self.message())
+    }
+}
+
+impl std::error::Error for CharacterBuildError {}
+
+/// All errors found while building the character, so they can be reported at once
+#[derive(Clone, Debug)]
+pub struct CharacterBuildErrors(pub Vec<CharacterBuildError>);
+
+impl Display for CharacterBuildErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Found {} error(s) while building the character:", self.0.len())?;
+
+        for error in &self.0 {
+            write!(f, "\n  {error}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for CharacterBuildErrors {}
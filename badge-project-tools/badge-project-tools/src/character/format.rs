@@ -0,0 +1,425 @@
+use crate::bp_data_FORMAT_VERSION;
+use crate::character::binary::{ANIMATION_FILE_SIZE, SEQUENCE_FRAME_FILE_SIZE};
+use crate::character::error::Resource;
+use crate::character::repr::{Character, FrameEncoding, StateImage};
+use crate::image::compress::Compression;
+use anyhow::{anyhow, bail, ensure};
+use std::fmt::{Display, Formatter};
+
+/// Version the repr types are encoded in by [BinaryRepr](crate::character::repr::BinaryRepr)
+pub const CURRENT_FORMAT_VERSION: u16 = bp_data_FORMAT_VERSION;
+
+/// Kinds of binary files found in the character archive, images aren't versioned
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    Character,
+    State,
+    Transition,
+    SequenceFrame,
+    Animation,
+    Action
+}
+
+/// Feature used by the character that the target format version can't store
+#[derive(Clone, Debug)]
+pub struct LostFeature {
+    pub location: String,
+    pub description: String
+}
+
+impl Display for LostFeature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.description)
+    }
+}
+
+/// Writes binaries in the layout of a specific format version
+pub trait FormatCodec: Sync {
+    fn version(&self) -> u16;
+
+    /// Lists everything that would get dropped when the character is written in this version
+    fn lost_features(&self, character: &Character) -> Vec<LostFeature>;
+
+    /// Converts file encoded in the current layout into layout of this version
+    fn encode(&self, kind: FileKind, current: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Upgrades files of one format version to the next one
+pub trait Migration: Sync {
+    fn source_version(&self) -> u16;
+
+    fn migrate(&self, kind: FileKind, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+}
+
+struct CurrentCodec;
+
+impl FormatCodec for CurrentCodec {
+    fn version(&self) -> u16 {
+        CURRENT_FORMAT_VERSION
+    }
+
+    fn lost_features(&self, _: &Character) -> Vec<LostFeature> {
+        vec![]
+    }
+
+    fn encode(&self, _: FileKind, current: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(current)
+    }
+}
+
+/// First version with compressed images, files get the layout of every image file appended
+pub const COMPRESSION_FORMAT_VERSION: u16 = 2;
+/// First version where animation frames can store only what changed
+pub const DELTA_FRAMES_FORMAT_VERSION: u16 = 3;
+/// First version where repeated animation frames are stored once and read through a frame map
+pub const FRAME_MAP_FORMAT_VERSION: u16 = 4;
+
+// Sizes of version 1 files that changed since
+const V1_ANIMATION_FILE_SIZE: usize = 40;
+const V1_SEQUENCE_FRAME_FILE_SIZE: usize = 88;
+// Version 1 animation fields end with the upscale flag, followed by padding or the frame data
+const ANIMATION_FIELDS_END: usize = 33;
+// Delta frames flag of the animation file, padding before version 3
+const ANIMATION_DELTA_FRAMES: usize = 33;
+// Frame map flag of the animation file, padding before version 4
+const ANIMATION_FRAME_MAP: usize = 34;
+// Single image data of the state file, union padding in version 1
+const STATE_IMAGE_DATA: std::ops::Range<usize> = 84..96;
+
+fn resize_animation(mut data: Vec<u8>, size: usize) -> anyhow::Result<Vec<u8>> {
+    ensure!(data.len() >= ANIMATION_FIELDS_END, "Animation file is too small");
+
+    data.truncate(ANIMATION_FIELDS_END);
+    data.resize(size, 0);
+
+    Ok(data)
+}
+
+fn set_version(data: &mut [u8], version: u16) -> anyhow::Result<()> {
+    ensure!(data.len() >= 2, "Character file is too small");
+    data[0..2].copy_from_slice(&version.to_le_bytes());
+
+    Ok(())
+}
+
+fn compressed_images(character: &Character) -> Vec<LostFeature> {
+    let compressed = |location: Resource, compression: Compression| {
+        (compression != Compression::None).then(|| LostFeature {
+            location: location.to_string(),
+            description: format!("{compression} compression, images are stored uncompressed"),
+        })
+    };
+
+    let states = character.states.values().flat_map(|state| match &state.image {
+        StateImage::Single { name, conversion, .. } => vec![compressed(Resource::Image(name.clone()), conversion.compression)],
+        StateImage::Sequence { frames, .. } => frames.iter()
+            .map(|frame| compressed(Resource::Image(frame.name.clone()), frame.conversion.compression))
+            .collect(),
+        _ => vec![]
+    });
+
+    let animations = character.animations.iter()
+        .map(|(name, animation)| compressed(Resource::Animation(name.clone()), animation.conversion.compression));
+
+    let mut features = states.chain(animations).flatten().collect::<Vec<_>>();
+    features.sort_by(|a, b| a.location.cmp(&b.location));
+    features.dedup_by(|a, b| a.location == b.location);
+
+    features
+}
+
+fn delta_animations(character: &Character) -> Vec<LostFeature> {
+    let mut features = character.animations.iter()
+        .filter(|(_, animation)| animation.encoding == FrameEncoding::Delta)
+        .map(|(name, _)| LostFeature {
+            location: Resource::Animation(name.clone()).to_string(),
+            description: "Delta frames, every frame is stored whole".to_string(),
+        })
+        .collect::<Vec<_>>();
+    features.sort_by(|a, b| a.location.cmp(&b.location));
+
+    features
+}
+
+fn deduplicated_animations(character: &Character) -> Vec<LostFeature> {
+    let mut features = character.animations.iter()
+        .filter(|(_, animation)| animation.deduplicate_frames)
+        .map(|(name, _)| LostFeature {
+            location: Resource::Animation(name.clone()).to_string(),
+            description: "Deduplicated frames, repeated frames are stored again".to_string(),
+        })
+        .collect::<Vec<_>>();
+    features.sort_by(|a, b| a.location.cmp(&b.location));
+
+    features
+}
+
+/// Version 3, every animation frame has its own file
+struct V3Codec;
+
+impl FormatCodec for V3Codec {
+    fn version(&self) -> u16 {
+        3
+    }
+
+    fn lost_features(&self, character: &Character) -> Vec<LostFeature> {
+        deduplicated_animations(character)
+    }
+
+    fn encode(&self, kind: FileKind, mut current: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match kind {
+            FileKind::Character => set_version(&mut current, self.version())?,
+            FileKind::Animation => {
+                ensure!(current.len() > ANIMATION_FRAME_MAP, "Animation file is too small");
+                current[ANIMATION_FRAME_MAP] = 0;
+            }
+            _ => {}
+        }
+
+        Ok(current)
+    }
+}
+
+/// Version 2, animation frames are always whole
+struct V2Codec;
+
+impl FormatCodec for V2Codec {
+    fn version(&self) -> u16 {
+        2
+    }
+
+    fn lost_features(&self, character: &Character) -> Vec<LostFeature> {
+        let mut features = delta_animations(character);
+        features.extend(deduplicated_animations(character));
+
+        features
+    }
+
+    fn encode(&self, kind: FileKind, mut current: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match kind {
+            FileKind::Character => set_version(&mut current, self.version())?,
+            FileKind::Animation => {
+                ensure!(current.len() > ANIMATION_FRAME_MAP, "Animation file is too small");
+                current[ANIMATION_DELTA_FRAMES] = 0;
+                current[ANIMATION_FRAME_MAP] = 0;
+            }
+            _ => {}
+        }
+
+        Ok(current)
+    }
+}
+
+/// Version 1, images are always uncompressed
+struct V1Codec;
+
+impl FormatCodec for V1Codec {
+    fn version(&self) -> u16 {
+        1
+    }
+
+    fn lost_features(&self, character: &Character) -> Vec<LostFeature> {
+        let mut features = compressed_images(character);
+        features.extend(delta_animations(character));
+        features.extend(deduplicated_animations(character));
+
+        features
+    }
+
+    fn encode(&self, kind: FileKind, mut current: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match kind {
+            FileKind::Character => set_version(&mut current, self.version())?,
+            FileKind::Animation => current = resize_animation(current, V1_ANIMATION_FILE_SIZE)?,
+            FileKind::SequenceFrame => current.truncate(V1_SEQUENCE_FRAME_FILE_SIZE),
+            FileKind::State => {
+                if current.len() >= STATE_IMAGE_DATA.end {
+                    current[STATE_IMAGE_DATA].fill(0);
+                }
+            }
+            FileKind::Transition | FileKind::Action => {}
+        }
+
+        Ok(current)
+    }
+}
+
+/// Appends uncompressed image data to files describing images
+struct V1ToV2;
+
+impl Migration for V1ToV2 {
+    fn source_version(&self) -> u16 {
+        1
+    }
+
+    fn migrate(&self, kind: FileKind, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match kind {
+            FileKind::Character => set_version(&mut data, COMPRESSION_FORMAT_VERSION)?,
+            FileKind::Animation => data = resize_animation(data, ANIMATION_FILE_SIZE)?,
+            FileKind::SequenceFrame => data.resize(SEQUENCE_FRAME_FILE_SIZE, 0),
+            // Zeroed padding is uncompressed data of unknown size
+            FileKind::State | FileKind::Transition | FileKind::Action => {}
+        }
+
+        Ok(data)
+    }
+}
+
+/// Zeroed padding already means whole frames
+struct V2ToV3;
+
+impl Migration for V2ToV3 {
+    fn source_version(&self) -> u16 {
+        2
+    }
+
+    fn migrate(&self, kind: FileKind, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if kind == FileKind::Character {
+            set_version(&mut data, DELTA_FRAMES_FORMAT_VERSION)?;
+        }
+
+        Ok(data)
+    }
+}
+
+/// Zeroed padding means every frame has its own file
+struct V3ToV4;
+
+impl Migration for V3ToV4 {
+    fn source_version(&self) -> u16 {
+        3
+    }
+
+    fn migrate(&self, kind: FileKind, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if kind == FileKind::Character {
+            set_version(&mut data, FRAME_MAP_FORMAT_VERSION)?;
+        }
+
+        Ok(data)
+    }
+}
+
+// Codecs for every version the tools can write, newest first.
+// Whenever format.hpp changes, the previous layout should get its own codec here
+static CODECS: &[&dyn FormatCodec] = &[&CurrentCodec, &V3Codec, &V2Codec, &V1Codec];
+
+// Each migration upgrades files by one version, until they reach the current version
+static MIGRATIONS: &[&dyn Migration] = &[&V1ToV2, &V2ToV3, &V3ToV4];
+
+pub fn supported_versions() -> impl Iterator<Item = u16> {
+    CODECS.iter().map(|codec| codec.version())
+}
+
+pub fn current_codec() -> &'static dyn FormatCodec {
+    CODECS[0]
+}
+
+pub fn find_codec(version: u16) -> anyhow::Result<&'static dyn FormatCodec> {
+    CODECS.iter()
+        .find(|codec| codec.version() == version)
+        .copied()
+        .ok_or_else(|| anyhow!(
+            "Format version {version} is not supported, supported versions: {}",
+            supported_versions().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+        ))
+}
+
+/// Format version is always the first field of character.bin, regardless of the version
+pub fn read_format_version(character_bin: &[u8]) -> anyhow::Result<u16> {
+    let Some(bytes) = character_bin.get(0..2) else {
+        bail!("Character file is too small to contain format version");
+    };
+
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Upgrades file of the specified version into the current layout
+pub fn migrate_file(version: u16, kind: FileKind, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    ensure!(
+        version <= CURRENT_FORMAT_VERSION,
+        "Format version {version} is newer than the newest supported version {CURRENT_FORMAT_VERSION}"
+    );
+
+    for from_version in version..CURRENT_FORMAT_VERSION {
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.source_version() == from_version) else {
+            bail!("Format version {from_version} can't be migrated to version {}", from_version + 1);
+        };
+
+        data = migration.migrate(kind, data)?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_version_is_registered() {
+        assert_eq!(current_codec().version(), CURRENT_FORMAT_VERSION);
+        assert!(find_codec(CURRENT_FORMAT_VERSION).is_ok());
+        assert!(find_codec(CURRENT_FORMAT_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn migration_rejects_unknown_versions() {
+        let data = vec![1, 2, 3];
+
+        assert_eq!(migrate_file(CURRENT_FORMAT_VERSION, FileKind::State, data.clone()).unwrap(), data);
+        assert!(migrate_file(CURRENT_FORMAT_VERSION + 1, FileKind::State, data.clone()).is_err());
+        assert!(migrate_file(0, FileKind::State, data).is_err());
+    }
+
+    #[test]
+    fn older_versions_round_trip_plain_files() {
+        use crate::character::repr::{Animation, BinaryRepr, SequenceFrame};
+
+        let files = [
+            (FileKind::Character, Character::default().to_bin().unwrap(), 194),
+            (FileKind::Animation, Animation::default().to_bin().unwrap(), V1_ANIMATION_FILE_SIZE),
+            (FileKind::SequenceFrame, SequenceFrame::default().to_bin().unwrap(), V1_SEQUENCE_FRAME_FILE_SIZE),
+        ];
+
+        for version in supported_versions() {
+            for (kind, current, v1_size) in &files {
+                let encoded = find_codec(version).unwrap().encode(*kind, current.clone()).unwrap();
+
+                if version == 1 {
+                    assert_eq!(encoded.len(), *v1_size, "{kind:?}");
+                }
+
+                assert_eq!(&migrate_file(version, *kind, encoded).unwrap(), current, "{kind:?} of version {version}");
+            }
+        }
+
+        let codec = find_codec(1).unwrap();
+
+        let mut character = Character::default();
+        character.animations.insert("wave".to_string(), Animation {
+            conversion: crate::image::ConversionOptions {
+                compression: Compression::Rle,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        assert_eq!(codec.lost_features(&character).len(), 1);
+        assert!(current_codec().lost_features(&character).is_empty());
+
+        character.animations.get_mut("wave").unwrap().encoding = FrameEncoding::Delta;
+        assert_eq!(codec.lost_features(&character).len(), 2);
+        assert_eq!(find_codec(2).unwrap().lost_features(&character).len(), 1);
+
+        character.animations.get_mut("wave").unwrap().deduplicate_frames = true;
+        assert_eq!(find_codec(3).unwrap().lost_features(&character).len(), 1);
+        assert!(current_codec().lost_features(&character).is_empty());
+
+        // Older versions read every frame from its own file
+        let mapped = Animation {
+            frame_map: true,
+            ..Default::default()
+        };
+        let encoded = find_codec(3).unwrap().encode(FileKind::Animation, mapped.to_bin().unwrap()).unwrap();
+        assert_eq!(encoded[ANIMATION_FRAME_MAP], 0);
+    }
+}
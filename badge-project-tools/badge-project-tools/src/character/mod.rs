@@ -1,14 +1,38 @@
-use crate::character::repr::{AnimationFrameSource, BinaryRepr, Character, StateImage};
-use crate::image::encode_image_data;
-use std::fs::File;
+use crate::character::format::{FileKind, FormatCodec, LostFeature, COMPRESSION_FORMAT_VERSION, DELTA_FRAMES_FORMAT_VERSION, FRAME_MAP_FORMAT_VERSION};
+use crate::character::error::{CharacterBuildError, CharacterBuildErrors, NameError, Resource};
+use crate::character::validation::ValidationErrors;
+use crate::character::repr::{Animation, AnimationFrameSource, BinaryRepr, Character, FrameEncoding, ImageData, StateImage};
+use crate::character::cache::{clear_build_cache, BuildCache};
+use crate::character::encode::{encode_all, encode_jobs, Encoded, Progress};
+use crate::image::compress::Compression;
+use crate::image::delta::FrameLayout;
+use crate::image::{encode_image, ConversionOptions, PixelFormat};
+use crate::target::{TargetArgs, TargetProfile};
+use crate::{bp_data_ACTION_DISPLAY_MAX_LEN, bp_data_IMAGE_NAME_MAX_LEN, bp_data_NAME_MAX_LEN, bp_data_SPECIES_MAX_LEN};
 use std::path::{Path, PathBuf};
 use std::{env, fs};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
 use std::io::Write;
 use tar::{Builder, Header};
+use anyhow::ensure;
+use indicatif::{ProgressBar, ProgressStyle};
 
 pub mod repr;
+pub mod binary;
+pub mod error;
+pub mod format;
 pub mod util;
+pub mod unpack;
+pub mod validation;
+pub mod sim;
+pub mod analysis;
+pub mod budget;
+pub mod cache;
+pub mod encode;
+pub mod diff;
+pub mod sdcard;
+pub mod bundle;
 
 #[derive(clap::Parser, Debug)]
 #[command(
@@ -21,183 +45,702 @@ pub struct CharacterCli {
     #[arg(help = "Output file")]
     output_file: PathBuf,
     #[arg(short = 's', help = "To include selected.lock into the archive", default_value_t = false)]
-    include_selected: bool
+    include_selected: bool,
+    #[arg(long, help = "Format version of the badge firmware to target, overrides the target profile")]
+    format_version: Option<u16>,
+    #[arg(long, help = "Pixel format of images and animation frames, overrides the target profile")]
+    pixel_format: Option<PixelFormat>,
+    #[arg(long, help = "Build the archive even if the character has validation errors", default_value_t = false)]
+    skip_validation: bool,
+    #[arg(long, help = "Encode every image again instead of reusing the build cache next to the character", default_value_t = false)]
+    clean: bool,
+    #[arg(long, help = "Build the archive a second time and fail unless both builds have the same bytes", default_value_t = false)]
+    reproducible: bool,
+    #[command(flatten)]
+    target: TargetArgs
 }
 
 fn append_vec<P: AsRef<Path>, T: Write>(builder: &mut Builder<T>, path: P, data: &[u8]) -> std::io::Result<()> {
+    // Nothing about the machine or the time of the build ends up in the archive
     let mut header = Header::new_gnu();
     header.set_mode(0o664);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
     header.set_size(data.len() as u64);
 
     builder.append_data(&mut header, path, data)
 }
 
-fn save_image<W: Write>(
-    char_path: impl AsRef<Path>,
-    mut archive: &mut Builder<W>,
-    name: &String,
-    location: &Path,
-    path: &Path,
-    width: u32,
-    height: u32,
-    upscale: bool,
-    saved_images: &mut HashSet<String>
-) -> anyhow::Result<()> {
-    if saved_images.contains(name) {
-        return Ok(())
+pub fn process_character_cli(cli: CharacterCli) -> anyhow::Result<()> {
+    let mut char: Character = serde_json::from_str(&fs::read_to_string(cli.input_file)?)?;
+    char.refresh_frame_counts(env::current_dir()?);
+    let mut profile = cli.target.profile()?;
+
+    if let Some(version) = cli.format_version {
+        profile.format_version = version;
+    }
+
+    if let Some(format) = cli.pixel_format {
+        profile.pixel_format = format;
     }
 
-    saved_images.insert(name.clone());
+    profile.check()?;
 
-    let real_width = if upscale {
-        width / 2
-    } else {
-        width
+    let location = env::current_dir()?;
+
+    if cli.clean {
+        clear_build_cache(&location)?;
+    }
+
+    let bar = ProgressBar::new(0).with_style(ProgressStyle::with_template("Encoding images {bar:40} {pos}/{len}")?);
+    let progress = |done, total| {
+        bar.set_length(total as u64);
+        bar.set_position(done as u64);
     };
 
-    let real_height = if upscale {
-        height / 2
+    let result = process_character_archive(
+        char.clone(),
+        &cli.output_file,
+        &location,
+        cli.include_selected,
+        !cli.skip_validation,
+        &profile,
+        &progress
+    );
+    bar.finish_and_clear();
+    result?.print();
+
+    if cli.reproducible {
+        // Second build mostly reuses the build cache, it checks that ordering and metadata don't change
+        let mut rebuilt = vec![];
+        write_character_tar(char, &mut rebuilt, &location, cli.include_selected, false, &profile, &|_, _| {})?;
+
+        ensure!(
+            fs::read(&cli.output_file)? == rebuilt,
+            "Archive isn't reproducible, building it again gave different bytes"
+        );
+
+        eprintln!("Archive is reproducible");
+    }
+
+    Ok(())
+}
+
+/// What happened to the character while its archive was built
+#[derive(Clone, Debug, Default)]
+pub struct BuildSummary {
+    /// Format version the archive was built for
+    pub format_version: u16,
+    /// Features that the format version couldn't store
+    pub lost_features: Vec<LostFeature>,
+    /// Images and animation frames with the same data as one that was already stored
+    pub duplicates: usize,
+    /// Size of the files that weren't written for the duplicates
+    pub saved_bytes: usize,
+    /// Images and frame sources reused from the build cache
+    pub cache_hits: usize,
+    /// Images and frame sources that had to be encoded
+    pub cache_misses: usize
+}
+
+impl BuildSummary {
+    /// Report of the build, shown after the export by both the CLI and the editor
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+
+        if self.cache_hits + self.cache_misses > 0 {
+            lines.push(format!("Build cache: {} hits, {} misses", self.cache_hits, self.cache_misses));
+        }
+
+        if self.duplicates > 0 {
+            lines.push(format!("Stored {} duplicate images and frames once, saving {} bytes", self.duplicates, self.saved_bytes));
+        }
+
+        if !self.lost_features.is_empty() {
+            lines.push(format!("Following features can't be stored in format version {} and were dropped:", self.format_version));
+            lines.extend(self.lost_features.iter().map(|feature| format!("  {feature}")));
+        }
+
+        lines
+    }
+
+    pub fn print(&self) {
+        for line in self.lines() {
+            eprintln!("{line}");
+        }
+    }
+}
+
+/// Validates the character unless told not to, then builds the archive and writes it
+pub fn process_character_archive(
+    char: Character,
+    path: impl AsRef<Path>,
+    location: impl AsRef<Path>,
+    include_select: bool,
+    validate: bool,
+    profile: &TargetProfile,
+    progress: Progress
+) -> anyhow::Result<BuildSummary> {
+    // Building in memory first, so nothing is written if the character has errors
+    let mut buffer = vec![];
+
+    let summary = write_character_tar(char, &mut buffer, location, include_select, validate, profile, progress)?;
+    fs::write(path, buffer)?;
+
+    Ok(summary)
+}
+
+/// File of the character archive, path is relative to the root of the archive
+pub type ArchiveEntry = (PathBuf, Vec<u8>);
+
+struct CharacterFilesBuilder<'a> {
+    codec: &'a dyn FormatCodec,
+    profile: &'a TargetProfile,
+    char_path: PathBuf,
+    files: Vec<ArchiveEntry>,
+    errors: Vec<CharacterBuildError>,
+    // Image name mapped to the size it was saved with, the name of the file it's stored in and how it's stored
+    saved_images: HashMap<String, ((u32, u32), String, ImageData)>,
+    // Encoded image data mapped to the image that stores it
+    stored_images: HashMap<Vec<u8>, (String, ImageData)>,
+    // Encoded images of every resource, taken out when the resource is built
+    encoded: HashMap<Resource, Result<Encoded, CharacterBuildError>>,
+    summary: BuildSummary
+}
+
+impl CharacterFilesBuilder<'_> {
+    fn add_binary(
+        &mut self,
+        path: PathBuf,
+        kind: FileKind,
+        resource: Resource,
+        result: Result<Vec<u8>, Vec<NameError>>
+    ) -> anyhow::Result<()> {
+        match result {
+            Ok(data) => {
+                let data = self.codec.encode(kind, data)?;
+                self.files.push((path, data));
+            }
+            Err(errors) => {
+                self.errors.extend(errors.into_iter().map(|err| err.at(resource.clone())));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_name(&mut self, resource: Resource, field: &'static str, value: &str, size: usize) {
+        if let Some(error) = NameError::check(field, value, size) {
+            self.errors.push(error.at(resource));
+        }
+    }
+
+    /// Checks the name against the limit of the target, longer names than the layout can store are reported by the binary encoding
+    fn check_limit(&mut self, resource: Resource, field: &'static str, value: &str, limit: usize, layout_size: usize) {
+        if value.len() >= layout_size {
+            return;
+        }
+
+        if let Some(error) = NameError::check(field, value, limit + 1) {
+            self.errors.push(error.at(resource));
+        }
+    }
+
+    /// Compression the image gets in the archive, versions before compression store everything uncompressed
+    fn compression(&self, conversion: &ConversionOptions) -> Compression {
+        if self.codec.version() >= COMPRESSION_FORMAT_VERSION {
+            conversion.compression
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Images encoded for the resource, errors of the encoding are collected when the resource is built
+    fn take_encoded(&mut self, resource: &Resource) -> Vec<Vec<u8>> {
+        match self.encoded.remove(resource) {
+            Some(Ok(encoded)) => encoded.images,
+            Some(Err(err)) => {
+                self.errors.push(err);
+                vec![]
+            }
+            None => vec![]
+        }
+    }
+
+    fn store_images(&mut self, images: Vec<(PathBuf, Vec<u8>, Vec<u8>)>, compression: Compression) -> ImageData {
+        let (paths, images): (Vec<_>, Vec<_>) = images.into_iter()
+            .map(|(path, header, data)| (path, (header, data)))
+            .unzip();
+
+        let (data, files) = store_images(images, compression, self.profile.pixel_format.pixel_size());
+        self.files.extend(paths.into_iter().zip(files));
+
+        data
+    }
+
+    /// Returns the image file and how it's stored, for the file describing the image.
+    /// Images with the same data as an already stored one point at its file
+    fn add_image(&mut self, name: &String, width: u32, height: u32, conversion: &ConversionOptions) -> (String, ImageData) {
+        let resource = Resource::Image(name.clone());
+
+        if let Some((saved_size, file, data)) = self.saved_images.get(name).cloned() {
+            if saved_size != (width, height) {
+                self.errors.push(CharacterBuildError::SizeMismatch {
+                    resource,
+                    expected: saved_size,
+                    actual: (width, height),
+                });
+            }
+
+            return (file, data);
+        }
+
+        self.check_limit(resource.clone(), "Image name", name, self.profile.name_limits.image_name, bp_data_IMAGE_NAME_MAX_LEN);
+
+        let (file, data) = match self.take_encoded(&resource).pop() {
+            Some(encoded) => match self.stored_images.get(&encoded) {
+                Some((file, data)) => {
+                    self.summary.duplicates += 1;
+                    self.summary.saved_bytes += data.stored_size as usize;
+
+                    (file.clone(), *data)
+                }
+                None => {
+                    let archive_path = self.char_path.join("images").join(format!("{name}.bin"));
+                    let data = self.store_images(vec![(archive_path, vec![], encoded.clone())], self.compression(conversion));
+                    self.stored_images.insert(encoded, (name.clone(), data));
+
+                    (name.clone(), data)
+                }
+            },
+            None => (name.clone(), ImageData::default())
+        };
+
+        self.saved_images.insert(name.clone(), ((width, height), file.clone(), data));
+
+        (file, data)
+    }
+
+    /// Stores every distinct frame once when asked to and the format has frame maps, returns how frames are stored
+    /// and the map of the frame files if any frame repeats
+    fn store_frames(
+        &mut self,
+        frame_path: impl Fn(usize) -> PathBuf,
+        paths: Vec<PathBuf>,
+        frames: Vec<(Vec<u8>, Vec<u8>)>,
+        compression: Compression,
+        deduplicate_frames: bool
+    ) -> (ImageData, Option<Vec<u8>>) {
+        let (unique, map) = deduplicate(frames.clone());
+
+        if !deduplicate_frames || self.codec.version() < FRAME_MAP_FORMAT_VERSION || unique.len() == frames.len() {
+            let data = self.store_images(
+                paths.into_iter().zip(frames).map(|(path, (header, data))| (path, header, data)).collect(),
+                compression
+            );
+
+            return (data, None);
+        }
+
+        let first_file = self.files.len();
+        let data = self.store_images(
+            unique.into_iter()
+                .enumerate()
+                .map(|(index, (header, data))| (frame_path(index + 1), header, data))
+                .collect(),
+            compression
+        );
+
+        let sizes = self.files[first_file..].iter().map(|(_, file)| file.len()).collect::<Vec<_>>();
+        self.summary.duplicates += map.len() - sizes.len();
+        self.summary.saved_bytes += map.iter().map(|&index| sizes[index]).sum::<usize>() - sizes.iter().sum::<usize>();
+
+        // Frame files are numbered from 1
+        let map = map.iter()
+            .flat_map(|&index| (index as u32 + 1).to_le_bytes())
+            .collect();
+
+        (data, Some(map))
+    }
+}
+
+/// Items in the order they first appear, and the index of the distinct item for every item
+pub fn deduplicate<T: Eq + Hash + Clone>(items: Vec<T>) -> (Vec<T>, Vec<usize>) {
+    let mut indices = HashMap::new();
+    let mut unique = vec![];
+
+    let map = items.into_iter()
+        .map(|item| {
+            *indices.entry(item.clone()).or_insert_with(|| {
+                unique.push(item);
+                unique.len() - 1
+            })
+        })
+        .collect();
+
+    (unique, map)
+}
+
+/// Compresses every image the same way, or leaves them all uncompressed if compression doesn't make them smaller.
+/// Images are a header that stays uncompressed and the data, returns how they are stored and their files
+pub fn store_images(images: Vec<(Vec<u8>, Vec<u8>)>, compression: Compression, pixel_size: usize) -> (ImageData, Vec<Vec<u8>>) {
+    let data_size = images.iter().map(|(_, data)| data.len()).max().unwrap_or_default();
+
+    let compressed = images.iter()
+        .map(|(_, data)| compression.compress(data, pixel_size))
+        .collect::<Vec<_>>();
+
+    let smaller = compressed.iter().map(Vec::len).sum::<usize>() < images.iter().map(|(_, data)| data.len()).sum::<usize>();
+
+    let (compression, stored) = if compression != Compression::None && smaller {
+        (compression, compressed)
     } else {
-        height
+        (Compression::None, images.iter().map(|(_, data)| data.clone()).collect())
     };
 
-    let file = fs::read(location.join(path))?;
-    append_vec(
-        &mut archive,
-        PathBuf::from(char_path.as_ref()).join("images").join(format!("{name}.bin")),
-        &encode_image_data(&file, real_width, real_height, true)?
-    )?;
-    Ok(())
+    let files = images.into_iter()
+        .zip(stored)
+        .map(|((mut header, _), data)| {
+            header.extend(data);
+            header
+        })
+        .collect::<Vec<_>>();
+
+    let data = ImageData {
+        compression,
+        stored_size: files.iter().map(Vec::len).max().unwrap_or_default() as u32,
+        data_size: data_size as u32,
+    };
+
+    (data, files)
 }
 
-pub fn process_character_cli(cli: CharacterCli) -> anyhow::Result<()> {
-    let char: Character = serde_json::from_str(&fs::read_to_string(cli.input_file)?)?;
-    process_character_archive(char, cli.output_file, env::current_dir()?, cli.include_selected)
+/// Headers and data of the frame files, delta frames start with the rectangle that changed
+pub fn frame_files(frames: Vec<Vec<u8>>, anim: &Animation, format: PixelFormat) -> Vec<(Vec<u8>, Vec<u8>)> {
+    if anim.encoding == FrameEncoding::Full {
+        return frames.into_iter().map(|frame| (vec![], frame)).collect();
+    }
+
+    let layout = FrameLayout {
+        width: anim.real_width(),
+        height: anim.real_height(),
+        format,
+        alpha_plane: anim.conversion.has_alpha_plane(format),
+    };
+
+    let deltas = frames.windows(2)
+        .map(|pair| {
+            let (rect, payload) = layout.encode_delta(&pair[0], &pair[1]);
+            (rect.to_bytes(), payload)
+        })
+        .collect::<Vec<_>>();
+
+    frames.into_iter()
+        .take(1)
+        .map(|frame| (vec![], frame))
+        .chain(deltas)
+        .collect()
+}
+
+/// Stored size of every frame of an animation, with whole and with delta frames
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnimationStorage {
+    pub frame_count: usize,
+    pub full_size: usize,
+    pub delta_size: usize
 }
 
-pub fn process_character_archive(char: Character, path: impl AsRef<Path>, location: impl AsRef<Path>, include_select: bool) -> anyhow::Result<()> {
-    let file = File::create(path)?;
+impl AnimationStorage {
+    pub fn stored_size(&self, encoding: FrameEncoding) -> usize {
+        match encoding {
+            FrameEncoding::Full => self.full_size,
+            FrameEncoding::Delta => self.delta_size
+        }
+    }
 
-    write_character_tar(char, file, location, include_select)
+    /// Average bytes read from the SD card every second while the animation plays
+    pub fn bandwidth(&self, encoding: FrameEncoding, fps: f64) -> f64 {
+        self.stored_size(encoding) as f64 / self.frame_count.max(1) as f64 * fps
+    }
 }
 
-pub fn write_character_tar(char: Character, writer: impl Write, location: impl AsRef<Path>, include_select: bool) -> anyhow::Result<()> {
-    let location = location.as_ref();
+/// Encodes the frames of the animation both ways, with the compression the target can store
+pub fn animation_storage(anim: &Animation, location: impl AsRef<Path>, profile: &TargetProfile) -> anyhow::Result<AnimationStorage> {
+    let frames = anim.frames.load(location)?
+        .iter()
+        .map(|image| encode_image(
+            image,
+            anim.real_width(),
+            anim.real_height(),
+            profile.pixel_format,
+            profile.frame_byte_order,
+            &anim.conversion
+        ))
+        .collect::<Vec<_>>();
+
+    let compression = if profile.format_version >= COMPRESSION_FORMAT_VERSION {
+        anim.conversion.compression
+    } else {
+        Compression::None
+    };
+
+    let stored_size = |encoding: FrameEncoding| {
+        let anim = Animation {
+            encoding,
+            ..anim.clone()
+        };
+        let (_, files) = store_images(
+            frame_files(frames.clone(), &anim, profile.pixel_format),
+            compression,
+            profile.pixel_format.pixel_size()
+        );
 
+        files.iter().map(Vec::len).sum()
+    };
+
+    Ok(AnimationStorage {
+        frame_count: frames.len(),
+        full_size: stored_size(FrameEncoding::Full),
+        delta_size: stored_size(FrameEncoding::Delta),
+    })
+}
+
+/// Encodes every file of the character archive, collecting all errors instead of stopping at the first one
+/// Encodes images and builds the files of the archive. Without the build cache nothing is read from or written to
+/// the location besides the image sources
+pub fn build_character_files(
+    char: &Character,
+    location: impl AsRef<Path>,
+    include_select: bool,
+    use_cache: bool,
+    profile: &TargetProfile,
+    progress: Progress
+) -> anyhow::Result<(Vec<ArchiveEntry>, BuildSummary)> {
+    let location = location.as_ref();
+    let codec = profile.codec()?;
+    let limits = &profile.name_limits;
     let char_path = Path::new("characters").join(&char.id);
 
-    let mut archive = Builder::new(writer);
-    append_vec(&mut archive, char_path.join("character.bin"), &char.to_bin()?)?;
+    // Images are encoded up front across all cores, the files are then built in a fixed order
+    let cache = if use_cache { BuildCache::new(location, &char.id)? } else { BuildCache::disabled() };
+    let encoded = encode_all(encode_jobs(char, profile), location, &cache, profile.pixel_format, progress);
 
-    if include_select {
-        append_vec(&mut archive, char_path.join("selected.lock"), &[])?;
+    // Every entry the character still needs was used above
+    if let Err(err) = cache.prune() {
+        eprintln!("Failed to prune build cache: {err}");
     }
 
-    let mut saved_images = HashSet::new();
+    let (cache_hits, cache_misses) = encoded.values()
+        .flatten()
+        .partition::<Vec<_>, _>(|encoded| encoded.cached);
+
+    let mut builder = CharacterFilesBuilder {
+        codec,
+        profile,
+        char_path: char_path.clone(),
+        files: vec![],
+        errors: vec![],
+        saved_images: HashMap::new(),
+        stored_images: HashMap::new(),
+        summary: BuildSummary {
+            format_version: codec.version(),
+            cache_hits: cache_hits.len(),
+            cache_misses: cache_misses.len(),
+            ..Default::default()
+        },
+        encoded,
+    };
+
+    builder.check_limit(Resource::Character, "Name", &char.name, limits.name, bp_data_NAME_MAX_LEN);
+    builder.check_limit(Resource::Character, "Species", &char.species, limits.species, bp_data_SPECIES_MAX_LEN);
+    builder.add_binary(char_path.join("character.bin"), FileKind::Character, Resource::Character, char.to_bin())?;
 
-    for (state_name, state) in &char.states {
+    if include_select {
+        builder.files.push((char_path.join("selected.lock"), vec![]));
+    }
+
+    // Sorted so the archive has the same bytes every time it's built
+    for (state_name, state) in BTreeMap::from_iter(&char.states) {
         let state_path = char_path.join("states").join(state_name);
-        append_vec(&mut archive, state_path.join("state.bin"), &state.to_bin()?)?;
+        let resource = Resource::State(state_name.clone());
+
+        builder.check_name(resource.clone(), "Name", state_name, limits.state_name + 1);
+
+        // Images are saved first, the state describes how they are stored
+        let mut state = state.clone();
 
         if let StateImage::Single {
             name,
-            path,
             width,
             height,
-            upscale,
+            conversion,
+            data,
             ..
-        } = &state.image {
-            save_image(
-                &char_path,
-                &mut archive,
-                name,
-                location,
-                path,
-                *width,
-                *height,
-                *upscale,
-                &mut saved_images
-            )?;
+        } = &mut state.image {
+            (*name, *data) = builder.add_image(name, *width, *height, conversion);
         }
 
+        builder.add_binary(state_path.join("state.bin"), FileKind::State, resource.clone(), state.to_bin())?;
+
         if let StateImage::Sequence {
             frames,
             ..
-        } = &state.image {
+        } = &mut state.image {
             let frames_path = state_path.join("frames");
-            for (index, frame) in frames.iter().enumerate() {
+            for (index, frame) in frames.iter_mut().enumerate() {
                 // Save image file
-                save_image(
-                    &char_path,
-                    &mut archive,
-                    &frame.name,
-                    location,
-                    &frame.path,
-                    frame.width,
-                    frame.height,
-                    frame.upscale,
-                    &mut saved_images
-                )?;
+                (frame.name, frame.data) = builder.add_image(&frame.name, frame.width, frame.height, &frame.conversion);
 
                 // Save frame
                 let frame_path = frames_path.join(format!("{index}.bin"));
-                append_vec(&mut archive, frame_path, &frame.to_bin()?)?;
+                builder.add_binary(frame_path, FileKind::SequenceFrame, resource.clone(), frame.to_bin())?;
             }
         }
 
         let transitions_path = state_path.join("transitions");
         for transition in &state.transitions {
-            append_vec(
-                &mut archive,
+            builder.add_binary(
                 transitions_path.join(&transition.to_state).join("transition.bin"),
-                &transition.to_bin()?
+                FileKind::Transition,
+                Resource::Transition {
+                    state: state_name.clone(),
+                    to_state: transition.to_state.clone(),
+                },
+                transition.to_bin()
             )?;
         }
     }
 
-    for (anim_name, anim) in &char.animations {
+    for (anim_name, anim) in BTreeMap::from_iter(&char.animations) {
         let anim_path = char_path.join("animations").join(anim_name);
-        append_vec(&mut archive, anim_path.join("animation.bin"), &anim.to_bin()?)?;
-
-        match &anim.frames {
-            AnimationFrameSource::Indexed {
-                count, folder, extension
-            } => {
-                let folder = location.join(folder);
-
-                for index in 1..=*count {
-                    let image = fs::read(
-                        folder.join(format!("{index}.{}", extension))
-                    )?;
-
-                    append_vec(
-                        &mut archive,
-                        anim_path.join("frames").join(format!("{index}.bin")),
-                        &encode_image_data(&image, anim.real_width(), anim.real_height(), false)?
-                    )?;
-                }
-            }
+        let resource = Resource::Animation(anim_name.clone());
 
-            AnimationFrameSource::List(list) => {
-                for (index, path) in list.iter().enumerate() {
-                    let image = fs::read(location.join(path))?;
+        builder.check_name(resource.clone(), "Name", anim_name, limits.animation_name + 1);
 
-                    append_vec(
-                        &mut archive,
-                        anim_path.join("frames").join(format!("{index}.bin")),
-                        &encode_image_data(&image, anim.real_width(), anim.real_height(), false)?
-                    )?;
-                }
+        let mut anim = anim.clone();
+        let frame_path = |index: usize| anim_path.join("frames").join(format!("{index}.bin"));
+
+        let mut paths = vec![];
+        let mut encoded = vec![];
+
+        let frames = builder.take_encoded(&resource);
+
+        if let AnimationFrameSource::File { count, .. } | AnimationFrameSource::Sheet { count, .. } = &mut anim.frames {
+            *count = frames.len() as u32;
+        }
+
+        for (index, frame) in frames.into_iter().enumerate() {
+            paths.push(frame_path(index + 1));
+            encoded.push(frame);
+        }
+
+        for (index, _) in anim.frames.paths() {
+            let frame = builder.take_encoded(&Resource::AnimationFrame {
+                animation: anim_name.clone(),
+                index,
+            });
+
+            if let Some(frame) = frame.into_iter().next() {
+                paths.push(frame_path(index));
+                encoded.push(frame);
             }
         }
 
+        if codec.version() < DELTA_FRAMES_FORMAT_VERSION {
+            anim.encoding = FrameEncoding::Full;
+        }
+
+        let frames = frame_files(encoded, &anim, profile.pixel_format);
+
+        // Frames are loaded into the same buffer, so they share the compression
+        let compression = builder.compression(&anim.conversion);
+        let (frame_data, frame_map) = builder.store_frames(frame_path, paths, frames, compression, anim.deduplicate_frames);
+        anim.frame_data = frame_data;
+        anim.frame_map = frame_map.is_some();
+
+        if let Some(frame_map) = frame_map {
+            builder.files.push((anim_path.join("frame_map.bin"), frame_map));
+        }
+
+        builder.add_binary(anim_path.join("animation.bin"), FileKind::Animation, resource, anim.to_bin())?;
     }
 
-    for (action_name, action) in &char.actions {
+    for (action_name, action) in BTreeMap::from_iter(&char.actions) {
         let action_path = char_path.join("actions").join(action_name);
-        append_vec(&mut archive, action_path.join("action.bin"), &action.to_bin()?)?;
+        builder.check_limit(Resource::Action(action_name.clone()), "Display name", &action.display, limits.action_display, bp_data_ACTION_DISPLAY_MAX_LEN);
+        builder.add_binary(
+            action_path.join("action.bin"),
+            FileKind::Action,
+            Resource::Action(action_name.clone()),
+            action.to_bin()
+        )?;
+    }
+
+    if !builder.errors.is_empty() {
+        return Err(CharacterBuildErrors(builder.errors).into());
+    }
+
+    Ok((builder.files, builder.summary))
+}
+
+/// Writes the character archive in the format version of the codec, returns features that the version couldn't store
+/// and how much deduplication saved
+pub fn write_character_tar(
+    char: Character,
+    writer: impl Write,
+    location: impl AsRef<Path>,
+    include_select: bool,
+    validate: bool,
+    profile: &TargetProfile,
+    progress: Progress
+) -> anyhow::Result<BuildSummary> {
+    if validate {
+        let mut errors = char.validate();
+        errors.extend(char.validate_target(profile));
+
+        if !errors.is_empty() {
+            return Err(ValidationErrors(errors).into());
+        }
+    }
+
+    let lost_features = profile.codec()?.lost_features(&char);
+    let (files, summary) = build_character_files(&char, location, include_select, true, profile, progress)?;
+
+    let mut archive = Builder::new(writer);
+
+    for (path, data) in &files {
+        append_vec(&mut archive, path, data)?;
     }
 
     archive.finish()?;
-    Ok(())
-}
\ No newline at end of file
+
+    Ok(BuildSummary {
+        lost_features,
+        ..summary
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_character_isnt_built() {
+        let char = Character {
+            default_state: "missing".to_string(),
+            ..Default::default()
+        };
+
+        let result = write_character_tar(char, vec![], "does-not-exist", false, true, &TargetProfile::default(), &|_, _| {});
+        assert!(result.unwrap_err().downcast::<ValidationErrors>().is_ok());
+    }
+
+    #[test]
+    fn repeated_items_point_at_first_one() {
+        let (unique, map) = deduplicate(vec!["a", "b", "a", "c", "b"]);
+
+        assert_eq!(unique, vec!["a", "b", "c"]);
+        assert_eq!(map, vec![0, 1, 0, 2, 1]);
+    }
+}
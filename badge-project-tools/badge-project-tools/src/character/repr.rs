@@ -1,15 +1,28 @@
 use std::collections::HashMap;
-use crate::character::util::{any_as_u8_vec, string_to_char_array, TuplePick};
-use crate::{bp_character_action_e_BP_CHARACTER_ACTION_SWITCH_STATE, bp_character_action_file_s, bp_character_action_u, bp_character_animation_file_s, bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_RAM, bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_SDCARD, bp_character_file_s, bp_character_image_descriptor_s, bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_ALL, bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_EACH, bp_character_state_animation_descriptor_s, bp_character_state_file_s, bp_character_state_image_e_BP_CHARACTER_STATE_ANIMATION, bp_character_state_image_e_BP_CHARACTER_STATE_NO_IMAGE, bp_character_state_image_e_BP_CHARACTER_STATE_SEQUENCE, bp_character_state_image_e_BP_CHARACTER_STATE_SINGLE_IMAGE, bp_character_state_image_u, bp_character_state_sequence_descriptor_s, bp_data_FORMAT_VERSION, bp_sequence_frame_file_s, bp_state_transition_file_s, bp_state_trigger_e_BP_STATE_TRIGGER_CLICKED, bp_state_trigger_e_BP_STATE_TRIGGER_ELAPSED_TIME, bp_state_trigger_e_BP_STATE_TRIGGER_RANDOM, bp_state_trigger_random_s, bp_state_trigger_s, bp_state_trigger_u};
+use crate::character::binary::{BinaryReader, BinaryWriter, ACTION_FILE_SIZE, ANIMATION_FILE_SIZE, CHARACTER_FILE_SIZE, SEQUENCE_FRAME_FILE_SIZE, STATE_FILE_SIZE, STATE_TRANSITION_FILE_SIZE};
+use crate::character::error::NameError;
+use crate::character::util::TuplePick;
+use crate::{bp_character_action_e_BP_CHARACTER_ACTION_SWITCH_STATE, bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_RAM, bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_SDCARD, bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_ALL, bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_EACH, bp_character_state_image_e_BP_CHARACTER_STATE_ANIMATION, bp_character_state_image_e_BP_CHARACTER_STATE_NO_IMAGE, bp_character_state_image_e_BP_CHARACTER_STATE_SEQUENCE, bp_character_state_image_e_BP_CHARACTER_STATE_SINGLE_IMAGE, bp_data_ACTION_DISPLAY_MAX_LEN, bp_data_ANIMATION_NAME_MAX_LEN, bp_data_FORMAT_VERSION, bp_data_IMAGE_NAME_MAX_LEN, bp_data_NAME_MAX_LEN, bp_data_SPECIES_MAX_LEN, bp_data_STATE_NAME_MAX_LEN, bp_state_trigger_e_BP_STATE_TRIGGER_CLICKED, bp_state_trigger_e_BP_STATE_TRIGGER_ELAPSED_TIME, bp_state_trigger_e_BP_STATE_TRIGGER_RANDOM};
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use std::ffi::NulError;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use either::Either;
 use strum::{Display, EnumIs, EnumIter};
-use crate::image::rgb_to_565;
+use crate::image::animated::{decode_animated, trim_frames};
+use crate::image::compress::Compression;
+use crate::image::sheet::SpriteSheet;
+use image::DynamicImage;
+use crate::image::{rgb_from_565, rgb_to_565, ConversionOptions};
 
 pub trait BinaryRepr {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError>;
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>>;
+}
+
+/// Counterpart of [BinaryRepr]. Anything that isn't stored in the binary itself (ids, paths, transitions,
+/// frame files) is left empty and has to be filled in from the surrounding archive
+pub trait FromBinary: Sized {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self>;
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -51,6 +64,13 @@ impl Character {
             actions: Default::default(),
         }
     }
+
+    /// Counts stored in the JSON can be missing or stale, so they're taken from the sources after loading
+    pub fn refresh_frame_counts(&mut self, location: impl AsRef<Path>) {
+        for animation in self.animations.values_mut() {
+            animation.frames.refresh_count(location.as_ref());
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
@@ -76,7 +96,11 @@ pub enum StateImage {
         #[serde(default)]
         upscale: bool,
         #[serde(default)]
-        layer_load: bool
+        layer_load: bool,
+        #[serde(default)]
+        conversion: ConversionOptions,
+        #[serde(skip)]
+        data: ImageData
     },
     Animation {
         name: String,
@@ -129,7 +153,27 @@ pub enum AnimationFrameSource {
         extension: String,
         count: u32
     },
-    List(Vec<PathBuf>)
+    List(Vec<PathBuf>),
+    /// Animated GIF, APNG or WebP
+    File {
+        path: PathBuf,
+        /// First used frame of the file, counted from 0
+        #[serde(default)]
+        first: u32,
+        /// Last used frame, the end of the file if not set
+        #[serde(default)]
+        last: Option<u32>,
+        /// Frames after trimming, updated from the file when the character is loaded and when the file is picked
+        #[serde(default)]
+        count: u32
+    },
+    /// Frames sliced out of a sprite sheet
+    Sheet {
+        sheet: SpriteSheet,
+        /// Picked regions, updated from the sheet when the character is loaded and when the sheet is edited
+        #[serde(default)]
+        count: u32
+    }
 }
 
 impl Default for AnimationFrameSource {
@@ -142,9 +186,69 @@ impl AnimationFrameSource {
     pub fn count(&self) -> u32 {
         match self {
             &AnimationFrameSource::Indexed { count, .. } => count,
-            AnimationFrameSource::List(list) => list.len() as u32
+            AnimationFrameSource::List(list) => list.len() as u32,
+            &AnimationFrameSource::File { count, .. } => count,
+            &AnimationFrameSource::Sheet { count, .. } => count
         }
     }
+
+    /// Counts the frames of animated files and sheets again, sources that can't be read keep their count for the build
+    /// to report
+    pub fn refresh_count(&mut self, location: impl AsRef<Path>) {
+        if !self.is_file() && !self.is_sheet() {
+            return;
+        }
+
+        let Ok(frames) = self.load(location) else {
+            return;
+        };
+
+        if let AnimationFrameSource::File { count, .. } | AnimationFrameSource::Sheet { count, .. } = self {
+            *count = frames.len() as u32;
+        }
+    }
+
+    /// Source images with the index of the frame file they are saved as, frames of animated files and sheets have no path
+    pub fn paths(&self) -> Vec<(usize, PathBuf)> {
+        match self {
+            AnimationFrameSource::Indexed { count, folder, extension } => {
+                (1..=*count)
+                    .map(|index| (index as usize, folder.join(format!("{index}.{}", extension))))
+                    .collect()
+            }
+
+            // Frame files are numbered from 1, like indexed frames
+            AnimationFrameSource::List(list) => {
+                list.iter()
+                    .cloned()
+                    .enumerate()
+                    .map(|(index, path)| (index + 1, path))
+                    .collect()
+            }
+
+            AnimationFrameSource::File { .. } | AnimationFrameSource::Sheet { .. } => vec![]
+        }
+    }
+
+    /// Reads every frame, for previews and estimates
+    pub fn load(&self, location: impl AsRef<Path>) -> anyhow::Result<Vec<DynamicImage>> {
+        let location = location.as_ref();
+
+        let frames = match self {
+            AnimationFrameSource::File { path, first, last, .. } => {
+                trim_frames(decode_animated(&fs::read(location.join(path))?)?, *first, *last)?
+            }
+            AnimationFrameSource::Sheet { sheet, .. } => sheet.load(location)?,
+            AnimationFrameSource::Indexed { .. } | AnimationFrameSource::List(_) => {
+                return self.paths()
+                    .into_iter()
+                    .map(|(_, path)| Ok(image::load_from_memory(&fs::read(location.join(path))?)?))
+                    .collect();
+            }
+        };
+
+        Ok(frames.into_iter().map(|frame| DynamicImage::ImageRgba8(frame.image)).collect())
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -162,7 +266,20 @@ pub struct Animation {
     #[serde(default)]
     pub mode: AnimationMode,
     #[serde(default)]
-    pub upscale: bool
+    pub upscale: bool,
+    #[serde(default)]
+    pub encoding: FrameEncoding,
+    /// Repeated frames are stored once, needs format version 4
+    #[serde(default)]
+    pub deduplicate_frames: bool,
+    /// Applies to every frame
+    #[serde(default)]
+    pub conversion: ConversionOptions,
+    #[serde(skip)]
+    pub frame_data: ImageData,
+    /// Whether repeated frames are stored once and read through the frame map, decided while the archive is built
+    #[serde(skip)]
+    pub frame_map: bool
 }
 
 impl Default for Animation {
@@ -178,6 +295,11 @@ impl Default for Animation {
             background_color: (0, 0, 0),
             mode: Default::default(),
             upscale: false,
+            encoding: Default::default(),
+            deduplicate_frames: false,
+            conversion: Default::default(),
+            frame_data: Default::default(),
+            frame_map: false,
         }
     }
 }
@@ -189,6 +311,16 @@ pub enum AnimationMode {
     FromRAM
 }
 
+/// How frames after the first one are stored
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, EnumIter, Display, PartialEq, Eq, EnumIs)]
+pub enum FrameEncoding {
+    /// Every frame is whole
+    #[default]
+    Full,
+    /// Only the rectangle that changed since the previous frame
+    Delta
+}
+
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, EnumIter, Display, PartialEq, Eq, EnumIs)]
 pub enum SequenceMode {
     LoadAll,
@@ -196,7 +328,7 @@ pub enum SequenceMode {
     LoadEach
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct SequenceFrame {
     pub name: String,
     pub path: PathBuf,
@@ -204,7 +336,43 @@ pub struct SequenceFrame {
     pub height: u32,
     #[serde(default)]
     pub upscale: bool,
-    pub duration: i64
+    pub duration: i64,
+    #[serde(default)]
+    pub conversion: ConversionOptions,
+    #[serde(skip)]
+    pub data: ImageData
+}
+
+/// How an image file is stored in the archive, filled in while the archive is built
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageData {
+    pub compression: Compression,
+    pub stored_size: u32,
+    pub data_size: u32
+}
+
+impl ImageData {
+    fn write<'a>(&self, writer: &'a mut BinaryWriter) -> &'a mut BinaryWriter {
+        writer.u32(self.compression.to_raw())
+            .u32(self.stored_size)
+            .u32(self.data_size)
+    }
+
+    fn read(reader: &mut BinaryReader) -> anyhow::Result<Self> {
+        Ok(Self {
+            compression: Compression::from_raw(reader.u32()?)?,
+            stored_size: reader.u32()?,
+            data_size: reader.u32()?,
+        })
+    }
+
+    /// Conversion options of unpacked images, only the compression is known
+    fn conversion(&self) -> ConversionOptions {
+        ConversionOptions {
+            compression: self.compression,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
@@ -225,29 +393,72 @@ impl Default for ActionType {
 }
 
 impl BinaryRepr for Character {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError> {
-        let file = bp_character_file_s {
-            format_version: bp_data_FORMAT_VERSION,
-            name: string_to_char_array(&self.name)?,
-            species: string_to_char_array(&self.species)?,
-            default_state: string_to_char_array(&self.default_state)?,
-        };
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>> {
+        BinaryWriter::new()
+            .u16(bp_data_FORMAT_VERSION)
+            .c_string("Name", &self.name, bp_data_NAME_MAX_LEN)
+            .c_string("Species", &self.species, bp_data_SPECIES_MAX_LEN)
+            .c_string("Default state", &self.default_state, bp_data_STATE_NAME_MAX_LEN)
+            .finish(CHARACTER_FILE_SIZE)
+    }
+}
+
+impl FromBinary for Character {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BinaryReader::new(data, CHARACTER_FILE_SIZE)?;
+
+        let version = reader.u16()?;
+        if version != bp_data_FORMAT_VERSION {
+            bail!("Unsupported format version {version}, expected {bp_data_FORMAT_VERSION}");
+        }
 
-        Ok(unsafe { any_as_u8_vec(&file) })
+        Ok(Self {
+            id: String::new(),
+            name: reader.c_string(bp_data_NAME_MAX_LEN)?,
+            species: reader.c_string(bp_data_SPECIES_MAX_LEN)?,
+            default_state: reader.c_string(bp_data_STATE_NAME_MAX_LEN)?,
+            states: Default::default(),
+            animations: Default::default(),
+            actions: Default::default(),
+        })
     }
 }
 
 impl BinaryRepr for SequenceFrame {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError> {
-        let file = bp_sequence_frame_file_s {
-            image_name: string_to_char_array(&self.name)?,
-            width: self.width,
-            height: self.height,
-            upscale: self.upscale,
-            duration_us: self.duration,
-        };
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>> {
+        let mut writer = BinaryWriter::new();
+        writer.c_string("Image name", &self.name, bp_data_IMAGE_NAME_MAX_LEN)
+            .u32(if self.upscale { self.width / 2 } else { self.width })
+            .u32(if self.upscale { self.height / 2 } else { self.height })
+            .bool(self.upscale)
+            .pad_to(80)
+            .i64(self.duration);
 
-        Ok(unsafe { any_as_u8_vec(&file) })
+        self.data.write(&mut writer).finish(SEQUENCE_FRAME_FILE_SIZE)
+    }
+}
+
+impl FromBinary for SequenceFrame {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BinaryReader::new(data, SEQUENCE_FRAME_FILE_SIZE)?;
+
+        let name = reader.c_string(bp_data_IMAGE_NAME_MAX_LEN)?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let upscale = reader.bool()?;
+        let duration = reader.skip_to(80).i64()?;
+        let data = ImageData::read(&mut reader)?;
+
+        Ok(Self {
+            name,
+            path: Default::default(),
+            width: full_size(width, upscale),
+            height: full_size(height, upscale),
+            upscale,
+            duration,
+            conversion: data.conversion(),
+            data,
+        })
     }
 }
 
@@ -270,156 +481,616 @@ impl Animation {
 }
 
 impl BinaryRepr for Animation {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError> {
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>> {
         let bg = self.background_color;
+        let mut writer = BinaryWriter::new();
 
-        let file = bp_character_animation_file_s {
-            x: self.x,
-            y: self.y,
-            width: self.real_width(),
-            height: self.real_height(),
-            frame_count: self.frames.count(),
-            interval_us: (1_000_000_f64 / self.fps).floor() as i64,
-            clear_screen: self.clear_screen,
-            background_color: rgb_to_565(bg.0, bg.1, bg.2).to_be(),
-            mode: match self.mode {
+        writer.u16(self.x)
+            .u16(self.y)
+            .u32(self.real_width())
+            .u32(self.real_height())
+            .u32(self.frames.count())
+            .i64((1_000_000_f64 / self.fps).floor() as i64)
+            .bool(self.clear_screen)
+            .pad_to(26)
+            // Firmware pushes the color straight to the display, which expects big-endian
+            .u16_be(rgb_to_565(bg.0, bg.1, bg.2))
+            .u32(match self.mode {
                 AnimationMode::FromSDCard => bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_SDCARD,
                 AnimationMode::FromRAM => bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_RAM,
-            },
-            upscale: self.upscale,
+            })
+            .bool(self.upscale)
+            .bool(self.encoding.is_delta())
+            .bool(self.frame_map)
+            .pad_to(36);
+
+        self.frame_data.write(&mut writer).finish(ANIMATION_FILE_SIZE)
+    }
+}
+
+impl FromBinary for Animation {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BinaryReader::new(data, ANIMATION_FILE_SIZE)?;
+
+        let x = reader.u16()?;
+        let y = reader.u16()?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let frame_count = reader.u32()?;
+        let interval_us = reader.i64()?;
+        let clear_screen = reader.bool()?;
+        let (r, g, b) = rgb_from_565(reader.skip_to(26).u16_be()?);
+
+        #[allow(non_upper_case_globals)]
+        let mode = match reader.u32()? {
+            bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_SDCARD => AnimationMode::FromSDCard,
+            bp_character_animation_mode_e_BP_CHARACTER_ANIMATION_MODE_FROM_RAM => AnimationMode::FromRAM,
+            other => bail!("Unknown animation mode {other}")
         };
 
-        Ok(unsafe { any_as_u8_vec(&file) })
+        let upscale = reader.bool()?;
+        let encoding = if reader.bool()? { FrameEncoding::Delta } else { FrameEncoding::Full };
+        let frame_map = reader.bool()?;
+        let frame_data = ImageData::read(reader.skip_to(36))?;
+
+        Ok(Self {
+            x,
+            y,
+            width: full_size(width, upscale),
+            height: full_size(height, upscale),
+            frames: AnimationFrameSource::List(vec![PathBuf::new(); frame_count as usize]),
+            fps: if interval_us > 0 {
+                1_000_000_f64 / interval_us as f64
+            } else {
+                Self::default().fps
+            },
+            clear_screen,
+            background_color: (r, g, b),
+            mode,
+            upscale,
+            encoding,
+            deduplicate_frames: frame_map,
+            conversion: frame_data.conversion(),
+            frame_data,
+            frame_map,
+        })
     }
 }
 
 impl BinaryRepr for StateTransition {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError> {
-        let file = match &self.trigger {
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>> {
+        let mut writer = BinaryWriter::new();
+
+        match &self.trigger {
             StateTransitionTrigger::ElapsedTime { duration } => {
-                bp_state_transition_file_s {
-                    trigger: bp_state_trigger_s {
-                        type_: bp_state_trigger_e_BP_STATE_TRIGGER_ELAPSED_TIME,
-                        data: bp_state_trigger_u {
-                            state_duration_us: *duration
-                        },
-                    },
-                }
+                writer.u32(bp_state_trigger_e_BP_STATE_TRIGGER_ELAPSED_TIME)
+                    .pad_to(8)
+                    .i64(*duration);
             }
             StateTransitionTrigger::Clicked => {
-                bp_state_transition_file_s {
-                    trigger: bp_state_trigger_s {
-                        type_: bp_state_trigger_e_BP_STATE_TRIGGER_CLICKED,
-                        data: bp_state_trigger_u {
-                            no_data: 0
-                        },
-                    },
-                }
+                writer.u32(bp_state_trigger_e_BP_STATE_TRIGGER_CLICKED);
             },
             StateTransitionTrigger::Random { duration_range, chance } => {
-                bp_state_transition_file_s {
-                    trigger: bp_state_trigger_s {
-                        type_: bp_state_trigger_e_BP_STATE_TRIGGER_RANDOM,
-                        data: bp_state_trigger_u {
-                            random_s: duration_range.either(
-                                |tuple| bp_state_trigger_random_s {
-                                    duration_start_range: tuple.pick_min(),
-                                    duration_end_range: tuple.pick_max(),
-                                    chance_mod: *chance,
-                                },
-                                |num| bp_state_trigger_random_s {
-                                    duration_start_range: num,
-                                    duration_end_range: num,
-                                    chance_mod: *chance
-                                }
-                            )
-                        }
-                    }
+                let (start, end) = duration_range.either(
+                    |tuple| (tuple.pick_min(), tuple.pick_max()),
+                    |num| (num, num)
+                );
+
+                writer.u32(bp_state_trigger_e_BP_STATE_TRIGGER_RANDOM)
+                    .pad_to(8)
+                    .i64(start)
+                    .i64(end)
+                    .u32(*chance);
+            }
+        }
+
+        writer.finish(STATE_TRANSITION_FILE_SIZE)
+    }
+}
+
+impl FromBinary for StateTransition {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BinaryReader::new(data, STATE_TRANSITION_FILE_SIZE)?;
+
+        #[allow(non_upper_case_globals)]
+        let trigger = match reader.u32()? {
+            bp_state_trigger_e_BP_STATE_TRIGGER_ELAPSED_TIME => StateTransitionTrigger::ElapsedTime {
+                duration: reader.skip_to(8).i64()?,
+            },
+            bp_state_trigger_e_BP_STATE_TRIGGER_CLICKED => StateTransitionTrigger::Clicked,
+            bp_state_trigger_e_BP_STATE_TRIGGER_RANDOM => {
+                let start = reader.skip_to(8).i64()?;
+                let end = reader.i64()?;
+
+                StateTransitionTrigger::Random {
+                    duration_range: if start == end {
+                        Either::Right(start)
+                    } else {
+                        Either::Left((start, end))
+                    },
+                    chance: reader.u32()?,
                 }
             }
+            other => bail!("Unknown trigger type {other}")
         };
 
-        Ok(unsafe { any_as_u8_vec(&file) })
+        Ok(Self {
+            to_state: String::new(),
+            trigger,
+        })
     }
 }
 
 impl BinaryRepr for State {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError> {
-        let file = match &self.image {
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>> {
+        let mut writer = BinaryWriter::new();
+        writer.u8(self.layer).pad_to(4);
+
+        match &self.image {
             StateImage::None => {
-                bp_character_state_file_s {
-                    layer: self.layer,
-                    image_type: bp_character_state_image_e_BP_CHARACTER_STATE_NO_IMAGE,
-                    image: bp_character_state_image_u {
-                        no_data: 0
-                    },
-                }
+                writer.u32(bp_character_state_image_e_BP_CHARACTER_STATE_NO_IMAGE);
             },
-            StateImage::Single { name, width, height, upscale, layer_load, .. } => {
-                bp_character_state_file_s {
-                    layer: self.layer,
-                    image_type: bp_character_state_image_e_BP_CHARACTER_STATE_SINGLE_IMAGE,
-                    image: bp_character_state_image_u {
-                        image: bp_character_image_descriptor_s {
-                            image_name: string_to_char_array(name)?,
-                            width: if *upscale { *width / 2 } else { *width },
-                            height: if *upscale { *height / 2 } else { *height },
-                            upscale: *upscale,
-                            layer_load: *layer_load,
-                        }
-                    },
-                }
+            StateImage::Single { name, width, height, upscale, layer_load, data, .. } => {
+                writer.u32(bp_character_state_image_e_BP_CHARACTER_STATE_SINGLE_IMAGE)
+                    .c_string("Image name", name, bp_data_IMAGE_NAME_MAX_LEN)
+                    .u32(if *upscale { *width / 2 } else { *width })
+                    .u32(if *upscale { *height / 2 } else { *height })
+                    .bool(*upscale)
+                    .bool(*layer_load)
+                    .pad_to(84);
+                data.write(&mut writer);
             }
             StateImage::Animation { name, next_state, loop_count, layer_load } => {
-                bp_character_state_file_s {
-                    layer: self.layer,
-                    image_type: bp_character_state_image_e_BP_CHARACTER_STATE_ANIMATION,
-                    image: bp_character_state_image_u {
-                        animation: bp_character_state_animation_descriptor_s {
-                            name: string_to_char_array(name)?,
-                            next_state: string_to_char_array(next_state)?,
-                            loop_count: *loop_count,
-                            layer_load: *layer_load
-                        }
-                    }
-                }
+                writer.u32(bp_character_state_image_e_BP_CHARACTER_STATE_ANIMATION)
+                    .c_string("Animation name", name, bp_data_ANIMATION_NAME_MAX_LEN)
+                    .c_string("Next state", next_state, bp_data_STATE_NAME_MAX_LEN)
+                    .u16(*loop_count)
+                    .bool(*layer_load);
             },
             StateImage::Sequence { frames, mode, layer_load, ..} => {
-                bp_character_state_file_s {
-                    layer: self.layer,
-                    image_type: bp_character_state_image_e_BP_CHARACTER_STATE_SEQUENCE,
-                    image: bp_character_state_image_u {
-                        sequence: bp_character_state_sequence_descriptor_s {
-                            layer_load: *layer_load,
-                            frame_count: frames.len() as u16,
-                            mode: match mode {
-                                SequenceMode::LoadAll => bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_ALL,
-                                SequenceMode::LoadEach => bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_EACH
-                            }
-                        }
-                    }
+                writer.u32(bp_character_state_image_e_BP_CHARACTER_STATE_SEQUENCE)
+                    .u16(frames.len() as u16)
+                    .pad_to(12)
+                    .u32(match mode {
+                        SequenceMode::LoadAll => bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_ALL,
+                        SequenceMode::LoadEach => bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_EACH
+                    })
+                    .bool(*layer_load);
+            }
+        }
+
+        writer.finish(STATE_FILE_SIZE)
+    }
+}
+
+impl FromBinary for State {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BinaryReader::new(data, STATE_FILE_SIZE)?;
+
+        let layer = reader.u8()?;
+
+        #[allow(non_upper_case_globals)]
+        let image = match reader.skip_to(4).u32()? {
+            bp_character_state_image_e_BP_CHARACTER_STATE_NO_IMAGE => StateImage::None,
+            bp_character_state_image_e_BP_CHARACTER_STATE_SINGLE_IMAGE => {
+                let name = reader.c_string(bp_data_IMAGE_NAME_MAX_LEN)?;
+                let width = reader.u32()?;
+                let height = reader.u32()?;
+                let upscale = reader.bool()?;
+                let layer_load = reader.bool()?;
+                let data = ImageData::read(reader.skip_to(84))?;
+
+                StateImage::Single {
+                    name,
+                    path: Default::default(),
+                    width: full_size(width, upscale),
+                    height: full_size(height, upscale),
+                    upscale,
+                    layer_load,
+                    conversion: data.conversion(),
+                    data,
+                }
+            }
+            bp_character_state_image_e_BP_CHARACTER_STATE_ANIMATION => StateImage::Animation {
+                name: reader.c_string(bp_data_ANIMATION_NAME_MAX_LEN)?,
+                next_state: reader.c_string(bp_data_STATE_NAME_MAX_LEN)?,
+                loop_count: reader.u16()?,
+                layer_load: reader.bool()?,
+            },
+            bp_character_state_image_e_BP_CHARACTER_STATE_SEQUENCE => {
+                let frame_count = reader.u16()?;
+
+                #[allow(non_upper_case_globals)]
+                let mode = match reader.skip_to(12).u32()? {
+                    bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_ALL => SequenceMode::LoadAll,
+                    bp_character_sequence_mode_e_BP_CHARACTER_SEQUENCE_MODE_LOAD_EACH => SequenceMode::LoadEach,
+                    other => bail!("Unknown sequence mode {other}")
+                };
+
+                StateImage::Sequence {
+                    name: None,
+                    frames: vec![SequenceFrame::default(); frame_count as usize],
+                    mode,
+                    layer_load: reader.bool()?,
                 }
             }
+            other => bail!("Unknown state image type {other}")
         };
 
-        Ok(unsafe { any_as_u8_vec(&file) })
+        Ok(Self {
+            layer,
+            image,
+            transitions: vec![],
+            node_pos: None,
+        })
     }
 }
 
 impl BinaryRepr for Action {
-    fn to_bin(&self) -> Result<Vec<u8>, NulError> {
-        let file = match &self.ty {
+    fn to_bin(&self) -> Result<Vec<u8>, Vec<NameError>> {
+        let mut writer = BinaryWriter::new();
+        writer.c_string("Display name", &self.display, bp_data_ACTION_DISPLAY_MAX_LEN);
+
+        match &self.ty {
             ActionType::SwitchState(state) => {
-                bp_character_action_file_s {
-                    display: string_to_char_array(&self.display)?,
-                    type_: bp_character_action_e_BP_CHARACTER_ACTION_SWITCH_STATE,
-                    data: bp_character_action_u {
-                        state_name: string_to_char_array(state)?
-                    },
-                }
+                writer.u32(bp_character_action_e_BP_CHARACTER_ACTION_SWITCH_STATE)
+                    .c_string("State name", state, bp_data_STATE_NAME_MAX_LEN);
             }
+        }
+
+        writer.finish(ACTION_FILE_SIZE)
+    }
+}
+
+impl FromBinary for Action {
+    fn from_bin(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BinaryReader::new(data, ACTION_FILE_SIZE)?;
+
+        let display = reader.c_string(bp_data_ACTION_DISPLAY_MAX_LEN)?;
+
+        #[allow(non_upper_case_globals)]
+        let ty = match reader.u32()? {
+            bp_character_action_e_BP_CHARACTER_ACTION_SWITCH_STATE => ActionType::SwitchState(
+                reader.c_string(bp_data_STATE_NAME_MAX_LEN)?
+            ),
+            other => bail!("Unknown action type {other}")
         };
 
-        Ok(unsafe { any_as_u8_vec(&file) })
+        Ok(Self {
+            display,
+            ty,
+        })
     }
-}
\ No newline at end of file
+}
+
+fn full_size(size: u32, upscale: bool) -> u32 {
+    if upscale {
+        size * 2
+    } else {
+        size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn golden(size: usize, fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0; size];
+
+        for (offset, bytes) in fields {
+            data[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        data
+    }
+
+    fn assert_round_trip<T: BinaryRepr + FromBinary>(value: &T, expected: &[u8]) {
+        let data = value.to_bin().unwrap();
+        assert_eq!(data, expected);
+        assert_eq!(T::from_bin(&data).unwrap().to_bin().unwrap(), expected);
+    }
+
+    #[test]
+    fn character_layout() {
+        let character = Character {
+            name: "Testy".to_string(),
+            species: "Test".to_string(),
+            ..Default::default()
+        };
+
+        assert_round_trip(&character, &golden(194, &[
+            (0, &[4, 0]),
+            (2, b"Testy"),
+            (66, b"Test"),
+            (130, b"idle"),
+        ]));
+    }
+
+    #[test]
+    fn invalid_names_are_reported() {
+        let character = Character {
+            name: "a".repeat(100),
+            species: "nul\0".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(character.to_bin().unwrap_err(), vec![
+            NameError::TooLong { field: "Name", length: 100, max_len: 63 },
+            NameError::ContainsNul { field: "Species" },
+        ]);
+
+        let character = Character {
+            name: "a".repeat(63),
+            ..Default::default()
+        };
+
+        let data = character.to_bin().unwrap();
+        assert_eq!(&data[2..65], "a".repeat(63).as_bytes());
+        assert_eq!(data[65], 0);
+    }
+
+    #[test]
+    fn transition_layout() {
+        assert_round_trip(&StateTransition {
+            to_state: "idle".to_string(),
+            trigger: StateTransitionTrigger::ElapsedTime { duration: 500_000 },
+        }, &golden(32, &[
+            (0, &[0, 0, 0, 0]),
+            (8, &500_000_i64.to_le_bytes()),
+        ]));
+
+        assert_round_trip(&StateTransition {
+            to_state: "idle".to_string(),
+            trigger: StateTransitionTrigger::Clicked,
+        }, &golden(32, &[
+            (0, &[1, 0, 0, 0]),
+        ]));
+
+        assert_round_trip(&StateTransition {
+            to_state: "idle".to_string(),
+            trigger: StateTransitionTrigger::Random {
+                duration_range: Either::Left((3_000_000, 1_000_000)),
+                chance: 5,
+            },
+        }, &golden(32, &[
+            (0, &[2, 0, 0, 0]),
+            (8, &1_000_000_i64.to_le_bytes()),
+            (16, &3_000_000_i64.to_le_bytes()),
+            (24, &[5, 0, 0, 0]),
+        ]));
+    }
+
+    #[test]
+    fn animation_layout() {
+        let animation = Animation {
+            x: 10,
+            y: 160,
+            width: 320,
+            height: 200,
+            frames: AnimationFrameSource::List(vec![PathBuf::new(); 3]),
+            fps: 20.0,
+            clear_screen: true,
+            background_color: (255, 0, 0),
+            mode: AnimationMode::FromRAM,
+            upscale: true,
+            encoding: FrameEncoding::Delta,
+            deduplicate_frames: true,
+            conversion: Default::default(),
+            frame_data: ImageData {
+                compression: Compression::Lz4,
+                stored_size: 20_000,
+                data_size: 32_000,
+            },
+            frame_map: true,
+        };
+
+        assert_round_trip(&animation, &golden(48, &[
+            (0, &[10, 0]),
+            (2, &[160, 0]),
+            (4, &[160, 0, 0, 0]),
+            (8, &[100, 0, 0, 0]),
+            (12, &[3, 0, 0, 0]),
+            (16, &50_000_i64.to_le_bytes()),
+            (24, &[1]),
+            (26, &[0xF8, 0x00]),
+            (28, &[1, 0, 0, 0]),
+            (32, &[1, 1, 1]),
+            (36, &[2, 0, 0, 0]),
+            (40, &20_000_u32.to_le_bytes()),
+            (44, &32_000_u32.to_le_bytes()),
+        ]));
+    }
+
+    #[test]
+    fn frame_counts_come_from_animated_files() {
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame, Rgba, RgbaImage};
+
+        let location = std::env::temp_dir().join(format!("bp-counts-{}", std::process::id()));
+        fs::create_dir_all(&location).unwrap();
+
+        {
+            let mut encoder = GifEncoder::new(fs::File::create(location.join("wave.gif")).unwrap());
+            for index in 0..3 {
+                let image = RgbaImage::from_pixel(4, 4, Rgba([index * 100, 0, 0, 255]));
+                encoder.encode_frame(Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1))).unwrap();
+            }
+        }
+
+        let mut character = Character::default();
+        character.animations.insert("wave".to_string(), Animation {
+            frames: AnimationFrameSource::File {
+                path: PathBuf::from("wave.gif"),
+                first: 1,
+                last: None,
+                count: 0,
+            },
+            ..Default::default()
+        });
+        character.refresh_frame_counts(&location);
+
+        assert_eq!(character.animations["wave"].frames.count(), 2);
+
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn frame_counts_come_from_sheets() {
+        use crate::image::sheet::{SheetLayout, SpriteSheet};
+        use image::{Rgba, RgbaImage};
+
+        let location = std::env::temp_dir().join(format!("bp-sheet-counts-{}", std::process::id()));
+        fs::create_dir_all(&location).unwrap();
+        RgbaImage::from_pixel(4, 2, Rgba([0, 0, 255, 255])).save(location.join("sheet.png")).unwrap();
+
+        let mut character = Character::default();
+        character.animations.insert("walk".to_string(), Animation {
+            frames: AnimationFrameSource::Sheet {
+                sheet: SpriteSheet {
+                    image: PathBuf::from("sheet.png"),
+                    layout: SheetLayout::Grid {
+                        columns: 2,
+                        rows: 1,
+                        margin_x: 0,
+                        margin_y: 0,
+                        spacing_x: 0,
+                        spacing_y: 0,
+                    },
+                    frames: vec![1, 0, 1],
+                },
+                count: 7,
+            },
+            ..Default::default()
+        });
+        character.refresh_frame_counts(&location);
+
+        assert_eq!(character.animations["walk"].frames.count(), 3);
+
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn frame_files_are_numbered_from_one() {
+        let list = AnimationFrameSource::List(vec![PathBuf::from("a.png"), PathBuf::from("b.png")]);
+        assert_eq!(list.paths(), vec![(1, PathBuf::from("a.png")), (2, PathBuf::from("b.png"))]);
+
+        let indexed = AnimationFrameSource::Indexed {
+            folder: PathBuf::from("wave"),
+            extension: "png".to_string(),
+            count: 2,
+        };
+        assert_eq!(indexed.paths(), vec![(1, PathBuf::from("wave/1.png")), (2, PathBuf::from("wave/2.png"))]);
+    }
+
+    #[test]
+    fn sequence_frame_layout() {
+        let frame = SequenceFrame {
+            name: "blink".to_string(),
+            path: Default::default(),
+            width: 64,
+            height: 32,
+            upscale: false,
+            duration: 250_000,
+            conversion: Default::default(),
+            data: ImageData {
+                compression: Compression::Rle,
+                stored_size: 1000,
+                data_size: 4096,
+            },
+        };
+
+        assert_round_trip(&frame, &golden(104, &[
+            (0, b"blink"),
+            (64, &[64, 0, 0, 0]),
+            (68, &[32, 0, 0, 0]),
+            (80, &250_000_i64.to_le_bytes()),
+            (88, &[1, 0, 0, 0]),
+            (92, &1000_u32.to_le_bytes()),
+            (96, &4096_u32.to_le_bytes()),
+        ]));
+    }
+
+    #[test]
+    fn state_layout() {
+        assert_round_trip(&State {
+            layer: 2,
+            image: StateImage::Single {
+                name: "idle".to_string(),
+                path: Default::default(),
+                width: 320,
+                height: 320,
+                upscale: true,
+                layer_load: true,
+                conversion: Default::default(),
+                data: ImageData {
+                    compression: Compression::None,
+                    stored_size: 51_200,
+                    data_size: 51_200,
+                },
+            },
+            ..Default::default()
+        }, &golden(140, &[
+            (0, &[2]),
+            (4, &[1, 0, 0, 0]),
+            (8, b"idle"),
+            (72, &[160, 0, 0, 0]),
+            (76, &[160, 0, 0, 0]),
+            (80, &[1, 1]),
+            (88, &51_200_u32.to_le_bytes()),
+            (92, &51_200_u32.to_le_bytes()),
+        ]));
+
+        assert_round_trip(&State {
+            layer: 0,
+            image: StateImage::Animation {
+                name: "booped".to_string(),
+                next_state: "idle".to_string(),
+                loop_count: 3,
+                layer_load: true,
+            },
+            ..Default::default()
+        }, &golden(140, &[
+            (4, &[2, 0, 0, 0]),
+            (8, b"booped"),
+            (72, b"idle"),
+            (136, &[3, 0]),
+            (138, &[1]),
+        ]));
+
+        assert_round_trip(&State {
+            layer: 0,
+            image: StateImage::Sequence {
+                name: None,
+                frames: vec![SequenceFrame::default(); 4],
+                mode: SequenceMode::LoadEach,
+                layer_load: false,
+            },
+            ..Default::default()
+        }, &golden(140, &[
+            (4, &[3, 0, 0, 0]),
+            (8, &[4, 0]),
+            (12, &[1, 0, 0, 0]),
+        ]));
+    }
+
+    #[test]
+    fn action_layout() {
+        assert_round_trip(&Action {
+            display: "Boop".to_string(),
+            ty: ActionType::SwitchState("booped".to_string()),
+        }, &golden(132, &[
+            (0, b"Boop"),
+            (64, &[0, 0, 0, 0]),
+            (68, b"booped"),
+        ]));
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        assert!(Character::from_bin(&[1, 0]).is_err());
+        assert!(Character::from_bin(&golden(194, &[(0, &[5, 0])])).is_err());
+        assert!(StateTransition::from_bin(&golden(32, &[(0, &[7, 0, 0, 0])])).is_err());
+        assert!(State::from_bin(&golden(140, &[(4, &[1, 0, 0, 0]), (80, &[2])])).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::character::ArchiveEntry;
+use anyhow::{ensure, Context};
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MIB: u64 = 1 << 20;
+const GIB: u64 = 1 << 30;
+const SECTOR_SIZE: u64 = 512;
+
+/// The partition starts at 1 MiB like on factory formatted cards, which keeps it aligned to erase blocks
+pub const PARTITION_START: u64 = MIB;
+
+/// Smallest disk image that still has enough clusters for FAT32
+pub const MIN_IMAGE_SIZE: u64 = 64 * MIB;
+
+// Sectors before the first FAT, the usual value for FAT32
+const RESERVED_SECTORS: u64 = 32;
+
+/// Cluster size of FAT32 on a card of the size, as picked by common formatting tools.
+/// Small cards get smaller clusters so they still have enough of them for FAT32
+pub fn cluster_size(card_size: u64) -> u64 {
+    match card_size {
+        size if size <= 128 * MIB => 512,
+        size if size <= 256 * MIB => 1024,
+        size if size <= 512 * MIB => 2048,
+        size if size <= 8 * GIB => 4096,
+        size if size <= 16 * GIB => 8192,
+        size if size <= 32 * GIB => 16384,
+        _ => 32768
+    }
+}
+
+/// Space the files take on a FAT32 card
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SdUsage {
+    pub used: u64,
+    pub available: u64,
+    pub cluster_size: u64
+}
+
+impl SdUsage {
+    pub fn fits(&self) -> bool {
+        self.used <= self.available
+    }
+}
+
+/// Files and directories take whole clusters. Directory entries are 32 bytes, long names take one more entry
+/// per 13 characters
+pub fn sd_usage(files: &[ArchiveEntry], card_size: u64) -> SdUsage {
+    let cluster_size = cluster_size(card_size);
+    let clusters = |bytes: u64| bytes.div_ceil(cluster_size);
+
+    let mut paths = BTreeSet::new();
+    let mut used_clusters = 0;
+
+    for (path, data) in files {
+        used_clusters += clusters(data.len() as u64);
+        paths.extend(path.ancestors().filter(|path| !path.as_os_str().is_empty()));
+    }
+
+    // Every directory starts with '.' and '..'
+    let mut entries: BTreeMap<&Path, u64> = BTreeMap::from([(Path::new(""), 2)]);
+
+    for path in &paths {
+        let name_len = path.file_name().map(|name| name.len() as u64).unwrap_or_default();
+        *entries.entry(path.parent().unwrap_or(Path::new(""))).or_insert(2) += 1 + name_len.div_ceil(13);
+    }
+
+    used_clusters += entries.values().map(|&count| clusters(count * 32)).sum::<u64>();
+
+    // Two copies of the FAT with 4 bytes per cluster
+    let volume = card_size.saturating_sub(PARTITION_START + RESERVED_SECTORS * SECTOR_SIZE);
+    let data_clusters = volume / (cluster_size + 8);
+
+    SdUsage {
+        used: used_clusters * cluster_size,
+        available: data_clusters * cluster_size,
+        cluster_size,
+    }
+}
+
+/// Smallest card size the files fit onto, in whole MiB and at least the size FAT32 needs
+pub fn min_card_size(files: &[ArchiveEntry]) -> u64 {
+    let mut size = MIN_IMAGE_SIZE;
+
+    while !sd_usage(files, size).fits() {
+        size += (size / 8).next_multiple_of(MIB);
+    }
+
+    size
+}
+
+/// Part of the disk image that the file system sees as the whole volume
+struct Partition<'a, T> {
+    disk: &'a mut T,
+    start: u64,
+    size: u64,
+    position: u64
+}
+
+impl<T: Seek> Partition<'_, T> {
+    /// Bytes left until the end of the partition, at most `len`
+    fn seek_disk(&mut self, len: usize) -> io::Result<usize> {
+        self.disk.seek(SeekFrom::Start(self.start + self.position))?;
+        Ok((self.size.saturating_sub(self.position)).min(len as u64) as usize)
+    }
+}
+
+impl<T: Read + Seek> Read for Partition<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.seek_disk(buf.len())?;
+        let read = self.disk.read(&mut buf[..len])?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl<T: Write + Seek> Write for Partition<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.seek_disk(buf.len())?;
+
+        if len == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Write past the end of the partition"));
+        }
+
+        let written = self.disk.write(&buf[..len])?;
+        self.position += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl<T> Seek for Partition<'_, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+        };
+
+        self.position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the partition"))?;
+
+        Ok(self.position)
+    }
+}
+
+/// Master boot record with one FAT32 partition that fills the rest of the disk
+fn master_boot_record(partition_sectors: u32) -> [u8; 512] {
+    let mut mbr = [0; 512];
+    let entry = &mut mbr[446..462];
+
+    // Cylinder-head-sector addresses are past the limit, so only the LBA fields are used
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = 0x0c;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&((PARTITION_START / SECTOR_SIZE) as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&partition_sectors.to_le_bytes());
+
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+
+    mbr
+}
+
+/// Writes a partitioned FAT32 disk image of the size with the files, ready to be written onto the card with dd.
+/// File times are left at the FAT epoch, so the same files give the same image
+pub fn write_disk_image<T: Read + Write + Seek>(disk: &mut T, size: u64, files: &[ArchiveEntry]) -> anyhow::Result<()> {
+    ensure!(size >= MIN_IMAGE_SIZE, "Disk image has to be at least {} MiB for FAT32", MIN_IMAGE_SIZE / MIB);
+
+    let sectors = u32::try_from((size - PARTITION_START) / SECTOR_SIZE).context("Disk image is too large for FAT32")?;
+
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&master_boot_record(sectors))?;
+
+    let mut partition = Partition {
+        disk,
+        start: PARTITION_START,
+        size: sectors as u64 * SECTOR_SIZE,
+        position: 0,
+    };
+
+    fatfs::format_volume(&mut partition, FormatVolumeOptions::new()
+        .fat_type(FatType::Fat32)
+        .bytes_per_cluster(cluster_size(size) as u32)
+        .volume_label(*b"BADGE      "))?;
+
+    partition.seek(SeekFrom::Start(0))?;
+    let fs = FileSystem::new(&mut partition, FsOptions::new())?;
+
+    for (path, data) in files {
+        let mut dir = fs.root_dir();
+        let names = path.iter()
+            .map(|name| name.to_str().with_context(|| format!("Path {} isn't valid UTF-8", path.display())))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let Some((file_name, folders)) = names.split_last() else {
+            continue;
+        };
+
+        for folder in folders {
+            dir = dir.create_dir(folder)?;
+        }
+
+        let mut file = dir.create_file(file_name)?;
+        file.truncate()?;
+        file.write_all(data).with_context(|| format!("Failed to write {} into the disk image", path.display()))?;
+    }
+
+    fs.unmount()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn disk_image_has_the_files() {
+        let files: Vec<ArchiveEntry> = vec![
+            (PathBuf::from("characters/cat/character.bin"), vec![1; 700]),
+            (PathBuf::from("characters/cat/selected.lock"), vec![]),
+            (PathBuf::from("characters/dog/states/idle/state.bin"), vec![2; 10]),
+        ];
+
+        let usage = sd_usage(&files, MIN_IMAGE_SIZE);
+        assert!(usage.fits());
+        assert_eq!(min_card_size(&files), MIN_IMAGE_SIZE);
+
+        let mut disk = Cursor::new(vec![0; MIN_IMAGE_SIZE as usize]);
+        write_disk_image(&mut disk, MIN_IMAGE_SIZE, &files).unwrap();
+        assert_eq!(&disk.get_ref()[510..512], &[0x55, 0xaa]);
+
+        let mut partition = Partition {
+            disk: &mut disk,
+            start: PARTITION_START,
+            size: MIN_IMAGE_SIZE - PARTITION_START,
+            position: 0,
+        };
+        let fs = FileSystem::new(&mut partition, FsOptions::new()).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat32);
+
+        let mut data = vec![];
+        fs.root_dir().open_file("characters/cat/character.bin").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![1; 700]);
+        assert!(fs.root_dir().open_file("characters/cat/selected.lock").is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::image::{ConversionOptions, PixelFormat};
+use anyhow::bail;
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+use std::rc::{Rc, Weak};
+
+/// Size of the static image storage, `CONFIG_IMAGE_STATIC_STORAGE_SIZE` of the firmware
+pub const IMAGE_STORAGE_SIZE: u64 = 7_000_000;
+
+/// Reads `CONFIG_IMAGE_STATIC_STORAGE_SIZE` from the sdkconfig of the board, it's given in KB
+pub fn storage_size_from_sdkconfig(path: impl AsRef<Path>) -> anyhow::Result<u64> {
+    let config = fs::read_to_string(path.as_ref())?;
+
+    let Some(value) = config.lines()
+        .find_map(|line| line.trim().strip_prefix("CONFIG_IMAGE_STATIC_STORAGE_SIZE="))
+    else {
+        bail!("CONFIG_IMAGE_STATIC_STORAGE_SIZE isn't set in '{}'", path.as_ref().display());
+    };
+
+    Ok(value.trim().parse::<u64>()? * 1000)
+}
+
+/// Size of image data in the pixel format, upscaled images are stored at half the size
+pub fn required_space(format: PixelFormat, options: &ConversionOptions, width: u32, height: u32, upscale: bool) -> u64 {
+    let width = if upscale { width / 2 } else { width };
+    let height = if upscale { height / 2 } else { height };
+
+    options.data_size(format, width, height)
+}
+
+/// First fit allocator of the image storage, same as `ImageDataAllocator` of the firmware.
+/// Allocations are freed as soon as the last [StrongAllocation] is dropped
+pub struct AllocatorState {
+    size: u64,
+    allocations: Vec<WeakAllocation>,
+}
+
+impl Default for AllocatorState {
+    fn default() -> Self {
+        Self::new(IMAGE_STORAGE_SIZE)
+    }
+}
+
+impl AllocatorState {
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            allocations: vec![],
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn clear_expired(&mut self) {
+        self.allocations.retain(|e| e.upgrade().is_some())
+    }
+
+    pub fn existing_allocations(&self) -> Vec<Allocation> {
+        self.allocations
+            .iter()
+            .filter_map(|e| e.upgrade())
+            .map(|e| e.deref().clone())
+            .collect()
+    }
+
+    pub fn used(&self) -> u64 {
+        self.existing_allocations()
+            .iter()
+            .map(Allocation::len)
+            .sum()
+    }
+
+    pub fn largest_free_block(&self) -> u64 {
+        let mut existing = self.existing_allocations();
+
+        existing.sort_unstable();
+
+        let mut largest = 0_u64;
+        let mut block_start = 0_u64;
+
+        for occlusion in &existing {
+            largest = largest.max(occlusion.start - block_start);
+            block_start = occlusion.end + 1
+        }
+
+        largest.max(self.size.saturating_sub(block_start))
+    }
+
+    fn find_space(&self, size: u64) -> Option<u64> {
+        let mut existing = self.existing_allocations();
+
+        existing.sort_unstable();
+
+        let mut block_start = 0_u64;
+
+        for occlusion in &existing {
+            let current_size = occlusion.start - block_start;
+
+            if current_size >= size {
+                return Some(block_start);
+            }
+
+            block_start = occlusion.end + 1
+        }
+
+        let remaining_size = self.size.saturating_sub(block_start);
+
+        if remaining_size >= size {
+            return Some(block_start);
+        }
+
+        None
+    }
+
+    pub fn allocate(&mut self, size: u64) -> Option<StrongAllocation> {
+        self.clear_expired();
+
+        let block_start = self.find_space(size)?;
+
+        let ptr = Rc::new(Allocation {
+            start: block_start,
+            end: block_start + size - 1,
+        });
+
+        self.allocations.push(Rc::downgrade(&ptr));
+
+        Some(ptr)
+    }
+}
+
+/// Allocations never overlap, so they're ordered by their position
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Allocation {
+    pub start: u64,
+    pub end: u64,
+}
+
+pub type StrongAllocation = Rc<Allocation>;
+pub type WeakAllocation = Weak<Allocation>;
+
+impl Allocation {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Allocations include their end, so they always hold at least a byte
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_space_is_reused_first() {
+        let mut allocator = AllocatorState::default();
+
+        let first = allocator.allocate(100).unwrap();
+        let second = allocator.allocate(50).unwrap();
+        assert_eq!((second.start, second.end), (100, 149));
+
+        drop(first);
+        assert_eq!(allocator.used(), 50);
+        assert_eq!(allocator.largest_free_block(), IMAGE_STORAGE_SIZE - 150);
+
+        let third = allocator.allocate(80).unwrap();
+        assert_eq!((third.start, third.end), (0, 79));
+
+        assert!(allocator.allocate(IMAGE_STORAGE_SIZE).is_none());
+    }
+}
@@ -0,0 +1,1314 @@
+use crate::character::repr::{ActionType, Animation, AnimationMode, Character, SequenceMode, State, StateImage, StateTransitionTrigger};
+use crate::character::sim::allocator::{required_space, AllocatorState, StrongAllocation};
+use crate::character::sim::random::{RandomRoll, RandomSource, RollKind};
+use crate::character::util::TuplePick;
+use crate::target::TargetProfile;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use strum::{Display, EnumIs};
+
+pub mod allocator;
+pub mod random;
+pub mod timeline;
+
+/// Interval between FSM ticks, `TASK_INTERVAL` of the firmware
+pub const TICK_INTERVAL_US: i64 = 50_000;
+/// Delay after every image loaded by cookers and layer loader, `COOKER_LOAD_DELAY` of the firmware
+pub const LOAD_DELAY_US: i64 = 30_000;
+/// `FrameTimer` never waits less than this between animation frames
+const MIN_FRAME_TIME_US: i64 = 30_000;
+
+/// How long the animation blocks the FSM when played `loop_count` times
+pub fn animation_duration(animation: &Animation, loop_count: u16) -> i64 {
+    let interval = (1_000_000_f64 / animation.fps).floor() as i64;
+    let frame_time = (interval / 1000 * 1000).max(MIN_FRAME_TIME_US);
+
+    frame_time * animation.frames.count() as i64 * loop_count as i64
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TraceEntry {
+    /// Microseconds since the character was loaded
+    pub time: i64,
+    pub event: SimEvent
+}
+
+#[derive(Clone, Debug, Display, Serialize)]
+pub enum SimEvent {
+    #[strum(to_string = "Entered state '{state}'")]
+    StateEntered {
+        state: String
+    },
+    #[strum(to_string = "'{from}' -> '{to}' ({cause}): {result}")]
+    Transition {
+        from: String,
+        to: String,
+        cause: SwitchCause,
+        result: SwitchResult
+    },
+    #[strum(to_string = "Clicked")]
+    Clicked,
+    #[strum(to_string = "Click ignored, animation is playing")]
+    ClickIgnored,
+    #[strum(to_string = "Unknown action '{0}'")]
+    UnknownAction(String),
+    #[strum(to_string = "Started cooking state '{state}' ({bytes} bytes)")]
+    CookingStarted {
+        state: String,
+        bytes: u64
+    },
+    #[strum(to_string = "Finished cooking state '{state}'")]
+    CookingFinished {
+        state: String
+    },
+    #[strum(to_string = "Started loading layer {layer} for state '{state}' ({bytes} bytes)")]
+    LayerLoadingStarted {
+        layer: u8,
+        state: String,
+        bytes: u64
+    },
+    #[strum(to_string = "Finished loading layer {layer}")]
+    LayerLoaded {
+        layer: u8
+    },
+    #[strum(to_string = "Playing animation '{animation}' for {duration}us")]
+    AnimationStarted {
+        animation: String,
+        duration: i64
+    },
+    #[strum(to_string = "Rolled {0}")]
+    RandomRolled(RandomRoll),
+    #[strum(to_string = "{0}")]
+    Failure(SimFailure)
+}
+
+#[derive(Clone, Debug, Display, Serialize)]
+pub enum SwitchCause {
+    #[strum(to_string = "clicked")]
+    Clicked,
+    #[strum(to_string = "elapsed time")]
+    ElapsedTime,
+    #[strum(to_string = "random")]
+    Random,
+    #[strum(to_string = "action '{0}'")]
+    Action(String),
+    #[strum(to_string = "animation ended")]
+    AnimationEnd,
+    #[strum(to_string = "queued")]
+    Queue,
+    #[strum(to_string = "manual")]
+    Manual
+}
+
+#[derive(Copy, Clone, Debug, Display, Serialize, PartialEq, Eq, EnumIs)]
+pub enum SwitchResult {
+    #[strum(to_string = "switched")]
+    Switched,
+    #[strum(to_string = "cooking")]
+    Cooking,
+    #[strum(to_string = "loading layer")]
+    LoadingLayer,
+    #[strum(to_string = "queued")]
+    Queued,
+    #[strum(to_string = "dropped")]
+    Dropped
+}
+
+#[derive(Clone, Debug, Display, Serialize, PartialEq, Eq)]
+pub enum SimFailure {
+    #[strum(to_string = "Out of memory cooking state '{state}', needed {requested} bytes, but largest free block is {largest_free} bytes")]
+    CookingOutOfMemory {
+        state: String,
+        requested: u64,
+        largest_free: u64
+    },
+    #[strum(to_string = "Out of memory loading layer {layer}, needed {requested} bytes, but largest free block is {largest_free} bytes, the firmware crashes")]
+    LayerOutOfMemory {
+        layer: u8,
+        requested: u64,
+        largest_free: u64
+    },
+    #[strum(to_string = "State '{0}' doesn't exist, the firmware crashes")]
+    MissingState(String),
+    #[strum(to_string = "Animation '{0}' doesn't exist, the firmware crashes")]
+    MissingAnimation(String),
+    #[strum(to_string = "Expected state '{expected}', but the current state is '{actual}'")]
+    UnexpectedState {
+        expected: String,
+        actual: String
+    }
+}
+
+impl SimFailure {
+    /// Failures that abort the firmware, nothing is simulated after them
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, SimFailure::CookingOutOfMemory { .. } | SimFailure::UnexpectedState { .. })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MemoryPeak {
+    pub bytes: u64,
+    pub time: i64,
+    /// State that was being loaded at the moment, or the current one
+    pub state: String
+}
+
+/// Everything that occupies the image storage, split the same way the firmware holds it
+#[derive(Default)]
+pub struct SimMemory {
+    pub allocator: AllocatorState,
+
+    pub layer_images: HashMap<String, StrongAllocation>,
+    pub layer_animations: HashMap<String, Vec<StrongAllocation>>,
+
+    pub layer_images_to_remove: HashSet<String>,
+    pub layer_animations_to_remove: HashSet<String>,
+
+    /// Loaded by the layer loader, but the state on the new layer isn't entered yet
+    pub new_layer_images: HashSet<String>,
+    pub new_layer_animations: HashSet<String>,
+
+    pub loaded_images: Vec<StrongAllocation>,
+    pub prepared_images: Vec<StrongAllocation>,
+
+    pub current_image: Option<StrongAllocation>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, EnumIs)]
+pub enum TaskKind {
+    Cooking,
+    LayerLoading(u8)
+}
+
+/// Cooker or layer loader running next to the FSM
+#[derive(Clone, Debug)]
+pub struct BackgroundTask {
+    pub kind: TaskKind,
+    pub state: String,
+    pub finishes_at: i64,
+    failure: Option<SimFailure>
+}
+
+struct PlayingAnimation {
+    ends_at: i64,
+    state: String,
+    next_state: String,
+    time_since_transition: i64
+}
+
+pub struct StateSwitchInfo {
+    pub name: String,
+    pub is_dynamic: bool,
+    pub is_layer_switch: bool
+}
+
+/// Headless model of `CharacterFSM` running on a virtual clock. Allocations, cooking, layer loading
+/// and trigger evaluation follow the firmware, including the order in which things are freed
+pub struct Simulator {
+    character: Character,
+    target: TargetProfile,
+    random: RandomSource,
+    memory: SimMemory,
+
+    now: i64,
+    next_tick: i64,
+
+    current_state: String,
+    last_transition_time: i64,
+    sequence_index: Option<usize>,
+    next_frame_time: i64,
+    ui_dirty: bool,
+    queued_state: Option<String>,
+    random_durations: HashMap<String, i64>,
+
+    prepared_load_layer: u8,
+    being_cooked_state: String,
+    task: Option<BackgroundTask>,
+    playing: Option<PlayingAnimation>,
+
+    trace: Vec<TraceEntry>,
+    peak: MemoryPeak,
+    state_peaks: BTreeMap<String, u64>,
+    crashed: bool
+}
+
+impl Simulator {
+    /// Loads the character like `CharacterFSM::load_character_sl`, preloading the default layer
+    pub fn new(character: Character, seed: u64) -> Self {
+        Self::with_target(character, seed, &TargetProfile::default())
+    }
+
+    /// Same as [Simulator::new] for a board with different image storage size and pixel format
+    pub fn with_target(character: Character, seed: u64, target: &TargetProfile) -> Self {
+        let default_layer = character.states.get(&character.default_state)
+            .map(|state| state.layer)
+            .unwrap_or_default();
+
+        let mut sim = Self {
+            character,
+            target: target.clone(),
+            random: RandomSource::new(seed),
+            memory: SimMemory {
+                allocator: AllocatorState::new(target.storage_size),
+                ..Default::default()
+            },
+            now: 0,
+            next_tick: TICK_INTERVAL_US,
+            current_state: String::new(),
+            last_transition_time: 0,
+            sequence_index: None,
+            next_frame_time: 0,
+            ui_dirty: false,
+            queued_state: None,
+            random_durations: HashMap::new(),
+            prepared_load_layer: default_layer,
+            being_cooked_state: String::new(),
+            task: None,
+            playing: None,
+            trace: vec![],
+            peak: MemoryPeak::default(),
+            state_peaks: BTreeMap::new(),
+            crashed: false,
+        };
+
+        let default_state = sim.character.default_state.clone();
+
+        if !sim.character.states.contains_key(&default_state) {
+            sim.fail(SimFailure::MissingState(default_state));
+            return sim;
+        }
+
+        sim.preload_layer_data(default_layer);
+
+        if !sim.crashed {
+            sim.switch_state_unchecked(&default_state);
+        }
+
+        sim
+    }
+
+    pub fn character(&self) -> &Character {
+        &self.character
+    }
+
+    pub fn target(&self) -> &TargetProfile {
+        &self.target
+    }
+
+    pub fn memory(&self) -> &SimMemory {
+        &self.memory
+    }
+
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+
+    /// Empty until the default state is entered
+    pub fn current_state(&self) -> &str {
+        &self.current_state
+    }
+
+    /// Layer that is currently loaded into memory
+    pub fn current_layer(&self) -> u8 {
+        self.prepared_load_layer
+    }
+
+    pub fn task(&self) -> Option<&BackgroundTask> {
+        self.task.as_ref()
+    }
+
+    pub fn trace(&self) -> &[TraceEntry] {
+        &self.trace
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = (i64, &SimFailure)> {
+        self.trace.iter()
+            .filter_map(|entry| match &entry.event {
+                SimEvent::Failure(failure) => Some((entry.time, failure)),
+                _ => None
+            })
+    }
+
+    pub fn peak(&self) -> &MemoryPeak {
+        &self.peak
+    }
+
+    /// Highest memory usage while each state was current or being loaded
+    pub fn state_peaks(&self) -> &BTreeMap<String, u64> {
+        &self.state_peaks
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.random.seed()
+    }
+
+    /// Values to use for the next random rolls instead of the seeded ones, like the ones recorded by [Simulator::rolls]
+    pub fn force_rolls(&mut self, values: impl IntoIterator<Item = u32>) {
+        self.random.force(values);
+    }
+
+    pub fn rolls(&self) -> impl Iterator<Item = (i64, &RandomRoll)> {
+        self.trace.iter()
+            .filter_map(|entry| match &entry.event {
+                SimEvent::RandomRolled(roll) => Some((entry.time, roll)),
+                _ => None
+            })
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Runs FSM ticks, animations and background tasks until the time
+    pub fn advance_to(&mut self, time: i64) {
+        while !self.crashed {
+            let animation_end = self.playing.as_ref().map(|playing| playing.ends_at);
+
+            // Cooker waits for the animation to stop before handing over the images
+            let task_end = self.task.as_ref().map(|task| match (task.kind, animation_end) {
+                (TaskKind::Cooking, Some(end)) => task.finishes_at.max(end),
+                _ => task.finishes_at
+            });
+
+            let next = [animation_end, task_end, Some(self.next_tick)].into_iter()
+                .flatten()
+                .min()
+                .unwrap_or(i64::MAX);
+
+            if next > time {
+                break;
+            }
+
+            self.now = next;
+
+            if animation_end == Some(next) {
+                self.end_animation();
+            } else if task_end == Some(next) {
+                self.finish_task();
+            } else {
+                self.tick();
+
+                self.next_tick = if self.playing.is_some() {
+                    i64::MAX
+                } else {
+                    self.now + TICK_INTERVAL_US
+                };
+            }
+        }
+
+        self.now = self.now.max(time);
+    }
+
+    pub fn advance(&mut self, duration: i64) {
+        self.advance_to(self.now + duration);
+    }
+
+    /// Finishes the running cooker or layer loader right away, without ticking the FSM
+    pub fn complete_task(&mut self) {
+        if let Some(task) = &self.task {
+            self.now = self.now.max(task.finishes_at);
+            self.finish_task();
+        }
+    }
+
+    /// Touch on the screen, `image_clicked` of the firmware
+    pub fn click(&mut self) {
+        // LVGL is locked for the whole animation, so the touch never reaches it
+        if self.playing.is_some() {
+            self.record(SimEvent::ClickIgnored);
+            return;
+        }
+
+        let Some(state) = self.character.states.get(&self.current_state).cloned() else {
+            return;
+        };
+
+        self.record(SimEvent::Clicked);
+
+        for transition in &state.transitions {
+            if transition.trigger.is_clicked() {
+                self.traced_switch(&transition.to_state, SwitchCause::Clicked, Self::switch_state);
+            }
+        }
+    }
+
+    /// Action invoked over BLE, returns false if it doesn't exist
+    pub fn invoke_action(&mut self, action: &str) -> bool {
+        let Some(ActionType::SwitchState(state)) = self.character.actions.get(action)
+            .map(|action| action.ty.clone())
+        else {
+            self.record(SimEvent::UnknownAction(action.to_string()));
+            return false;
+        };
+
+        self.traced_switch(&state, SwitchCause::Action(action.to_string()), Self::switch_state);
+        true
+    }
+
+    /// Switches to the state right away, as if some trigger fired
+    pub fn request_switch(&mut self, state: &str) -> SwitchResult {
+        self.traced_switch(state, SwitchCause::Manual, Self::switch_state)
+    }
+
+    /// Records a failure if the current state isn't the expected one
+    pub fn expect_state(&mut self, state: &str) -> bool {
+        if self.current_state == state {
+            return true;
+        }
+
+        self.fail(SimFailure::UnexpectedState {
+            expected: state.to_string(),
+            actual: self.current_state.clone(),
+        });
+
+        false
+    }
+
+    pub fn switch_info(&self, state: &str) -> Option<StateSwitchInfo> {
+        let state_data = self.character.states.get(state)?;
+
+        Some(StateSwitchInfo {
+            name: state.to_string(),
+            is_dynamic: self.needs_cooking(state_data),
+            is_layer_switch: state_data.layer != self.prepared_load_layer,
+        })
+    }
+
+    /// States the current one can switch to by its own transitions
+    pub fn possible_transitions(&self) -> Vec<StateSwitchInfo> {
+        let Some(state) = self.character.states.get(&self.current_state) else {
+            return vec![];
+        };
+
+        if let StateImage::Animation { next_state, .. } = &state.image {
+            return self.switch_info(next_state).into_iter().collect();
+        }
+
+        state.transitions.iter()
+            .filter_map(|transition| self.switch_info(&transition.to_state))
+            .collect()
+    }
+
+    /// States that can be switched to by actions, sorted by action name
+    pub fn possible_actions(&self) -> Vec<StateSwitchInfo> {
+        let actions = self.character.actions.iter().collect::<BTreeMap<_, _>>();
+
+        actions.values()
+            .filter_map(|action| {
+                let ActionType::SwitchState(state) = &action.ty;
+                self.switch_info(state)
+            })
+            .collect()
+    }
+
+    fn record(&mut self, event: SimEvent) {
+        self.trace.push(TraceEntry {
+            time: self.now,
+            event,
+        });
+    }
+
+    fn fail(&mut self, failure: SimFailure) {
+        if failure.is_fatal() {
+            self.crashed = true;
+        }
+
+        self.record(SimEvent::Failure(failure));
+    }
+
+    fn traced_switch(
+        &mut self,
+        state: &str,
+        cause: SwitchCause,
+        switch: impl FnOnce(&mut Self, &str) -> SwitchResult
+    ) -> SwitchResult {
+        let from = self.current_state.clone();
+
+        // Transition goes before everything it caused
+        let index = self.trace.len();
+        let result = switch(self, state);
+
+        self.trace.insert(index, TraceEntry {
+            time: self.now,
+            event: SimEvent::Transition {
+                from,
+                to: state.to_string(),
+                cause,
+                result,
+            },
+        });
+
+        result
+    }
+
+    fn allocate(&mut self, size: u64) -> Option<StrongAllocation> {
+        let allocation = self.memory.allocator.allocate(size)?;
+
+        let used = self.memory.allocator.used();
+        let state = match &self.task {
+            Some(task) => task.state.clone(),
+            // Default layer is preloaded before any state is entered
+            None if self.current_state.is_empty() => self.character.default_state.clone(),
+            None => self.current_state.clone()
+        };
+
+        if used > self.peak.bytes {
+            self.peak = MemoryPeak {
+                bytes: used,
+                time: self.now,
+                state: state.clone(),
+            };
+        }
+
+        let state_peak = self.state_peaks.entry(state).or_default();
+        *state_peak = (*state_peak).max(used);
+
+        Some(allocation)
+    }
+
+    fn animation_frames(&mut self, name: &str) -> Option<(u64, u32)> {
+        let Some(animation) = self.character.animations.get(name) else {
+            self.fail(SimFailure::MissingAnimation(name.to_string()));
+            return None;
+        };
+
+        Some((
+            required_space(self.target.pixel_format, &animation.conversion, animation.width, animation.height, animation.upscale),
+            animation.frames.count()
+        ))
+    }
+
+    /// `preload_layer_data` of the firmware
+    fn preload_layer_data(&mut self, layer: u8) {
+        let states = self.character.states.values()
+            .filter(|state| state.layer == layer)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut images = vec![];
+        let mut animations = vec![];
+
+        for state in &states {
+            match &state.image {
+                StateImage::Single { name, width, height, upscale, layer_load: true, conversion, .. } => {
+                    images.push((name.clone(), required_space(self.target.pixel_format, conversion, *width, *height, *upscale)));
+                }
+                StateImage::Sequence { frames, layer_load: true, .. } => {
+                    images.extend(frames.iter()
+                        .map(|frame| (frame.name.clone(), required_space(self.target.pixel_format, &frame.conversion, frame.width, frame.height, frame.upscale))));
+                }
+                StateImage::Animation { name, layer_load: true, .. } => {
+                    animations.push(name.clone());
+                }
+                _ => {}
+            }
+        }
+
+        images.sort();
+        animations.sort();
+
+        for (name, size) in images {
+            // Duplicates are still loaded, and then thrown away
+            let Some(allocation) = self.allocate(size) else {
+                self.layer_out_of_memory(layer, size);
+                return;
+            };
+
+            self.memory.layer_images.entry(name).or_insert(allocation);
+        }
+
+        // Animation used by multiple states gets its frames loaded again for every state
+        for name in animations {
+            let Some((size, count)) = self.animation_frames(&name) else {
+                return;
+            };
+
+            for _ in 0..count {
+                let Some(allocation) = self.allocate(size) else {
+                    self.layer_out_of_memory(layer, size);
+                    return;
+                };
+
+                self.memory.layer_animations.entry(name.clone()).or_default().push(allocation);
+            }
+        }
+    }
+
+    fn layer_out_of_memory(&mut self, layer: u8, requested: u64) {
+        let largest_free = self.memory.allocator.largest_free_block();
+
+        self.fail(SimFailure::LayerOutOfMemory {
+            layer,
+            requested,
+            largest_free,
+        });
+    }
+
+    /// `layer_loader` of the firmware, up to the point where it switches to the state
+    fn start_layer_loading(&mut self, state: &str, layer: u8) -> SwitchResult {
+        let mut images = BTreeMap::new();
+        let mut animations = BTreeMap::new();
+
+        let states = self.character.states.values()
+            .filter(|state| state.layer == layer)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for layer_state in &states {
+            match &layer_state.image {
+                StateImage::Single { name, width, height, upscale, layer_load: true, conversion, .. } => {
+                    images.entry(name.clone()).or_insert(required_space(self.target.pixel_format, conversion, *width, *height, *upscale));
+                }
+                StateImage::Sequence { frames, layer_load: true, .. } => {
+                    for frame in frames {
+                        images.entry(frame.name.clone())
+                            .or_insert(required_space(self.target.pixel_format, &frame.conversion, frame.width, frame.height, frame.upscale));
+                    }
+                }
+                StateImage::Animation { name, layer_load: true, .. } => {
+                    let Some(frames) = self.animation_frames(name) else {
+                        return SwitchResult::Dropped;
+                    };
+
+                    animations.insert(name.clone(), frames);
+                }
+                _ => {}
+            }
+        }
+
+        self.memory.layer_images_to_remove = self.memory.layer_images.keys()
+            .filter(|name| !images.contains_key(*name))
+            .cloned()
+            .collect();
+        self.memory.layer_animations_to_remove = self.memory.layer_animations.keys()
+            .filter(|name| !animations.contains_key(*name))
+            .cloned()
+            .collect();
+
+        let mut loaded = 0_i64;
+        let mut bytes = 0_u64;
+        let mut failure = None;
+
+        'load: {
+            for (name, size) in images {
+                if self.memory.layer_images.contains_key(&name) {
+                    continue;
+                }
+
+                let Some(allocation) = self.allocate(size) else {
+                    failure = Some((size, self.memory.allocator.largest_free_block()));
+                    break 'load;
+                };
+
+                self.memory.layer_images.insert(name.clone(), allocation);
+                self.memory.new_layer_images.insert(name);
+
+                loaded += 1;
+                bytes += size;
+            }
+
+            for (name, (size, count)) in animations {
+                if self.memory.layer_animations.contains_key(&name) {
+                    continue;
+                }
+
+                let mut frames = vec![];
+
+                for _ in 0..count {
+                    let Some(allocation) = self.allocate(size) else {
+                        failure = Some((size, self.memory.allocator.largest_free_block()));
+                        break 'load;
+                    };
+
+                    frames.push(allocation);
+
+                    loaded += 1;
+                    bytes += size;
+                }
+
+                self.memory.layer_animations.insert(name.clone(), frames);
+                self.memory.new_layer_animations.insert(name);
+            }
+        }
+
+        self.task = Some(BackgroundTask {
+            kind: TaskKind::LayerLoading(layer),
+            state: state.to_string(),
+            finishes_at: self.now + loaded * LOAD_DELAY_US,
+            failure: failure.map(|(requested, largest_free)| SimFailure::LayerOutOfMemory {
+                layer,
+                requested,
+                largest_free,
+            }),
+        });
+
+        self.record(SimEvent::LayerLoadingStarted {
+            layer,
+            state: state.to_string(),
+            bytes,
+        });
+
+        SwitchResult::LoadingLayer
+    }
+
+    /// Whether switching to the state on the same layer starts a cooker
+    fn needs_cooking(&self, state: &State) -> bool {
+        match &state.image {
+            StateImage::None => false,
+            StateImage::Single { layer_load, .. } => !*layer_load,
+            StateImage::Animation { name, layer_load, .. } => {
+                !*layer_load && self.character.animations.get(name)
+                    .is_some_and(|animation| animation.mode.is_from_ram())
+            }
+            StateImage::Sequence { layer_load, .. } => !*layer_load,
+        }
+    }
+
+    /// `cook_if_needed` and the cookers of the firmware
+    fn start_cooking(&mut self, state_name: &str, state: &State) {
+        let (sizes, delay) = match &state.image {
+            StateImage::Single { width, height, upscale, conversion, .. } => {
+                (vec![required_space(self.target.pixel_format, conversion, *width, *height, *upscale)], 0)
+            }
+            StateImage::Animation { name, .. } => {
+                let Some((size, count)) = self.animation_frames(name) else {
+                    return;
+                };
+
+                (vec![size; count as usize], LOAD_DELAY_US)
+            }
+            StateImage::Sequence { frames, mode: SequenceMode::LoadAll, .. } => {
+                let sizes = frames.iter()
+                    .map(|frame| required_space(self.target.pixel_format, &frame.conversion, frame.width, frame.height, frame.upscale))
+                    .collect();
+
+                (sizes, LOAD_DELAY_US)
+            }
+            StateImage::Sequence { frames, mode: SequenceMode::LoadEach, .. } => {
+                let largest = frames.iter()
+                    .map(|frame| required_space(self.target.pixel_format, &frame.conversion, frame.width, frame.height, frame.upscale))
+                    .max();
+
+                // Two buffers, one on screen and one being loaded
+                (largest.map(|size| vec![size; 2]).unwrap_or_default(), 0)
+            }
+            StateImage::None => (vec![], 0)
+        };
+
+        self.being_cooked_state = state_name.to_string();
+        self.memory.prepared_images.clear();
+
+        self.record(SimEvent::CookingStarted {
+            state: state_name.to_string(),
+            bytes: sizes.iter().sum(),
+        });
+
+        let mut loaded = 0_i64;
+        let mut failure = None;
+
+        // Task is set first, so the allocations are attributed to the cooked state
+        self.task = Some(BackgroundTask {
+            kind: TaskKind::Cooking,
+            state: state_name.to_string(),
+            finishes_at: self.now,
+            failure: None,
+        });
+
+        for size in sizes {
+            let Some(allocation) = self.allocate(size) else {
+                failure = Some(SimFailure::CookingOutOfMemory {
+                    state: state_name.to_string(),
+                    requested: size,
+                    largest_free: self.memory.allocator.largest_free_block(),
+                });
+                break;
+            };
+
+            self.memory.prepared_images.push(allocation);
+            loaded += 1;
+        }
+
+        if let Some(task) = &mut self.task {
+            task.finishes_at = self.now + loaded * delay;
+            task.failure = failure;
+        }
+    }
+
+    /// `done_cooking_sl` and the end of `layer_loader` of the firmware
+    fn finish_task(&mut self) {
+        let Some(task) = self.task.take() else {
+            return;
+        };
+
+        match task.kind {
+            TaskKind::Cooking => {
+                if let Some(failure) = task.failure {
+                    self.fail(failure);
+                } else {
+                    self.memory.loaded_images = std::mem::take(&mut self.memory.prepared_images);
+                    self.record(SimEvent::CookingFinished {
+                        state: task.state.clone(),
+                    });
+                    self.switch_state_internal(&task.state);
+                }
+
+                self.remove_unneeded_layer_data();
+            }
+            TaskKind::LayerLoading(layer) => {
+                if let Some(failure) = task.failure {
+                    self.fail(failure);
+                    return;
+                }
+
+                self.prepared_load_layer = layer;
+                self.record(SimEvent::LayerLoaded { layer });
+                self.switch_state_unchecked(&task.state);
+            }
+        }
+    }
+
+    fn remove_unneeded_layer_data(&mut self) {
+        let memory = &mut self.memory;
+
+        for name in memory.layer_images_to_remove.drain() {
+            memory.layer_images.remove(&name);
+        }
+
+        for name in memory.layer_animations_to_remove.drain() {
+            memory.layer_animations.remove(&name);
+        }
+
+        memory.new_layer_images.clear();
+        memory.new_layer_animations.clear();
+    }
+
+    fn switch_state_internal(&mut self, state: &str) {
+        self.current_state = state.to_string();
+        self.last_transition_time = self.now;
+        self.sequence_index = None;
+        self.next_frame_time = 0;
+        self.random_durations.clear();
+        self.ui_dirty = true;
+
+        self.record(SimEvent::StateEntered {
+            state: state.to_string(),
+        });
+    }
+
+    fn switch_state_unchecked(&mut self, state_name: &str) -> SwitchResult {
+        let Some(state) = self.character.states.get(state_name).cloned() else {
+            self.fail(SimFailure::MissingState(state_name.to_string()));
+            return SwitchResult::Dropped;
+        };
+
+        if state.layer != self.prepared_load_layer {
+            if self.task.as_ref().is_some_and(|task| task.kind.is_layer_loading()) {
+                return SwitchResult::Dropped;
+            }
+
+            return self.start_layer_loading(state_name, state.layer);
+        }
+
+        if self.needs_cooking(&state) {
+            self.start_cooking(state_name, &state);
+            return SwitchResult::Cooking;
+        }
+
+        self.switch_state_internal(state_name);
+
+        self.memory.loaded_images.clear();
+        self.remove_unneeded_layer_data();
+
+        SwitchResult::Switched
+    }
+
+    /// `switch_state_sl` of the firmware, queues the switch if the FSM is busy
+    fn switch_state(&mut self, state: &str) -> SwitchResult {
+        if self.playing.is_some() || self.task.is_some() {
+            let cooking = self.task.as_ref().is_some_and(|task| task.kind.is_cooking());
+
+            if !cooking && self.being_cooked_state != state {
+                self.queued_state = Some(state.to_string());
+                return SwitchResult::Queued;
+            }
+
+            return SwitchResult::Dropped;
+        }
+
+        self.switch_state_unchecked(state)
+    }
+
+    fn address_queue(&mut self) {
+        let cooking = self.task.as_ref().is_some_and(|task| task.kind.is_cooking());
+
+        if self.playing.is_some() || cooking {
+            return;
+        }
+
+        if let Some(state) = self.queued_state.take() {
+            self.traced_switch(&state, SwitchCause::Queue, Self::switch_state);
+        }
+    }
+
+    /// `esp_random()` taken modulo of the range, every roll is recorded
+    fn roll(&mut self, state: &str, kind: RollKind) -> u32 {
+        let (raw, forced) = self.random.next_value();
+
+        let value = match kind {
+            RollKind::Duration(distance) => (raw as i64 % distance) as u32,
+            RollKind::Chance(chance) => raw % chance
+        };
+
+        self.record(SimEvent::RandomRolled(RandomRoll {
+            state: state.to_string(),
+            kind,
+            value,
+            forced,
+        }));
+
+        value
+    }
+
+    /// `get_random_duration` of the firmware
+    fn random_duration(&mut self, state: &str, start: i64, end: i64, time_since_transition: i64) -> i64 {
+        if let Some(duration) = self.random_durations.get(state) {
+            return *duration;
+        }
+
+        let distance = end - start + 1;
+        let duration = if distance <= 0 {
+            time_since_transition + start
+        } else {
+            let offset = self.roll(state, RollKind::Duration(distance));
+            time_since_transition + start + offset as i64
+        };
+
+        self.random_durations.insert(state.to_string(), duration);
+        duration
+    }
+
+    /// `set_ui_image` of the firmware, returns how long the animation plays and where it goes after
+    fn set_ui_image(&mut self, state: &State) -> Option<(i64, String)> {
+        self.ui_dirty = false;
+
+        let layer_loaded = state.layer == self.prepared_load_layer;
+        let memory = &mut self.memory;
+
+        match &state.image {
+            StateImage::None => {}
+            StateImage::Single { name, layer_load, .. } => {
+                memory.current_image = if layer_loaded && *layer_load {
+                    memory.layer_images.get(name).cloned()
+                } else {
+                    memory.loaded_images.first().cloned()
+                };
+            }
+            StateImage::Animation { name, next_state, loop_count, layer_load } => {
+                let Some(animation) = self.character.animations.get(name) else {
+                    self.fail(SimFailure::MissingAnimation(name.clone()));
+                    return None;
+                };
+
+                let duration = animation_duration(animation, *loop_count);
+
+                memory.current_image = if layer_loaded && *layer_load {
+                    memory.layer_animations.get(name).and_then(|frames| frames.first()).cloned()
+                } else if animation.mode == AnimationMode::FromRAM {
+                    memory.loaded_images.first().cloned()
+                } else {
+                    None
+                };
+
+                self.record(SimEvent::AnimationStarted {
+                    animation: name.clone(),
+                    duration,
+                });
+
+                return Some((duration, next_state.clone()));
+            }
+            StateImage::Sequence { frames, mode, layer_load, .. } => {
+                if self.now <= self.next_frame_time {
+                    return None;
+                }
+
+                if frames.is_empty() {
+                    self.next_frame_time = i64::MAX;
+                    return None;
+                }
+
+                let index = self.sequence_index.map_or(0, |index| (index + 1) % frames.len());
+                let frame = &frames[index];
+
+                self.sequence_index = Some(index);
+                self.next_frame_time = self.now + frame.duration;
+
+                memory.current_image = if layer_loaded && *layer_load {
+                    memory.layer_images.get(&frame.name).cloned()
+                } else {
+                    match mode {
+                        SequenceMode::LoadAll => memory.loaded_images.get(index).cloned(),
+                        SequenceMode::LoadEach => memory.loaded_images.get(index % 2).cloned()
+                    }
+                };
+            }
+        }
+
+        None
+    }
+
+    /// `CharacterFSM::tick` of the firmware
+    fn tick(&mut self) {
+        let Some(state) = self.character.states.get(&self.current_state).cloned() else {
+            return;
+        };
+
+        let state_name = self.current_state.clone();
+        let time_since_transition = self.now - self.last_transition_time;
+
+        if (self.ui_dirty || (self.sequence_index.is_some() && self.now > self.next_frame_time))
+            && let Some((duration, next_state)) = self.set_ui_image(&state) {
+            // FSM task is blocked until the animation ends
+            self.playing = Some(PlayingAnimation {
+                ends_at: self.now + duration,
+                state: state_name,
+                next_state,
+                time_since_transition,
+            });
+
+            return;
+        }
+
+        self.finish_tick(&state, time_since_transition);
+    }
+
+    fn end_animation(&mut self) {
+        let Some(playing) = self.playing.take() else {
+            return;
+        };
+
+        self.traced_switch(&playing.next_state, SwitchCause::AnimationEnd, Self::switch_state_unchecked);
+
+        // Rest of the tick still runs with the state the animation was started from
+        if let Some(state) = self.character.states.get(&playing.state).cloned() {
+            self.finish_tick(&state, playing.time_since_transition);
+        }
+
+        self.next_tick = self.now + TICK_INTERVAL_US;
+    }
+
+    fn finish_tick(&mut self, state: &State, time_since_transition: i64) {
+        self.address_queue();
+
+        if self.task.is_some() || self.crashed {
+            return;
+        }
+
+        for transition in &state.transitions {
+            match &transition.trigger {
+                StateTransitionTrigger::ElapsedTime { duration } => {
+                    if time_since_transition > *duration {
+                        self.traced_switch(&transition.to_state, SwitchCause::ElapsedTime, Self::switch_state);
+                        break;
+                    }
+                }
+                StateTransitionTrigger::Random { duration_range, chance } => {
+                    let (start, end) = duration_range.either(
+                        |tuple| (tuple.pick_min(), tuple.pick_max()),
+                        |num| (num, num)
+                    );
+
+                    let duration = self.random_duration(&transition.to_state, start, end, time_since_transition);
+
+                    if time_since_transition > duration {
+                        self.random_durations.remove(&transition.to_state);
+
+                        if *chance != 0 && self.roll(&transition.to_state, RollKind::Chance(*chance)) != 0 {
+                            continue;
+                        }
+
+                        self.traced_switch(&transition.to_state, SwitchCause::Random, Self::switch_state);
+                        break;
+                    }
+                }
+                StateTransitionTrigger::Clicked => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::{Animation, AnimationFrameSource, StateTransition};
+    use crate::character::sim::allocator::IMAGE_STORAGE_SIZE;
+    use either::Either;
+    use std::path::PathBuf;
+
+    fn single(name: &str, width: u32, height: u32, layer_load: bool) -> StateImage {
+        StateImage::Single {
+            name: name.to_string(),
+            path: PathBuf::new(),
+            width,
+            height,
+            upscale: false,
+            layer_load,
+            conversion: Default::default(),
+            data: Default::default(),
+        }
+    }
+
+    fn state(layer: u8, image: StateImage, transitions: &[(&str, StateTransitionTrigger)]) -> State {
+        State {
+            layer,
+            image,
+            transitions: transitions.iter()
+                .map(|(to_state, trigger)| StateTransition {
+                    to_state: to_state.to_string(),
+                    trigger: trigger.clone(),
+                })
+                .collect(),
+            node_pos: None,
+        }
+    }
+
+    fn entered_states(sim: &Simulator) -> Vec<(i64, &str)> {
+        sim.trace().iter()
+            .filter_map(|entry| match &entry.event {
+                SimEvent::StateEntered { state } => Some((entry.time, state.as_str())),
+                _ => None
+            })
+            .collect()
+    }
+
+    #[test]
+    fn click_cooks_state_and_time_brings_it_back() {
+        let mut char = Character::default();
+
+        char.states.insert("idle".to_string(), state(0, single("idle", 100, 100, true), &[
+            ("booped", StateTransitionTrigger::Clicked),
+        ]));
+        char.states.insert("booped".to_string(), state(0, StateImage::Animation {
+            name: "boop".to_string(),
+            next_state: "idle".to_string(),
+            loop_count: 2,
+            layer_load: false,
+        }, &[]));
+        char.animations.insert("boop".to_string(), Animation {
+            width: 10,
+            height: 10,
+            frames: AnimationFrameSource::List(vec![PathBuf::new(); 3]),
+            fps: 10.0,
+            mode: AnimationMode::FromRAM,
+            ..Default::default()
+        });
+
+        let mut sim = Simulator::new(char, 0);
+        assert_eq!(sim.memory().allocator.used(), 20_000);
+
+        sim.advance_to(1_000_000);
+        sim.click();
+        sim.advance_to(3_000_000);
+
+        // 3 frames cooked for 90ms, then 2 loops of 3 frames for 100ms each at the next tick
+        assert_eq!(entered_states(&sim), vec![
+            (0, "idle"),
+            (1_090_000, "booped"),
+            (1_100_000 + 600_000, "idle"),
+        ]);
+        assert_eq!(sim.peak().bytes, 20_000 + 600);
+        assert_eq!(sim.memory().allocator.used(), 20_000);
+        assert_eq!(sim.failures().count(), 0);
+    }
+
+    #[test]
+    fn elapsed_time_switches_layers() {
+        let mut char = Character::default();
+
+        char.states.insert("idle".to_string(), state(0, single("idle", 320, 320, true), &[
+            ("sleep", StateTransitionTrigger::ElapsedTime { duration: 500_000 }),
+        ]));
+        char.states.insert("sleep".to_string(), state(1, single("sleep", 320, 320, true), &[]));
+
+        let mut sim = Simulator::new(char, 0);
+        sim.advance_to(1_000_000);
+
+        assert_eq!(sim.current_state(), "sleep");
+        assert_eq!(sim.current_layer(), 1);
+        assert_eq!(sim.peak().bytes, 2 * 320 * 320 * 2);
+        assert!(sim.memory().layer_images.contains_key("sleep"));
+        assert!(!sim.memory().layer_images.contains_key("idle"));
+    }
+
+    #[test]
+    fn out_of_memory_is_reported() {
+        let mut char = Character::default();
+        let half = (IMAGE_STORAGE_SIZE / 2 / 2) as u32;
+
+        char.states.insert("idle".to_string(), state(0, single("idle", half, 1, true), &[
+            ("big", StateTransitionTrigger::Clicked),
+            ("other", StateTransitionTrigger::Clicked),
+        ]));
+        char.states.insert("big".to_string(), state(0, single("big", half + 1, 1, false), &[]));
+        char.states.insert("other".to_string(), state(1, single("other", half + 1, 2, true), &[]));
+
+        let mut sim = Simulator::new(char, 0);
+        sim.click();
+        sim.advance(0);
+
+        assert_eq!(sim.failures().map(|(_, failure)| failure.clone()).collect::<Vec<_>>(), vec![
+            SimFailure::CookingOutOfMemory {
+                state: "big".to_string(),
+                requested: (half as u64 + 1) * 2,
+                largest_free: IMAGE_STORAGE_SIZE / 2,
+            },
+        ]);
+        assert!(!sim.is_crashed());
+
+        sim.request_switch("other");
+        sim.advance(TICK_INTERVAL_US);
+        assert!(sim.is_crashed());
+        assert_eq!(sim.current_state(), "idle");
+    }
+
+    #[test]
+    fn random_transitions_repeat_with_seed() {
+        let run = |seed, forced: Vec<RandomRoll>| {
+            let mut char = Character::default();
+
+            char.states.insert("idle".to_string(), state(0, StateImage::None, &[
+                ("blink", StateTransitionTrigger::Random {
+                    duration_range: Either::Left((1_000_000, 3_000_000)),
+                    chance: 2,
+                }),
+            ]));
+            char.states.insert("blink".to_string(), state(0, StateImage::None, &[
+                ("idle", StateTransitionTrigger::ElapsedTime { duration: 200_000 }),
+            ]));
+
+            let mut sim = Simulator::new(char, seed);
+            sim.force_rolls(forced.iter().map(|roll| roll.value));
+            sim.advance_to(60_000_000);
+
+            let states = entered_states(&sim).into_iter()
+                .map(|(time, state)| (time, state.to_string()))
+                .collect::<Vec<_>>();
+            let rolls = sim.rolls()
+                .map(|(_, roll)| RandomRoll {
+                    forced: false,
+                    ..roll.clone()
+                })
+                .collect::<Vec<_>>();
+
+            (states, rolls)
+        };
+
+        assert_eq!(run(7, vec![]), run(7, vec![]));
+        assert_ne!(run(7, vec![]), run(8, vec![]));
+
+        // Rolls recorded with one seed give the same run with any other seed
+        let (states, rolls) = run(7, vec![]);
+        assert_eq!(run(8, rolls.clone()), (states, rolls.clone()));
+        assert!(rolls.iter().any(|roll| roll.value != 0));
+    }
+}
@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+/// Stand-in for `esp_random()` of the firmware, the same seed always gives the same sequence (SplitMix64)
+#[derive(Clone, Debug)]
+pub struct SimRng {
+    state: u64
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        (z >> 32) as u32
+    }
+}
+
+/// Values for `esp_random()` calls, forced values are used up first and then the seeded generator takes over.
+/// Every rolled value is smaller than what it's taken modulo of, so recorded rolls can be forced
+/// again to get exactly the same run
+#[derive(Clone, Debug)]
+pub struct RandomSource {
+    seed: u64,
+    rng: SimRng,
+    forced: VecDeque<u32>
+}
+
+impl RandomSource {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: SimRng::new(seed),
+            forced: VecDeque::new(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn force(&mut self, values: impl IntoIterator<Item = u32>) {
+        self.forced.extend(values);
+    }
+
+    /// Returns the value and whether it was forced
+    pub fn next_value(&mut self) -> (u32, bool) {
+        match self.forced.pop_front() {
+            Some(value) => (value, true),
+            None => (self.rng.next_u32(), false)
+        }
+    }
+}
+
+#[derive(Clone, Debug, strum::Display, Serialize, PartialEq, Eq)]
+pub enum RollKind {
+    /// Offset added to the start of the duration range
+    #[strum(to_string = "duration offset below {0}us")]
+    Duration(i64),
+    #[strum(to_string = "1 in {0} chance")]
+    Chance(u32)
+}
+
+/// Result of `esp_random() % ...` done by a Random trigger
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct RandomRoll {
+    /// Target state of the transition
+    pub state: String,
+    pub kind: RollKind,
+    pub value: u32,
+    pub forced: bool
+}
+
+impl Display for RandomRoll {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} for {} of transition to '{}'", self.value, self.kind, self.state)?;
+
+        if self.forced {
+            write!(f, " (forced)")?;
+        }
+
+        Ok(())
+    }
+}
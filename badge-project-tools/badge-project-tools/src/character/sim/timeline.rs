@@ -0,0 +1,254 @@
+use crate::character::repr::Character;
+use crate::character::sim::{MemoryPeak, SimFailure, Simulator, TraceEntry};
+use crate::character::validation::ValidationErrors;
+use crate::target::{StorageArgs, TargetArgs};
+use anyhow::bail;
+use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{env, fs};
+use std::path::PathBuf;
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Plays a timeline of clicks and actions on the character the way the badge would, prints state trace, memory peaks and failures, exits with code 1 if any failures happened",
+    long_about=None
+)]
+pub struct SimulateCli {
+    #[arg(help = "Character JSON file")]
+    input_file: PathBuf,
+    #[arg(help = "Timeline JSON file, without it the character is left alone")]
+    timeline_file: Option<PathBuf>,
+    #[arg(long, help = "How long to simulate in milliseconds, overrides the duration of the timeline")]
+    duration: Option<i64>,
+    #[arg(long, help = "Seed for random transitions", default_value_t = 0)]
+    seed: u64,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated values for the first random rolls, as printed at the end of a previous run or logged by the badge"
+    )]
+    rolls: Vec<u32>,
+    #[command(flatten)]
+    target: TargetArgs,
+    #[command(flatten)]
+    storage: StorageArgs,
+    #[arg(long, help = "Print results as JSON", default_value_t = false)]
+    json: bool
+}
+
+/// Scripted inputs, all times are in milliseconds since the character was loaded
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Timeline {
+    /// Simulation goes on at least until the last event
+    #[serde(default)]
+    pub duration: i64,
+    #[serde(default)]
+    pub events: Vec<TimelineEvent>,
+    /// Values for the first random rolls, used before the seeded ones
+    #[serde(default)]
+    pub rolls: Vec<u32>
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TimelineEvent {
+    pub at: i64,
+    pub input: TimelineInput
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum TimelineInput {
+    Click,
+    /// Action invoked over BLE
+    Action(String),
+    /// Fails the run if the character isn't in the state at the time
+    ExpectState(String)
+}
+
+impl Timeline {
+    pub fn end(&self) -> i64 {
+        self.events.iter()
+            .map(|event| event.at)
+            .max()
+            .unwrap_or_default()
+            .max(self.duration)
+    }
+
+    pub fn play(&self, sim: &mut Simulator) {
+        sim.force_rolls(self.rolls.iter().copied());
+
+        let mut events = self.events.clone();
+        events.sort_by_key(|event| event.at);
+
+        for event in events {
+            sim.advance_to(event.at * 1000);
+
+            if sim.is_crashed() {
+                return;
+            }
+
+            match &event.input {
+                TimelineInput::Click => sim.click(),
+                TimelineInput::Action(action) => {
+                    sim.invoke_action(action);
+                }
+                TimelineInput::ExpectState(state) => {
+                    sim.expect_state(state);
+                }
+            }
+        }
+
+        sim.advance_to(self.end() * 1000);
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    seed: u64,
+    trace: &'a [TraceEntry],
+    peak: &'a MemoryPeak,
+    state_peaks: &'a BTreeMap<String, u64>,
+    failures: Vec<&'a SimFailure>,
+    rolls: Vec<u32>
+}
+
+fn format_time(time: i64) -> String {
+    format!("{:>9.3}s", time as f64 / 1_000_000.0)
+}
+
+pub fn process_simulate_cli(cli: SimulateCli) -> anyhow::Result<()> {
+    let mut char: Character = serde_json::from_str(&fs::read_to_string(&cli.input_file)?)?;
+    char.refresh_frame_counts(env::current_dir()?);
+
+    let mut profile = cli.target.profile()?;
+    cli.storage.apply(&mut profile)?;
+
+    let mut errors = char.validate();
+    errors.extend(char.validate_target(&profile));
+
+    if !errors.is_empty() {
+        return Err(ValidationErrors(errors).into());
+    }
+
+    let mut timeline = match &cli.timeline_file {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        None => Timeline::default()
+    };
+
+    if let Some(duration) = cli.duration {
+        timeline.duration = duration;
+    }
+
+    timeline.rolls.extend(cli.rolls);
+
+    if timeline.end() <= 0 {
+        bail!("Nothing to simulate, set the duration of the timeline or use --duration");
+    }
+
+    let mut sim = Simulator::with_target(char, cli.seed, &profile);
+    timeline.play(&mut sim);
+
+    let failed = sim.failures().count() > 0;
+    let rolls = sim.rolls()
+        .map(|(_, roll)| roll.value)
+        .collect::<Vec<_>>();
+
+    if cli.json {
+        let report = JsonReport {
+            seed: cli.seed,
+            trace: sim.trace(),
+            peak: sim.peak(),
+            state_peaks: sim.state_peaks(),
+            failures: sim.failures().map(|(_, failure)| failure).collect(),
+            rolls,
+        };
+
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("Trace:");
+        for entry in sim.trace() {
+            println!("  [{}] {}", format_time(entry.time), entry.event);
+        }
+
+        let peak = sim.peak();
+
+        println!("Memory:");
+        println!(
+            "  Peak {}b / {}b at {} in state '{}'",
+            peak.bytes.to_formatted_string(&Locale::en),
+            sim.memory().allocator.size().to_formatted_string(&Locale::en),
+            format_time(peak.time).trim_start(),
+            peak.state
+        );
+        for (state, bytes) in sim.state_peaks() {
+            println!("  {state}: {}b", bytes.to_formatted_string(&Locale::en));
+        }
+
+        if !rolls.is_empty() {
+            let rolls = rolls.iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>();
+
+            println!("Random rolls (seed {}): --rolls {}", cli.seed, rolls.join(","));
+        }
+
+        if failed {
+            println!("Failures:");
+            for (time, failure) in sim.failures() {
+                println!("  [{}] {failure}", format_time(time));
+            }
+        } else {
+            println!("No failures");
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::{Action, ActionType, State, StateTransition, StateTransitionTrigger};
+
+    #[test]
+    fn timeline_checks_expected_states() {
+        let mut char = Character::default();
+
+        char.states.insert("happy".to_string(), State {
+            transitions: vec![StateTransition {
+                to_state: "idle".to_string(),
+                trigger: StateTransitionTrigger::ElapsedTime { duration: 1_000_000 },
+            }],
+            ..Default::default()
+        });
+        char.actions.insert("pet".to_string(), Action {
+            display: "Pet".to_string(),
+            ty: ActionType::SwitchState("happy".to_string()),
+        });
+
+        let timeline: Timeline = serde_json::from_str(r#"{
+            "duration": 3000,
+            "events": [
+                { "at": 2000, "input": "Click" },
+                { "at": 500, "input": { "Action": "pet" } },
+                { "at": 1000, "input": { "ExpectState": "happy" } },
+                { "at": 1600, "input": { "ExpectState": "happy" } }
+            ]
+        }"#).unwrap();
+
+        let mut sim = Simulator::new(char, 0);
+        timeline.play(&mut sim);
+
+        assert_eq!(sim.now(), 3_000_000);
+        assert_eq!(sim.failures().map(|(time, failure)| (time, failure.clone())).collect::<Vec<_>>(), vec![
+            (1_600_000, SimFailure::UnexpectedState {
+                expected: "happy".to_string(),
+                actual: "idle".to_string(),
+            }),
+        ]);
+    }
+}
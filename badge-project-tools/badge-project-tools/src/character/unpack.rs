@@ -0,0 +1,498 @@
+use crate::character::format::{migrate_file, read_format_version, FileKind, CURRENT_FORMAT_VERSION};
+use crate::character::util::is_file_name;
+use crate::character::repr::{Animation, AnimationFrameSource, Character, FrameEncoding, FromBinary, ImageData, State, StateImage, StateTransition};
+use crate::image::delta::{DeltaRect, FrameLayout, DELTA_HEADER_SIZE};
+use crate::image::alpha::AlphaMode;
+use crate::image::{decode_alpha, decode_image_data, ByteOrder, ConversionOptions, PixelFormat};
+use crate::target::{TargetArgs, TargetProfile};
+use anyhow::{anyhow, bail, ensure, Context};
+use image::{Rgba, RgbaImage};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Unpacks character archive or SD card folder back into editable character JSON projects",
+    long_about=None
+)]
+pub struct UnpackCli {
+    #[arg(help = "Character archive (.tar) or folder that contains 'characters' folder")]
+    input: PathBuf,
+    #[arg(help = "Folder to write character projects into")]
+    output_folder: PathBuf,
+    #[arg(long, help = "Pixel format the archive was built with, overrides the target profile")]
+    pixel_format: Option<PixelFormat>,
+    #[command(flatten)]
+    target: TargetArgs
+}
+
+/// Relative file path inside the archive mapped to its contents
+pub type ArchiveFiles = BTreeMap<PathBuf, Vec<u8>>;
+
+pub struct UnpackedCharacter {
+    pub character: Character,
+    /// Decoded images, paths are relative to the character project folder
+    pub images: Vec<(PathBuf, RgbaImage)>,
+    pub selected: bool,
+    /// Format version the archive was written in, before migration
+    pub format_version: u16
+}
+
+pub fn process_unpack_cli(cli: UnpackCli) -> anyhow::Result<()> {
+    let mut profile = cli.target.profile()?;
+
+    if let Some(format) = cli.pixel_format {
+        profile.pixel_format = format;
+    }
+
+    profile.check()?;
+    let files = if cli.input.is_dir() {
+        read_folder_files(&cli.input)?
+    } else {
+        read_archive_files(File::open(&cli.input)?)?
+    };
+
+    let characters = read_characters(&files, &profile)?;
+
+    if characters.is_empty() {
+        bail!("No characters were found in {}", cli.input.display());
+    }
+
+    for unpacked in characters {
+        let folder = cli.output_folder.join(&unpacked.character.id);
+        let json_path = write_unpacked_character(&unpacked, &folder)?;
+
+        println!(
+            "Unpacked '{}'{} into {}",
+            unpacked.character.id,
+            if unpacked.selected { " (selected)" } else { "" },
+            json_path.display()
+        );
+
+        if unpacked.format_version != CURRENT_FORMAT_VERSION {
+            println!("  Migrated from format version {} to {CURRENT_FORMAT_VERSION}", unpacked.format_version);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_archive_files(reader: impl Read) -> anyhow::Result<ArchiveFiles> {
+    let mut archive = tar::Archive::new(reader);
+    let mut files = ArchiveFiles::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.to_path_buf();
+
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+
+        files.insert(path, data);
+    }
+
+    Ok(files)
+}
+
+pub fn read_folder_files(folder: impl AsRef<Path>) -> anyhow::Result<ArchiveFiles> {
+    fn walk(root: &Path, current: &Path, files: &mut ArchiveFiles) -> anyhow::Result<()> {
+        for entry in fs::read_dir(current)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                walk(root, &path, files)?;
+            } else {
+                files.insert(path.strip_prefix(root)?.to_path_buf(), fs::read(&path)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    let folder = folder.as_ref();
+    let mut files = ArchiveFiles::new();
+    walk(folder, folder, &mut files)?;
+
+    Ok(files)
+}
+
+/// Names of immediate children (files or folders) of the folder inside the archive
+fn list_children(files: &ArchiveFiles, folder: &Path) -> BTreeSet<String> {
+    files.keys()
+        .filter_map(|path| path.strip_prefix(folder).ok())
+        .filter_map(|relative| relative.components().next())
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+fn read_file<'a>(files: &'a ArchiveFiles, path: &Path) -> anyhow::Result<&'a [u8]> {
+    files.get(path)
+        .map(|data| data.as_slice())
+        .ok_or_else(|| anyhow!("Missing file {}", path.display()))
+}
+
+fn read_binary<T: FromBinary>(files: &ArchiveFiles, path: &Path, version: u16, kind: FileKind) -> anyhow::Result<T> {
+    let data = migrate_file(version, kind, read_file(files, path)?.to_vec())?;
+
+    T::from_bin(&data)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Image data of the file, decompressed as its descriptor says
+fn read_image_file(files: &ArchiveFiles, path: &Path, data: ImageData) -> anyhow::Result<Vec<u8>> {
+    data.compression.decompress(read_file(files, path)?, data.data_size as usize)
+        .with_context(|| format!("Failed to decompress {}", path.display()))
+}
+
+/// Names in the archive become file and folder names of the project, so they can't leave their folder
+fn checked_name(name: &str) -> anyhow::Result<&str> {
+    ensure!(is_file_name(name), "Name '{name}' isn't a valid file name");
+
+    Ok(name)
+}
+
+/// Alpha mode the image data was encoded with, as far as the data tells. Color keys are plain colors of the data,
+/// so those images come back opaque and still encode the same
+fn stored_alpha(data: &[u8], width: u32, height: u32, format: PixelFormat) -> AlphaMode {
+    let transparent = if format.palette_len() > 0 {
+        // Colors of the palette are opaque, only the reserved transparent one isn't
+        data.get(3) == Some(&0)
+    } else {
+        data.len() as u64 > format.data_size(width, height)
+    };
+
+    if transparent {
+        AlphaMode::Channel
+    } else {
+        AlphaMode::Opaque
+    }
+}
+
+/// Decodes image data together with its alpha, returns the alpha mode it was encoded with
+fn decode_rgba(data: &[u8], width: u32, height: u32, format: PixelFormat, byte_order: ByteOrder) -> Option<(RgbaImage, AlphaMode)> {
+    let options = ConversionOptions {
+        alpha: stored_alpha(data, width, height, format),
+        ..Default::default()
+    };
+
+    let image = decode_image_data(data, width, height, format, byte_order)?;
+    let alpha = decode_alpha(data, width, height, format, byte_order, &options)?;
+
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        let [r, g, b] = image.get_pixel(x, y).0;
+        Rgba([r, g, b, alpha[(y * width + x) as usize]])
+    });
+
+    Some((image, options.alpha))
+}
+
+fn real_size(size: u32, upscale: bool) -> u32 {
+    if upscale {
+        size / 2
+    } else {
+        size
+    }
+}
+
+/// Images are decoded with the pixel format and byte orders of the target they were built for
+pub fn read_characters(files: &ArchiveFiles, profile: &TargetProfile) -> anyhow::Result<Vec<UnpackedCharacter>> {
+    list_children(files, Path::new("characters"))
+        .into_iter()
+        .filter(|id| files.contains_key(&Path::new("characters").join(id).join("character.bin")))
+        .map(|id| read_character(files, &id, profile).with_context(|| format!("Failed to read character '{id}'")))
+        .collect()
+}
+
+pub fn read_character(files: &ArchiveFiles, id: &str, profile: &TargetProfile) -> anyhow::Result<UnpackedCharacter> {
+    let char_path = Path::new("characters").join(checked_name(id)?);
+
+    let character_bin_path = char_path.join("character.bin");
+    let version = read_format_version(read_file(files, &character_bin_path)?)?;
+
+    let mut character: Character = read_binary(files, &character_bin_path, version, FileKind::Character)?;
+    character.id = id.to_string();
+
+    // Image name mapped to its real size on the badge and how it's stored
+    let mut image_sizes: BTreeMap<String, ((u32, u32), ImageData)> = BTreeMap::new();
+
+    let states_path = char_path.join("states");
+
+    for (index, state_name) in list_children(files, &states_path).into_iter().enumerate() {
+        let state_path = states_path.join(&state_name);
+        let mut state = read_state(files, &state_path, &state_name, version, &mut image_sizes)
+            .with_context(|| format!("Failed to read state '{state_name}'"))?;
+
+        // Lay out nodes in a grid, so they don't end up stacked on top of each other in the editor
+        state.node_pos = Some(((index % 4) as f32 * 220.0, (index / 4) as f32 * 160.0));
+
+        character.states.insert(state_name, state);
+    }
+
+    let mut images = vec![];
+    let mut alpha_modes = HashMap::new();
+
+    for (name, ((width, height), image_data)) in &image_sizes {
+        let bin_path = char_path.join("images").join(format!("{name}.bin"));
+        let data = read_image_file(files, &bin_path, *image_data)?;
+
+        let (image, alpha) = decode_rgba(&data, *width, *height, profile.pixel_format, profile.image_byte_order)
+            .ok_or_else(|| anyhow!("Image {} doesn't match size {width}x{height}", bin_path.display()))?;
+
+        images.push((image_path(name)?, image));
+        alpha_modes.insert(name.clone(), alpha);
+    }
+
+    // Compression is read with the descriptors, transparency is only known once the images are decoded
+    for state in character.states.values_mut() {
+        match &mut state.image {
+            StateImage::Single { name, conversion, .. } => {
+                conversion.alpha = alpha_modes[name];
+            }
+            StateImage::Sequence { frames, .. } => {
+                for frame in frames {
+                    frame.conversion.alpha = alpha_modes[&frame.name];
+                }
+            }
+            StateImage::None | StateImage::Animation { .. } => {}
+        }
+    }
+
+    let animations_path = char_path.join("animations");
+
+    for anim_name in list_children(files, &animations_path) {
+        let anim_path = animations_path.join(&anim_name);
+        let (animation, frames) = read_animation(files, &anim_path, &anim_name, version, profile)
+            .with_context(|| format!("Failed to read animation '{anim_name}'"))?;
+
+        images.extend(frames);
+        character.animations.insert(anim_name, animation);
+    }
+
+    let actions_path = char_path.join("actions");
+
+    for action_name in list_children(files, &actions_path) {
+        let action = read_binary(files, &actions_path.join(&action_name).join("action.bin"), version, FileKind::Action)?;
+        character.actions.insert(action_name, action);
+    }
+
+    Ok(UnpackedCharacter {
+        character,
+        images,
+        selected: files.contains_key(&char_path.join("selected.lock")),
+        format_version: version,
+    })
+}
+
+fn image_path(name: &str) -> anyhow::Result<PathBuf> {
+    Ok(Path::new("images").join(format!("{}.png", checked_name(name)?)))
+}
+
+fn read_state(
+    files: &ArchiveFiles,
+    state_path: &Path,
+    state_name: &str,
+    version: u16,
+    image_sizes: &mut BTreeMap<String, ((u32, u32), ImageData)>
+) -> anyhow::Result<State> {
+    let mut state: State = read_binary(files, &state_path.join("state.bin"), version, FileKind::State)?;
+
+    match &mut state.image {
+        StateImage::Single { name, path, width, height, upscale, data, .. } => {
+            image_sizes.insert(name.clone(), ((real_size(*width, *upscale), real_size(*height, *upscale)), *data));
+            *path = image_path(name)?;
+        }
+        StateImage::Sequence { name, frames, .. } => {
+            // Sequence names aren't stored in the archive
+            *name = Some(state_name.to_string());
+
+            let frames_path = state_path.join("frames");
+
+            for (index, frame) in frames.iter_mut().enumerate() {
+                *frame = read_binary(files, &frames_path.join(format!("{index}.bin")), version, FileKind::SequenceFrame)?;
+                frame.path = image_path(&frame.name)?;
+
+                image_sizes.insert(
+                    frame.name.clone(),
+                    ((real_size(frame.width, frame.upscale), real_size(frame.height, frame.upscale)), frame.data)
+                );
+            }
+        }
+        StateImage::None | StateImage::Animation { .. } => {}
+    }
+
+    let transitions_path = state_path.join("transitions");
+
+    for to_state in list_children(files, &transitions_path) {
+        let mut transition: StateTransition = read_binary(
+            files,
+            &transitions_path.join(&to_state).join("transition.bin"),
+            version,
+            FileKind::Transition
+        )?;
+        transition.to_state = to_state;
+
+        state.transitions.push(transition);
+    }
+
+    Ok(state)
+}
+
+fn read_animation(
+    files: &ArchiveFiles,
+    anim_path: &Path,
+    anim_name: &str,
+    version: u16,
+    profile: &TargetProfile
+) -> anyhow::Result<(Animation, Vec<(PathBuf, RgbaImage)>)> {
+    let mut animation: Animation = read_binary(files, &anim_path.join("animation.bin"), version, FileKind::Animation)?;
+    let (width, height) = (animation.real_width(), animation.real_height());
+
+    // Frames are numbered from 1 by the firmware, but older archives might start at 0
+    let frames_path = anim_path.join("frames");
+    let indices = if animation.frame_map {
+        // Repeated frames point at the same file
+        let map_path = anim_path.join("frame_map.bin");
+        let map = read_file(files, &map_path)?;
+        ensure!(map.len() % 4 == 0, "Frame map {} isn't made of 32-bit numbers", map_path.display());
+
+        map.chunks_exact(4)
+            .map(|index| u32::from_le_bytes([index[0], index[1], index[2], index[3]]))
+            .collect::<Vec<_>>()
+    } else {
+        let mut indices = list_children(files, &frames_path)
+            .into_iter()
+            .filter_map(|name| name.strip_suffix(".bin")?.parse::<u32>().ok())
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices
+    };
+
+    let folder = Path::new("animations").join(checked_name(anim_name)?);
+    let mut frames = vec![];
+
+    // Alpha plane isn't stored in the archive, but makes the frames larger
+    let layout = FrameLayout {
+        width,
+        height,
+        format: profile.pixel_format,
+        alpha_plane: animation.frame_data.data_size as u64 > profile.pixel_format.data_size(width, height),
+    };
+    let mut previous: Option<Vec<u8>> = None;
+
+    for (position, index) in indices.iter().enumerate() {
+        let bin_path = frames_path.join(format!("{index}.bin"));
+
+        let data = match previous {
+            Some(mut frame) if animation.encoding == FrameEncoding::Delta => {
+                let file = read_file(files, &bin_path)?;
+                let rect = DeltaRect::from_bytes(file)?;
+                let payload = animation.frame_data.compression
+                    .decompress(&file[DELTA_HEADER_SIZE..], layout.payload_size(rect))
+                    .with_context(|| format!("Failed to decompress {}", bin_path.display()))?;
+
+                layout.apply_delta(&mut frame, rect, &payload)
+                    .with_context(|| format!("Failed to apply delta frame {}", bin_path.display()))?;
+                frame
+            }
+            _ => read_image_file(files, &bin_path, animation.frame_data)?
+        };
+
+        let (image, alpha) = decode_rgba(&data, width, height, profile.pixel_format, profile.frame_byte_order)
+            .ok_or_else(|| anyhow!("Frame {} doesn't match size {width}x{height}", bin_path.display()))?;
+        animation.conversion.alpha = alpha;
+
+        frames.push((folder.join(format!("{}.png", position + 1)), image));
+        previous = Some(data);
+    }
+
+    animation.frames = AnimationFrameSource::Indexed {
+        folder,
+        extension: "png".to_string(),
+        count: frames.len() as u32,
+    };
+
+    Ok((animation, frames))
+}
+
+/// Writes character JSON and decoded images into the folder, returns path to the written JSON
+pub fn write_unpacked_character(unpacked: &UnpackedCharacter, folder: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let folder = folder.as_ref();
+
+    for (path, image) in &unpacked.images {
+        ensure!(
+            path.components().all(|component| matches!(component, Component::Normal(_))),
+            "Image path {} leaves the character folder",
+            path.display()
+        );
+
+        let path = folder.join(path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        image.save(&path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    fs::create_dir_all(folder)?;
+
+    let json_path = folder.join("character.json");
+    fs::write(&json_path, serde_json::to_string_pretty(&unpacked.character)?)?;
+
+    Ok(json_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::encode_pixels;
+
+    #[test]
+    fn names_cant_leave_the_project_folder() {
+        assert_eq!(image_path("idle").unwrap(), Path::new("images/idle.png"));
+
+        for name in ["../../x", "..", ".", "", "a/b", "a\\b", "/etc/passwd"] {
+            assert!(image_path(name).is_err(), "{name}");
+        }
+
+        let unpacked = UnpackedCharacter {
+            character: Character::default(),
+            images: vec![(PathBuf::from("../escaped.png"), RgbaImage::new(1, 1))],
+            selected: false,
+            format_version: CURRENT_FORMAT_VERSION,
+        };
+
+        let folder = std::env::temp_dir().join(format!("bp-unpack-{}", std::process::id()));
+        assert!(write_unpacked_character(&unpacked, &folder).is_err());
+        assert!(!folder.with_file_name("escaped.png").exists());
+    }
+
+    #[test]
+    fn transparency_survives_unpacking() {
+        let image = RgbaImage::from_fn(4, 2, |x, _| Rgba([248, 0, 0, if x == 0 { 0 } else { 255 }]));
+
+        for format in [PixelFormat::Rgb565, PixelFormat::Indexed4] {
+            for alpha in [AlphaMode::Opaque, AlphaMode::Channel] {
+                let options = ConversionOptions {
+                    alpha,
+                    ..Default::default()
+                };
+
+                let data = encode_pixels(&image, format, ByteOrder::Little, &options);
+                let (unpacked, unpacked_alpha) = decode_rgba(&data, 4, 2, format, ByteOrder::Little).unwrap();
+
+                assert_eq!(unpacked_alpha, alpha, "{format}");
+                assert_eq!(encode_pixels(&unpacked, format, ByteOrder::Little, &options), data, "{format} {alpha}");
+            }
+        }
+    }
+}
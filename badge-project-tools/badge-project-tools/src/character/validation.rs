@@ -0,0 +1,600 @@
+use crate::character::build_character_files;
+use crate::character::budget::{check_memory_budget, BudgetFailure, BudgetReport};
+use crate::character::error::{CharacterBuildError, CharacterBuildErrors};
+use crate::character::repr::{ActionType, Character, State, StateImage};
+use crate::character::sim::animation_duration;
+use crate::character::util::is_file_name;
+use crate::target::{StorageArgs, TargetArgs, TargetProfile};
+use num_format::{Locale, ToFormattedString};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::{env, fs};
+use strum::Display;
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Checks character JSON file for errors without building the archive, exits with code 1 if any were found",
+    long_about=None
+)]
+pub struct ValidateCli {
+    #[arg(help = "Character JSON file")]
+    input_file: PathBuf,
+    #[arg(long, help = "Print results as JSON", default_value_t = false)]
+    json: bool,
+    #[arg(long, help = "Skip checking that images can be read and encoded", default_value_t = false)]
+    skip_images: bool,
+    #[arg(long, help = "Skip checking that every reachable state fits into the image storage", default_value_t = false)]
+    skip_memory: bool,
+    #[command(flatten)]
+    target: TargetArgs,
+    #[command(flatten)]
+    storage: StorageArgs
+}
+
+#[derive(Clone, Debug, Display, Serialize)]
+#[derive(PartialEq, Eq)]
+pub enum ValidationError {
+    #[strum(to_string = "Duplicate state '{0}'!")]
+    DuplicateState(String),
+    #[strum(to_string = "Duplicate animation '{0}'!")]
+    DuplicateAnimation(String),
+    #[strum(to_string = "Duplicate action '{0}'!")]
+    DuplicateAction(String),
+    #[strum(to_string = "Duplicate image '{0}'!")]
+    DuplicateImage(String),
+    #[strum(to_string = "Duplicate sequence '{0}'!")]
+    DuplicateSequence(String),
+    #[strum(to_string = "Selected sequence in state '{0}' doesn't exist!")]
+    InvalidSequenceInState(String),
+    #[strum(to_string = "Selected animation in state '{0}' doesn't exist!")]
+    InvalidAnimationInState(String),
+    #[strum(to_string = "Selected next state in animation of state '{0}' doesn't exist!")]
+    InvalidNextStateInAnimation(String),
+    #[strum(to_string = "Selected image in state '{0}' doesn't exist!")]
+    InvalidImageInState(String),
+    #[strum(to_string = "Selected image in frame #{1} of sequence '{0}' doesn't exist!")]
+    InvalidImageInSequenceFrame(String, usize),
+    #[strum(to_string = "Transition from state '{0}' leads to state '{1}' that doesn't exist!")]
+    InvalidTransitionTarget(String, String),
+    #[strum(to_string = "Selected action type in action '{0}' is invalid!")]
+    InvalidActionType(String),
+    #[strum(to_string = "Selected default state doesn't exist!")]
+    InvalidDefaultState,
+    #[strum(to_string = "Character ID '{0}' can't be used as a folder name!")]
+    InvalidId(String),
+    #[strum(to_string = "Selected state in action '{0}' doesn't exist!")]
+    InvalidActionState(String),
+    #[strum(to_string = "State name can't be empty!")]
+    EmptyStateName,
+    #[strum(to_string = "Animation name can't be empty!")]
+    EmptyAnimationName,
+    #[strum(to_string = "Action name can't be empty!")]
+    EmptyActionName,
+    #[strum(to_string = "Image name can't be empty!")]
+    EmptyImageName,
+    #[strum(to_string = "Sequence name can't be empty!")]
+    EmptySequenceName,
+    #[strum(to_string = "Animation '{0}' doesn't fit on the {1}x{2} display!")]
+    AnimationOffScreen(String, u32, u32),
+    #[strum(to_string = "Image '{0}' is larger than the {1}x{2} display!")]
+    ImageLargerThanScreen(String, u32, u32),
+    #[strum(to_string = "{0}")]
+    Build(CharacterBuildError),
+    #[strum(to_string = "{0}")]
+    MemoryBudget(BudgetFailure)
+}
+
+/// All validation errors of the character, so they can be reported at once
+#[derive(Clone, Debug)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Found {} error(s) in the character:", self.0.len())?;
+
+        for error in &self.0 {
+            write!(f, "\n  {error}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// States named in a warning, in name order
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct StateList(pub Vec<String>);
+
+impl Display for StateList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let names = self.0.iter()
+            .map(|name| format!("'{name}'"))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// Structural problems of the state graph, the character still builds and runs with them
+#[derive(Clone, Debug, Display, Serialize)]
+#[derive(PartialEq, Eq)]
+pub enum ValidationWarning {
+    #[strum(to_string = "State '{0}' can't be reached from the default state or any action")]
+    UnreachableState(String),
+    #[strum(to_string = "State '{0}' has no transitions, the badge stays in it forever")]
+    DeadEndState(String),
+    #[strum(to_string = "States {0} only lead to each other on click, the badge does nothing on its own there")]
+    ClickOnlyCycle(StateList),
+    #[strum(to_string = "Animations of states {0} take no time and lead back to each other, the badge keeps switching between them")]
+    InstantAnimationLoop(StateList)
+}
+
+impl ValidationWarning {
+    pub fn states(&self) -> Vec<&str> {
+        match self {
+            ValidationWarning::UnreachableState(state) => vec![state],
+            ValidationWarning::DeadEndState(state) => vec![state],
+            ValidationWarning::ClickOnlyCycle(states) => states.0.iter().map(String::as_str).collect(),
+            ValidationWarning::InstantAnimationLoop(states) => states.0.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// States the state can switch to on its own or on click
+fn next_states(state: &State) -> Vec<&str> {
+    let mut next = state.transitions.iter()
+        .map(|transition| transition.to_state.as_str())
+        .collect::<Vec<_>>();
+
+    if let StateImage::Animation { next_state, .. } = &state.image {
+        next.push(next_state.as_str());
+    }
+
+    next
+}
+
+/// Groups of states that can get from any of them to any other through the edges, single states only with an edge to themselves
+fn find_cycles<'a>(edges: &BTreeMap<&'a str, Vec<&'a str>>) -> Vec<StateList> {
+    let reachable_from = |start: &'a str| {
+        let mut reached = BTreeSet::new();
+        let mut pending = edges.get(start).cloned().unwrap_or_default();
+
+        while let Some(state) = pending.pop() {
+            if reached.insert(state) {
+                pending.extend(edges.get(state).into_iter().flatten());
+            }
+        }
+
+        reached
+    };
+
+    let reachable = edges.keys()
+        .map(|state| (*state, reachable_from(state)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut seen = BTreeSet::new();
+    let mut cycles = vec![];
+
+    for (state, reached) in &reachable {
+        if seen.contains(state) || !reached.contains(state) {
+            continue;
+        }
+
+        let cycle = reached.iter()
+            .filter(|other| reachable.get(*other).is_some_and(|back| back.contains(state)))
+            .copied()
+            .collect::<Vec<_>>();
+
+        seen.extend(cycle.iter().copied());
+        cycles.push(StateList(cycle.into_iter().map(str::to_string).collect()));
+    }
+
+    cycles
+}
+
+impl Character {
+    /// Checks references between states, animations and actions
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = vec![];
+
+        // Sorted, so the errors come out in the same order every time
+        let states = self.states.iter().collect::<BTreeMap<_, _>>();
+        let actions = self.actions.iter().collect::<BTreeMap<_, _>>();
+
+        if !is_file_name(&self.id) {
+            errors.push(ValidationError::InvalidId(self.id.clone()));
+        }
+
+        if self.states.contains_key("") {
+            errors.push(ValidationError::EmptyStateName);
+        }
+        if self.animations.contains_key("") {
+            errors.push(ValidationError::EmptyAnimationName);
+        }
+        if self.actions.contains_key("") {
+            errors.push(ValidationError::EmptyActionName);
+        }
+
+        if !self.states.contains_key(&self.default_state) {
+            errors.push(ValidationError::InvalidDefaultState);
+        }
+
+        for (state_name, state) in &states {
+            match &state.image {
+                StateImage::Single { name, .. } => {
+                    if name.is_empty() && !errors.contains(&ValidationError::EmptyImageName) {
+                        errors.push(ValidationError::EmptyImageName);
+                    }
+                }
+                StateImage::Animation { name, next_state, .. } => {
+                    if !self.animations.contains_key(name) {
+                        errors.push(ValidationError::InvalidAnimationInState(state_name.to_string()));
+                    }
+
+                    if !self.states.contains_key(next_state) {
+                        errors.push(ValidationError::InvalidNextStateInAnimation(state_name.to_string()));
+                    }
+                }
+                StateImage::Sequence { frames, .. } => {
+                    if frames.iter().any(|frame| frame.name.is_empty())
+                        && !errors.contains(&ValidationError::EmptyImageName) {
+                        errors.push(ValidationError::EmptyImageName);
+                    }
+                }
+                StateImage::None => {}
+            }
+
+            for transition in &state.transitions {
+                if !self.states.contains_key(&transition.to_state) {
+                    errors.push(ValidationError::InvalidTransitionTarget(
+                        state_name.to_string(),
+                        transition.to_state.clone()
+                    ));
+                }
+            }
+        }
+
+        for (action_name, action) in &actions {
+            let ActionType::SwitchState(state) = &action.ty;
+
+            if !self.states.contains_key(state) {
+                errors.push(ValidationError::InvalidActionState(action_name.to_string()));
+            }
+        }
+
+        errors
+    }
+
+    /// Checks that animations and images fit on the display of the target
+    pub fn validate_target(&self, profile: &TargetProfile) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        let (width, height) = (profile.width, profile.height);
+
+        for (name, anim) in self.animations.iter().collect::<BTreeMap<_, _>>() {
+            if anim.x as u32 + anim.width > width || anim.y as u32 + anim.height > height {
+                errors.push(ValidationError::AnimationOffScreen(name.clone(), width, height));
+            }
+        }
+
+        let mut images = BTreeMap::new();
+
+        for state in self.states.values() {
+            match &state.image {
+                StateImage::Single { name, width, height, .. } => {
+                    images.insert(name, (*width, *height));
+                }
+                StateImage::Sequence { frames, .. } => {
+                    images.extend(frames.iter().map(|frame| (&frame.name, (frame.width, frame.height))));
+                }
+                _ => {}
+            }
+        }
+
+        for (name, (image_width, image_height)) in images {
+            if image_width > width || image_height > height {
+                errors.push(ValidationError::ImageLargerThanScreen(name.clone(), width, height));
+            }
+        }
+
+        errors
+    }
+
+    /// Looks for states that can't be reached or that the badge can get stuck in, missing references are skipped
+    pub fn warnings(&self) -> Vec<ValidationWarning> {
+        let mut warnings = vec![];
+
+        let states = self.states.iter()
+            .map(|(name, state)| (name.as_str(), state))
+            .collect::<BTreeMap<_, _>>();
+
+        // Actions can switch to their state at any time
+        let mut reached = BTreeSet::new();
+        let mut pending = self.actions.values()
+            .map(|action| {
+                let ActionType::SwitchState(state) = &action.ty;
+                state.as_str()
+            })
+            .chain([self.default_state.as_str()])
+            .collect::<Vec<_>>();
+
+        while let Some(name) = pending.pop() {
+            let Some(state) = states.get(name) else {
+                continue;
+            };
+
+            if reached.insert(name) {
+                pending.extend(next_states(state));
+            }
+        }
+
+        for (name, state) in &states {
+            if !reached.contains(name) {
+                warnings.push(ValidationWarning::UnreachableState(name.to_string()));
+            }
+
+            if next_states(state).is_empty() {
+                warnings.push(ValidationWarning::DeadEndState(name.to_string()));
+            }
+        }
+
+        // Clicks are the only way out of these states
+        let click_only = states.iter()
+            .filter(|(_, state)| !matches!(state.image, StateImage::Animation { .. })
+                && !state.transitions.is_empty()
+                && state.transitions.iter().all(|transition| transition.trigger.is_clicked())
+            )
+            .map(|(name, state)| (*name, state.transitions.iter()
+                .map(|transition| transition.to_state.as_str())
+                .collect::<Vec<_>>()
+            ))
+            .collect::<BTreeMap<_, _>>();
+
+        let click_only_edges = click_only.iter()
+            .map(|(name, next)| (*name, next.iter()
+                .copied()
+                .filter(|next| click_only.contains_key(next))
+                .collect()
+            ))
+            .collect();
+
+        warnings.extend(find_cycles(&click_only_edges).into_iter().map(ValidationWarning::ClickOnlyCycle));
+
+        // Animation is switched away from right away, transitions of the state never get a chance
+        let instant = states.iter()
+            .filter_map(|(name, state)| match &state.image {
+                StateImage::Animation { name: animation, next_state, loop_count, .. } => self.animations.get(animation)
+                    .filter(|animation| animation_duration(animation, *loop_count) == 0)
+                    .map(|_| (*name, next_state.as_str())),
+                _ => None
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let instant_edges = instant.iter()
+            .map(|(name, next)| (*name, instant.contains_key(next).then_some(*next).into_iter().collect()))
+            .collect();
+
+        warnings.extend(find_cycles(&instant_edges).into_iter().map(ValidationWarning::InstantAnimationLoop));
+
+        warnings
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    valid: bool,
+    errors: Vec<JsonError<'a>>,
+    warnings: Vec<JsonWarning<'a>>,
+    memory: Option<&'a BudgetReport>
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    message: String,
+    error: &'a ValidationError
+}
+
+#[derive(Serialize)]
+struct JsonWarning<'a> {
+    message: String,
+    warning: &'a ValidationWarning
+}
+
+pub fn process_validate_cli(cli: ValidateCli) -> anyhow::Result<()> {
+    let mut char: Character = serde_json::from_str(&fs::read_to_string(&cli.input_file)?)?;
+    char.refresh_frame_counts(env::current_dir()?);
+
+    let mut profile = cli.target.profile()?;
+    cli.storage.apply(&mut profile)?;
+
+    let mut errors = char.validate();
+    errors.extend(char.validate_target(&profile));
+
+    // Encoding images is only worth it if the structure is fine
+    if errors.is_empty()
+        && !cli.skip_images
+        && let Err(err) = build_character_files(&char, env::current_dir()?, false, false, &profile, &|_, _| {}) {
+        let build_errors = err.downcast::<CharacterBuildErrors>()?;
+        errors.extend(build_errors.0.into_iter().map(ValidationError::Build));
+    }
+
+    let memory = if errors.is_empty() && !cli.skip_memory {
+        let report = check_memory_budget(&char, &profile);
+        errors.extend(report.failures.iter().cloned().map(ValidationError::MemoryBudget));
+
+        Some(report)
+    } else {
+        None
+    };
+
+    let warnings = char.warnings();
+
+    if cli.json {
+        let report = JsonReport {
+            valid: errors.is_empty(),
+            errors: errors.iter()
+                .map(|error| JsonError {
+                    message: error.to_string(),
+                    error,
+                })
+                .collect(),
+            warnings: warnings.iter()
+                .map(|warning| JsonWarning {
+                    message: warning.to_string(),
+                    warning,
+                })
+                .collect(),
+            memory: memory.as_ref(),
+        };
+
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        if errors.is_empty() {
+            println!("No errors found in '{}'", char.id);
+        } else {
+            println!("{}", ValidationErrors(errors.clone()));
+        }
+
+        if let Some(memory) = &memory {
+            for (layer, budget) in &memory.layers {
+                println!(
+                    "Layer {layer} uses up to {}b / {}b in state '{}', up to {:.0}% of free memory is fragmented",
+                    budget.peak.to_formatted_string(&Locale::en),
+                    memory.storage_size.to_formatted_string(&Locale::en),
+                    budget.peak_state,
+                    budget.fragmentation * 100.0
+                );
+            }
+
+            if memory.truncated {
+                println!("Not every memory layout could be checked, the character has too many of them");
+            }
+        }
+
+        if !warnings.is_empty() {
+            println!("Found {} warning(s) in the character:", warnings.len());
+
+            for warning in &warnings {
+                println!("  {warning}");
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::repr::{Action, State, StateTransition, StateTransitionTrigger};
+
+    #[test]
+    fn default_character_is_valid() {
+        assert_eq!(Character::default().validate(), vec![]);
+    }
+
+    #[test]
+    fn dangling_references_are_reported() {
+        let mut char = Character {
+            default_state: "missing".to_string(),
+            ..Default::default()
+        };
+
+        char.states.insert("booped".to_string(), State {
+            image: StateImage::Animation {
+                name: "boop".to_string(),
+                next_state: "gone".to_string(),
+                loop_count: 1,
+                layer_load: false,
+            },
+            transitions: vec![StateTransition {
+                to_state: "nowhere".to_string(),
+                trigger: StateTransitionTrigger::Clicked,
+            }],
+            ..Default::default()
+        });
+
+        char.actions.insert("poke".to_string(), Action {
+            display: "Poke".to_string(),
+            ty: ActionType::SwitchState("elsewhere".to_string()),
+        });
+
+        assert_eq!(char.validate(), vec![
+            ValidationError::InvalidDefaultState,
+            ValidationError::InvalidAnimationInState("booped".to_string()),
+            ValidationError::InvalidNextStateInAnimation("booped".to_string()),
+            ValidationError::InvalidTransitionTarget("booped".to_string(), "nowhere".to_string()),
+            ValidationError::InvalidActionState("poke".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn id_has_to_be_folder_name() {
+        for id in ["..", ".", "a/../..", "cat/dog", "", "..\\cat"] {
+            assert_eq!(Character::from_id(id).validate(), vec![ValidationError::InvalidId(id.to_string())], "{id}");
+        }
+
+        assert!(Character::from_id("cat").validate().is_empty());
+    }
+
+    #[test]
+    fn graph_problems_are_warnings() {
+        let mut char = Character::default();
+        assert_eq!(char.warnings(), vec![ValidationWarning::DeadEndState("idle".to_string())]);
+
+        let transition = |to_state: &str, trigger: StateTransitionTrigger| StateTransition {
+            to_state: to_state.to_string(),
+            trigger,
+        };
+        let animation = |next_state: &str| StateImage::Animation {
+            name: "boop".to_string(),
+            next_state: next_state.to_string(),
+            loop_count: 0,
+            layer_load: false,
+        };
+
+        char.animations.insert("boop".to_string(), Default::default());
+        char.states.insert("idle".to_string(), State {
+            transitions: vec![
+                transition("menu", StateTransitionTrigger::Clicked),
+                transition("spin", StateTransitionTrigger::ElapsedTime { duration: 1_000_000 }),
+            ],
+            ..Default::default()
+        });
+        char.states.insert("menu".to_string(), State {
+            transitions: vec![transition("submenu", StateTransitionTrigger::Clicked)],
+            ..Default::default()
+        });
+        char.states.insert("submenu".to_string(), State {
+            transitions: vec![transition("menu", StateTransitionTrigger::Clicked)],
+            ..Default::default()
+        });
+        char.states.insert("spin".to_string(), State {
+            image: animation("spin"),
+            ..Default::default()
+        });
+        char.states.insert("forgotten".to_string(), State::default());
+
+        assert_eq!(char.validate(), vec![]);
+        assert_eq!(char.warnings(), vec![
+            ValidationWarning::UnreachableState("forgotten".to_string()),
+            ValidationWarning::DeadEndState("forgotten".to_string()),
+            ValidationWarning::ClickOnlyCycle(StateList(vec!["menu".to_string(), "submenu".to_string()])),
+            ValidationWarning::InstantAnimationLoop(StateList(vec!["spin".to_string()])),
+        ]);
+
+        // Reaching it through an action is enough
+        char.actions.insert("forget".to_string(), Action {
+            display: "Forget".to_string(),
+            ty: ActionType::SwitchState("forgotten".to_string()),
+        });
+        assert!(!char.warnings().contains(&ValidationWarning::UnreachableState("forgotten".to_string())));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::character::analysis::{analyze_with_progress, format_duration, AnalysisOptions, AnalysisReport};
+use crate::character::repr::Character;
+use crate::character::util::AsRichText;
+use crate::character::validation::ValidationError;
+use crate::gui::app::util::SPACING;
+use crate::target::TargetProfile;
+use egui::{Color32, DragValue, Grid, ProgressBar, ScrollArea, Ui};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct AnalysisState {
+    options: AnalysisOptions,
+    report: Option<AnalysisReport>,
+    task: Option<AnalysisTask>
+}
+
+/// Analysis running on its own thread, long runs would freeze the editor otherwise
+struct AnalysisTask {
+    progress: Arc<(AtomicUsize, AtomicUsize)>,
+    cancelled: Arc<AtomicBool>,
+    result: Receiver<Option<AnalysisReport>>
+}
+
+impl AnalysisState {
+    /// Starts the analysis, unless one is already running
+    pub fn run(&mut self, character: &Character, target: &TargetProfile) {
+        if self.task.is_some() {
+            return;
+        }
+
+        self.options.target = target.clone();
+
+        let progress = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (sender, result) = channel();
+
+        let character = character.clone();
+        let options = self.options.clone();
+        let shared = progress.clone();
+        let shared_cancelled = cancelled.clone();
+
+        thread::spawn(move || {
+            let report = analyze_with_progress(&character, &options, &|done, total| {
+                shared.0.store(done, Ordering::Relaxed);
+                shared.1.store(total, Ordering::Relaxed);
+            }, &shared_cancelled);
+
+            let _ = sender.send(report);
+        });
+
+        self.task = Some(AnalysisTask {
+            progress,
+            cancelled,
+            result,
+        });
+    }
+
+    /// Takes the report of a finished analysis, a cancelled one keeps the previous report
+    fn poll(&mut self) {
+        let Some(task) = &self.task else {
+            return;
+        };
+
+        match task.result.try_recv() {
+            Ok(report) => {
+                self.report = report.or(self.report.take());
+                self.task = None;
+            }
+            Err(TryRecvError::Disconnected) => self.task = None,
+            Err(TryRecvError::Empty) => {}
+        }
+    }
+}
+
+/// Returns true when the analysis should be run again
+pub fn analysis_ui(
+    ui: &mut Ui,
+    state: &mut AnalysisState,
+    validations: &[ValidationError],
+) -> bool {
+    let mut run_requested = false;
+
+    state.poll();
+
+    let running = state.task.is_some();
+    let options = &mut state.options;
+
+    ui.horizontal(|ui| {
+        ui.label("Runs:");
+        ui.add(DragValue::new(&mut options.runs).range(1..=10_000));
+
+        ui.label("Run length:");
+        ui.add(DragValue::from_get_set(|value| {
+            if let Some(value) = value {
+                options.duration = (value * 1_000_000.0).floor() as i64;
+            }
+
+            options.duration as f64 / 1_000_000.0
+        }).range(1..=86_400).suffix(" secs"));
+
+        let mut clicks = options.click_interval.is_some();
+
+        if ui.checkbox(&mut clicks, "Click every").changed() {
+            options.click_interval = clicks.then_some(10_000_000);
+        }
+
+        if let Some(interval) = &mut options.click_interval {
+            ui.add(DragValue::from_get_set(|value| {
+                if let Some(value) = value {
+                    *interval = (value * 1_000_000.0).floor() as i64;
+                }
+
+                *interval as f64 / 1_000_000.0
+            }).range(0.1..=86_400.0).suffix(" secs on average"));
+        }
+
+        ui.label("Seed:");
+        ui.add(DragValue::new(&mut options.seed));
+
+        ui.add_enabled_ui(validations.is_empty() && !running, |ui| {
+            if ui.button("Run Analysis").clicked() {
+                run_requested = true;
+            }
+        });
+    });
+
+    if let Some(task) = &state.task {
+        let done = task.progress.0.load(Ordering::Relaxed);
+        let total = task.progress.1.load(Ordering::Relaxed);
+
+        ui.horizontal(|ui| {
+            ui.add(ProgressBar::new(done as f32 / total.max(1) as f32).desired_width(250.0));
+            ui.label(format!("Simulated {done} of {total} runs"));
+
+            if ui.button("Cancel").clicked() {
+                task.cancelled.store(true, Ordering::Relaxed);
+            }
+        });
+
+        // Nothing else triggers a repaint while the thread works
+        ui.ctx().request_repaint_after(Duration::from_millis(100));
+    }
+
+    if !validations.is_empty() {
+        ui.label("Fix the errors in the character to run the analysis".rich().color(Color32::RED));
+    }
+
+    let Some(report) = &state.report else {
+        return run_requested;
+    };
+
+    ui.add_space(SPACING);
+
+    let monte_carlo = &report.monte_carlo;
+
+    ui.label(format!(
+        "Simulated {} runs of {}, {} crashed",
+        monte_carlo.runs,
+        format_duration(monte_carlo.duration as f64),
+        monte_carlo.crashed_runs
+    ));
+
+    let markov = match &report.markov {
+        Ok(markov) => Some(markov),
+        Err(err) => {
+            ui.label(format!("Markov model not available: {err}").rich().color(Color32::ORANGE));
+            None
+        }
+    };
+
+    ui.add_space(SPACING);
+
+    let format_time = |time: Option<f64>| time.map(format_duration).unwrap_or("-".to_string());
+
+    ScrollArea::both()
+        .id_salt("analysis.report")
+        .show(ui, |ui| {
+            Grid::new("analysis.states")
+                .striped(true)
+                .num_columns(8)
+                .show(ui, |ui| {
+                    for header in [
+                        "State", "Time", "Time (Markov)", "Average Stay", "Average Stay (Markov)",
+                        "First Entry", "First Entry (Markov)", "Reached In Runs"
+                    ] {
+                        ui.strong(header);
+                    }
+                    ui.end_row();
+
+                    for (name, stats) in &monte_carlo.states {
+                        let model = markov.and_then(|markov| markov.states.get(name));
+
+                        ui.label(name);
+                        ui.label(format!("{:.1}%", stats.time_fraction * 100.0));
+                        ui.label(model.map(|model| format!("{:.1}%", model.time_fraction * 100.0)).unwrap_or("-".to_string()));
+                        ui.label(format_time(stats.mean_dwell));
+                        ui.label(format_time(model.map(|model| model.mean_dwell)));
+                        ui.label(format_time(stats.mean_first_entry));
+                        ui.label(format_time(model.and_then(|model| model.mean_first_entry)));
+                        ui.label(format!("{:.0}%", stats.reached * 100.0));
+                        ui.end_row();
+                    }
+                });
+        });
+
+    run_requested
+}
@@ -0,0 +1,90 @@
+use crate::character::encode::Progress;
+use crate::character::BuildSummary;
+use anyhow::anyhow;
+use egui::{Align2, Context, ProgressBar, Window};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Export running on its own thread, so the editor keeps drawing while images are encoded
+pub struct ExportTask {
+    title: &'static str,
+    progress: Arc<(AtomicUsize, AtomicUsize)>,
+    result: Receiver<anyhow::Result<BuildSummary>>
+}
+
+impl ExportTask {
+    pub fn spawn(title: &'static str, export: impl FnOnce(Progress) -> anyhow::Result<BuildSummary> + Send + 'static) -> Self {
+        let progress = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let (sender, result) = channel();
+
+        let shared = progress.clone();
+        thread::spawn(move || {
+            let result = export(&|done, total| {
+                shared.0.store(done, Ordering::Relaxed);
+                shared.1.store(total, Ordering::Relaxed);
+            });
+
+            let _ = sender.send(result);
+        });
+
+        Self {
+            title,
+            progress,
+            result,
+        }
+    }
+
+    /// Result of the export once it's finished
+    pub fn poll(&self) -> Option<anyhow::Result<BuildSummary>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(anyhow!("Export stopped unexpectedly")))
+        }
+    }
+
+    pub fn ui(&self, ctx: &Context) {
+        let done = self.progress.0.load(Ordering::Relaxed);
+        let total = self.progress.1.load(Ordering::Relaxed);
+
+        Window::new(self.title)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.add(ProgressBar::new(done as f32 / total.max(1) as f32).desired_width(250.0));
+                ui.label(format!("Encoded {done} of {total} images"));
+            });
+
+        // Nothing else triggers a repaint while the thread works
+        ctx.request_repaint_after(Duration::from_millis(100));
+    }
+}
+
+/// Result dialog of a finished export, returns true once it's closed
+pub fn summary_ui(ctx: &Context, summary: &BuildSummary) -> bool {
+    let mut closed = false;
+
+    Window::new("Export Finished")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            let lines = summary.lines();
+
+            if lines.is_empty() {
+                ui.label("Character was exported");
+            }
+
+            for line in lines {
+                ui.label(line);
+            }
+
+            closed = ui.button("OK").clicked();
+        });
+
+    closed
+}
@@ -1,5 +1,6 @@
 use crate::character::repr::{Action, ActionType, Animation, SequenceFrame, SequenceMode, State, StateImage, StateTransition, StateTransitionTrigger};
 use crate::gui::app::shared::{MutableStringScope, SharedString};
+use crate::image::{ConversionOptions, PixelFormat};
 use crate::gui::app::util::{load_image_or_black, pick_unique_name};
 use egui::{pos2, Pos2, TextureHandle};
 use image::DynamicImage;
@@ -172,6 +173,8 @@ impl InterState {
                         height: image_data.height,
                         upscale: image_data.upscale,
                         layer_load,
+                        conversion: image_data.conversion,
+                        data: Default::default(),
                     }
                 },
                 InterStateImage::Animation {
@@ -201,6 +204,8 @@ impl InterState {
                                     height: image.height,
                                     upscale: image.upscale,
                                     duration: e.duration,
+                                    conversion: image.conversion,
+                                    data: Default::default(),
                                 })
                             })
                             .collect(),
@@ -284,7 +289,19 @@ pub struct LoadedImage {
     pub width: u32,
     pub height: u32,
     pub upscale: bool,
-    pub handle: Option<TextureHandle>
+    pub conversion: ConversionOptions,
+    pub handle: Option<TextureHandle>,
+    /// Source and quantized preview, made again when the options change
+    pub preview: Option<ConversionPreview>
+}
+
+#[derive(Clone)]
+pub struct ConversionPreview {
+    pub key: (PathBuf, u32, u32, bool, ConversionOptions, PixelFormat),
+    pub source: TextureHandle,
+    pub result: TextureHandle,
+    /// Compressed and uncompressed size of the image data
+    pub stored_size: (usize, usize)
 }
 
 impl Default for LoadedImage {
@@ -295,7 +312,9 @@ impl Default for LoadedImage {
             width: 320,
             height: 320,
             upscale: false,
+            conversion: Default::default(),
             handle: None,
+            preview: None,
         }
     }
 }
@@ -303,19 +322,30 @@ impl Default for LoadedImage {
 pub type SharedLoadedImage = Rc<RefCell<LoadedImage>>;
 
 pub fn find_images(map: &HashMap<String, State>, location: impl AsRef<Path>) -> Vec<(SharedString, SharedLoadedImage)> {
-    let mut found_images: Vec<(SharedString, (PathBuf, (u32, u32), bool))> = vec![];
+    let base_location = location.as_ref().to_path_buf();
+    let mut found_images: Vec<(SharedString, LoadedImage)> = vec![];
 
     let mut add_unique = |
         name: &String,
         path: &PathBuf,
         width: u32,
         height: u32,
-        upscale: bool
+        upscale: bool,
+        conversion: &ConversionOptions
     | {
         if !found_images.iter().any(|(k, _)| k.refer(|k| k == name)) {
             found_images.push((
                 name.to_string().into(),
-                (path.clone(), (width, height), upscale)
+                LoadedImage {
+                    image: load_image_or_black(base_location.join(path)),
+                    width,
+                    height,
+                    path: path.clone(),
+                    handle: None,
+                    upscale,
+                    conversion: conversion.clone(),
+                    preview: None,
+                }
             ))
         }
     };
@@ -323,14 +353,15 @@ pub fn find_images(map: &HashMap<String, State>, location: impl AsRef<Path>) ->
     for state in map.values() {
         match &state.image {
             StateImage::Single {
-                name, path, width, height, upscale, ..
+                name, path, width, height, upscale, conversion, ..
             } => {
                 add_unique(
                     name,
                     path,
                     *width,
                     *height,
-                    *upscale
+                    *upscale,
+                    conversion
                 );
             }
             StateImage::Sequence { frames, .. } => {
@@ -340,7 +371,8 @@ pub fn find_images(map: &HashMap<String, State>, location: impl AsRef<Path>) ->
                         &frame.path,
                         frame.width,
                         frame.height,
-                        frame.upscale
+                        frame.upscale,
+                        &frame.conversion
                     )
                 }
             }
@@ -348,17 +380,7 @@ pub fn find_images(map: &HashMap<String, State>, location: impl AsRef<Path>) ->
         }
     }
 
-    let base_location = location.as_ref().to_path_buf();
-
     found_images.into_iter()
-        .map(|(k, (path, (width, height), upscale))|
-            (k, Rc::new(RefCell::new(LoadedImage {
-                image: load_image_or_black(base_location.join(&path)),
-                width,
-                height,
-                path,
-                handle: None,
-                upscale,
-            }))))
+        .map(|(k, image)| (k, Rc::new(RefCell::new(image))))
         .collect()
 }
\ No newline at end of file
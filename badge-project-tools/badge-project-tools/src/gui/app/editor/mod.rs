@@ -3,23 +3,38 @@ mod resources;
 mod nodes;
 mod validation;
 mod simulator;
+mod analysis;
+mod export;
 
-use crate::character::{process_character_archive, write_character_tar};
+use crate::character::cache::clear_build_cache;
+use crate::character::{process_character_archive, write_character_tar, BuildSummary};
+use crate::character::error::{CharacterBuildError, CharacterBuildErrors, Resource};
+use crate::character::format::supported_versions;
 use crate::character::repr::{Animation, Character, State};
 use crate::character::util::AsRichText;
-use crate::gui::app::editor::intermediate::{find_images, InterAction, InterSequence, InterState, LoadedImage, SharedInterState, SharedLoadedImage};
-use crate::gui::app::editor::nodes::{snarl_from_states, snarl_style, ViewerSelection};
+use crate::gui::app::editor::intermediate::{find_images, ConversionPreview, InterAction, InterSequence, InterState, LoadedImage, SharedInterState, SharedLoadedImage};
+use crate::gui::app::editor::nodes::{snarl_from_states, snarl_style, ViewerSelection, WARNING_COLOR};
 use crate::gui::app::editor::simulator::{simulator_ui, SimulatorState};
-use crate::gui::app::editor::validation::ValidationError;
+use crate::gui::app::editor::analysis::{analysis_ui, AnalysisState};
+use crate::gui::app::editor::export::{summary_ui, ExportTask};
+use crate::gui::app::editor::validation::MemoryCheck;
+use crate::character::validation::{ValidationError, ValidationWarning};
 use crate::gui::app::shared::SharedString;
 use crate::gui::app::start::StartScreen;
 use crate::gui::app::util::{inline_style_label, pick_unique_name, ChangeTracker};
 use crate::gui::app::{util, BoxedGuiPage, GuiPage, PageResponse};
+use crate::image::alpha::{checkerboard_composite, AlphaMode};
+use crate::image::fit::fit_image;
+use crate::image::compress::Compression;
+use crate::image::pixel_art::{firmware_upscale, UPSCALE_FACTOR};
+use crate::image::{decode_alpha, decode_image_data, encode_pixels, ByteOrder, PixelFormat};
+use crate::target::TargetProfile;
 use anyhow::anyhow;
 use egui::containers::menu::{MenuButton, MenuConfig};
-use egui::{pos2, vec2, Align2, Button, CentralPanel, Color32, ColorImage, ComboBox, FontId, Image, InnerResponse, Key, PopupCloseBehavior, Rect, Sense, Stroke, StrokeKind, TextureHandle, TextureOptions, TopBottomPanel, Ui, WidgetText};
+use egui::{pos2, vec2, Align2, Button, CentralPanel, Color32, ColorImage, ComboBox, FontId, Image, InnerResponse, Key, PopupCloseBehavior, Rect, ScrollArea, Sense, Stroke, StrokeKind, TextureHandle, TextureOptions, TopBottomPanel, Ui, WidgetText};
 use egui_snarl::ui::SnarlStyle;
 use egui_snarl::Snarl;
+use image::{DynamicImage, RgbImage};
 use std::cell::{RefCell, RefMut};
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
@@ -33,6 +48,9 @@ pub struct CharacterEditor {
     location: PathBuf,
     file_path: Option<PathBuf>,
     include_select_export: bool,
+    export_task: Option<ExportTask>,
+    export_summary: Option<BuildSummary>,
+    target: TargetProfile,
     last_save: Option<Instant>,
     id: String,
     name: String,
@@ -48,7 +66,11 @@ pub struct CharacterEditor {
     graph_selection: ViewerSelection,
     tracker: ChangeTracker,
     validation_errors: Vec<ValidationError>,
-    simulator_state: Option<SimulatorState>
+    validation_warnings: Vec<ValidationWarning>,
+    memory_check: MemoryCheck,
+    build_errors: Vec<CharacterBuildError>,
+    simulator_state: Option<SimulatorState>,
+    analysis_state: AnalysisState
 }
 
 #[derive(Copy, Clone, EnumIter, Default, Display, Eq, PartialEq)]
@@ -57,7 +79,8 @@ enum EditorTab {
     Resources,
     #[strum(to_string = "State Machine")]
     StateMachine,
-    Simulator
+    Simulator,
+    Analysis
 }
 
 impl CharacterEditor {
@@ -100,6 +123,9 @@ impl CharacterEditor {
             location,
             file_path: original,
             include_select_export: false,
+            export_task: None,
+            export_summary: None,
+            target: TargetProfile::default(),
             last_save: None,
             id: char.id,
             name: char.name,
@@ -116,10 +142,14 @@ impl CharacterEditor {
             graph_selection: ViewerSelection::default(),
             tracker: Default::default(),
             validation_errors: vec![],
+            validation_warnings: vec![],
+            memory_check: MemoryCheck::default(),
+            build_errors: vec![],
             simulator_state: None,
+            analysis_state: Default::default(),
         };
 
-        state.validation_errors = state.validate_state();
+        state.validate();
 
         state
     }
@@ -132,7 +162,7 @@ impl CharacterEditor {
         let path = path.as_ref().to_path_buf();
 
         let contents = fs::read_to_string(&path)?;
-        let character = serde_json::from_str::<Character>(&contents)?;
+        let mut character = serde_json::from_str::<Character>(&contents)?;
 
         let location = if let Some(parent) = path.parent() {
             parent.to_path_buf()
@@ -140,6 +170,8 @@ impl CharacterEditor {
             path.clone()
         };
 
+        character.refresh_frame_counts(&location);
+
         Ok(Box::new(Self::from_character(character, location, Some(path))))
     }
 
@@ -213,7 +245,7 @@ impl CharacterEditor {
         };
     }
 
-    pub fn export_character(&self) -> anyhow::Result<()> {
+    pub fn export_character(&self) -> anyhow::Result<ExportTask> {
         let Some(picked_file) = rfd::FileDialog::new()
             .set_title("Save character archive file")
             .add_filter("Character Archive", &["tar"])
@@ -224,18 +256,56 @@ impl CharacterEditor {
         };
 
         let char = self.as_repr();
+        let location = self.location.clone();
+        let include_select = self.include_select_export;
+        let target = self.target.clone();
 
-        process_character_archive(char, picked_file, &self.location, self.include_select_export)
+        Ok(ExportTask::spawn("Exporting Character", move |progress| process_character_archive(
+            char,
+            picked_file,
+            location,
+            include_select,
+            true,
+            &target,
+            progress
+        )))
     }
 
-    pub fn export(&self) {
-        match self.export_character() {
-            Ok(_) => {}
-            Err(err) => eprintln!("Error while exporting: {err}")
+    pub fn export(&mut self) {
+        self.start_export(Self::export_character);
+    }
+
+    /// Only one export runs at a time, the result is handled once its thread is done
+    fn start_export(&mut self, export: impl FnOnce(&Self) -> anyhow::Result<ExportTask>) {
+        if self.export_task.is_some() {
+            return;
+        }
+
+        match export(self) {
+            Ok(task) => self.export_task = Some(task),
+            Err(err) => self.handle_export_result(Err(err))
         }
     }
 
-    pub fn export_to_folder(&self) -> anyhow::Result<()> {
+    fn handle_export_result(&mut self, result: anyhow::Result<BuildSummary>) {
+        match result {
+            Ok(summary) => {
+                self.build_errors.clear();
+                self.export_summary = Some(summary);
+            }
+            Err(err) => {
+                if let Some(errors) = err.downcast_ref::<CharacterBuildErrors>() {
+                    self.build_errors = errors.0.clone();
+                }
+
+                eprintln!("Error while exporting: {err}")
+            }
+        }
+
+        self.validate();
+    }
+
+    pub fn export_to_folder(&self) -> anyhow::Result<ExportTask> {
         let Some(picked_location) = rfd::FileDialog::new()
             .set_title("Export character binaries to location")
             .set_directory(&self.location)
@@ -245,22 +315,46 @@ impl CharacterEditor {
         };
 
         let char = self.as_repr();
+        let location = self.location.clone();
+        let include_select = self.include_select_export;
+        let target = self.target.clone();
 
-        let mut buffer: Vec<u8> = vec![];
-        write_character_tar(char, &mut buffer, &self.location, self.include_select_export)?;
+        Ok(ExportTask::spawn("Exporting Character", move |progress| {
+            let mut buffer: Vec<u8> = vec![];
+            let summary = write_character_tar(char, &mut buffer, location, include_select, true, &target, progress)?;
 
-        let mut archive = tar::Archive::new(buffer.as_slice());
-        archive.unpack(picked_location)?;
+            let mut archive = tar::Archive::new(buffer.as_slice());
+            archive.unpack(picked_location)?;
 
-        Ok(())
+            Ok(summary)
+        }))
     }
 
-    pub fn handle_export_to_folder(&self) {
-        match self.export_to_folder() {
-            Ok(_) => {}
-            Err(err) => eprintln!("Error while exporting: {err}")
+    pub fn handle_export_to_folder(&mut self) {
+        self.start_export(Self::export_to_folder);
+    }
+
+    pub fn load_target(&mut self) {
+        let Some(picked_file) = rfd::FileDialog::new()
+            .set_title("Open target profile")
+            .add_filter("Target Profile", &["toml", "json"])
+            .set_directory(&self.location)
+            .pick_file()
+        else {
+            return;
+        };
+
+        match TargetProfile::load(picked_file) {
+            Ok(target) => self.set_target(target),
+            Err(err) => eprintln!("Error while loading target profile: {err}")
         }
     }
+
+    fn set_target(&mut self, target: TargetProfile) {
+        self.target = target;
+        self.simulator_state = None;
+        self.validate();
+    }
 }
 
 pub const IMAGE_EXTENSIONS: &[&'static str] = &["png", "jpg", "jpeg", "bmp", "tga", "tiff"];
@@ -307,6 +401,7 @@ fn try_set_loaded_image(
     value.image = image;
     value.path = path.to_path_buf();
     value.handle = None;
+    value.preview = None;
 
     tracker.mark_change()
 }
@@ -376,6 +471,112 @@ pub fn inline_image_picker(
     })
 }
 
+fn rgb_texture(ui: &Ui, name: String, image: &RgbImage) -> TextureHandle {
+    ui.ctx().load_texture(
+        name,
+        ColorImage::from_rgb([image.width() as _, image.height() as _], image.as_raw()),
+        TextureOptions::NEAREST
+    )
+}
+
+/// Source next to the result of the conversion to the pixel format of the target, side by side.
+/// Upscaled results are shown the way the firmware draws them
+pub fn inline_conversion_preview(ui: &mut Ui, value: &mut RefMut<LoadedImage>, format: PixelFormat, width: f32) {
+    let factor = if value.upscale { UPSCALE_FACTOR } else { 1 };
+    let (real_width, real_height) = (value.width / factor, value.height / factor);
+
+    if real_width == 0 || real_height == 0 || value.image.width() == 0 {
+        return;
+    }
+
+    let key = (value.path.clone(), value.width, value.height, value.upscale, value.conversion.clone(), format);
+
+    if value.preview.as_ref().is_none_or(|preview| preview.key != key) {
+        let options = &value.conversion;
+        let converted = fit_image(&value.image, real_width, real_height, options);
+        let data = encode_pixels(&converted, format, ByteOrder::Little, options);
+
+        let (Some(result), Some(alpha)) = (
+            decode_image_data(&data, real_width, real_height, format, ByteOrder::Little),
+            decode_alpha(&data, real_width, real_height, format, ByteOrder::Little, options)
+        ) else {
+            return;
+        };
+
+        // Transparency shows as checkerboard on both sides
+        let source = fit_image(&value.image, value.width, value.height, options);
+        let source_alpha = source.pixels().map(|pixel| pixel.0[3]).collect::<Vec<_>>();
+        let source = checkerboard_composite(&DynamicImage::ImageRgba8(source).to_rgb8(), &source_alpha, false);
+        let result = checkerboard_composite(
+            &result,
+            &alpha,
+            options.premultiplied && options.alpha == AlphaMode::Channel
+        );
+        let result = firmware_upscale(&result, factor);
+
+        let name = value.path.to_string_lossy();
+        let stored_size = (options.compression.compress(&data, format.pixel_size()).len(), data.len());
+
+        value.preview = Some(ConversionPreview {
+            source: rgb_texture(ui, format!("{name}-source"), &source),
+            result: rgb_texture(ui, format!("{name}-{format}"), &result),
+            stored_size,
+            key,
+        });
+    }
+
+    let Some(preview) = &value.preview else {
+        return;
+    };
+
+    // Scaled down previews hide single pixels, so they can be shown at the size of the display
+    let id = ui.id().with("actual_size");
+    let mut actual_size = ui.memory(|mem| mem.data.get_temp::<bool>(id).unwrap_or_default());
+
+    ui.horizontal(|ui| {
+        ui.add_space(width + ui.style().spacing.item_spacing.x);
+        if ui.checkbox(&mut actual_size, "Actual Size").changed() {
+            ui.memory_mut(|mem| mem.data.insert_temp(id, actual_size));
+        }
+    });
+
+    let scale = if actual_size {
+        1.0
+    } else {
+        (IMAGE_SIZE / value.width.max(value.height) as f32).min(1.0)
+    };
+    let img_size = vec2(value.width as f32, value.height as f32) * scale;
+
+    ui.horizontal(|ui| {
+        ui.add_space(width + ui.style().spacing.item_spacing.x);
+
+        ScrollArea::horizontal()
+            .id_salt(id)
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    for texture in [&preview.source, &preview.result] {
+                        ui.add_sized(img_size, Image::new(texture).fit_to_exact_size(img_size));
+                    }
+                });
+            });
+    });
+
+    if value.conversion.compression != Compression::None {
+        let (stored, data_size) = preview.stored_size;
+
+        ui.horizontal(|ui| {
+            ui.add_space(width + ui.style().spacing.item_spacing.x);
+
+            // The archive keeps images uncompressed when compression doesn't help
+            ui.label(format!(
+                "Stored {} of {data_size} bytes ({:.0}%)",
+                stored.min(data_size),
+                stored.min(data_size) as f64 * 100.0 / data_size.max(1) as f64
+            ));
+        });
+    }
+}
+
 pub fn inline_image_resource_picker(
     ui: &mut Ui,
     label: impl Into<WidgetText>,
@@ -497,6 +698,26 @@ pub fn inline_validation_error(
     }
 }
 
+pub fn inline_build_errors(
+    ui: &mut Ui,
+    validations: &Vec<ValidationError>,
+    condition: impl Fn(&Resource) -> bool,
+    width: f32
+) {
+    for error in validations {
+        let ValidationError::Build(error) = error else {
+            continue;
+        };
+
+        if condition(error.resource()) {
+            ui.horizontal(|ui| {
+                ui.add_space(width + ui.style().spacing.item_spacing.x);
+                ui.label(error.message().rich().color(Color32::RED))
+            });
+        }
+    }
+}
+
 pub fn inline_layer_selector(
     ui: &mut Ui,
     label: impl Into<WidgetText>,
@@ -584,6 +805,20 @@ impl GuiPage for CharacterEditor {
             self.handle_export_to_folder();
         }
 
+        if let Some(task) = &self.export_task {
+            match task.poll() {
+                Some(result) => {
+                    self.export_task = None;
+                    self.handle_export_result(result);
+                }
+                None => task.ui(ui.ctx())
+            }
+        }
+
+        if let Some(summary) = &self.export_summary && summary_ui(ui.ctx(), summary) {
+            self.export_summary = None;
+        }
+
         let button_resp = TopBottomPanel::top("editor.top")
             .show(ui.ctx(), |ui| {
                 ui.add_enabled_ui(self.simulator_state.is_none(), |ui| {
@@ -612,6 +847,61 @@ impl GuiPage for CharacterEditor {
 
                                 ui.checkbox(&mut self.include_select_export, "Include Select");
 
+                                // Next export encodes every image again
+                                if ui.button("Clean Build Cache").clicked()
+                                    && let Err(err) = clear_build_cache(&self.location) {
+                                    eprintln!("Error while cleaning build cache: {err}");
+                                }
+
+                                ui.horizontal(|ui| {
+                                    ui.label("Format Version");
+
+                                    ComboBox::new("editor.format_version", "")
+                                        .selected_text(self.target.format_version.to_string())
+                                        .show_ui(ui, |ui| {
+                                            for version in supported_versions() {
+                                                ui.selectable_value(&mut self.target.format_version, version, version.to_string());
+                                            }
+                                        });
+                                });
+
+                                ui.horizontal(|ui| {
+                                    ui.label("Pixel Format");
+
+                                    let pixel_format = self.target.pixel_format;
+
+                                    ComboBox::new("editor.pixel_format", "")
+                                        .selected_text(pixel_format.to_string())
+                                        .show_ui(ui, |ui| {
+                                            for format in self.target.pixel_formats.clone() {
+                                                ui.selectable_value(&mut self.target.pixel_format, format, format.to_string());
+                                            }
+                                        });
+
+                                    // Image sizes change, so the memory budget has to be checked again
+                                    if pixel_format != self.target.pixel_format {
+                                        self.simulator_state = None;
+                                        self.validate();
+                                    }
+                                });
+
+                                ui.separator();
+
+                                ui.label(format!(
+                                    "Target: {} ({}x{})",
+                                    self.target.name,
+                                    self.target.width,
+                                    self.target.height
+                                ));
+
+                                if ui.button("Load Target Profile").clicked() {
+                                    self.load_target()
+                                }
+
+                                if ui.button("Use Stock Target").clicked() {
+                                    self.set_target(TargetProfile::default())
+                                }
+
                                 ui.separator();
 
                                 if ui.button("Exit to Start").clicked() {
@@ -666,6 +956,16 @@ impl GuiPage for CharacterEditor {
                 .show(ui.ctx(), |ui| {
                     ui.label(error.rich().color(Color32::RED));
                 });
+        } else if let Some(warning) = self.validation_warnings.first() {
+            TopBottomPanel::bottom("editor.bottom")
+                .show(ui.ctx(), |ui| {
+                    ui.label(warning.rich().color(WARNING_COLOR));
+                });
+        } else if self.memory_check.is_busy() {
+            TopBottomPanel::bottom("editor.bottom")
+                .show(ui.ctx(), |ui| {
+                    ui.label("Checking memory budget...".rich().color(Color32::GRAY));
+                });
         }
 
         CentralPanel::default()
@@ -679,28 +979,33 @@ impl GuiPage for CharacterEditor {
                         self.state_machine_ui(ui);
                     }
                     EditorTab::Simulator => {
+                        let character = self.simulator_state.is_none().then(|| self.as_repr());
+
                         simulator_ui(
                             ui,
                             &mut self.simulator_state,
-                            &self.images,
-                            &self.sequences,
-                            &self.animations,
-                            &self.states,
+                            character,
                             &mut self.state_graph,
                             self.graph_style,
-                            &self.actions,
-                            &self.default_state,
-                            &self.validation_errors
+                            &self.validation_errors,
+                            &self.target
                         )
                     }
+                    EditorTab::Analysis => {
+                        if analysis_ui(ui, &mut self.analysis_state, &self.validation_errors) {
+                            self.analysis_state.run(&self.as_repr(), &self.target);
+                        }
+                    }
                 }
             });
 
         if self.tracker.changed() {
-            self.validation_errors = self.validate_state();
+            self.validate();
             self.tracker.mark_clean();
         }
 
+        self.update_memory_check(ui.ctx());
+
         PageResponse::Nothing
     }
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
 use crate::character::repr::StateTransitionTrigger;
 use crate::character::util::AsRichText;
 use crate::gui::app::editor::intermediate::{InterState, InterStateImage, InterStateTransition, SharedInterState, SharedInterStateTransition};
-use crate::gui::app::editor::validation::ValidationError;
-use crate::gui::app::editor::{inline_image_resource_picker, inline_layer_selector, inline_validation_error, CharacterEditor};
+use crate::character::validation::{ValidationError, ValidationWarning};
+use crate::character::error::Resource;
+use crate::gui::app::editor::{inline_build_errors, inline_image_resource_picker, inline_layer_selector, inline_validation_error, CharacterEditor};
 use crate::gui::app::shared::{MutableStringScope, SharedString};
 use crate::gui::app::util::{inline_checkbox, inline_drag_value, inline_duration_value, inline_enum_edit, inline_resource_picker, inline_style_label, inline_text_edit, pick_unique_name, ChangeTracker};
 use eframe::emath::{Pos2, Rect};
@@ -108,12 +109,15 @@ pub struct StateViewer<'a> {
     states: &'a mut Vec<(SharedString, SharedInterState)>,
     tracker: &'a mut ChangeTracker,
     validation_errors: &'a Vec<ValidationError>,
+    validation_warnings: &'a Vec<ValidationWarning>,
 }
 
 pub const WIRE_COLOR: Color32 = Color32::from_rgb(190, 190, 190);
 const SELECTED_COLOR: Color32 = Color32::CYAN;
 const SELECTED_BG_COLOR: Color32 = Color32::from_rgb(0, 70, 70);
 const ERROR_BG_COLOR: Color32 = Color32::from_rgb(70, 0, 0);
+pub const WARNING_COLOR: Color32 = Color32::from_rgb(255, 170, 0);
+const WARNING_BG_COLOR: Color32 = Color32::from_rgb(70, 45, 0);
 
 impl StateViewer<'_> {
     fn create_state(&mut self, pos: Pos2, out_pin_id: Option<OutPinId>, snarl: &mut Snarl<StateNode>) {
@@ -153,6 +157,13 @@ impl StateViewer<'_> {
                 ValidationError::InvalidNextStateInAnimation(name) => Some(name),
                 ValidationError::InvalidImageInState(name) => Some(name),
                 ValidationError::InvalidImageInSequenceFrame(name, _) => Some(name),
+                ValidationError::InvalidTransitionTarget(name, _) => Some(name),
+                ValidationError::MemoryBudget(failure) => failure.path.last(),
+                ValidationError::Build(error) => match error.resource() {
+                    Resource::State(name) => Some(name),
+                    Resource::Transition { state, .. } => Some(state),
+                    _ => None
+                },
                 _ => None
             };
 
@@ -169,6 +180,12 @@ impl StateViewer<'_> {
 
         false
     }
+
+    fn does_node_contain_warning(&self, node: NodeId, snarl: &Snarl<StateNode>) -> bool {
+        self.validation_warnings.iter()
+            .flat_map(ValidationWarning::states)
+            .any(|name| snarl[node].0.str_eq(name))
+    }
 }
 
 impl SnarlViewer<StateNode> for StateViewer<'_> {
@@ -185,6 +202,7 @@ impl SnarlViewer<StateNode> for StateViewer<'_> {
         snarl: &Snarl<StateNode>,
     ) -> Frame {
         let has_error = self.does_node_contain_error(node, snarl);
+        let has_warning = !has_error && self.does_node_contain_warning(node, snarl);
 
         if let ViewerSelection::SelectedState {
             node: selected_node,
@@ -194,7 +212,13 @@ impl SnarlViewer<StateNode> for StateViewer<'_> {
             if node == *selected_node {
                 return default
                     .stroke(Stroke::new(1.0, SELECTED_COLOR))
-                    .fill(if has_error { ERROR_BG_COLOR } else { SELECTED_BG_COLOR });
+                    .fill(if has_error {
+                        ERROR_BG_COLOR
+                    } else if has_warning {
+                        WARNING_BG_COLOR
+                    } else {
+                        SELECTED_BG_COLOR
+                    });
             }
         }
 
@@ -204,6 +228,12 @@ impl SnarlViewer<StateNode> for StateViewer<'_> {
                 .fill(ERROR_BG_COLOR)
         }
 
+        if has_warning {
+            return default
+                .stroke(Stroke::new(1.0, WARNING_COLOR))
+                .fill(WARNING_BG_COLOR)
+        }
+
         default
     }
 
@@ -556,6 +586,28 @@ impl CharacterEditor {
                                 TEXT_WIDTH
                             );
 
+                            inline_build_errors(
+                                ui,
+                                &self.validation_errors,
+                                |resource| {
+                                    let Resource::State(name) = resource else {
+                                        return false;
+                                    };
+
+                                    state.0.str_eq(name)
+                                },
+                                TEXT_WIDTH
+                            );
+
+                            for warning in &self.validation_warnings {
+                                if warning.states().into_iter().any(|name| state.0.str_eq(name)) {
+                                    ui.horizontal(|ui| {
+                                        ui.add_space(TEXT_WIDTH + ui.style().spacing.item_spacing.x);
+                                        ui.label(warning.rich().color(WARNING_COLOR))
+                                    });
+                                }
+                            }
+
                             let mut borrowed_state = state.1.borrow_mut();
 
                             ui.horizontal(|ui| {
@@ -735,6 +787,19 @@ impl CharacterEditor {
                                 );
                             });
 
+                            inline_build_errors(
+                                ui,
+                                &self.validation_errors,
+                                |resource| {
+                                    let Resource::Transition { state, to_state } = resource else {
+                                        return false;
+                                    };
+
+                                    parent.0.str_eq(state) && borrowed_transition.to_state.str_eq(to_state)
+                                },
+                                TEXT_WIDTH
+                            );
+
                             ui.horizontal(|ui| {
                                 let id = inline_style_label(ui, "Trigger:", TEXT_WIDTH)
                                     .response
@@ -817,7 +882,8 @@ impl CharacterEditor {
                     selection: &mut self.graph_selection,
                     states: &mut self.states,
                     tracker: &mut self.tracker,
-                    validation_errors: &self.validation_errors
+                    validation_errors: &self.validation_errors,
+                    validation_warnings: &self.validation_warnings,
                 }, ui);
         });
     }
@@ -1,12 +1,25 @@
-use crate::character::repr::{Animation, AnimationFrameSource};
+use crate::character::format::DELTA_FRAMES_FORMAT_VERSION;
+use crate::character::repr::{Animation, AnimationFrameSource, FrameEncoding};
+use crate::character::{animation_storage, AnimationStorage};
 use crate::character::util::AsRichText;
-use crate::gui::app::editor::intermediate::{InterAction, InterActionType, InterSequence, SharedLoadedImage};
-use crate::gui::app::editor::validation::ValidationError;
-use crate::gui::app::editor::{inline_image_picker, inline_image_resource_picker, inline_validation_error, CharacterEditor, SharedInterState, IMAGE_EXTENSIONS};
+use crate::gui::app::editor::intermediate::{InterAction, InterActionType, InterSequence, InterSequenceFrame, LoadedImage, SharedLoadedImage};
+use crate::character::validation::ValidationError;
+use crate::character::error::Resource;
+use crate::gui::app::editor::{inline_build_errors, inline_conversion_preview, inline_image_picker, inline_image_resource_picker, inline_validation_error, CharacterEditor, SharedInterState, IMAGE_EXTENSIONS};
 use crate::gui::app::shared::SharedString;
-use crate::gui::app::util::{inline_checkbox, inline_color_edit_rgb_tuple, inline_drag_value, inline_duration_value, inline_enum_edit, inline_folder_picker, inline_resource_picker, inline_style_label, inline_text_edit, pair_list_ui, vec_ui, ChangeTracker, SPACING};
-use egui::{CentralPanel, CollapsingHeader, ComboBox, ScrollArea, SidePanel, Ui};
-use std::path::PathBuf;
+use crate::image::alpha::{AlphaMode, Background};
+use crate::image::animated::{average_fps, constant_fps, decode_animated, extract_frames, ANIMATED_EXTENSIONS};
+use crate::image::fit::{CropRect, FitMode};
+use crate::image::sheet::{parse_atlas, Atlas, SheetLayout, SheetRegion, SpriteSheet, ATLAS_EXTENSIONS};
+use crate::image::ConversionOptions;
+use crate::target::TargetProfile;
+use crate::gui::app::util::{load_image, pick_unique_name, inline_checkbox, inline_color_edit_rgb_tuple, inline_drag_value, inline_duration_value, inline_enum_edit, inline_file_picker, inline_folder_picker, inline_resource_picker, inline_style_label, inline_text_edit, pair_list_ui, vec_ui, ChangeTracker, SPACING};
+use egui::{pos2, vec2, Align2, CentralPanel, CollapsingHeader, Color32, ColorImage, ComboBox, FontId, Rect, ScrollArea, Sense, SidePanel, Stroke, StrokeKind, TextureHandle, TextureOptions, Ui};
+use image::DynamicImage;
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 impl CharacterEditor {
     pub(crate) fn resources_ui(&mut self, ui: &mut Ui) {
@@ -40,6 +53,12 @@ impl CharacterEditor {
                     },
                     WIDTH
                 );
+                inline_build_errors(
+                    ui,
+                    &self.validation_errors,
+                    |resource| matches!(resource, Resource::Character),
+                    WIDTH
+                );
             });
 
         CentralPanel::default()
@@ -79,6 +98,18 @@ impl CharacterEditor {
                                 );
 
                                 inline_image_picker(ui, "Image:", el, &self.location, TEXT_WIDTH, tracker);
+                                inline_build_errors(
+                                    ui,
+                                    &self.validation_errors,
+                                    |resource| {
+                                        let Resource::Image(name) = resource else {
+                                            return false;
+                                        };
+
+                                        key.str_eq(name)
+                                    },
+                                    TEXT_WIDTH
+                                );
 
                                 ui.separator();
 
@@ -93,7 +124,31 @@ impl CharacterEditor {
 
                                 inline_drag_value(ui, "Width:", &mut borrowed.width, TEXT_WIDTH, tracker);
                                 inline_drag_value(ui, "Height:", &mut borrowed.height, TEXT_WIDTH, tracker);
+
+                                inline_validation_error(
+                                    ui,
+                                    &self.validation_errors,
+                                    format!("Larger than the {}x{} display!", self.target.width, self.target.height),
+                                    |err| {
+                                        let ValidationError::ImageLargerThanScreen(name, ..) = err else {
+                                            return false;
+                                        };
+
+                                        key.str_eq(name)
+                                    },
+                                    TEXT_WIDTH
+                                );
+
                                 inline_checkbox(ui, "Upscale:", &mut borrowed.upscale, TEXT_WIDTH, tracker);
+
+                                let source_size = (borrowed.image.width(), borrowed.image.height());
+                                conversion_options_ui(ui, &mut borrowed.conversion, source_size, TEXT_WIDTH, tracker);
+
+                                CollapsingHeader::new("Conversion Preview")
+                                    .id_salt(key.to_string())
+                                    .show(ui, |ui| {
+                                        inline_conversion_preview(ui, &mut borrowed, self.target.pixel_format, TEXT_WIDTH);
+                                    });
                             }, &mut self.tracker)
                         });
 
@@ -105,7 +160,7 @@ impl CharacterEditor {
 
                         ui.collapsing("Animations", |ui| {
                             pair_list_ui(ui, &mut self.animations, (), |ui, _, key, el, _, tracker| {
-                                animation_edit_ui(ui, key, el, &self.location, tracker, &self.validation_errors)
+                                animation_edit_ui(ui, key, el, &self.location, &self.target, tracker, &self.validation_errors)
                             }, &mut self.tracker)
                         });
 
@@ -162,6 +217,45 @@ pub fn sequence_edit_ui(
         CollapsingHeader::new("Frames")
             .id_salt(ui.id().with(key.0.as_ptr()))
             .show(ui, |ui| {
+                // Every frame keeps its own duration, unlike animations
+                if ui.button("Import Animated File").clicked()
+                    && let Some(picked) = rfd::FileDialog::new()
+                        .set_title("Pick animated file")
+                        .add_filter("Animation", ANIMATED_EXTENSIONS)
+                        .set_directory(location)
+                        .pick_file()
+                    && let Err(err) = import_sequence_frames(&picked, element, images, location, tracker) {
+                    eprintln!("Failed to import {}! {err}", picked.display());
+                }
+
+                CollapsingHeader::new("Import Sprite Sheet")
+                    .id_salt(ui.id().with("sheet_import"))
+                    .show(ui, |ui| {
+                        // The sheet isn't part of the character until its frames are added
+                        let id = ui.id().with("sheet");
+                        let mut sheet = ui.memory(|mem| mem.data.get_temp::<SpriteSheet>(id)).unwrap_or_default();
+                        let mut sheet_tracker = ChangeTracker::default();
+
+                        let regions = sheet_edit_ui(ui, &mut sheet, location, TEXT_WIDTH, &mut sheet_tracker);
+
+                        if sheet_tracker.changed() {
+                            ui.memory_mut(|mem| mem.data.insert_temp(id, sheet.clone()));
+                        }
+
+                        let Some(regions) = regions else {
+                            return;
+                        };
+
+                        ui.horizontal(|ui| {
+                            ui.add_space(TEXT_WIDTH + ui.style().spacing.item_spacing.x);
+
+                            if ui.button("Add Frames").clicked()
+                                && let Err(err) = import_sheet_frames(&sheet, &regions, element, images, location, tracker) {
+                                eprintln!("Failed to import {}! {err}", sheet.image.display());
+                            }
+                        });
+                    });
+
                 vec_ui(ui, &mut element.frames, images, |ui, index, frame, images, tracker| {
                     inline_image_resource_picker(
                         ui,
@@ -198,11 +292,86 @@ pub fn sequence_edit_ui(
 
 }
 
+/// Extracts frames of the file next to it and appends them as new images
+fn import_sequence_frames(
+    path: &Path,
+    element: &mut InterSequence,
+    images: &mut Vec<(SharedString, SharedLoadedImage)>,
+    location: &Path,
+    tracker: &mut ChangeTracker
+) -> anyhow::Result<()> {
+    let frames = decode_animated(&fs::read(path)?)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let folder = path.with_file_name(format!("{stem}_frames"));
+
+    for (frame, frame_path) in frames.iter().zip(extract_frames(&frames, &folder)?) {
+        let name = pick_unique_name(format!("{stem}_{}", element.frames.len() + 1), images);
+
+        images.push((name.clone(), Rc::new(RefCell::new(LoadedImage {
+            path: frame_path.strip_prefix(location).map(Path::to_path_buf).unwrap_or(frame_path),
+            width: frame.image.width(),
+            height: frame.image.height(),
+            image: DynamicImage::ImageRgba8(frame.image.clone()),
+            ..Default::default()
+        }))));
+
+        element.frames.push(InterSequenceFrame {
+            image: name,
+            duration: frame.duration_us,
+        });
+    }
+
+    tracker.mark_change();
+
+    Ok(())
+}
+
+/// Appends the regions as new images that crop the sheet, so it's sliced when the archive is built
+fn import_sheet_frames(
+    sheet: &SpriteSheet,
+    regions: &[SheetRegion],
+    element: &mut InterSequence,
+    images: &mut Vec<(SharedString, SharedLoadedImage)>,
+    location: &Path,
+    tracker: &mut ChangeTracker
+) -> anyhow::Result<()> {
+    let image = load_image(location.join(&sheet.image))?;
+    let stem = sheet.image.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+    let conversions = regions.iter()
+        .map(|region| region.crop_options(&ConversionOptions::default()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    for (region, conversion) in regions.iter().zip(conversions) {
+        let (width, height) = region.size();
+        let name = pick_unique_name(format!("{stem}_{}", element.frames.len() + 1), images);
+
+        images.push((name.clone(), Rc::new(RefCell::new(LoadedImage {
+            path: sheet.image.clone(),
+            image: image.clone(),
+            width,
+            height,
+            conversion,
+            ..Default::default()
+        }))));
+
+        element.frames.push(InterSequenceFrame {
+            image: name,
+            duration: region.duration_us,
+        });
+    }
+
+    tracker.mark_change();
+
+    Ok(())
+}
+
 pub fn animation_edit_ui(
     ui: &mut Ui,
     key: &mut SharedString,
     element: &mut Animation,
     location: &PathBuf,
+    target: &TargetProfile,
     tracker: &mut ChangeTracker,
     validations: &Vec<ValidationError>
 ) {
@@ -236,11 +405,38 @@ pub fn animation_edit_ui(
         TEXT_WIDTH
     );
 
+    inline_build_errors(
+        ui,
+        validations,
+        |resource| match resource {
+            Resource::Animation(name) => key.str_eq(name),
+            Resource::AnimationFrame { animation, .. } => key.str_eq(animation),
+            _ => false
+        },
+        TEXT_WIDTH
+    );
+
     inline_drag_value(ui, "X:", &mut element.x, TEXT_WIDTH, tracker);
     inline_drag_value(ui, "Y:", &mut element.y, TEXT_WIDTH, tracker);
     inline_drag_value(ui, "Width:", &mut element.width, TEXT_WIDTH, tracker);
     inline_drag_value(ui, "Height:", &mut element.height, TEXT_WIDTH, tracker);
 
+    inline_validation_error(
+        ui,
+        validations,
+        format!("Doesn't fit on the {}x{} display!", target.width, target.height),
+        |err| {
+            let ValidationError::AnimationOffScreen(name, ..) = err else {
+                return false;
+            };
+
+            key.str_eq(name)
+        },
+        TEXT_WIDTH
+    );
+
+    screen_preview(ui, element, target, TEXT_WIDTH);
+
     CollapsingHeader::new("Frames")
         .id_salt(key.0.as_ptr())
         .show(ui, |ui| {
@@ -259,8 +455,28 @@ pub fn animation_edit_ui(
                     element.frames = AnimationFrameSource::List(vec![]);
                     tracker.mark_change();
                 }
+
+                if ui.radio(element.frames.is_file(), "File").clicked() {
+                    element.frames = AnimationFrameSource::File {
+                        path: Default::default(),
+                        first: 0,
+                        last: None,
+                        count: 0,
+                    };
+                    tracker.mark_change();
+                }
+
+                if ui.radio(element.frames.is_sheet(), "Sprite Sheet").clicked() {
+                    element.frames = AnimationFrameSource::Sheet {
+                        sheet: Default::default(),
+                        count: 0,
+                    };
+                    tracker.mark_change();
+                }
             });
 
+            let mut file_fps = None;
+
             match &mut element.frames {
                 AnimationFrameSource::Indexed { folder, extension, count } => {
                     inline_folder_picker(ui, "Folder:", folder, location, TEXT_WIDTH, tracker);
@@ -311,6 +527,91 @@ pub fn animation_edit_ui(
 
                     ui.add_space(SPACING);
                 }
+
+                AnimationFrameSource::File { path, first, last, count } => {
+                    let picked = inline_file_picker(ui, "File:", path, ("Animation", ANIMATED_EXTENSIONS), location, TEXT_WIDTH, tracker);
+
+                    let id = ui.id().with("file_timings");
+                    let cached = ui.memory(|mem| mem.data.get_temp::<(PathBuf, Result<Vec<i64>, String>)>(id))
+                        .filter(|(cached_path, _)| cached_path == path);
+
+                    // Only frame timings are kept, decoding the file again is slow
+                    let timings = match cached {
+                        Some((_, timings)) => timings,
+                        None => {
+                            let timings = fs::read(location.join(&path))
+                                .map_err(anyhow::Error::from)
+                                .and_then(|data| decode_animated(&data))
+                                .map(|frames| frames.iter().map(|frame| frame.duration_us).collect::<Vec<_>>())
+                                .map_err(|err| err.to_string());
+
+                            ui.memory_mut(|mem| mem.data.insert_temp(id, (path.clone(), timings.clone())));
+                            timings
+                        }
+                    };
+
+                    let total = timings.as_ref().map(|timings| timings.len() as u32).unwrap_or_default();
+
+                    inline_drag_value(ui, "First Frame:", first, TEXT_WIDTH, tracker);
+
+                    let mut trimmed = last.is_some();
+                    inline_checkbox(ui, "Trim End:", &mut trimmed, TEXT_WIDTH, tracker);
+
+                    match (trimmed, last.as_mut()) {
+                        (true, None) => *last = Some(total.saturating_sub(1)),
+                        (false, Some(_)) => *last = None,
+                        (true, Some(last)) => {
+                            inline_drag_value(ui, "Last Frame:", last, TEXT_WIDTH, tracker);
+                        }
+                        (false, None) => {}
+                    }
+
+                    let end = last.map(|last| last + 1).unwrap_or(total).min(total);
+                    let new_count = end.saturating_sub(*first);
+
+                    if *count != new_count {
+                        *count = new_count;
+                        tracker.mark_change();
+                    }
+
+                    let frames = timings.as_ref()
+                        .map(|timings| timings[(*first as usize).min(end as usize)..end as usize].to_vec())
+                        .unwrap_or_default();
+
+                    match &timings {
+                        Err(err) => {
+                            ui.horizontal(|ui| {
+                                ui.add_space(TEXT_WIDTH + ui.style().spacing.item_spacing.x);
+                                ui.label(format!("File can't be decoded: {err}"));
+                            });
+                        }
+                        Ok(_) => {
+                            let summary = format!("{count} of {total} frames");
+                            file_fps = frame_rate_ui(ui, summary, &frames, "Use File FPS", picked, TEXT_WIDTH);
+                        }
+                    }
+
+                    ui.add_space(SPACING);
+                }
+
+                AnimationFrameSource::Sheet { sheet, count } => {
+                    if let Some(regions) = sheet_edit_ui(ui, sheet, location, TEXT_WIDTH, tracker) {
+                        if *count != regions.len() as u32 {
+                            *count = regions.len() as u32;
+                            tracker.mark_change();
+                        }
+
+                        let durations = regions.iter().map(|region| region.duration_us).collect::<Vec<_>>();
+                        file_fps = frame_rate_ui(ui, format!("{count} frames"), &durations, "Use Sheet FPS", false, TEXT_WIDTH);
+                    }
+
+                    ui.add_space(SPACING);
+                }
+            }
+
+            if let Some(fps) = file_fps {
+                element.fps = fps;
+                tracker.mark_change();
             }
         });
 
@@ -326,6 +627,417 @@ pub fn animation_edit_ui(
 
     inline_enum_edit(ui, "Mode:", &mut element.mode, TEXT_WIDTH, tracker);
     inline_checkbox(ui, "Upscale:", &mut element.upscale, TEXT_WIDTH, tracker);
+    inline_enum_edit(ui, "Frame Encoding:", &mut element.encoding, TEXT_WIDTH, tracker);
+    inline_checkbox(ui, "Deduplicate Frames:", &mut element.deduplicate_frames, TEXT_WIDTH, tracker);
+
+    // Frames are usually the size of the animation
+    conversion_options_ui(ui, &mut element.conversion, (element.width, element.height), TEXT_WIDTH, tracker);
+
+    storage_estimate_ui(ui, element, location, target, TEXT_WIDTH);
+}
+
+/// Frame rate of the frames with a button to use it, returns it when it should be used
+fn frame_rate_ui(ui: &mut Ui, summary: String, durations: &[i64], button: &str, use_now: bool, width: f32) -> Option<f64> {
+    let info = match constant_fps(durations) {
+        Some(fps) => format!("{summary} at {fps:.1} FPS"),
+        None => format!(
+            "{summary} with variable timings, {:.1} FPS on average. Import them into a sequence to keep the timings",
+            average_fps(durations)
+        )
+    };
+
+    ui.horizontal(|ui| {
+        ui.add_space(width + ui.style().spacing.item_spacing.x);
+        ui.label(info);
+    });
+
+    ui.horizontal(|ui| {
+        ui.add_space(width + ui.style().spacing.item_spacing.x);
+
+        let clicked = ui.button(button).clicked();
+        ((use_now || clicked) && !durations.is_empty()).then(|| constant_fps(durations).unwrap_or_else(|| average_fps(durations)))
+    }).inner
+}
+
+/// Sheet image and the layout cutting it, with a preview to pick regions on.
+/// Returns the picked regions once the sheet can be sliced
+fn sheet_edit_ui(ui: &mut Ui, sheet: &mut SpriteSheet, location: &Path, width: f32, tracker: &mut ChangeTracker) -> Option<Vec<SheetRegion>> {
+    inline_file_picker(ui, "Sheet:", &mut sheet.image, ("Image", IMAGE_EXTENSIONS), location, width, tracker);
+
+    ui.horizontal(|ui| {
+        inline_style_label(ui, "Layout:", width);
+
+        if ui.radio(sheet.layout.is_grid(), "Grid").clicked() && !sheet.layout.is_grid() {
+            sheet.layout = SheetLayout::default();
+            sheet.frames.clear();
+            tracker.mark_change();
+        }
+
+        if ui.radio(sheet.layout.is_atlas(), "Atlas").clicked() && !sheet.layout.is_atlas() {
+            sheet.layout = SheetLayout::Atlas(PathBuf::new());
+            sheet.frames.clear();
+            tracker.mark_change();
+        }
+    });
+
+    match &mut sheet.layout {
+        SheetLayout::Grid { columns, rows, margin_x, margin_y, spacing_x, spacing_y } => {
+            inline_drag_value(ui, "Columns:", columns, width, tracker);
+            inline_drag_value(ui, "Rows:", rows, width, tracker);
+            inline_drag_value(ui, "Margin X:", margin_x, width, tracker);
+            inline_drag_value(ui, "Margin Y:", margin_y, width, tracker);
+            inline_drag_value(ui, "Spacing X:", spacing_x, width, tracker);
+            inline_drag_value(ui, "Spacing Y:", spacing_y, width, tracker);
+        }
+
+        SheetLayout::Atlas(path) => {
+            // Atlases name the image they were exported with, next to them
+            if inline_file_picker(ui, "Atlas:", path, ("Atlas", ATLAS_EXTENSIONS), location, width, tracker)
+                && let Ok(atlas) = fs::read_to_string(location.join(&path))
+                    .map_err(anyhow::Error::from)
+                    .and_then(|json| parse_atlas(&json))
+                && let Some(image) = atlas.image {
+                sheet.image = path.with_file_name(image);
+            }
+        }
+    }
+
+    let info = |ui: &mut Ui, text: String| {
+        ui.horizontal(|ui| {
+            ui.add_space(width + ui.style().spacing.item_spacing.x);
+            ui.label(text);
+        });
+    };
+
+    if sheet.image.as_os_str().is_empty() {
+        return None;
+    }
+
+    let id = ui.id().with("sheet_image");
+    let cached = ui.memory(|mem| mem.data.get_temp::<(PathBuf, Result<(TextureHandle, (u32, u32)), String>)>(id))
+        .filter(|(cached_path, _)| *cached_path == sheet.image);
+
+    let loaded = match cached {
+        Some((_, loaded)) => loaded,
+        None => {
+            let loaded = load_image(location.join(&sheet.image))
+                .map(|image| {
+                    let image = image.to_rgba8();
+                    let texture = ui.ctx().load_texture(
+                        sheet.image.to_string_lossy(),
+                        ColorImage::from_rgba_unmultiplied([image.width() as _, image.height() as _], image.as_raw()),
+                        TextureOptions::NEAREST
+                    );
+
+                    (texture, image.dimensions())
+                })
+                .map_err(|err| err.to_string());
+
+            ui.memory_mut(|mem| mem.data.insert_temp(id, (sheet.image.clone(), loaded.clone())));
+            loaded
+        }
+    };
+
+    let (texture, size) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            info(ui, format!("Sheet can't be loaded: {err}"));
+            return None;
+        }
+    };
+
+    // Atlas files are read again only when the layout changes
+    let id = ui.id().with("sheet_atlas");
+    let cached = ui.memory(|mem| mem.data.get_temp::<(SheetLayout, (u32, u32), Result<Atlas, String>)>(id))
+        .filter(|(layout, cached_size, _)| *layout == sheet.layout && *cached_size == size);
+
+    let atlas = match cached {
+        Some((_, _, atlas)) => atlas,
+        None => {
+            let atlas = sheet.atlas(location, size).map_err(|err| err.to_string());
+            ui.memory_mut(|mem| mem.data.insert_temp(id, (sheet.layout.clone(), size, atlas.clone())));
+            atlas
+        }
+    };
+
+    let atlas = match atlas {
+        Ok(atlas) => atlas,
+        Err(err) => {
+            info(ui, format!("Sheet can't be sliced: {err}"));
+            return None;
+        }
+    };
+
+    if !atlas.tags.is_empty() {
+        ui.horizontal_wrapped(|ui| {
+            inline_style_label(ui, "Tags:", width);
+
+            for (name, frames) in &atlas.tags {
+                if ui.button(name).clicked() {
+                    sheet.frames = frames.clone();
+                    tracker.mark_change();
+                }
+            }
+        });
+    }
+
+    ui.horizontal(|ui| {
+        ui.add_space(width + ui.style().spacing.item_spacing.x);
+
+        if ui.button("Use All").clicked() && !sheet.frames.is_empty() {
+            sheet.frames.clear();
+            tracker.mark_change();
+        }
+
+        if sheet.frames.is_empty() {
+            ui.label(format!("All {} regions, click regions to pick frames", atlas.regions.len()));
+        } else {
+            ui.label(format!("{} of {} regions", sheet.frames.len(), atlas.regions.len()));
+        }
+    });
+
+    sheet_preview_ui(ui, &texture, size, &atlas.regions, &mut sheet.frames, width, tracker);
+
+    match sheet.selected(&atlas.regions) {
+        Ok(regions) => Some(regions),
+        Err(err) => {
+            info(ui, err.to_string());
+            None
+        }
+    }
+}
+
+/// Sheet with its regions drawn over it, numbered in the order they are played.
+/// Clicking a region adds it as the last frame, or removes it if it's picked already
+fn sheet_preview_ui(
+    ui: &mut Ui,
+    texture: &TextureHandle,
+    (sheet_width, sheet_height): (u32, u32),
+    regions: &[SheetRegion],
+    frames: &mut Vec<u32>,
+    indent: f32,
+    tracker: &mut ChangeTracker
+) {
+    const PREVIEW_WIDTH: f32 = 320.0;
+    const PREVIEW_HEIGHT: f32 = 480.0;
+
+    let scale = (PREVIEW_WIDTH / sheet_width.max(1) as f32).min(PREVIEW_HEIGHT / sheet_height.max(1) as f32);
+
+    ui.horizontal(|ui| {
+        ui.add_space(indent + ui.style().spacing.item_spacing.x);
+
+        let (resp, painter) = ui.allocate_painter(
+            vec2(sheet_width as f32, sheet_height as f32) * scale,
+            Sense::click()
+        );
+        let sheet = resp.rect;
+
+        let region_rect = |region: &SheetRegion| Rect::from_min_size(
+            sheet.min + vec2(region.rect.x as f32, region.rect.y as f32) * scale,
+            vec2(region.rect.width as f32, region.rect.height as f32) * scale
+        );
+
+        painter.rect_filled(sheet, 0, Color32::BLACK);
+        painter.image(texture.id(), sheet, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), Color32::WHITE);
+
+        for (index, region) in regions.iter().enumerate() {
+            let rect = region_rect(region);
+
+            // Regions used more than once show where they are first played
+            let order = if frames.is_empty() {
+                Some(index)
+            } else {
+                frames.iter().position(|&frame| frame as usize == index)
+            };
+
+            match order {
+                Some(order) => {
+                    painter.rect_filled(rect, 0, Color32::LIGHT_BLUE.gamma_multiply(0.3));
+                    painter.rect_stroke(rect, 0, Stroke::new(1.0, Color32::LIGHT_BLUE), StrokeKind::Inside);
+                    painter.text(rect.left_top() + vec2(2.0, 1.0), Align2::LEFT_TOP, format!("{}", order + 1), FontId::monospace(10.0), Color32::WHITE);
+                }
+                None => {
+                    painter.rect_stroke(rect, 0, Stroke::new(1.0, Color32::GRAY), StrokeKind::Inside);
+                }
+            }
+        }
+
+        let hovered = resp.hover_pos()
+            .and_then(|pos| regions.iter().position(|region| region_rect(region).contains(pos)));
+
+        let Some(index) = hovered else {
+            return;
+        };
+
+        if resp.clicked() {
+            match frames.iter().position(|&frame| frame as usize == index) {
+                Some(position) => {
+                    frames.remove(position);
+                }
+                None => frames.push(index as u32)
+            }
+
+            tracker.mark_change();
+        }
+
+        let region = &regions[index];
+        resp.on_hover_text_at_pointer(format!(
+            "{}: {}x{} at {}, {}",
+            region.name, region.rect.width, region.rect.height, region.rect.x, region.rect.y
+        ));
+    });
+}
+
+/// Encoding every frame takes a while, so it's only done on request and kept until the animation changes
+fn storage_estimate_ui(ui: &mut Ui, element: &Animation, location: &PathBuf, target: &TargetProfile, width: f32) {
+    let id = ui.id().with("storage_estimate");
+    let key = format!("{}{}{}", serde_json::to_string(element).unwrap_or_default(), target.pixel_format, target.format_version);
+
+    let mut estimate = ui.memory(|mem| mem.data.get_temp::<(String, Result<AnimationStorage, String>)>(id))
+        .filter(|(estimate_key, _)| *estimate_key == key)
+        .map(|(_, estimate)| estimate);
+
+    ui.horizontal(|ui| {
+        inline_style_label(ui, "Storage:", width);
+
+        if ui.button("Estimate").clicked() {
+            let result = animation_storage(element, location, target).map_err(|err| err.to_string());
+            ui.memory_mut(|mem| mem.data.insert_temp(id, (key.clone(), result.clone())));
+            estimate = Some(result);
+        }
+    });
+
+    let lines = match estimate {
+        None => return,
+        Some(Err(err)) => vec![format!("Frames can't be encoded: {err}")],
+        Some(Ok(storage)) => {
+            let stored = storage.stored_size(element.encoding);
+            let saved = storage.full_size as i64 - storage.delta_size as i64;
+
+            let mut lines = vec![
+                format!("{} frames, {stored} bytes", storage.frame_count),
+                format!(
+                    "Delta frames save {saved} bytes ({:.0}%)",
+                    saved as f64 * 100.0 / storage.full_size.max(1) as f64
+                ),
+                format!("SD card reads {:.1} KB/s at {} FPS", storage.bandwidth(element.encoding, element.fps) / 1024.0, element.fps),
+            ];
+
+            if element.encoding == FrameEncoding::Delta && target.format_version < DELTA_FRAMES_FORMAT_VERSION {
+                lines.push(format!("Format version {} stores whole frames", target.format_version));
+            }
+
+            lines
+        }
+    };
+
+    for line in lines {
+        ui.horizontal(|ui| {
+            ui.add_space(width + ui.style().spacing.item_spacing.x);
+            ui.label(line);
+        });
+    }
+}
+
+/// Source size is what a new crop rectangle covers
+fn conversion_options_ui(ui: &mut Ui, options: &mut ConversionOptions, source_size: (u32, u32), width: f32, tracker: &mut ChangeTracker) {
+    inline_enum_edit(ui, "Fit:", &mut options.fit, width, tracker);
+
+    if options.fit != FitMode::Stretch {
+        inline_enum_edit(ui, "Anchor:", &mut options.anchor, width, tracker);
+        inline_drag_value(ui, "Offset X:", &mut options.offset_x, width, tracker);
+        inline_drag_value(ui, "Offset Y:", &mut options.offset_y, width, tracker);
+    }
+
+    if options.fit != FitMode::None {
+        inline_enum_edit(ui, "Pixel Art:", &mut options.pixel_art, width, tracker);
+        inline_enum_edit(ui, "Filter:", &mut options.filter, width, tracker);
+    }
+
+    let mut cropped = options.crop.is_some();
+    inline_checkbox(ui, "Crop:", &mut cropped, width, tracker);
+
+    match (cropped, &mut options.crop) {
+        (true, None) => {
+            options.crop = Some(CropRect {
+                width: source_size.0,
+                height: source_size.1,
+                ..Default::default()
+            });
+        }
+        (false, Some(_)) => {
+            options.crop = None;
+            options.trim = None;
+        }
+        (true, Some(crop)) => {
+            inline_drag_value(ui, "Crop X:", &mut crop.x, width, tracker);
+            inline_drag_value(ui, "Crop Y:", &mut crop.y, width, tracker);
+            inline_drag_value(ui, "Crop Width:", &mut crop.width, width, tracker);
+            inline_drag_value(ui, "Crop Height:", &mut crop.height, width, tracker);
+        }
+        (false, None) => {}
+    }
+
+    inline_enum_edit(ui, "Quantization:", &mut options.quantization, width, tracker);
+    inline_checkbox(ui, "Gamma Aware:", &mut options.gamma_aware, width, tracker);
+
+    inline_enum_edit(ui, "Alpha:", &mut options.alpha, width, tracker);
+
+    match options.alpha {
+        AlphaMode::Opaque => {}
+        AlphaMode::ColorKey => {
+            inline_color_edit_rgb_tuple(ui, "Color Key:", &mut options.color_key, width, tracker);
+        }
+        AlphaMode::Channel => {
+            inline_checkbox(ui, "Premultiplied:", &mut options.premultiplied, width, tracker);
+        }
+    }
+
+    inline_enum_edit(ui, "Background:", &mut options.background, width, tracker);
+
+    if options.background == Background::Color {
+        inline_color_edit_rgb_tuple(ui, "Background Color:", &mut options.background_color, width, tracker);
+    }
+
+    inline_enum_edit(ui, "Compression:", &mut options.compression, width, tracker);
+}
+
+/// Display of the target scaled down, with the area the animation is drawn to
+fn screen_preview(ui: &mut Ui, element: &Animation, target: &TargetProfile, indent: f32) {
+    const PREVIEW_HEIGHT: f32 = 120.0;
+
+    let scale = PREVIEW_HEIGHT / target.height as f32;
+
+    ui.horizontal(|ui| {
+        ui.add_space(indent + ui.style().spacing.item_spacing.x);
+
+        let (resp, painter) = ui.allocate_painter(
+            vec2(target.width as f32 * scale, PREVIEW_HEIGHT),
+            Sense::hover()
+        );
+        let screen = resp.rect;
+
+        let animation = Rect::from_min_size(
+            screen.min + vec2(element.x as f32, element.y as f32) * scale,
+            vec2(element.width as f32, element.height as f32) * scale
+        );
+
+        let color = if screen.contains_rect(animation) {
+            Color32::LIGHT_BLUE
+        } else {
+            Color32::RED
+        };
+
+        painter.rect_filled(screen, 0, Color32::BLACK);
+        painter.rect_filled(animation.intersect(screen), 0, color.gamma_multiply(0.4));
+        painter.rect_stroke(animation, 0, Stroke::new(1.0, color), StrokeKind::Inside);
+        painter.rect_stroke(screen, 0, Stroke::new(1.0, Color32::GRAY), StrokeKind::Outside);
+
+        resp.on_hover_text(format!(
+            "{}x{} at {}, {} on the {}x{} display of {}",
+            element.width, element.height, element.x, element.y, target.width, target.height, target.name
+        ));
+    });
 }
 
 pub fn action_edit_ui(
@@ -366,6 +1078,19 @@ pub fn action_edit_ui(
         TEXT_WIDTH
     );
 
+    inline_build_errors(
+        ui,
+        validations,
+        |resource| {
+            let Resource::Action(name) = resource else {
+                return false;
+            };
+
+            key == name
+        },
+        TEXT_WIDTH
+    );
+
     inline_text_edit(ui, "Display Name:", &mut element.display, TEXT_WIDTH, tracker);
 
     ui.horizontal(|ui| {
@@ -1,45 +1,39 @@
-use crate::character::repr::{Animation, SequenceMode};
+use crate::character::repr::Character;
+use crate::character::sim::allocator::Allocation;
+use crate::character::sim::{SimMemory, Simulator, StateSwitchInfo, TICK_INTERVAL_US};
 use crate::character::util::AsRichText;
-use crate::gui::app::editor::intermediate::{InterAction, InterActionType, InterSequence, InterStateImage, SharedInterState, SharedLoadedImage};
+use crate::gui::app::editor::intermediate::{InterStateImage, SharedInterState};
 use crate::gui::app::editor::nodes::{StateNode, WIRE_COLOR};
-use crate::gui::app::editor::validation::ValidationError;
+use crate::character::validation::ValidationError;
 use crate::gui::app::shared::SharedString;
 use crate::gui::app::util::SPACING;
+use crate::target::TargetProfile;
 use eframe::epaint::{Shape, Stroke};
-use egui::{vec2, Align2, CentralPanel, Color32, FontId, Frame, Id, Painter, Rect, ScrollArea, Sense, SidePanel, Style, TopBottomPanel, Ui};
+use egui::{vec2, Align2, CentralPanel, Color32, DragValue, FontId, Frame, Id, Painter, Rect, ScrollArea, Sense, SidePanel, Style, TextEdit, TopBottomPanel, Ui};
 use egui_snarl::ui::{
     BackgroundPattern, PinInfo, PinResponse, SnarlPin, SnarlStyle, SnarlViewer, SnarlWidget,
 };
 use egui_snarl::{InPin, InPinId, NodeId, OutPin, OutPinId, Snarl};
 use num_format::{Locale, ToFormattedString};
-use std::cmp::{Ordering, PartialEq};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::num::ParseIntError;
 use std::ops::Deref;
-use std::rc::{Rc, Weak};
-use strum::EnumIs;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Character to start the session with is only needed while the session is closed
 pub fn simulator_ui(
     ui: &mut Ui,
     simulator_state: &mut Option<SimulatorState>,
-    images: &Vec<(SharedString, SharedLoadedImage)>,
-    sequences: &Vec<(SharedString, InterSequence)>,
-    animations: &Vec<(SharedString, Animation)>,
-    states: &Vec<(SharedString, SharedInterState)>,
+    character: Option<Character>,
     state_graph: &mut Snarl<(SharedString, SharedInterState)>,
     graph_style: SnarlStyle,
-    actions: &Vec<(String, InterAction)>,
-    default_state: &SharedString,
     validations: &Vec<ValidationError>,
+    target: &TargetProfile,
 ) {
     if let Some(state) = simulator_state  {
-        let exit_requested = Simulator {
+        let exit_requested = SimulatorView {
             sim_state: state,
-            images,
-            sequences,
-            animations,
-            actions,
-            states,
             snarl: state_graph,
             graph_style,
         }.show_ui(ui);
@@ -56,32 +50,11 @@ pub fn simulator_ui(
                 !validations.contains(&ValidationError::InvalidDefaultState),
                 |ui| {
                     if ui.button("Start Simulator").clicked() {
-                        let Some((_, default_state_info)) =
-                            states.iter().find(|(k, _)| k == default_state) else {
-                            eprintln!("Default state is not found??");
+                        let Some(character) = character else {
                             return;
                         };
 
-                        let borrowed_state = default_state_info.borrow();
-
-                        *simulator_state = Some(SimulatorState {
-                            status: Default::default(),
-                            current_layer: borrowed_state.layer,
-                            current_state: default_state.clone(),
-                            next_state: None,
-                            possible_transitions: vec![],
-                            possible_actions: vec![],
-                            current_image: None,
-                            new_layer_images: Default::default(),
-                            new_layer_animations: Default::default(),
-                            loaded_layer_images: Default::default(),
-                            loaded_layer_animations: Default::default(),
-                            layer_images_to_remove: Default::default(),
-                            layer_animations_to_remove: Default::default(),
-                            loaded_images: vec![],
-                            prepared_images: vec![],
-                            allocator: AllocatorState::default(),
-                        })
+                        *simulator_state = Some(SimulatorState::new(character, target))
                     }
                 },
             );
@@ -89,121 +62,210 @@ pub fn simulator_ui(
     }
 }
 
-pub struct Simulator<'a> {
+pub struct SimulatorState {
+    pub sim: Simulator,
+    /// Seed for the next restart
+    pub seed: u64,
+    /// Comma separated rolls forced on the next restart
+    pub forced_rolls: String,
+}
+
+impl SimulatorState {
+    pub fn new(character: Character, target: &TargetProfile) -> Self {
+        Self {
+            sim: Simulator::with_target(character, 0, target),
+            seed: 0,
+            forced_rolls: String::new(),
+        }
+    }
+
+    fn parse_forced_rolls(&self) -> Result<Vec<u32>, ParseIntError> {
+        self.forced_rolls.split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    /// Starts over with the same character, seed and forced rolls
+    fn restart(&mut self) {
+        let Ok(rolls) = self.parse_forced_rolls() else {
+            return;
+        };
+
+        self.sim = Simulator::with_target(self.sim.character().clone(), self.seed, self.sim.target());
+        self.sim.force_rolls(rolls);
+    }
+
+    /// Starts over with a fresh seed
+    fn reroll(&mut self) {
+        self.seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+        self.restart();
+    }
+}
+
+pub struct SimulatorView<'a> {
     pub sim_state: &'a mut SimulatorState,
-    pub images: &'a Vec<(SharedString, SharedLoadedImage)>,
-    pub sequences: &'a Vec<(SharedString, InterSequence)>,
-    pub animations: &'a Vec<(SharedString, Animation)>,
-    pub actions: &'a Vec<(String, InterAction)>,
-    pub states: &'a Vec<(SharedString, SharedInterState)>,
     pub snarl: &'a mut Snarl<(SharedString, SharedInterState)>,
     pub graph_style: SnarlStyle,
 }
 
-impl Simulator<'_> {
+impl SimulatorView<'_> {
     pub fn show_ui(&mut self, ui: &mut Ui) -> bool {
         let mut exit_requested = false;
+        let sim_state = &mut *self.sim_state;
 
-        if self.sim_state.status.is_uninitialized() {
-            if let None = self.preload() {
-                self.sim_state.error("Out of memory during preload!");
-                return exit_requested;
-            }
+        TopBottomPanel::top("simulator.header")
+            .resizable(false)
+            .show(ui.ctx(), |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Close Session").clicked() {
+                        exit_requested = true;
+                    }
 
-            let current = self.sim_state.current_state.clone();
-            if !self.schedule_or_switch(&current) {
-                self.sim_state
-                    .error("Out of memory trying to load default state!");
-                return exit_requested;
-            }
+                    ui.separator();
 
-            self.switch_to_scheduled();
+                    ui.label("Seed:");
+                    ui.add(DragValue::new(&mut sim_state.seed));
+                    ui.label("Forced rolls:");
+                    ui.add(TextEdit::singleline(&mut sim_state.forced_rolls)
+                        .hint_text("e.g. 3,0,1")
+                        .desired_width(120.0));
 
-            self.sim_state.status = SimulatorStatus::Loaded;
+                    let rolls_valid = sim_state.parse_forced_rolls().is_ok();
+
+                    ui.add_enabled_ui(rolls_valid, |ui| {
+                        if ui.button("Restart").on_hover_text("Start over with the seed and forced rolls").clicked() {
+                            sim_state.restart();
+                        }
+
+                        if ui.button("Reroll").on_hover_text("Start over with a new seed").clicked() {
+                            sim_state.reroll();
+                        }
+                    });
+
+                    if !rolls_valid {
+                        ui.label("Rolls must be comma separated numbers".rich().color(Color32::RED));
+                    }
+                });
+
+                visualize_allocator(ui, sim_state.sim.memory());
+            });
+
+        let sim = &mut sim_state.sim;
+
+        if sim.is_crashed() {
+            let error = sim.failures()
+                .last()
+                .map(|(_, failure)| failure.to_string())
+                .unwrap_or_default();
+
+            CentralPanel::default()
+                .show(ui.ctx(), |ui| {
+                    ui.heading(format!("Error: {error}").rich().color(Color32::RED));
+                });
+
+            return exit_requested;
         }
 
-        TopBottomPanel::top("simulator.header")
+        SidePanel::left("simulator.traversal")
             .resizable(false)
             .show(ui.ctx(), |ui| {
-                if ui.button("Close Session").clicked() {
-                    exit_requested = true;
-                }
+                ui.label(format!("Time: {:.3}s", sim.now() as f64 / 1_000_000.0));
+                ui.label(format!("Current State: {}", sim.current_state()));
+                ui.label(format!("Current Layer: {}", sim.current_layer()));
+
+                ui.horizontal(|ui| {
+                    for (label, duration) in [("+50ms", TICK_INTERVAL_US), ("+1s", 1_000_000), ("+10s", 10_000_000)] {
+                        if ui.button(label).clicked() {
+                            sim.advance(duration);
+                        }
+                    }
 
-                visualize_allocator(ui, &self.sim_state);
-            });
+                    if ui.button("Click").clicked() {
+                        sim.click();
+                    }
+                });
 
-        match &self.sim_state.status {
-            SimulatorStatus::Error(err) => {
-                CentralPanel::default()
-                    .show(ui.ctx(), |ui| {
-                        ui.heading(format!("Error: {err}").rich().color(Color32::RED));
-                    });
-            }
+                if let Some(task) = sim.task() {
+                    ui.add_space(SPACING);
 
-            SimulatorStatus::Loaded => {
-                SidePanel::left("simulator.traversal")
-                    .resizable(false)
-                    .show(ui.ctx(), |ui| {
-                        ui.label(format!("Current State: {}", self.sim_state.current_state));
-                        ui.label(format!("Current Layer: {}", self.sim_state.current_layer));
+                    ui.label(format!("Next State: {}", task.state));
 
-                        if let Some(next) = &self.sim_state.next_state {
-                            ui.add_space(SPACING);
+                    if ui.button("Switch to next state").clicked() {
+                        sim.complete_task();
+                    }
+                }
 
-                            ui.label(format!("Next State: {}", next));
+                for (_, failure) in sim.failures() {
+                    ui.label(failure.to_string().rich().color(Color32::RED));
+                }
 
-                            if ui.button("Switch to next state").clicked() {
-                                self.switch_to_scheduled();
-                            }
-                        }
+                ui.add_space(SPACING);
 
-                        ui.add_space(SPACING);
+                ui.horizontal(|ui| {
+                    ui.label(format!("Random Rolls (seed {}):", sim.seed()));
 
-                        ui.add_enabled_ui(self.sim_state.next_state.is_none(), |ui| {
-                            if let Some(next_state) = Self::next_state_ui(
-                                ui,
-                                "Possible Transitions:",
-                                &self.sim_state.possible_transitions,
-                            ) {
-                                if !self.schedule_or_switch(&next_state) {
-                                    self.sim_state.error("Out of memory trying to cook state!")
-                                }
-                            }
-
-                            if let Some(next_state) = Self::next_state_ui(
-                                ui,
-                                "Possible Action Switches:",
-                                &self.sim_state.possible_actions,
-                            ) {
-                                if !self.schedule_or_switch(&next_state) {
-                                    self.sim_state.error("Out of memory trying to cook state!")
-                                }
-                            }
-                        });
-                    });
+                    if ui.button("Copy").on_hover_text("Copy the rolls to reproduce this run with any seed").clicked() {
+                        let rolls = sim.rolls()
+                            .map(|(_, roll)| roll.value.to_string())
+                            .collect::<Vec<_>>();
 
-                CentralPanel::default()
-                    .show(ui.ctx(), |ui| {
-                        SnarlWidget::new()
-                            .id(Id::new("simulator.graph"))
-                            .style(self.graph_style)
-                            .show(&mut self.snarl, &mut SimulatorNodeViewer {
-                                current_state: &self.sim_state.current_state,
-                                next_state: self.sim_state.next_state.as_ref(),
-                            }, ui);
+                        ui.ctx().copy_text(rolls.join(","));
+                    }
+                });
+                ScrollArea::vertical()
+                    .id_salt("simulator.rolls")
+                    .max_height(100.0)
+                    .show(ui, |ui| {
+                        for (time, roll) in sim.rolls() {
+                            ui.label(format!("[{:.3}s] {roll}", time as f64 / 1_000_000.0));
+                        }
                     });
-            }
-            _ => {}
-        }
+
+                ui.add_space(SPACING);
+
+                ui.add_enabled_ui(sim.task().is_none(), |ui| {
+                    if let Some(next_state) = Self::next_state_ui(
+                        ui,
+                        "Possible Transitions:",
+                        &sim.possible_transitions(),
+                    ) {
+                        sim.request_switch(&next_state);
+                    }
+
+                    if let Some(next_state) = Self::next_state_ui(
+                        ui,
+                        "Possible Action Switches:",
+                        &sim.possible_actions(),
+                    ) {
+                        sim.request_switch(&next_state);
+                    }
+                });
+            });
+
+        CentralPanel::default()
+            .show(ui.ctx(), |ui| {
+                SnarlWidget::new()
+                    .id(Id::new("simulator.graph"))
+                    .style(self.graph_style)
+                    .show(self.snarl, &mut SimulatorNodeViewer {
+                        current_state: sim.current_state(),
+                        next_state: sim.task().map(|task| task.state.as_str()),
+                    }, ui);
+            });
 
         exit_requested
     }
 
-    fn next_state_ui<'a>(
+    fn next_state_ui(
         ui: &mut Ui,
         label: impl Display,
         transitions: &Vec<StateSwitchInfo>,
-    ) -> Option<SharedString> {
+    ) -> Option<String> {
         let label = label.to_string();
 
         ui.label(&label);
@@ -238,569 +300,11 @@ impl Simulator<'_> {
         )
         .inner
     }
-
-    pub fn is_dynamic_or_layer_switch(&self, state: &SharedString) -> Option<(bool, bool)> {
-        let Some((_, state)) = self.states.iter().find(|(k, _)| k == state) else {
-            return None;
-        };
-
-        let state = state.borrow();
-
-        let is_layer_switch = state.layer != self.sim_state.current_layer;
-
-        let is_dynamic = match &state.image {
-            InterStateImage::None => false,
-            InterStateImage::Single { layer_load, .. } => !*layer_load,
-            InterStateImage::Animation {
-                layer_load, animation, ..
-            } => {
-                if *layer_load {
-                    false
-                } else {
-                    let Some((_, animation)) = self.animations.iter().find(|(k, _)| k == animation)
-                    else {
-                        return None;
-                    };
-
-                    animation.mode.is_from_ram()
-                }
-            }
-            InterStateImage::Sequence { layer_load, .. } => !*layer_load,
-        };
-
-        Some((is_dynamic, is_layer_switch))
-    }
-
-    pub fn cook_state(&mut self, state: &SharedString) -> bool {
-        let Some((name, state)) = self.states.iter().find(|(k, _)| k == state) else {
-            return true;
-        };
-
-        let state = state.borrow();
-
-        self.sim_state.next_state = Some(name.clone());
-        self.sim_state.prepared_images.clear();
-
-        match &state.image {
-            InterStateImage::None => {}
-            InterStateImage::Single { image, layer_load } => {
-                if *layer_load {
-                    return true;
-                }
-
-                let Some((_, image)) = self.images.iter().find(|(k, _)| k == image) else {
-                    return true;
-                };
-
-                let image = image.borrow();
-
-                let size = calc_required_space(image.width, image.height, image.upscale);
-
-                let Some(allocation) = self.sim_state.allocator.allocate(size) else {
-                    return false;
-                };
-
-                self.sim_state.prepared_images.push(allocation)
-            }
-            InterStateImage::Animation {
-                animation, layer_load, ..
-            } => {
-                if *layer_load {
-                    return true;
-                }
-
-                let Some((_, animation)) = self.animations.iter().find(|(k, _)| k == animation)
-                else {
-                    return true;
-                };
-
-                if animation.mode.is_from_sd_card() {
-                    return true;
-                }
-
-                let size = calc_required_space(
-                    animation.width,
-                    animation.height,
-                    animation.upscale,
-                );
-
-                for _ in 0..animation.frames.count() {
-                    let Some(allocation) = self.sim_state.allocator.allocate(size) else {
-                        return false;
-                    };
-
-                    self.sim_state.prepared_images.push(allocation)
-                }
-            }
-            InterStateImage::Sequence { layer_load, mode, sequence } => {
-                if *layer_load {
-                    return true;
-                }
-
-                let Some((_, sequence)) = self.sequences.iter()
-                    .find(|(k, _)| k == sequence) else {
-                    return true;
-                };
-
-                match mode {
-                    SequenceMode::LoadAll => {
-                        for frame in &sequence.frames {
-                            let Some((_, image)) = self.images.iter()
-                                .find(|(k, _)| k == &frame.image)
-                            else {
-                                continue;
-                            };
-
-                            let image = image.borrow();
-
-                            let size = calc_required_space(
-                                image.width,
-                                image.height,
-                                image.upscale,
-                            );
-
-                            let Some(allocation) = self.sim_state.allocator.allocate(size) else {
-                                return false;
-                            };
-
-                            self.sim_state.prepared_images.push(allocation)
-                        }
-                    }
-                    SequenceMode::LoadEach => {
-                        let mut largest_size = 0_u64;
-
-                        for frame in &sequence.frames {
-                            let Some((_, image)) = self.images.iter().find(|(k, _)| k == &frame.image)
-                            else {
-                                continue;
-                            };
-
-                            let image = image.borrow();
-
-                            let size = calc_required_space(
-                                image.width,
-                                image.height,
-                                image.upscale,
-                            );
-
-                            if largest_size < size {
-                                largest_size = size
-                            }
-                        }
-
-                        for _ in 0..2 {
-                            let Some(allocation) = self.sim_state.allocator.allocate(largest_size)
-                            else {
-                                return false;
-                            };
-
-                            self.sim_state.prepared_images.push(allocation)
-                        }
-                    }
-                }
-            },
-        }
-
-        true
-    }
-
-    pub fn prepare_layer(&mut self, new_layer: u8) -> bool {
-        self.sim_state.current_layer = new_layer;
-
-        let mut new_layer_images = HashSet::<SharedString>::new();
-        let mut new_layer_anims = HashSet::<SharedString>::new();
-
-        // Layer discovery
-        for (_, state) in self.states {
-            let borrowed_state = state.borrow();
-
-            if borrowed_state.layer != new_layer {
-                continue;
-            }
-
-            match &borrowed_state.image {
-                InterStateImage::Single { layer_load, image } => {
-                    if *layer_load {
-                        new_layer_images.insert(image.clone());
-                    }
-                }
-                InterStateImage::Animation { animation, layer_load, .. } => {
-                    if *layer_load {
-                        new_layer_anims.insert(animation.clone());
-                    }
-                }
-                InterStateImage::Sequence { sequence, layer_load, .. } => {
-                    if *layer_load {
-                        let Some((_, sequence)) = self.sequences.iter()
-                            .find(|(k, _)| k == sequence)
-                        else {
-                            continue
-                        };
-
-                        for frame in &sequence.frames {
-                            new_layer_images.insert(frame.image.clone());
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-
-        // Write down unneeded images and animations
-        self.sim_state.layer_images_to_remove = self.sim_state.loaded_layer_images.iter()
-            .filter_map(|(k, _)| {
-                if !new_layer_images.contains(k) {
-                    return Some(k.clone())
-                }
-                None
-            })
-            .collect();
-        self.sim_state.layer_animations_to_remove = self.sim_state.loaded_layer_animations.iter()
-            .filter_map(|(k, _)| {
-                if !new_layer_anims.contains(k) {
-                    return Some(k.clone())
-                }
-                None
-            })
-            .collect();
-
-        // Load missing images and animations
-        for image_name in new_layer_images {
-            if !self.sim_state.loaded_layer_images.contains_key(&image_name) {
-                let Some((_, image)) = self.images.iter().find(|(k, _)| k == &image_name) else {
-                    continue
-                };
-
-                let image = image.borrow();
-
-                let size = calc_required_space(image.width, image.height, image.upscale);
-
-                let Some(allocation) = self.sim_state.allocator.allocate(size) else {
-                    return false;
-                };
-
-                self.sim_state.new_layer_images.insert(image_name, allocation);
-            }
-        }
-
-        for anim_name in new_layer_anims {
-            if !self.sim_state.loaded_layer_animations.contains_key(&anim_name) {
-                let Some((_, animation)) = self.animations.iter().find(|(k, _)| k == &anim_name)
-                else {
-                    return true;
-                };
-
-                let size = calc_required_space(
-                    animation.width,
-                    animation.height,
-                    animation.upscale,
-                );
-
-                let mut frames = vec![];
-                for _ in 0..animation.frames.count() {
-                    let Some(allocation) = self.sim_state.allocator.allocate(size) else {
-                        return false;
-                    };
-
-                    frames.push(allocation)
-                }
-
-                self.sim_state.new_layer_animations.insert(anim_name, frames);
-            }
-        }
-
-        true
-    }
-
-    pub fn schedule_or_switch(&mut self, state: &SharedString) -> bool {
-        let Some((is_dynamic, is_layer_switch)) = self.is_dynamic_or_layer_switch(state) else {
-            return true;
-        };
-
-        if is_layer_switch {
-            self.sim_state.next_state = Some(state.clone());
-
-            let Some((_, state_ref)) = self.states.iter().find(|(k, _)| k == state) else {
-                println!("Couldn't find state reference!");
-                return true;
-            };
-
-            if !self.prepare_layer(state_ref.borrow().layer) {
-                return false;
-            }
-
-            if is_dynamic {
-                if !self.cook_state(state) {
-                    return false
-                }
-            }
-
-            true
-        } else if is_dynamic {
-            self.cook_state(state)
-        } else {
-            self.sim_state.next_state = Some(state.clone());
-            self.switch_to_scheduled();
-            true
-        }
-    }
-
-    pub fn find_possible_transitions(&mut self) {
-        let Some((_, state)) = self
-            .states
-            .iter()
-            .find(|(k, _)| k == &self.sim_state.current_state)
-        else {
-            return;
-        };
-
-        self.sim_state.possible_transitions.clear();
-        self.sim_state.possible_actions.clear();
-
-        let state = state.borrow();
-
-        if let InterStateImage::Animation { next_state, .. } = &state.image {
-            if let Some((is_dynamic, is_layer_switch)) = self.is_dynamic_or_layer_switch(next_state) {
-                self.sim_state
-                    .possible_transitions
-                    .push(StateSwitchInfo {
-                        name: next_state.clone(),
-                        is_dynamic,
-                        is_layer_switch,
-                    });
-            };
-        } else {
-            for transition in &state.transitions {
-                let transition = transition.borrow();
-
-                let Some((is_dynamic, is_layer_switch)) = self.is_dynamic_or_layer_switch(&transition.to_state) else {
-                    continue;
-                };
-
-                self.sim_state
-                    .possible_transitions
-                    .push(StateSwitchInfo {
-                        name: transition.to_state.clone(),
-                        is_dynamic,
-                        is_layer_switch,
-                    });
-            }
-
-            for (_, action) in self.actions {
-                let InterActionType::SwitchState(action_state) = &action.ty else {
-                    continue;
-                };
-
-                let Some((is_dynamic, is_layer_switch)) = self.is_dynamic_or_layer_switch(action_state) else {
-                    continue;
-                };
-
-                self.sim_state
-                    .possible_actions
-                    .push(StateSwitchInfo {
-                        name: action_state.clone(),
-                        is_dynamic,
-                        is_layer_switch,
-                    });
-            }
-        }
-    }
-
-    pub fn switch_to_scheduled(&mut self) {
-        let sim = &mut *self.sim_state;
-
-        if let Some(state) = &sim.next_state {
-            sim.current_state = state.clone();
-            sim.loaded_images = sim.prepared_images.clone();
-            sim.prepared_images.clear();
-
-            // Insert new images into the layer
-            if !sim.new_layer_images.is_empty() {
-                sim.loaded_layer_images.extend(
-                    sim.new_layer_images.clone()
-                );
-                sim.new_layer_images.clear();
-            }
-
-            if !sim.new_layer_animations.is_empty() {
-                sim.loaded_layer_animations.extend(
-                    sim.new_layer_animations.clone()
-                );
-                sim.new_layer_animations.clear();
-            }
-
-            // Delete unneeded images
-            for to_remove in &sim.layer_images_to_remove {
-                sim.loaded_layer_images.remove(to_remove);
-            }
-            sim.layer_images_to_remove.clear();
-
-            for to_remove in &sim.layer_animations_to_remove {
-                sim.loaded_layer_animations.remove(to_remove);
-            }
-            sim.layer_animations_to_remove.clear();
-
-
-            let Some((_, state_data)) = self.states.iter().find(|(k, _)| k == state) else {
-                return;
-            };
-
-            let state_data = state_data.borrow();
-
-            match &state_data.image {
-                InterStateImage::None => {}
-                InterStateImage::Single { image, layer_load } => {
-                    if *layer_load {
-                        let Some(alloc) = sim.loaded_layer_images.get(image) else {
-                            return;
-                        };
-
-                        sim.current_image = Some(alloc.clone())
-                    } else {
-                        sim.current_image = Some(sim.loaded_images[0].clone())
-                    }
-                }
-                InterStateImage::Animation {
-                    animation, layer_load, ..
-                } => {
-                    if *layer_load {
-                        let Some(frames) = sim.loaded_layer_animations.get(animation)
-                        else {
-                            return;
-                        };
-
-                        let Some(first) = frames.first() else {
-                            return;
-                        };
-
-                        sim.current_image = Some(first.clone())
-                    } else {
-                        sim.current_image = sim.loaded_images.get(0).cloned();
-                    }
-                }
-                InterStateImage::Sequence { layer_load, sequence, .. } => {
-                    let Some((_, sequence)) = self.sequences.iter()
-                        .find(|(k, _)| k == sequence) else {
-                        return;
-                    };
-
-                    if *layer_load {
-                        let Some(first_frame) = sequence.frames.first() else {
-                            return;
-                        };
-
-                        let Some(alloc) = sim.loaded_layer_images.get(&first_frame.image)
-                        else {
-                            return;
-                        };
-
-                        sim.current_image = Some(alloc.clone())
-                    } else {
-                        sim.current_image = sim.loaded_images.get(0).cloned();
-                    }
-                }
-            }
-        }
-
-        sim.next_state = None;
-        self.find_possible_transitions();
-    }
-
-    pub fn preload(&mut self) -> Option<()> {
-        for (_, state) in self.states {
-            let borrowed = state.borrow();
-
-            if self.sim_state.current_layer != borrowed.layer {
-                continue;
-            }
-
-            match &borrowed.image {
-                InterStateImage::None => {}
-                InterStateImage::Single { image, layer_load } => {
-                    if !*layer_load {
-                        continue;
-                    }
-
-                    let Some((_, image_data)) = self.images.iter().find(|(k, _)| k == image) else {
-                        continue;
-                    };
-
-                    let image_data = image_data.borrow();
-
-                    self.sim_state.loaded_layer_images.insert(
-                        image.clone(),
-                        self.sim_state.allocator.allocate(calc_required_space(
-                            image_data.width,
-                            image_data.height,
-                            image_data.upscale,
-                        ))?,
-                    );
-                }
-                InterStateImage::Animation {
-                    animation, layer_load, ..
-                } => {
-                    if !*layer_load {
-                        continue;
-                    }
-
-                    let Some((_, animation_info)) =
-                        self.animations.iter().find(|(k, _)| k == animation)
-                    else {
-                        continue;
-                    };
-
-                    let frame_size = calc_required_space(
-                        animation_info.width,
-                        animation_info.height,
-                        animation_info.upscale,
-                    );
-
-                    self.sim_state.loaded_layer_animations.insert(
-                        animation.clone(),
-                        (0..animation_info.frames.count())
-                            .map(|_| self.sim_state.allocator.allocate(frame_size))
-                            .collect::<Option<Vec<_>>>()?,
-                    );
-                }
-                InterStateImage::Sequence { layer_load, sequence, .. } => {
-                    if !*layer_load {
-                        continue;
-                    }
-
-                    let Some((_, sequence)) = self.sequences.iter()
-                        .find(|(k, _)| k == sequence) else {
-                        continue;
-                    };
-
-                    for frame in &sequence.frames {
-                        let Some((_, image_data)) =
-                            self.images.iter().find(|(k, _)| k == &frame.image)
-                        else {
-                            continue;
-                        };
-
-                        let image_data = image_data.borrow();
-
-                        self.sim_state.loaded_layer_images.insert(
-                            frame.image.clone(),
-                            self.sim_state.allocator.allocate(calc_required_space(
-                                image_data.width,
-                                image_data.height,
-                                image_data.upscale,
-                            ))?,
-                        );
-                    }
-                }
-            }
-        }
-
-        Some(())
-    }
 }
 
 pub struct SimulatorNodeViewer<'a> {
-    current_state: &'a SharedString,
-    next_state: Option<&'a SharedString>,
+    current_state: &'a str,
+    next_state: Option<&'a str>,
 }
 
 impl SnarlViewer<StateNode> for SimulatorNodeViewer<'_> {
@@ -818,12 +322,12 @@ impl SnarlViewer<StateNode> for SimulatorNodeViewer<'_> {
     ) -> Frame {
         let name = &snarl[node].0;
 
-        if name == self.current_state {
+        if name.str_eq(self.current_state) {
             return default.fill(Color32::DARK_GREEN);
         }
 
-        if let Some(next) = &self.next_state
-            && *next == name
+        if let Some(next) = self.next_state
+            && name.str_eq(next)
         {
             return default.fill(Color32::DARK_RED);
         }
@@ -952,12 +456,6 @@ impl SnarlViewer<StateNode> for SimulatorNodeViewer<'_> {
     }
 }
 
-fn calc_required_space(width: u32, height: u32, upscale: bool) -> u64 {
-    let width = if upscale { width / 2 } else { width } as u64;
-    let height = if upscale { height / 2 } else { height } as u64;
-
-    width * height * 2_u64
-}
 
 #[derive(Default)]
 struct AllocationSize {
@@ -968,7 +466,7 @@ struct AllocationSize {
     current_size: u64,
 }
 
-fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
+fn visualize_allocator(ui: &mut Ui, memory: &SimMemory) {
     {
         const BG: Color32 = Color32::DARK_BLUE;
         const OCCUPIED: Color32 = Color32::LIGHT_BLUE;
@@ -1000,7 +498,7 @@ fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
                 Rect::from_min_max(inner_rect.min + vec2(0.0, text_height), inner_rect.max);
             painter.rect_filled(total_bar_rect, 0, BG);
 
-            let bytes_per_pixel = IMAGE_STORAGE_SIZE / inner_rect.width().floor() as u64;
+            let bytes_per_pixel = (memory.allocator.size() / inner_rect.width().floor() as u64).max(1);
 
             let paint_allocation =
                 |alloc: &Allocation, y_offset: f32, height: f32, color: Color32| {
@@ -1018,66 +516,50 @@ fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
             // Actual allocations / Top half of the bar
             let mut occupied_space = 0_u64;
 
-            for allocation in state.allocator.existing_allocations() {
+            for allocation in memory.allocator.existing_allocations() {
                 occupied_space += allocation.len();
                 paint_allocation(&allocation, 0.0, actual_alloc_height, OCCUPIED);
             }
 
             let aware_offset = actual_alloc_height;
 
-            for (image_name, image) in &state.loaded_layer_images {
-                sizes.layer_size += image.len();
-                paint_allocation(
-                    image.deref(),
-                    aware_offset,
-                    aware_alloc_height,
-                    if state.layer_images_to_remove.contains(image_name) {
+            for (image_name, image) in &memory.layer_images {
+                let color = if memory.new_layer_images.contains(image_name) {
+                    sizes.new_layer_size += image.len();
+                    NEW_LAYER_IMAGE
+                } else {
+                    sizes.layer_size += image.len();
+
+                    if memory.layer_images_to_remove.contains(image_name) {
                         MARKED_LAYER_IMAGE
                     } else {
                         LAYER_IMAGE
-                    },
-                );
+                    }
+                };
+
+                paint_allocation(image.deref(), aware_offset, aware_alloc_height, color);
             }
 
-            for (anim_name, frames) in &state.loaded_layer_animations {
+            for (anim_name, frames) in &memory.layer_animations {
                 for frame in frames {
-                    sizes.layer_size += frame.len();
-                    paint_allocation(
-                        frame.deref(),
-                        aware_offset,
-                        aware_alloc_height,
-                        if state.layer_animations_to_remove.contains(anim_name) {
+                    let color = if memory.new_layer_animations.contains(anim_name) {
+                        sizes.new_layer_size += frame.len();
+                        NEW_LAYER_IMAGE
+                    } else {
+                        sizes.layer_size += frame.len();
+
+                        if memory.layer_animations_to_remove.contains(anim_name) {
                             MARKED_LAYER_IMAGE
                         } else {
                             LAYER_IMAGE
-                        },
-                    );
-                }
-            }
-
-            for (_, image) in &state.new_layer_images {
-                sizes.new_layer_size += image.len();
-                paint_allocation(
-                    image.deref(),
-                    aware_offset,
-                    aware_alloc_height,
-                    NEW_LAYER_IMAGE,
-                );
-            }
+                        }
+                    };
 
-            for (_, frames) in &state.new_layer_animations {
-                for frame in frames {
-                    sizes.new_layer_size += frame.len();
-                    paint_allocation(
-                        frame.deref(),
-                        aware_offset,
-                        aware_alloc_height,
-                        NEW_LAYER_IMAGE,
-                    );
+                    paint_allocation(frame.deref(), aware_offset, aware_alloc_height, color);
                 }
             }
 
-            for image in &state.loaded_images {
+            for image in &memory.loaded_images {
                 sizes.loaded_size += image.len();
                 paint_allocation(
                     image.deref(),
@@ -1087,7 +569,7 @@ fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
                 );
             }
 
-            for image in &state.prepared_images {
+            for image in &memory.prepared_images {
                 sizes.prepared_size += image.len();
                 paint_allocation(
                     image.deref(),
@@ -1097,7 +579,7 @@ fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
                 );
             }
 
-            if let Some(current) = &state.current_image {
+            if let Some(current) = &memory.current_image {
                 sizes.current_size += current.len();
                 paint_allocation(
                     current.deref(),
@@ -1121,7 +603,7 @@ fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
                 format!(
                     "{}b / {}b",
                     occupied_space.to_formatted_string(&Locale::en),
-                    IMAGE_STORAGE_SIZE.to_formatted_string(&Locale::en)
+                    memory.allocator.size().to_formatted_string(&Locale::en)
                 ),
                 FontId::proportional(10.0),
                 Color32::GRAY,
@@ -1196,139 +678,3 @@ fn visualize_allocator(ui: &mut Ui, state: &SimulatorState) {
     }
 }
 
-pub struct StateSwitchInfo {
-    pub name: SharedString,
-    pub is_dynamic: bool,
-    pub is_layer_switch: bool
-}
-
-pub struct SimulatorState {
-    pub status: SimulatorStatus,
-    pub allocator: AllocatorState,
-
-    pub current_layer: u8,
-    pub current_state: SharedString,
-    pub next_state: Option<SharedString>,
-
-    pub possible_transitions: Vec<StateSwitchInfo>,
-    pub possible_actions: Vec<StateSwitchInfo>,
-
-    pub current_image: Option<StrongAllocation>,
-
-    pub new_layer_images: HashMap<SharedString, StrongAllocation>,
-    pub new_layer_animations: HashMap<SharedString, Vec<StrongAllocation>>,
-
-    pub loaded_layer_images: HashMap<SharedString, StrongAllocation>,
-    pub loaded_layer_animations: HashMap<SharedString, Vec<StrongAllocation>>,
-
-    pub layer_images_to_remove: HashSet<SharedString>,
-    pub layer_animations_to_remove: HashSet<SharedString>,
-
-    pub loaded_images: Vec<StrongAllocation>,
-    pub prepared_images: Vec<StrongAllocation>,
-}
-
-#[derive(Default, EnumIs, Clone)]
-pub enum SimulatorStatus {
-    #[default]
-    Uninitialized,
-    Loaded,
-    Error(String),
-}
-
-impl SimulatorState {
-    pub fn error(&mut self, error: impl Display) {
-        self.status = SimulatorStatus::Error(error.to_string())
-    }
-}
-
-#[derive(Default)]
-pub struct AllocatorState {
-    allocations: Vec<WeakAllocation>,
-}
-
-pub const IMAGE_STORAGE_SIZE: u64 = 7_000_000;
-
-impl AllocatorState {
-    fn clear_expired(&mut self) {
-        self.allocations.retain(|e| e.upgrade().is_some())
-    }
-
-    pub fn existing_allocations(&self) -> Vec<Allocation> {
-        self.allocations
-            .iter()
-            .filter_map(|e| e.upgrade())
-            .map(|e| e.deref().clone())
-            .collect()
-    }
-
-    fn find_space(&self, size: u64) -> Option<u64> {
-        let mut existing = self.existing_allocations();
-
-        existing.sort_unstable();
-
-        let mut block_start = 0_u64;
-
-        for occlusion in &existing {
-            let current_size = occlusion.start - block_start;
-
-            if current_size >= size {
-                return Some(block_start);
-            }
-
-            block_start = occlusion.end + 1
-        }
-
-        let remaining_size = IMAGE_STORAGE_SIZE - block_start;
-
-        if remaining_size >= size {
-            return Some(block_start);
-        }
-
-        None
-    }
-
-    pub fn allocate(&mut self, size: u64) -> Option<StrongAllocation> {
-        self.clear_expired();
-
-        let block_start = self.find_space(size)?;
-
-        let ptr = Rc::new(Allocation {
-            start: block_start,
-            end: block_start + size - 1,
-        });
-
-        self.allocations.push(Rc::downgrade(&ptr));
-
-        Some(ptr)
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq, Ord)]
-pub struct Allocation {
-    pub start: u64,
-    pub end: u64,
-}
-
-pub type StrongAllocation = Rc<Allocation>;
-pub type WeakAllocation = Weak<Allocation>;
-
-impl PartialOrd for Allocation {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.end < other.start {
-            return Some(Ordering::Less);
-        }
-
-        if other.end < self.start {
-            return Some(Ordering::Greater);
-        }
-
-        Some(Ordering::Equal)
-    }
-}
-
-impl Allocation {
-    pub fn len(&self) -> u64 {
-        self.end - self.start + 1
-    }
-}
@@ -1,52 +1,101 @@
 use std::collections::HashSet;
-use std::fmt::{Debug, Display};
+use std::fmt::Display;
 use std::hash::Hash;
-use strum::Display;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use egui::Context;
+use crate::character::budget::{check_memory_budget, BudgetFailure};
+use crate::character::repr::State;
+use crate::character::validation::ValidationError;
 use crate::gui::app::editor::CharacterEditor;
 use crate::gui::app::editor::intermediate::{InterActionType, InterStateImage};
 
-#[derive(Clone, Debug, Display)]
-#[derive(PartialEq, Eq)]
-pub enum ValidationError {
-    #[strum(to_string = "Duplicate state '{0}'!")]
-    DuplicateState(String),
-    #[strum(to_string = "Duplicate animation '{0}'!")]
-    DuplicateAnimation(String),
-    #[strum(to_string = "Duplicate action '{0}'!")]
-    DuplicateAction(String),
-    #[strum(to_string = "Duplicate image '{0}'!")]
-    DuplicateImage(String),
-    #[strum(to_string = "Duplicate sequence '{0}'!")]
-    DuplicateSequence(String),
-    #[strum(to_string = "Selected sequence in state '{0}' doesn't exist!")]
-    InvalidSequenceInState(String),
-    #[strum(to_string = "Selected animation in state '{0}' doesn't exist!")]
-    InvalidAnimationInState(String),
-    #[strum(to_string = "Selected next state in animation of state '{0}' doesn't exist!")]
-    InvalidNextStateInAnimation(String),
-    #[strum(to_string = "Selected image in state '{0}' doesn't exist!")]
-    InvalidImageInState(String),
-    #[strum(to_string = "Selected image in frame #{1} of sequence '{0}' doesn't exist!")]
-    InvalidImageInSequenceFrame(String, usize),
-    #[strum(to_string = "Selected action type in action '{0}' is invalid!")]
-    InvalidActionType(String),
-    #[strum(to_string = "Selected default state doesn't exist!")]
-    InvalidDefaultState,
-    #[strum(to_string = "Selected state in action '{0}' doesn't exist!")]
-    InvalidActionState(String),
-    #[strum(to_string = "State name can't be empty!")]
-    EmptyStateName,
-    #[strum(to_string = "Animation name can't be empty!")]
-    EmptyAnimationName,
-    #[strum(to_string = "Action name can't be empty!")]
-    EmptyActionName,
-    #[strum(to_string = "Image name can't be empty!")]
-    EmptyImageName,
-    #[strum(to_string = "Sequence name can't be empty!")]
-    EmptySequenceName
+/// Changes closer together than this only start one memory budget check
+const MEMORY_CHECK_DELAY: Duration = Duration::from_millis(500);
+
+/// Memory budget check on its own thread, it can take a while for big characters.
+/// Failures of the last finished check are shown until the next one is done
+#[derive(Default)]
+pub struct MemoryCheck {
+    requested: Option<Instant>,
+    running: Option<Receiver<Vec<BudgetFailure>>>,
+    failures: Vec<BudgetFailure>
+}
+
+impl MemoryCheck {
+    fn request(&mut self) {
+        self.requested = Some(Instant::now());
+    }
+
+    /// Results of a running check are dropped, the character isn't checked until it's valid again
+    fn cancel(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.requested.is_some() || self.running.is_some()
+    }
+
+    fn errors(&self) -> impl Iterator<Item = ValidationError> + '_ {
+        self.failures.iter().cloned().map(ValidationError::MemoryBudget)
+    }
 }
 
 impl CharacterEditor {
+    /// Refreshes errors and warnings, memory is only checked once everything else is fine
+    pub fn validate(&mut self) {
+        self.validation_errors = self.validate_state();
+
+        let char = self.as_repr();
+        self.validation_warnings = char.warnings();
+
+        if self.validation_errors.is_empty() && char.validate().is_empty() {
+            self.memory_check.request();
+            self.validation_errors.extend(self.memory_check.errors());
+        } else {
+            self.memory_check.cancel();
+        }
+    }
+
+    /// Starts the requested memory budget check once the character stopped changing
+    /// and swaps in the failures of a finished one
+    pub fn update_memory_check(&mut self, ctx: &Context) {
+        if let Some(running) = &self.memory_check.running {
+            match running.try_recv() {
+                Ok(failures) => {
+                    self.memory_check.running = None;
+                    self.memory_check.failures = failures;
+
+                    self.validation_errors.retain(|error| !matches!(error, ValidationError::MemoryBudget(_)));
+                    self.validation_errors.extend(self.memory_check.errors());
+                }
+                Err(TryRecvError::Disconnected) => self.memory_check.running = None,
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+
+        if self.memory_check.running.is_none()
+            && self.memory_check.requested.is_some_and(|requested| requested.elapsed() >= MEMORY_CHECK_DELAY)
+        {
+            let char = self.as_repr();
+            let target = self.target.clone();
+            let (sender, receiver) = channel();
+
+            thread::spawn(move || {
+                let _ = sender.send(check_memory_budget(&char, &target).failures);
+            });
+
+            self.memory_check.requested = None;
+            self.memory_check.running = Some(receiver);
+        }
+
+        // Nothing else triggers a repaint while waiting
+        if self.memory_check.is_busy() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+    }
+
     pub fn validate_state(&self) -> Vec<ValidationError> {
         let mut errors = vec![];
 
@@ -81,16 +130,7 @@ impl CharacterEditor {
                 .map(|e| ValidationError::DuplicateSequence(e.to_string()))
         );
 
-        // Check for empty names
-        if check_for_empty(&self.states) {
-            errors.push(ValidationError::EmptyStateName)
-        }
-        if check_for_empty(&self.animations) {
-            errors.push(ValidationError::EmptyAnimationName)
-        }
-        if check_for_empty(&self.actions) {
-            errors.push(ValidationError::EmptyActionName)
-        }
+        // Empty state, animation and action names are checked by the shared rules
         if check_for_empty(&self.images) {
             errors.push(ValidationError::EmptyImageName)
         }
@@ -107,31 +147,13 @@ impl CharacterEditor {
             .map(|(k, _)| k.clone())
             .collect::<HashSet<_>>();
 
-        let animation_names = self.animations.iter()
-            .map(|(k, _)| k.clone())
-            .collect::<HashSet<_>>();
-
-        let state_names = self.states.iter()
-            .map(|(k, _)| k.clone())
-            .collect::<HashSet<_>>();
-
         for (state_name, v) in &self.states {
             let b_state = v.borrow();
 
             match &b_state.image {
-                InterStateImage::Animation { animation, next_state, .. } => {
-                    if !animation_names.contains(animation) {
-                        errors.push(ValidationError::InvalidAnimationInState(state_name.to_string()));
-                    }
-
-                    if !state_names.contains(next_state) {
-                        errors.push(ValidationError::InvalidNextStateInAnimation(state_name.to_string()));
-                    }
-                }
-
                 InterStateImage::Single { image, .. } => {
                     if !image_names.contains(image) {
-                    errors.push(ValidationError::InvalidImageInState(state_name.to_string()));
+                        errors.push(ValidationError::InvalidImageInState(state_name.to_string()));
                     }
                 }
 
@@ -148,12 +170,6 @@ impl CharacterEditor {
             if let InterActionType::None = &action.ty {
                 errors.push(ValidationError::InvalidActionType(action_name.to_string()))
             }
-
-            if let InterActionType::SwitchState(state) = &action.ty {
-                if !state_names.contains(state) {
-                    errors.push(ValidationError::InvalidActionState(action_name.to_string()))
-                }
-            }
         }
         
         for (sequence_name, sequence) in &self.sequences {
@@ -164,10 +180,31 @@ impl CharacterEditor {
             }
         }
 
-        if !state_names.contains(&self.default_state) {
-            errors.push(ValidationError::InvalidDefaultState)
+        // Rules shared with the CLI, states that couldn't be converted because of errors above are kept
+        // without an image, so references to them don't produce extra errors
+        let mut char = self.as_repr();
+
+        for (state_name, state) in &self.states {
+            let borrowed = state.borrow();
+
+            char.states.entry(state_name.to_string()).or_insert_with(|| State {
+                layer: borrowed.layer,
+                transitions: borrowed.transitions.iter()
+                    .map(|t| t.borrow().clone().into())
+                    .collect(),
+                ..Default::default()
+            });
+        }
+
+        for error in char.validate().into_iter().chain(char.validate_target(&self.target)) {
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
         }
 
+        // Errors from the last export, kept until the next one
+        errors.extend(self.build_errors.iter().cloned().map(ValidationError::Build));
+
         errors
     }
 }
@@ -381,6 +381,43 @@ pub fn inline_folder_picker(
     })
 }
 
+/// Returns whether another file was picked
+pub fn inline_file_picker(
+    ui: &mut Ui,
+    label: impl Into<WidgetText>,
+    value: &mut PathBuf,
+    (filter, extensions): (&str, &[&str]),
+    location: impl AsRef<Path>,
+    width: f32,
+    tracker: &mut ChangeTracker
+) -> bool {
+    let location = location.as_ref().to_path_buf();
+    let mut picked = false;
+
+    ui.horizontal(|ui| {
+        inline_style_label(ui, label, width);
+        if ui.button("Pick File").clicked()
+            && let Some(picked_file) = rfd::FileDialog::new()
+                .set_title("Pick file")
+                .add_filter(filter, extensions)
+                .set_directory(&location)
+                .pick_file()
+        {
+            match picked_file.strip_prefix(&location) {
+                Ok(relative) => {
+                    *value = relative.to_path_buf();
+                }
+                Err(_) => *value = picked_file,
+            }
+            picked = true;
+            tracker.mark_change();
+        }
+        disabled_text_edit(ui, value.to_string_lossy(), BUTTON_WIDTH);
+    });
+
+    picked
+}
+
 pub fn load_image(path: impl AsRef<Path>) -> anyhow::Result<DynamicImage> {
     Ok(ImageReader::open(path)?.decode()?)
 }
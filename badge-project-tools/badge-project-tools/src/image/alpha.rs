@@ -0,0 +1,132 @@
+use crate::image::ConversionOptions;
+use anyhow::{bail, Context};
+use image::{Rgb, RgbImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+/// Pixels with less alpha are transparent for formats that can't store partial transparency
+pub const ALPHA_THRESHOLD: u8 = 128;
+
+const CHECKER_SIZE: u32 = 8;
+
+/// What transparent pixels and the letterbox area are composited over
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum Background {
+    #[default]
+    Color,
+    /// Light gray squares, like image editors show transparency
+    Checkerboard
+}
+
+/// How transparency is passed on to the firmware
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum AlphaMode {
+    /// Composited over the background
+    #[default]
+    Opaque,
+    /// Transparent pixels get the key color, so they can be skipped when drawing over lower layers
+    #[strum(to_string = "Color Key")]
+    ColorKey,
+    /// An A8 plane follows the pixel data. Indexed formats use the first palette color as transparent instead
+    #[strum(to_string = "Alpha Channel")]
+    Channel
+}
+
+impl AlphaMode {
+    /// Pixel data of indexed formats keeps the first palette color for transparent pixels
+    pub fn reserves_palette_color(self) -> bool {
+        self != AlphaMode::Opaque
+    }
+}
+
+/// Parses colors as `#RRGGBB` or `RRGGBB`
+pub fn parse_color(value: &str) -> anyhow::Result<(u8, u8, u8)> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+
+    if hex.len() != 6 || !hex.is_ascii() {
+        bail!("Color '{value}' has to be in #RRGGBB format");
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16)
+        .with_context(|| format!("Color '{value}' has to be in #RRGGBB format"));
+
+    Ok((channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn background_at(options: &ConversionOptions, x: u32, y: u32) -> [u8; 3] {
+    match options.background {
+        Background::Color => options.background_color.into(),
+        Background::Checkerboard => if (x / CHECKER_SIZE + y / CHECKER_SIZE).is_multiple_of(2) {
+            [204; 3]
+        } else {
+            [255; 3]
+        }
+    }
+}
+
+fn blend(color: [u8; 3], background: [u8; 3], alpha: u8) -> Rgb<u8> {
+    let alpha = alpha as u32;
+
+    Rgb(std::array::from_fn(|i| {
+        ((color[i] as u32 * alpha + background[i] as u32 * (255 - alpha) + 127) / 255) as u8
+    }))
+}
+
+/// Colors that get quantized. Opaque and color keyed images are composited over the background,
+/// with the alpha channel they keep straight or premultiplied colors
+pub fn flatten(image: &RgbaImage, options: &ConversionOptions) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *image.get_pixel(x, y);
+
+        match options.alpha {
+            AlphaMode::Opaque | AlphaMode::ColorKey => blend([r, g, b], background_at(options, x, y), a),
+            AlphaMode::Channel if options.premultiplied => blend([r, g, b], [0; 3], a),
+            AlphaMode::Channel => Rgb([r, g, b])
+        }
+    })
+}
+
+/// Composites decoded image data over a checkerboard, to show what's transparent
+pub fn checkerboard_composite(image: &RgbImage, alpha: &[u8], premultiplied: bool) -> RgbImage {
+    let options = ConversionOptions {
+        background: Background::Checkerboard,
+        ..Default::default()
+    };
+
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let a = alpha[(y * image.width() + x) as usize];
+        let background = background_at(&options, x, y);
+        let color = image.get_pixel(x, y).0;
+
+        if premultiplied {
+            Rgb(std::array::from_fn(|i| color[i].saturating_add(((background[i] as u32 * (255 - a as u32)) / 255) as u8)))
+        } else {
+            blend(color, background, a)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transparent_pixels_follow_alpha_mode() {
+        let image = RgbaImage::from_pixel(1, 1, Rgba([200, 100, 50, 128]));
+
+        let mut options = ConversionOptions {
+            background_color: (0, 0, 255),
+            ..Default::default()
+        };
+        assert_eq!(flatten(&image, &options).get_pixel(0, 0), &Rgb([100, 50, 152]));
+
+        options.alpha = AlphaMode::Channel;
+        assert_eq!(flatten(&image, &options).get_pixel(0, 0), &Rgb([200, 100, 50]));
+
+        options.premultiplied = true;
+        assert_eq!(flatten(&image, &options).get_pixel(0, 0), &Rgb([100, 50, 25]));
+
+        assert_eq!(parse_color("#FF00ff").unwrap(), (255, 0, 255));
+        assert!(parse_color("#FF00").is_err());
+    }
+}
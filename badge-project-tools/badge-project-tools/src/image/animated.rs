@@ -0,0 +1,148 @@
+use anyhow::{bail, ensure};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Frames, ImageFormat, RgbaImage};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Extensions of files that can hold an animation
+pub const ANIMATED_EXTENSIONS: &[&str] = &["gif", "png", "apng", "webp"];
+
+/// Browsers show frames without delay for 100 ms, files rely on that. Sprite sheets without timings get it too
+pub const DEFAULT_DURATION_US: i64 = 100_000;
+
+/// Frame of an animated file, composited over the previous ones like viewers show it
+#[derive(Clone, Debug)]
+pub struct AnimatedFrame {
+    pub image: RgbaImage,
+    pub duration_us: i64
+}
+
+fn collect(frames: Frames) -> anyhow::Result<Vec<AnimatedFrame>> {
+    frames
+        .map(|frame| {
+            let frame = frame?;
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            let duration_us = numerator as i64 * 1000 / denominator.max(1) as i64;
+
+            Ok(AnimatedFrame {
+                image: frame.into_buffer(),
+                duration_us: if duration_us > 0 { duration_us } else { DEFAULT_DURATION_US },
+            })
+        })
+        .collect()
+}
+
+/// Decodes every frame of animated GIF, APNG or WebP. Still PNG and WebP are a single frame
+pub fn decode_animated(data: &[u8]) -> anyhow::Result<Vec<AnimatedFrame>> {
+    let frames = match image::guess_format(data)? {
+        ImageFormat::Gif => collect(GifDecoder::new(Cursor::new(data))?.into_frames())?,
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(data))?;
+
+            if decoder.is_apng()? {
+                collect(decoder.apng()?.into_frames())?
+            } else {
+                vec![AnimatedFrame {
+                    image: image::load_from_memory(data)?.to_rgba8(),
+                    duration_us: DEFAULT_DURATION_US,
+                }]
+            }
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data))?;
+
+            if decoder.has_animation() {
+                collect(decoder.into_frames())?
+            } else {
+                vec![AnimatedFrame {
+                    image: image::load_from_memory(data)?.to_rgba8(),
+                    duration_us: DEFAULT_DURATION_US,
+                }]
+            }
+        }
+        format => bail!("{format:?} files can't be animated, use GIF, APNG or WebP")
+    };
+
+    ensure!(!frames.is_empty(), "File has no frames");
+
+    Ok(frames)
+}
+
+/// Keeps frames from `first` to `last`, both included and counted from 0
+pub fn trim_frames(mut frames: Vec<AnimatedFrame>, first: u32, last: Option<u32>) -> anyhow::Result<Vec<AnimatedFrame>> {
+    let last = last.map(|last| last as usize).unwrap_or(frames.len().saturating_sub(1));
+
+    ensure!(
+        first as usize <= last && last < frames.len(),
+        "Frames {first} to {last} aren't in the file, it has {} frames",
+        frames.len()
+    );
+
+    frames.truncate(last + 1);
+    frames.drain(..first as usize);
+
+    Ok(frames)
+}
+
+/// Saves frames as numbered PNG files into the folder, numbered from 1
+pub fn extract_frames(frames: &[AnimatedFrame], folder: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    fs::create_dir_all(folder)?;
+
+    frames.iter()
+        .enumerate()
+        .map(|(index, frame)| {
+            let path = folder.join(format!("{}.png", index + 1));
+            frame.image.save(&path)?;
+
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Frame rate of frames with the durations, if every frame is shown for the same time
+pub fn constant_fps(durations_us: &[i64]) -> Option<f64> {
+    let duration = *durations_us.first()?;
+
+    durations_us.iter()
+        .all(|&other| other == duration)
+        .then(|| 1_000_000.0 / duration as f64)
+}
+
+/// Frame rate that plays frames with the durations in the same total time
+pub fn average_fps(durations_us: &[i64]) -> f64 {
+    durations_us.len() as f64 * 1_000_000.0 / durations_us.iter().sum::<i64>().max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, Rgba};
+
+    #[test]
+    fn gif_frames_keep_timings() {
+        let mut data = vec![];
+
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            for (index, delay) in [100, 100, 250].into_iter().enumerate() {
+                let image = RgbaImage::from_pixel(4, 4, Rgba([index as u8 * 100, 0, 0, 255]));
+                encoder.encode_frame(Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(delay, 1))).unwrap();
+            }
+        }
+
+        let frames = decode_animated(&data).unwrap();
+        let durations = frames.iter().map(|frame| frame.duration_us).collect::<Vec<_>>();
+        assert_eq!(durations, vec![100_000, 100_000, 250_000]);
+        assert_eq!(constant_fps(&durations), None);
+        assert_eq!(constant_fps(&durations[..2]), Some(10.0));
+
+        let trimmed = trim_frames(frames.clone(), 1, None).unwrap();
+        assert_eq!(trimmed.len(), 2);
+        assert_eq!(trimmed[0].image.get_pixel(0, 0).0[0], frames[1].image.get_pixel(0, 0).0[0]);
+        assert!(trim_frames(frames, 2, Some(3)).is_err());
+    }
+}
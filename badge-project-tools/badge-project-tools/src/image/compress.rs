@@ -0,0 +1,315 @@
+use crate::bp_image_compression_e_BP_IMAGE_COMPRESSION_LZ4;
+use crate::bp_image_compression_e_BP_IMAGE_COMPRESSION_NONE;
+use crate::bp_image_compression_e_BP_IMAGE_COMPRESSION_RLE;
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+/// Compression of image files, picked from what the firmware can decode cheaply
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Runs of the same pixel, good for flat colors
+    #[strum(to_string = "RLE")]
+    Rle,
+    /// Repeated byte sequences, good for patterns and dithering
+    #[strum(to_string = "LZ4")]
+    Lz4
+}
+
+impl Compression {
+    pub fn to_raw(self) -> u32 {
+        match self {
+            Compression::None => bp_image_compression_e_BP_IMAGE_COMPRESSION_NONE,
+            Compression::Rle => bp_image_compression_e_BP_IMAGE_COMPRESSION_RLE,
+            Compression::Lz4 => bp_image_compression_e_BP_IMAGE_COMPRESSION_LZ4
+        }
+    }
+
+    pub fn from_raw(value: u32) -> anyhow::Result<Self> {
+        Ok(match value {
+            bp_image_compression_e_BP_IMAGE_COMPRESSION_NONE => Compression::None,
+            bp_image_compression_e_BP_IMAGE_COMPRESSION_RLE => Compression::Rle,
+            bp_image_compression_e_BP_IMAGE_COMPRESSION_LZ4 => Compression::Lz4,
+            _ => bail!("Unknown image compression {value}")
+        })
+    }
+
+    /// Pixel size is what RLE repeats, it's ignored by the others
+    pub fn compress(self, data: &[u8], pixel_size: usize) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Rle => rle_compress(data, pixel_size),
+            Compression::Lz4 => lz4_compress(data)
+        }
+    }
+
+    /// Output stops at `data_size`, the same way the firmware fills its image buffer
+    pub fn decompress(self, data: &[u8], data_size: usize) -> anyhow::Result<Vec<u8>> {
+        let decompressed = match self {
+            Compression::None => data[..data.len().min(data_size)].to_vec(),
+            Compression::Rle => rle_decompress(data, data_size)?,
+            Compression::Lz4 => lz4_decompress(data, data_size)?
+        };
+
+        ensure!(
+            decompressed.len() == data_size,
+            "{self} image data decompressed into {} bytes instead of {data_size}",
+            decompressed.len()
+        );
+
+        Ok(decompressed)
+    }
+}
+
+const RLE_LITERAL_MAX: usize = 128;
+const RLE_RUN_MAX: usize = 129;
+
+/// Data that isn't a whole number of pixels gets padded, the decompressed size tells where it ends
+fn rle_compress(data: &[u8], pixel_size: usize) -> Vec<u8> {
+    let pixel_size = pixel_size.max(1);
+    let mut padded = data.to_vec();
+    padded.resize(data.len().next_multiple_of(pixel_size), 0);
+
+    let pixels = padded.chunks_exact(pixel_size).collect::<Vec<_>>();
+    let mut result = vec![pixel_size as u8];
+    let mut literals: Vec<&[u8]> = vec![];
+
+    let flush = |literals: &mut Vec<&[u8]>, result: &mut Vec<u8>| {
+        for chunk in literals.chunks(RLE_LITERAL_MAX) {
+            result.push(chunk.len() as u8 - 1);
+            result.extend(chunk.concat());
+        }
+        literals.clear();
+    };
+
+    let mut index = 0;
+
+    while index < pixels.len() {
+        let run = pixels[index..].iter()
+            .take(RLE_RUN_MAX)
+            .take_while(|pixel| **pixel == pixels[index])
+            .count();
+
+        if run >= 2 {
+            flush(&mut literals, &mut result);
+            result.push((run + 126) as u8);
+            result.extend(pixels[index]);
+        } else {
+            literals.push(pixels[index]);
+        }
+
+        index += run;
+    }
+
+    flush(&mut literals, &mut result);
+
+    result
+}
+
+fn rle_decompress(data: &[u8], data_size: usize) -> anyhow::Result<Vec<u8>> {
+    let Some((&pixel_size, mut packets)) = data.split_first() else {
+        bail!("RLE image data is empty");
+    };
+    let pixel_size = pixel_size as usize;
+    ensure!(pixel_size > 0, "RLE pixel size can't be zero");
+
+    let mut result = Vec::with_capacity(data_size);
+
+    while let Some((&control, rest)) = packets.split_first() && result.len() < data_size {
+        let (count, bytes) = if control < 128 {
+            (1, (control as usize + 1) * pixel_size)
+        } else {
+            (control as usize - 126, pixel_size)
+        };
+
+        let chunk = rest.get(..bytes).context("RLE image data ends in the middle of a packet")?;
+
+        for _ in 0..count {
+            let copied = bytes.min(data_size - result.len());
+            result.extend_from_slice(&chunk[..copied]);
+        }
+
+        packets = &rest[bytes..];
+    }
+
+    Ok(result)
+}
+
+const LZ4_MIN_MATCH: usize = 4;
+// The block format requires the last 5 bytes to be literals, and the last match to start 12 bytes before the end
+const LZ4_LAST_LITERALS: usize = 5;
+const LZ4_MATCH_LIMIT: usize = 12;
+const LZ4_HASH_BITS: u32 = 16;
+
+fn lz4_length(result: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        result.push(255);
+        length -= 255;
+    }
+
+    result.push(length as u8);
+}
+
+fn lz4_sequence(result: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let literal_token = literals.len().min(15) as u8;
+    let match_token = matched.map(|(_, length)| (length - LZ4_MIN_MATCH).min(15) as u8).unwrap_or_default();
+
+    result.push(literal_token << 4 | match_token);
+
+    if literals.len() >= 15 {
+        lz4_length(result, literals.len() - 15);
+    }
+
+    result.extend_from_slice(literals);
+
+    if let Some((offset, length)) = matched {
+        result.extend_from_slice(&(offset as u16).to_le_bytes());
+
+        if length - LZ4_MIN_MATCH >= 15 {
+            lz4_length(result, length - LZ4_MIN_MATCH - 15);
+        }
+    }
+}
+
+/// Greedy LZ4 block compressor with a single entry hash table
+fn lz4_compress(data: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    let mut table = vec![usize::MAX; 1 << LZ4_HASH_BITS];
+
+    let hash = |position: usize| {
+        let value = u32::from_le_bytes(data[position..position + 4].try_into().unwrap());
+        (value.wrapping_mul(2654435761) >> (32 - LZ4_HASH_BITS)) as usize
+    };
+
+    let mut anchor = 0;
+    let mut position = 0;
+
+    while data.len() >= LZ4_MATCH_LIMIT && position + LZ4_MATCH_LIMIT <= data.len() {
+        let slot = hash(position);
+        let candidate = table[slot];
+        table[slot] = position;
+
+        let matches = candidate != usize::MAX
+            && position - candidate <= u16::MAX as usize
+            && data[candidate..candidate + LZ4_MIN_MATCH] == data[position..position + LZ4_MIN_MATCH];
+
+        if !matches {
+            position += 1;
+            continue;
+        }
+
+        let limit = data.len() - LZ4_LAST_LITERALS;
+        let length = LZ4_MIN_MATCH + data[position + LZ4_MIN_MATCH..limit].iter()
+            .zip(&data[candidate + LZ4_MIN_MATCH..])
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        lz4_sequence(&mut result, &data[anchor..position], Some((position - candidate, length)));
+
+        position += length;
+        anchor = position;
+    }
+
+    lz4_sequence(&mut result, &data[anchor..], None);
+
+    result
+}
+
+fn lz4_read_length(data: &[u8], position: &mut usize, mut length: usize) -> anyhow::Result<usize> {
+    loop {
+        let byte = *data.get(*position).context("LZ4 image data ends in the middle of a length")?;
+        *position += 1;
+        length += byte as usize;
+
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+fn lz4_decompress(data: &[u8], data_size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(data_size);
+    let mut position = 0;
+
+    while position < data.len() && result.len() < data_size {
+        let token = data[position];
+        position += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = lz4_read_length(data, &mut position, literals)?;
+        }
+
+        let chunk = data.get(position..position + literals).context("LZ4 image data ends in the middle of literals")?;
+        result.extend_from_slice(&chunk[..literals.min(data_size - result.len())]);
+        position += literals;
+
+        // Last sequence has no match
+        if position == data.len() {
+            break;
+        }
+
+        let offset = data.get(position..position + 2).context("LZ4 image data ends in the middle of an offset")?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        position += 2;
+
+        ensure!(offset > 0 && offset <= result.len(), "LZ4 match offset {offset} is outside of the data");
+
+        let mut length = (token & 0xF) as usize;
+        if length == 15 {
+            length = lz4_read_length(data, &mut position, length)?;
+        }
+        length += LZ4_MIN_MATCH;
+
+        // Matches can overlap the bytes they produce
+        let start = result.len() - offset;
+        for index in 0..length.min(data_size - result.len()) {
+            result.push(result[start + index]);
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn every_compression_survives_round_trip() {
+        let flat = vec![0x12; 1000];
+        let pattern = (0..3001).map(|i| (i % 7 * 31 + i / 500) as u8).collect::<Vec<_>>();
+        let noise = (0..777u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect::<Vec<_>>();
+
+        for compression in Compression::iter() {
+            for data in [&flat, &pattern, &noise, &vec![], &vec![1, 2, 3]] {
+                for pixel_size in [1, 2, 3] {
+                    let compressed = compression.compress(data, pixel_size);
+                    let decompressed = compression.decompress(&compressed, data.len()).unwrap();
+
+                    assert_eq!(&decompressed, data, "{compression} with {pixel_size} byte pixels");
+                }
+            }
+
+            assert_eq!(Compression::from_raw(compression.to_raw()).unwrap(), compression);
+        }
+
+        assert!(Compression::Rle.compress(&flat, 2).len() < 30);
+        assert!(Compression::Lz4.compress(&pattern, 1).len() < 100);
+    }
+
+    #[test]
+    fn output_stops_at_data_size() {
+        let flat = vec![7; 100_000];
+
+        for compression in Compression::iter() {
+            let compressed = compression.compress(&flat, 2);
+
+            assert_eq!(compression.decompress(&compressed, 10).unwrap(), vec![7; 10], "{compression}");
+            assert!(compression.decompress(&compressed, 100_001).is_err(), "{compression}");
+        }
+    }
+}
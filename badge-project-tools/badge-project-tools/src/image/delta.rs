@@ -0,0 +1,221 @@
+use crate::image::PixelFormat;
+use anyhow::ensure;
+
+/// Size of the rectangle in front of every delta frame
+pub const DELTA_HEADER_SIZE: usize = 8;
+
+/// Area of the frame that changed since the previous one, in pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeltaRect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16
+}
+
+impl DeltaRect {
+    pub fn to_bytes(self) -> Vec<u8> {
+        [self.x, self.y, self.width, self.height].iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        ensure!(data.len() >= DELTA_HEADER_SIZE, "Delta frame is too small to contain its rectangle");
+
+        let value = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        Ok(Self {
+            x: value(0),
+            y: value(1),
+            width: value(2),
+            height: value(3),
+        })
+    }
+}
+
+/// Layout of a whole encoded frame, what delta rectangles are cut from
+#[derive(Clone, Copy, Debug)]
+pub struct FrameLayout {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub alpha_plane: bool
+}
+
+impl FrameLayout {
+    fn palette_size(&self) -> usize {
+        self.format.palette_len() * 4
+    }
+
+    fn row_size(&self) -> usize {
+        self.format.row_size(self.width) as usize
+    }
+
+    /// Smallest part of a row that can be replaced, a pixel or a byte of packed pixels
+    fn unit_bytes(&self) -> usize {
+        self.format.pixel_size()
+    }
+
+    fn unit_pixels(&self) -> usize {
+        (8 / self.format.bits_per_pixel() as usize).max(1)
+    }
+
+    fn alpha_start(&self) -> usize {
+        self.palette_size() + self.row_size() * self.height as usize
+    }
+
+    /// Byte range of the rectangle in a row of pixel data
+    fn row_range(&self, rect: DeltaRect) -> std::ops::Range<usize> {
+        let start = rect.x as usize / self.unit_pixels() * self.unit_bytes();
+        let end = (rect.x as usize + rect.width as usize).div_ceil(self.unit_pixels()) * self.unit_bytes();
+
+        start..end.min(self.row_size())
+    }
+
+    pub fn payload_size(&self, rect: DeltaRect) -> usize {
+        let alpha = if self.alpha_plane { rect.width as usize } else { 0 };
+
+        self.palette_size() + (self.row_range(rect).len() + alpha) * rect.height as usize
+    }
+
+    fn changed(&self, previous: &[u8], current: &[u8], unit: usize, y: usize) -> bool {
+        let row = self.palette_size() + y * self.row_size();
+        let bytes = row + unit * self.unit_bytes()..row + (unit + 1) * self.unit_bytes();
+
+        if previous[bytes.clone()] != current[bytes] {
+            return true;
+        }
+
+        if !self.alpha_plane {
+            return false;
+        }
+
+        let row = self.alpha_start() + y * self.width as usize;
+        let pixels = unit * self.unit_pixels()..((unit + 1) * self.unit_pixels()).min(self.width as usize);
+
+        previous[row + pixels.start..row + pixels.end] != current[row + pixels.start..row + pixels.end]
+    }
+
+    /// Bounding box of the changed pixels, both frames are whole frames of this layout
+    pub fn changed_rect(&self, previous: &[u8], current: &[u8]) -> DeltaRect {
+        let units = self.row_size() / self.unit_bytes();
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+
+        for y in 0..self.height as usize {
+            for unit in 0..units {
+                if !self.changed(previous, current, unit, y) {
+                    continue;
+                }
+
+                bounds = Some(match bounds {
+                    Some((left, top, right, _)) => (left.min(unit), top, right.max(unit), y),
+                    None => (unit, y, unit, y)
+                });
+            }
+        }
+
+        let Some((left, top, right, bottom)) = bounds else {
+            return DeltaRect::default();
+        };
+
+        let x = left * self.unit_pixels();
+        let end = ((right + 1) * self.unit_pixels()).min(self.width as usize);
+
+        DeltaRect {
+            x: x as u16,
+            y: top as u16,
+            width: (end - x) as u16,
+            height: (bottom - top + 1) as u16,
+        }
+    }
+
+    /// Palette and the rectangle of the current frame
+    pub fn encode_delta(&self, previous: &[u8], current: &[u8]) -> (DeltaRect, Vec<u8>) {
+        let rect = self.changed_rect(previous, current);
+        let mut payload = current[..self.palette_size()].to_vec();
+
+        let row_range = self.row_range(rect);
+        for y in rect.y as usize..(rect.y + rect.height) as usize {
+            let row = self.palette_size() + y * self.row_size();
+            payload.extend_from_slice(&current[row + row_range.start..row + row_range.end]);
+        }
+
+        if self.alpha_plane {
+            for y in rect.y as usize..(rect.y + rect.height) as usize {
+                let row = self.alpha_start() + y * self.width as usize + rect.x as usize;
+                payload.extend_from_slice(&current[row..row + rect.width as usize]);
+            }
+        }
+
+        (rect, payload)
+    }
+
+    /// Turns the previous frame into the next one
+    pub fn apply_delta(&self, frame: &mut [u8], rect: DeltaRect, payload: &[u8]) -> anyhow::Result<()> {
+        ensure!(
+            rect.x as u32 + rect.width as u32 <= self.width && rect.y as u32 + rect.height as u32 <= self.height,
+            "Delta rectangle {}x{} at {}, {} is outside of the frame", rect.width, rect.height, rect.x, rect.y
+        );
+        ensure!(payload.len() >= self.payload_size(rect), "Delta frame is smaller than its rectangle");
+
+        let (palette, mut rest) = payload.split_at(self.palette_size());
+        frame[..palette.len()].copy_from_slice(palette);
+
+        let row_range = self.row_range(rect);
+        for y in rect.y as usize..(rect.y + rect.height) as usize {
+            let row = self.palette_size() + y * self.row_size();
+            let (bytes, next) = rest.split_at(row_range.len());
+            frame[row + row_range.start..row + row_range.end].copy_from_slice(bytes);
+            rest = next;
+        }
+
+        if self.alpha_plane {
+            for y in rect.y as usize..(rect.y + rect.height) as usize {
+                let row = self.alpha_start() + y * self.width as usize + rect.x as usize;
+                let (bytes, next) = rest.split_at(rect.width as usize);
+                frame[row..row + bytes.len()].copy_from_slice(bytes);
+                rest = next;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_rebuild_every_frame() {
+        for format in [PixelFormat::Rgb565, PixelFormat::L4, PixelFormat::L1, PixelFormat::Indexed4] {
+            for alpha_plane in [false, true] {
+                let layout = FrameLayout {
+                    width: 13,
+                    height: 7,
+                    format,
+                    alpha_plane: alpha_plane && format.palette_len() == 0,
+                };
+                let size = format.data_size(13, 7) as usize + if layout.alpha_plane { 13 * 7 } else { 0 };
+
+                let first = vec![0; size];
+                let mut second = first.clone();
+                second[size - 1] = 0xFF;
+                let mut third = second.clone();
+                third[layout.palette_size() + layout.row_size() * 2 + 1] = 0x42;
+
+                let mut frame = first.clone();
+                for (previous, current) in [(&first, &second), (&second, &third), (&third, &third)] {
+                    let (rect, payload) = layout.encode_delta(previous, current);
+                    assert_eq!(payload.len(), layout.payload_size(rect));
+
+                    layout.apply_delta(&mut frame, DeltaRect::from_bytes(&rect.to_bytes()).unwrap(), &payload).unwrap();
+                    assert_eq!(&frame, current, "{format} with alpha plane {alpha_plane}");
+                }
+
+                assert_eq!(layout.changed_rect(&third, &third), DeltaRect::default());
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::image::pixel_art::{block_size, box_downsample, integer_upscale, native_pixels};
+use crate::image::ConversionOptions;
+use anyhow::{bail, Context};
+use image::imageops::{self, replace, FilterType};
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+/// How the source image is scaled to the target size
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum FitMode {
+    /// Whole image is shown, the rest is background
+    #[default]
+    Contain,
+    /// Target is filled, the image is cropped
+    Cover,
+    /// Target is filled, aspect ratio isn't kept
+    Stretch,
+    /// Original size
+    None
+}
+
+/// Where the scaled image is placed in the target
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum Anchor {
+    #[strum(to_string = "Top Left")]
+    TopLeft,
+    Top,
+    #[strum(to_string = "Top Right")]
+    TopRight,
+    Left,
+    #[default]
+    Center,
+    Right,
+    #[strum(to_string = "Bottom Left")]
+    BottomLeft,
+    Bottom,
+    #[strum(to_string = "Bottom Right")]
+    BottomRight
+}
+
+impl Anchor {
+    /// Position of the image of the given size along both axes
+    fn position(self, (width, height): (u32, u32), (target_width, target_height): (u32, u32)) -> (i64, i64) {
+        let place = |size: u32, target: u32, side: i64| match side {
+            -1 => 0,
+            0 => target as i64 / 2 - size as i64 / 2,
+            _ => target as i64 - size as i64
+        };
+
+        let (x_side, y_side) = match self {
+            Anchor::TopLeft => (-1, -1),
+            Anchor::Top => (0, -1),
+            Anchor::TopRight => (1, -1),
+            Anchor::Left => (-1, 0),
+            Anchor::Center => (0, 0),
+            Anchor::Right => (1, 0),
+            Anchor::BottomLeft => (-1, 1),
+            Anchor::Bottom => (0, 1),
+            Anchor::BottomRight => (1, 1)
+        };
+
+        (place(width, target_width, x_side), place(height, target_height, y_side))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum ResizeFilter {
+    /// Keeps hard edges of pixel art
+    Nearest,
+    Bilinear,
+    Bicubic,
+    #[default]
+    Lanczos
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(value: ResizeFilter) -> Self {
+        match value {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Bilinear => FilterType::Triangle,
+            ResizeFilter::Bicubic => FilterType::CatmullRom,
+            ResizeFilter::Lanczos => FilterType::Lanczos3
+        }
+    }
+}
+
+/// Part of the source image that is used, in source pixels
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+/// Parses crop rectangles as `X,Y,WIDTH,HEIGHT`
+pub fn parse_crop(value: &str) -> anyhow::Result<CropRect> {
+    let parts = value.split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Crop '{value}' has to be X,Y,WIDTH,HEIGHT"))?;
+
+    let [x, y, width, height] = parts[..] else {
+        bail!("Crop '{value}' has to be X,Y,WIDTH,HEIGHT");
+    };
+
+    Ok(CropRect { x, y, width, height })
+}
+
+fn scaled_size((width, height): (u32, u32), (target_width, target_height): (u32, u32), cover: bool) -> (u32, u32) {
+    let width_ratio = target_width as f64 / width as f64;
+    let height_ratio = target_height as f64 / height as f64;
+
+    let ratio = if cover {
+        width_ratio.max(height_ratio)
+    } else {
+        width_ratio.min(height_ratio)
+    };
+
+    (
+        ((width as f64 * ratio).round() as u32).max(1),
+        ((height as f64 * ratio).round() as u32).max(1)
+    )
+}
+
+/// Scales the native pixels by whole numbers. Sources too large for the target are shrunk
+/// by averaging blocks, or by picking one pixel of every block with the nearest filter
+fn scale_pixel_art(image: &RgbaImage, (width, height): (u32, u32), options: &ConversionOptions) -> RgbaImage {
+    let native = native_pixels(image, block_size(image));
+    let (native_width, native_height) = native.dimensions();
+
+    let factor = match options.fit {
+        FitMode::Stretch => return imageops::resize(&native, width, height, FilterType::Nearest),
+        FitMode::Cover => width.div_ceil(native_width).max(height.div_ceil(native_height)),
+        FitMode::Contain | FitMode::None => (width / native_width).min(height / native_height)
+    };
+
+    if factor > 0 {
+        return integer_upscale(&native, factor);
+    }
+
+    let divisor = native_width.div_ceil(width).max(native_height.div_ceil(height));
+
+    if options.filter == ResizeFilter::Nearest {
+        native_pixels(&native, divisor)
+    } else {
+        box_downsample(&native, divisor)
+    }
+}
+
+/// Crops, scales and places the image on transparent background of the target size
+pub fn fit_image(input: &DynamicImage, width: u32, height: u32, options: &ConversionOptions) -> RgbaImage {
+    let mut image = RgbaImage::new(width, height);
+
+    let input = match options.crop {
+        Some(crop) => input.crop_imm(crop.x, crop.y, crop.width, crop.height),
+        None => input.clone()
+    };
+
+    // Trimmed border of atlas frames is put back, so they scale like the whole frame
+    let input = match options.trim {
+        Some(trim) => {
+            let mut frame = RgbaImage::new(trim.source_width, trim.source_height);
+            replace(&mut frame, &input.to_rgba8(), trim.x as i64, trim.y as i64);
+
+            DynamicImage::ImageRgba8(frame)
+        }
+        None => input
+    };
+
+    if input.width() == 0 || input.height() == 0 || width == 0 || height == 0 {
+        return image;
+    }
+
+    let filter = options.filter.into();
+    let source_size = (input.width(), input.height());
+    let pixels = input.to_rgba8();
+
+    let scaled_image = if options.fit != FitMode::None && options.pixel_art.applies_to(&pixels) {
+        scale_pixel_art(&pixels, (width, height), options)
+    } else {
+        match options.fit {
+            // Same rounding as the tools always had
+            FitMode::Contain => input.resize(width, height, filter),
+            FitMode::Cover => {
+                let (scaled_width, scaled_height) = scaled_size(source_size, (width, height), true);
+                input.resize_exact(scaled_width, scaled_height, filter)
+            }
+            FitMode::Stretch => input.resize_exact(width, height, filter),
+            FitMode::None => input
+        }.to_rgba8()
+    };
+
+    let (x, y) = options.anchor.position(scaled_image.dimensions(), (width, height));
+
+    replace(&mut image, &scaled_image, x + options.offset_x as i64, y + options.offset_y as i64);
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::pixel_art::PixelArtMode;
+    use image::Rgba;
+
+    #[test]
+    fn fit_modes_place_image() {
+        // 4x2 source, left half red and right half blue
+        let source = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 2, |x, _| {
+            if x < 2 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) }
+        }));
+
+        let mut options = ConversionOptions {
+            filter: ResizeFilter::Nearest,
+            ..Default::default()
+        };
+
+        let contained = fit_image(&source, 4, 4, &options);
+        assert_eq!(contained.get_pixel(0, 0).0[3], 0);
+        assert_eq!(contained.get_pixel(0, 1), &Rgba([255, 0, 0, 255]));
+
+        options.fit = FitMode::Cover;
+        let covered = fit_image(&source, 4, 4, &options);
+        assert_eq!(covered.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(covered.get_pixel(3, 3), &Rgba([0, 0, 255, 255]));
+
+        options.fit = FitMode::None;
+        options.anchor = Anchor::BottomRight;
+        options.offset_x = -1;
+        let placed = fit_image(&source, 6, 6, &options);
+        assert_eq!(placed.get_pixel(4, 5), &Rgba([0, 0, 255, 255]));
+        assert_eq!(placed.get_pixel(5, 5).0[3], 0);
+
+        options.fit = FitMode::Stretch;
+        options.offset_x = 0;
+        options.crop = Some(parse_crop("2, 0, 2, 2").unwrap());
+        let cropped = fit_image(&source, 3, 3, &options);
+        assert!(cropped.pixels().all(|pixel| pixel == &Rgba([0, 0, 255, 255])));
+    }
+
+    #[test]
+    fn few_colors_scale_smoothly_unless_asked() {
+        // Three colors, without larger blocks of pixels
+        let logo = DynamicImage::ImageRgba8(RgbaImage::from_fn(100, 100, |x, y| Rgba([((x * y) % 3 * 100) as u8, 128, 255, 255])));
+        let opaque_rows = |image: &RgbaImage| image.rows().filter(|row| row.clone().any(|pixel| pixel.0[3] > 0)).count();
+
+        let mut options = ConversionOptions::default();
+        assert_eq!(opaque_rows(&fit_image(&logo, 320, 480, &options)), 320);
+
+        options.pixel_art = PixelArtMode::Detect;
+        assert_eq!(opaque_rows(&fit_image(&logo, 320, 480, &options)), 300);
+    }
+}
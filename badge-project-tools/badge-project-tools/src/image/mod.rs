@@ -0,0 +1,592 @@
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use crate::image::alpha::{flatten, parse_color, AlphaMode, Background, ALPHA_THRESHOLD};
+use crate::image::compress::Compression;
+use crate::image::fit::{fit_image, parse_crop, Anchor, CropRect, FitMode, ResizeFilter};
+use crate::image::pixel_art::PixelArtMode;
+use crate::image::sheet::{parse_trim, Trim};
+use crate::image::quantize::{quantize, Quantization};
+use crate::target::TargetArgs;
+use color_quant::NeuQuant;
+use image::{DynamicImage, ImageResult, Rgb, RgbImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+pub mod alpha;
+pub mod animated;
+pub mod compress;
+pub mod delta;
+pub mod fit;
+pub mod pixel_art;
+pub mod quantize;
+pub mod sheet;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big
+}
+
+/// Pixel layout of the display. Formats with less than a byte per pixel are packed from the most significant bit
+/// and every row starts on a new byte. Indexed formats start with the palette, 4 bytes per color in BGRA order
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum PixelFormat {
+    /// Byte order of the target applies
+    #[default]
+    #[strum(to_string = "RGB565")]
+    Rgb565,
+    /// Stored as BGR in little endian and RGB in big endian
+    #[strum(to_string = "RGB888")]
+    Rgb888,
+    #[strum(to_string = "RGB332")]
+    Rgb332,
+    #[strum(to_string = "L8")]
+    L8,
+    #[strum(to_string = "L4")]
+    L4,
+    /// Set bits are white
+    #[strum(to_string = "L1")]
+    L1,
+    #[strum(to_string = "Indexed 4-bit")]
+    Indexed4,
+    #[strum(to_string = "Indexed 8-bit")]
+    Indexed8
+}
+
+impl PixelFormat {
+    pub fn bits_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 16,
+            PixelFormat::Rgb888 => 24,
+            PixelFormat::Rgb332 | PixelFormat::L8 | PixelFormat::Indexed8 => 8,
+            PixelFormat::L4 | PixelFormat::Indexed4 => 4,
+            PixelFormat::L1 => 1
+        }
+    }
+
+    /// Number of colors in the palette, zero for formats without one
+    pub fn palette_len(self) -> usize {
+        match self {
+            PixelFormat::Indexed4 => 16,
+            PixelFormat::Indexed8 => 256,
+            _ => 0
+        }
+    }
+
+    /// Bytes RLE repeats, formats with less than a byte per pixel repeat whole bytes
+    pub fn pixel_size(self) -> usize {
+        (self.bits_per_pixel() as usize / 8).max(1)
+    }
+
+    pub fn row_size(self, width: u32) -> u64 {
+        (width as u64 * self.bits_per_pixel() as u64).div_ceil(8)
+    }
+
+    /// Size of the image data in bytes, with the palette
+    pub fn data_size(self, width: u32, height: u32) -> u64 {
+        self.palette_len() as u64 * 4 + self.row_size(width) * height as u64
+    }
+}
+
+/// How a source image is turned into image data, set per image in the character
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, clap::Args)]
+#[serde(default)]
+pub struct ConversionOptions {
+    #[arg(long, value_enum, help = "How colors are reduced to the ones of the pixel format", default_value_t)]
+    pub quantization: Quantization,
+    #[arg(long, help = "Compare colors and spread dithering errors in linear light instead of sRGB values", default_value_t = false)]
+    pub gamma_aware: bool,
+    #[arg(long, value_enum, help = "What transparent pixels and the letterbox area are composited over", default_value_t)]
+    pub background: Background,
+    #[arg(long, value_parser = parse_color, help = "Background color as #RRGGBB", default_value = "#000000")]
+    pub background_color: (u8, u8, u8),
+    #[arg(long, value_enum, help = "How transparency is passed on to the firmware", default_value_t)]
+    pub alpha: AlphaMode,
+    #[arg(long, help = "Multiply colors by alpha, only with the alpha channel", default_value_t = false)]
+    pub premultiplied: bool,
+    #[arg(long, value_parser = parse_color, help = "Color of transparent pixels with color key as #RRGGBB", default_value = "#FF00FF")]
+    pub color_key: (u8, u8, u8),
+    #[arg(long, value_enum, help = "How the image is scaled to the target size", default_value_t)]
+    pub fit: FitMode,
+    #[arg(long, value_enum, help = "Where the scaled image is placed", default_value_t)]
+    pub anchor: Anchor,
+    #[arg(long, allow_negative_numbers = true, help = "Horizontal offset from the anchor in pixels", default_value_t = 0)]
+    pub offset_x: i32,
+    #[arg(long, allow_negative_numbers = true, help = "Vertical offset from the anchor in pixels", default_value_t = 0)]
+    pub offset_y: i32,
+    #[arg(long, value_enum, help = "Filter used for scaling", default_value_t)]
+    pub filter: ResizeFilter,
+    #[arg(long, value_parser = parse_crop, help = "Part of the source image to use as X,Y,WIDTH,HEIGHT")]
+    pub crop: Option<CropRect>,
+    #[arg(long, value_parser = parse_trim, help = "Empty border put back around the cropped area before scaling, as X,Y of the area and WIDTH,HEIGHT of the whole frame")]
+    pub trim: Option<Trim>,
+    #[arg(long, value_enum, help = "Scale pixel art by whole numbers so pixels stay sharp", default_value_t)]
+    pub pixel_art: PixelArtMode,
+    #[arg(long, value_enum, help = "Compression of the image file, for smaller and faster SD card reads", default_value_t)]
+    pub compression: Compression
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            quantization: Quantization::default(),
+            gamma_aware: false,
+            background: Background::default(),
+            background_color: (0, 0, 0),
+            alpha: AlphaMode::default(),
+            premultiplied: false,
+            color_key: (255, 0, 255),
+            fit: FitMode::default(),
+            anchor: Anchor::default(),
+            offset_x: 0,
+            offset_y: 0,
+            filter: ResizeFilter::default(),
+            crop: None,
+            trim: None,
+            pixel_art: PixelArtMode::default(),
+            compression: Compression::default(),
+        }
+    }
+}
+
+impl ConversionOptions {
+    /// Whether an A8 plane follows the pixel data
+    pub fn has_alpha_plane(&self, format: PixelFormat) -> bool {
+        self.alpha == AlphaMode::Channel && format.palette_len() == 0
+    }
+
+    /// Size of the image data in bytes, with the alpha plane
+    pub fn data_size(&self, format: PixelFormat, width: u32, height: u32) -> u64 {
+        let alpha_plane = if self.has_alpha_plane(format) {
+            width as u64 * height as u64
+        } else {
+            0
+        };
+
+        format.data_size(width, height) + alpha_plane
+    }
+}
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    about="Converts any image data into binary file that can be directly loaded by microcontroller to be sent as display data",
+    long_about=None
+)]
+pub struct ImageCli {
+    #[arg(help = "Image file to process")]
+    input_file: PathBuf,
+    #[arg(help = "Path to write to")]
+    output_file: PathBuf,
+    #[arg(long = "width", help = "Width of the result raw image, display width of the target by default")]
+    width: Option<u32>,
+    #[arg(long = "height", help = "Height of the result raw image, display height of the target by default")]
+    height: Option<u32>,
+    #[arg(long, help = "Pixel format of the result raw image, pixel format of the target by default")]
+    pixel_format: Option<PixelFormat>,
+    #[arg(short = 'l', help = "To use little endian instead of the byte order of animation frames of the target", default_value_t = false)]
+    little_endian: bool,
+    #[command(flatten)]
+    conversion: ConversionOptions,
+    #[command(flatten)]
+    target: TargetArgs
+}
+pub fn process_image(cli: ImageCli) -> anyhow::Result<()> {
+    let mut profile = cli.target.profile()?;
+
+    if let Some(format) = cli.pixel_format {
+        profile.pixel_format = format;
+    }
+
+    profile.check()?;
+
+    let bytes = fs::read(cli.input_file)?;
+
+    let byte_order = if cli.little_endian { ByteOrder::Little } else { profile.frame_byte_order };
+    let format = profile.pixel_format;
+
+    let result: Vec<u8> = encode_image_data(
+        &bytes,
+        cli.width.unwrap_or(profile.width),
+        cli.height.unwrap_or(profile.height),
+        format,
+        byte_order,
+        &cli.conversion
+    )?;
+
+    fs::write(cli.output_file, cli.conversion.compression.compress(&result, format.pixel_size()))?;
+
+    Ok(())
+}
+
+
+pub fn encode_image_data(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    byte_order: ByteOrder,
+    options: &ConversionOptions
+) -> ImageResult<Vec<u8>> {
+    Ok(encode_image(&image::load_from_memory(input)?, width, height, format, byte_order, options))
+}
+
+/// Encodes already decoded image
+pub fn encode_image(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    byte_order: ByteOrder,
+    options: &ConversionOptions
+) -> Vec<u8> {
+    encode_pixels(&fit_image(image, width, height, options), format, byte_order, options)
+}
+
+/// Encodes already sized image
+pub fn encode_pixels(image: &RgbaImage, format: PixelFormat, byte_order: ByteOrder, options: &ConversionOptions) -> Vec<u8> {
+    let transparent = image.pixels()
+        .map(|pixel| options.alpha != AlphaMode::Opaque && pixel.0[3] < ALPHA_THRESHOLD)
+        .collect::<Vec<_>>();
+    let flat = flatten(image, options);
+
+    if format.palette_len() > 0 {
+        return encode_indexed(&flat, &transparent, format, options);
+    }
+
+    let mut quantized = quantize(&flat, format, &[], options);
+
+    if options.alpha == AlphaMode::ColorKey {
+        let (r, g, b) = options.color_key;
+
+        for (pixel, transparent) in quantized.pixels_mut().zip(&transparent) {
+            if *transparent {
+                *pixel = Rgb([r, g, b]);
+            }
+        }
+    }
+
+    let mut data = encode_quantized(&quantized, format, byte_order);
+
+    if options.alpha == AlphaMode::Channel {
+        data.extend(image.pixels().map(|pixel| pixel.0[3]));
+    }
+
+    data
+}
+
+/// Transparent pixels take the first palette color, the other colors are picked from the rest
+fn encode_indexed(image: &RgbImage, transparent: &[bool], format: PixelFormat, options: &ConversionOptions) -> Vec<u8> {
+    let reserved = options.alpha.reserves_palette_color() as usize;
+
+    let opaque = image.pixels()
+        .zip(transparent)
+        .filter(|(_, transparent)| !**transparent)
+        .map(|(pixel, _)| *pixel)
+        .collect::<Vec<_>>();
+    let palette = build_palette(&opaque, format.palette_len() - reserved);
+    let image = quantize(image, format, &palette, options);
+
+    let mut data = vec![];
+
+    match options.alpha {
+        AlphaMode::Opaque => {}
+        AlphaMode::ColorKey => {
+            let (r, g, b) = options.color_key;
+            data.extend([b, g, r, 255]);
+        }
+        AlphaMode::Channel => data.extend([0; 4])
+    }
+
+    data.extend(palette.iter().flat_map(|[r, g, b]| [*b, *g, *r, 255]));
+    data.resize(format.palette_len() * 4, 0);
+
+    // Quantized colors are in the palette, the search is only needed without quantization
+    let mut indices = HashMap::new();
+
+    data.extend(pack_rows(image.width(), format.bits_per_pixel(), image.pixels().zip(transparent).map(|(pixel, transparent)| {
+        if *transparent {
+            0
+        } else {
+            *indices.entry(pixel.0).or_insert_with(|| nearest_index(&palette, pixel.0) + reserved as u8)
+        }
+    })));
+
+    data
+}
+
+fn encode_quantized(image: &RgbImage, format: PixelFormat, byte_order: ByteOrder) -> Vec<u8> {
+    match format {
+        PixelFormat::Rgb565 => image.pixels()
+            .flat_map(|Rgb([r, g, b])| match byte_order {
+                ByteOrder::Little => rgb_to_565(*r, *g, *b).to_le_bytes(),
+                ByteOrder::Big => rgb_to_565(*r, *g, *b).to_be_bytes()
+            })
+            .collect(),
+        PixelFormat::Rgb888 => image.pixels()
+            .flat_map(|Rgb([r, g, b])| match byte_order {
+                ByteOrder::Little => [*b, *g, *r],
+                ByteOrder::Big => [*r, *g, *b]
+            })
+            .collect(),
+        PixelFormat::Rgb332 => pack_rows(image.width(), 8, image.pixels().map(|Rgb([r, g, b])| rgb_to_332(*r, *g, *b))),
+        PixelFormat::L8 => pack_rows(image.width(), 8, image.pixels().map(|Rgb([r, g, b])| luma(*r, *g, *b))),
+        PixelFormat::L4 => pack_rows(image.width(), 4, image.pixels().map(|Rgb([r, g, b])| luma(*r, *g, *b) >> 4)),
+        PixelFormat::L1 => pack_rows(image.width(), 1, image.pixels().map(|Rgb([r, g, b])| (luma(*r, *g, *b) >= 128) as u8)),
+        PixelFormat::Indexed4 | PixelFormat::Indexed8 => unreachable!("Indexed formats are encoded with the palette")
+    }
+}
+
+fn nearest_index(palette: &[[u8; 3]], color: [u8; 3]) -> u8 {
+    palette.iter()
+        .enumerate()
+        .min_by_key(|(_, entry)| entry.iter()
+            .zip(color)
+            .map(|(a, b)| (*a as i32 - b as i32).pow(2))
+            .sum::<i32>())
+        .map(|(index, _)| index as u8)
+        .unwrap_or_default()
+}
+
+/// Images with few colors get them exactly, others get them picked by NeuQuant
+fn build_palette(pixels: &[Rgb<u8>], len: usize) -> Vec<[u8; 3]> {
+    let mut colors = pixels.iter()
+        .map(|pixel| pixel.0)
+        .collect::<Vec<_>>();
+    colors.sort_unstable();
+    colors.dedup();
+
+    if colors.len() <= len {
+        return colors;
+    }
+
+    let rgba = pixels.iter()
+        .flat_map(|Rgb([r, g, b])| [*r, *g, *b, 255])
+        .collect::<Vec<_>>();
+
+    let quant = NeuQuant::new(10, len, &rgba);
+
+    quant.color_map_rgb()
+        .chunks_exact(3)
+        .map(|color| [color[0], color[1], color[2]])
+        .collect()
+}
+
+/// Packs values of up to 8 bits per pixel, starting every row on a new byte
+fn pack_rows(width: u32, bits: u32, values: impl IntoIterator<Item = u8>) -> Vec<u8> {
+    let mut data = vec![];
+    let mut byte = 0;
+    let mut filled = 0;
+
+    for (index, value) in values.into_iter().enumerate() {
+        byte |= value << (8 - bits - filled);
+        filled += bits;
+
+        if filled == 8 || (index as u32 + 1).is_multiple_of(width) {
+            data.push(byte);
+            byte = 0;
+            filled = 0;
+        }
+    }
+
+    data
+}
+
+pub fn rgb_to_565(r: u8, g: u8, b: u8) -> u16 {
+    let r5 = r / 8;
+    let g6 = g / 4;
+    let b5 = b / 8;
+
+    (r5 as u16) << 11 | (g6 as u16) << 5 | b5 as u16
+}
+
+pub fn rgb_to_332(r: u8, g: u8, b: u8) -> u8 {
+    (r & 0xE0) | (g & 0xE0) >> 3 | b >> 6
+}
+
+/// Perceived brightness, same weights as ITU-R BT.601
+pub fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+
+pub fn decode_image_data(data: &[u8], width: u32, height: u32, format: PixelFormat, byte_order: ByteOrder) -> Option<RgbImage> {
+    if (data.len() as u64) < format.data_size(width, height) {
+        return None;
+    }
+
+    let (palette, pixels) = data.split_at(format.palette_len() * 4);
+    let row_size = format.row_size(width) as usize;
+    let bits = format.bits_per_pixel();
+
+    Some(RgbImage::from_fn(width, height, |x, y| {
+        let row = &pixels[y as usize * row_size..][..row_size];
+        let start = (x * bits / 8) as usize;
+
+        let (r, g, b) = match format {
+            PixelFormat::Rgb565 => {
+                let bytes = [row[start], row[start + 1]];
+
+                rgb_from_565(match byte_order {
+                    ByteOrder::Little => u16::from_le_bytes(bytes),
+                    ByteOrder::Big => u16::from_be_bytes(bytes)
+                })
+            }
+            PixelFormat::Rgb888 => match byte_order {
+                ByteOrder::Little => (row[start + 2], row[start + 1], row[start]),
+                ByteOrder::Big => (row[start], row[start + 1], row[start + 2])
+            },
+            PixelFormat::Rgb332 => rgb_from_332(row[start]),
+            PixelFormat::L8 => {
+                let value = row[start];
+                (value, value, value)
+            }
+            PixelFormat::L4 => {
+                let value = read_packed(row, x, 4) * 17;
+                (value, value, value)
+            }
+            PixelFormat::L1 => {
+                let value = read_packed(row, x, 1) * 255;
+                (value, value, value)
+            }
+            PixelFormat::Indexed4 | PixelFormat::Indexed8 => {
+                let color = &palette[read_packed(row, x, bits) as usize * 4..];
+                (color[2], color[1], color[0])
+            }
+        };
+
+        Rgb([r, g, b])
+    }))
+}
+
+/// Alpha of every pixel of image data, as far as the alpha mode of the conversion stores it
+pub fn decode_alpha(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    byte_order: ByteOrder,
+    options: &ConversionOptions
+) -> Option<Vec<u8>> {
+    let pixel_count = width as usize * height as usize;
+
+    if (data.len() as u64) < options.data_size(format, width, height) {
+        return None;
+    }
+
+    if options.alpha == AlphaMode::Opaque {
+        return Some(vec![255; pixel_count]);
+    }
+
+    if format.palette_len() > 0 {
+        let pixels = &data[format.palette_len() * 4..];
+        let row_size = format.row_size(width) as usize;
+
+        return Some((0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let index = read_packed(&pixels[y as usize * row_size..], x, format.bits_per_pixel());
+                if index == 0 { 0 } else { 255 }
+            })
+            .collect());
+    }
+
+    if options.alpha == AlphaMode::Channel {
+        let start = format.data_size(width, height) as usize;
+        return Some(data[start..start + pixel_count].to_vec());
+    }
+
+    // Key color as it comes out of the pixel format
+    let (r, g, b) = options.color_key;
+    let key_data = encode_quantized(&RgbImage::from_pixel(1, 1, Rgb([r, g, b])), format, byte_order);
+    let key = decode_image_data(&key_data, 1, 1, format, byte_order)?;
+    let key = key.get_pixel(0, 0);
+
+    let image = decode_image_data(data, width, height, format, byte_order)?;
+
+    Some(image.pixels()
+        .map(|pixel| if pixel == key { 0 } else { 255 })
+        .collect())
+}
+
+fn read_packed(row: &[u8], x: u32, bits: u32) -> u8 {
+    let offset = x * bits;
+    let byte = row[(offset / 8) as usize];
+
+    (byte >> (8 - bits - offset % 8)) & ((1u16 << bits) - 1) as u8
+}
+
+pub fn rgb_from_565(color: u16) -> (u8, u8, u8) {
+    let r5 = (color >> 11) & 0x1F;
+    let g6 = (color >> 5) & 0x3F;
+    let b5 = color & 0x1F;
+
+    (
+        (r5 << 3 | r5 >> 2) as u8,
+        (g6 << 2 | g6 >> 4) as u8,
+        (b5 << 3 | b5 >> 2) as u8
+    )
+}
+
+pub fn rgb_from_332(color: u8) -> (u8, u8, u8) {
+    let r3 = (color >> 5) as u32;
+    let g3 = ((color >> 2) & 0x7) as u32;
+    let b2 = (color & 0x3) as u32;
+
+    ((r3 * 255 / 7) as u8, (g3 * 255 / 7) as u8, (b2 * 255 / 3) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn every_format_survives_round_trip() {
+        // Odd width, so packed rows need padding
+        let image = RgbaImage::from_fn(5, 3, |x, y| {
+            if (x + y) % 2 == 0 { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 255]) }
+        });
+
+        for format in PixelFormat::iter() {
+            for byte_order in ByteOrder::iter() {
+                let data = encode_pixels(&image, format, byte_order, &ConversionOptions::default());
+                assert_eq!(data.len() as u64, format.data_size(5, 3), "{format}");
+
+                let decoded = decode_image_data(&data, 5, 3, format, byte_order).unwrap();
+
+                for (expected, actual) in image.pixels().zip(decoded.pixels()) {
+                    for (expected, actual) in expected.0.iter().zip(actual.0) {
+                        // Palettes are only approximated
+                        assert!(expected.abs_diff(actual) <= 8, "{format} {byte_order}: {expected} != {actual}");
+                    }
+                }
+            }
+        }
+
+        // Left column is transparent
+        let image = RgbaImage::from_fn(5, 3, |x, _| Rgba([255, 255, 255, if x == 0 { 0 } else { 255 }]));
+
+        for format in PixelFormat::iter() {
+            for alpha in [AlphaMode::ColorKey, AlphaMode::Channel] {
+                let options = ConversionOptions {
+                    alpha,
+                    ..Default::default()
+                };
+
+                let data = encode_pixels(&image, format, ByteOrder::Little, &options);
+                assert_eq!(data.len() as u64, options.data_size(format, 5, 3), "{format} {alpha}");
+
+                let decoded = decode_alpha(&data, 5, 3, format, ByteOrder::Little, &options).unwrap();
+                let expected = image.pixels().map(|pixel| pixel.0[3]).collect::<Vec<_>>();
+
+                assert_eq!(decoded, expected, "{format} {alpha}");
+            }
+        }
+
+        assert_eq!(PixelFormat::L1.row_size(5), 1);
+        assert_eq!(PixelFormat::L4.row_size(5), 3);
+        assert!(decode_image_data(&[0; 2], 5, 3, PixelFormat::L1, ByteOrder::Big).is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use image::{Rgba, RgbaImage, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use strum::{Display, EnumIter};
+
+/// The firmware doubles upscaled images, the format only stores whether they are
+pub const UPSCALE_FACTOR: u32 = 2;
+
+/// Sources with at most this many colors are pixel art even when drawn at their native size
+const PIXEL_ART_COLORS: usize = 32;
+
+/// Whether the source gets scaled by whole numbers, so pixels stay sharp squares. Off unless asked for,
+/// since it changes the size existing images are scaled to
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum PixelArtMode {
+    /// Upscaled sprites and images with few colors are pixel art
+    Detect,
+    Always,
+    #[default]
+    Never
+}
+
+impl PixelArtMode {
+    pub fn applies_to(self, image: &RgbaImage) -> bool {
+        match self {
+            PixelArtMode::Detect => block_size(image) > 1 || has_few_colors(image),
+            PixelArtMode::Always => true,
+            PixelArtMode::Never => false
+        }
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Size of the squares the image is drawn with, 1 unless it's pixel art exported at a larger scale
+pub fn block_size(image: &RgbaImage) -> u32 {
+    let (width, height) = image.dimensions();
+    let mut size = 0;
+
+    // Every run of the same color along rows and columns is a multiple of the block size
+    let lines = (0..height).map(|y| (0..width).map(move |x| (x, y)).collect::<Vec<_>>())
+        .chain((0..width).map(|x| (0..height).map(move |y| (x, y)).collect::<Vec<_>>()));
+
+    for line in lines {
+        let mut run = 0;
+        let mut previous = None;
+
+        for (x, y) in line {
+            let pixel = image.get_pixel(x, y);
+
+            if previous.is_some_and(|previous| previous != pixel) {
+                size = gcd(size, run);
+                run = 0;
+            }
+
+            previous = Some(pixel);
+            run += 1;
+        }
+
+        size = gcd(size, run);
+
+        if size == 1 {
+            return 1;
+        }
+    }
+
+    size.max(1)
+}
+
+fn has_few_colors(image: &RgbaImage) -> bool {
+    let mut colors = HashSet::new();
+
+    image.pixels().all(|pixel| {
+        colors.insert(pixel.0);
+        colors.len() <= PIXEL_ART_COLORS
+    })
+}
+
+/// The image with every block of pixels shrunk to one
+pub fn native_pixels(image: &RgbaImage, block: u32) -> RgbaImage {
+    RgbaImage::from_fn(image.width() / block, image.height() / block, |x, y| *image.get_pixel(x * block, y * block))
+}
+
+/// Averages every square of `factor` pixels, weighted by alpha so transparent colors don't bleed in
+pub fn box_downsample(image: &RgbaImage, factor: u32) -> RgbaImage {
+    let width = image.width().div_ceil(factor);
+    let height = image.height().div_ceil(factor);
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let mut sums = [0u64; 3];
+        let mut alpha = 0u64;
+        let mut count = 0u64;
+
+        for source_y in y * factor..((y + 1) * factor).min(image.height()) {
+            for source_x in x * factor..((x + 1) * factor).min(image.width()) {
+                let Rgba([r, g, b, a]) = *image.get_pixel(source_x, source_y);
+
+                for (sum, value) in sums.iter_mut().zip([r, g, b]) {
+                    *sum += value as u64 * a as u64;
+                }
+                alpha += a as u64;
+                count += 1;
+            }
+        }
+
+        let [r, g, b] = sums.map(|sum| sum.checked_div(alpha).unwrap_or_default() as u8);
+
+        Rgba([r, g, b, (alpha / count) as u8])
+    })
+}
+
+pub fn integer_upscale(image: &RgbaImage, factor: u32) -> RgbaImage {
+    RgbaImage::from_fn(image.width() * factor, image.height() * factor, |x, y| *image.get_pixel(x / factor, y / factor))
+}
+
+/// What the firmware shows for an upscaled image, every pixel repeated
+pub fn firmware_upscale(image: &RgbImage, factor: u32) -> RgbImage {
+    RgbImage::from_fn(image.width() * factor, image.height() * factor, |x, y| *image.get_pixel(x / factor, y / factor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upscaled_sprite_is_detected() {
+        let sprite = RgbaImage::from_fn(3, 2, |x, y| Rgba([(x * 80) as u8, (y * 120) as u8, 0, 255]));
+        let exported = integer_upscale(&sprite, 4);
+
+        assert_eq!(block_size(&exported), 4);
+        assert_eq!(native_pixels(&exported, 4), sprite);
+        assert_eq!(box_downsample(&exported, 4), sprite);
+
+        // Photo like gradient
+        let photo = RgbaImage::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255]));
+        assert_eq!(block_size(&photo), 1);
+        assert!(!PixelArtMode::Detect.applies_to(&photo));
+        assert!(PixelArtMode::Detect.applies_to(&exported));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::image::{ConversionOptions, PixelFormat};
+use image::{Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use strum::{Display, EnumIter};
+
+/// How colors are reduced to the ones the pixel format can show
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display, EnumIter, clap::ValueEnum)]
+pub enum Quantization {
+    /// Drops the low bits, what the tools always did
+    #[default]
+    Truncate,
+    #[strum(to_string = "Round to Nearest")]
+    Nearest,
+    #[strum(to_string = "Ordered (Bayer)")]
+    Bayer,
+    #[strum(to_string = "Floyd-Steinberg")]
+    FloydSteinberg,
+    #[strum(to_string = "Blue Noise")]
+    BlueNoise
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Colors the pixel format can show
+enum Levels {
+    /// Highest level of every channel
+    Channels([u32; 3]),
+    /// Highest gray level
+    Luma(u32),
+    /// Palette colors in the working space
+    Palette(Vec<[f32; 3]>)
+}
+
+struct Quantizer {
+    format: PixelFormat,
+    levels: Levels,
+    gamma_aware: bool
+}
+
+impl Quantizer {
+    fn new(format: PixelFormat, palette: &[[u8; 3]], gamma_aware: bool) -> Self {
+        let levels = match format {
+            PixelFormat::Rgb565 => Levels::Channels([31, 63, 31]),
+            PixelFormat::Rgb888 => Levels::Channels([255; 3]),
+            PixelFormat::Rgb332 => Levels::Channels([7, 7, 3]),
+            PixelFormat::L8 => Levels::Luma(255),
+            PixelFormat::L4 => Levels::Luma(15),
+            PixelFormat::L1 => Levels::Luma(1),
+            PixelFormat::Indexed4 | PixelFormat::Indexed8 => Levels::Palette(vec![])
+        };
+
+        let mut quantizer = Self { format, levels, gamma_aware };
+
+        if let Levels::Palette(_) = quantizer.levels {
+            quantizer.levels = Levels::Palette(palette.iter().map(|color| quantizer.color_space(*color)).collect());
+        }
+
+        quantizer
+    }
+
+    /// Value of the channel in the working space, 0 to 255 either way
+    fn space(&self, value: u8) -> f32 {
+        if self.gamma_aware {
+            to_linear(value as f32)
+        } else {
+            value as f32
+        }
+    }
+
+    fn color_space(&self, color: [u8; 3]) -> [f32; 3] {
+        color.map(|value| self.space(value))
+    }
+
+    /// Value the display shows for the level, same as decoding does
+    fn level_value(&self, channel: usize, level: u32, max: u32) -> u8 {
+        match self.format {
+            PixelFormat::Rgb565 if channel == 1 => (level << 2 | level >> 4) as u8,
+            PixelFormat::Rgb565 => (level << 3 | level >> 2) as u8,
+            _ => (level * 255 / max) as u8
+        }
+    }
+
+    /// Picks between the levels around the wanted value, the higher one is picked when the wanted value is further
+    /// than the threshold on the way to it
+    fn pick_level(&self, channel: usize, wanted: f32, max: u32, threshold: f32) -> u8 {
+        let value = |level| self.space(self.level_value(channel, level, max));
+
+        if wanted <= value(0) {
+            return self.level_value(channel, 0, max);
+        }
+
+        if wanted >= value(max) {
+            return self.level_value(channel, max, max);
+        }
+
+        let (mut low, mut high) = (0, max);
+
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+
+            if value(middle) <= wanted {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+
+        let fraction = (wanted - value(low)) / (value(high) - value(low));
+
+        self.level_value(channel, if fraction > threshold { high } else { low }, max)
+    }
+
+    /// Threshold is 0.5 for rounding to nearest, dithering moves it around
+    fn pick(&self, wanted: [f32; 3], threshold: f32) -> [u8; 3] {
+        match &self.levels {
+            Levels::Channels(max) => [0, 1, 2].map(|channel| self.pick_level(channel, wanted[channel], max[channel], threshold)),
+            Levels::Luma(max) => {
+                let [r, g, b] = wanted;
+                let value = self.pick_level(0, (r * 299.0 + g * 587.0 + b * 114.0) / 1000.0, *max, threshold);
+
+                [value; 3]
+            }
+            Levels::Palette(colors) => {
+                // Palette colors aren't evenly spread, the threshold shifts the wanted color instead
+                let spread = 255.0 / (colors.len() as f32).cbrt();
+                let wanted = wanted.map(|value| value + (threshold - 0.5) * spread);
+
+                let index = colors.iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| distance(a, &wanted).total_cmp(&distance(b, &wanted)))
+                    .map(|(index, _)| index)
+                    .unwrap_or_default();
+
+                self.palette_color(index)
+            }
+        }
+    }
+
+    fn palette_color(&self, index: usize) -> [u8; 3] {
+        let Some(color) = (match &self.levels {
+            Levels::Palette(colors) => colors.get(index),
+            _ => None
+        }) else {
+            return [0; 3];
+        };
+
+        color.map(|value| {
+            if self.gamma_aware {
+                to_srgb(value).round() as u8
+            } else {
+                value.round() as u8
+            }
+        })
+    }
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// sRGB value to linear light, both from 0 to 255
+fn to_linear(value: f32) -> f32 {
+    let value = value / 255.0;
+
+    255.0 * if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_srgb(value: f32) -> f32 {
+    let value = (value / 255.0).clamp(0.0, 1.0);
+
+    255.0 * if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Threshold of the 8x8 Bayer matrix
+fn bayer(x: u32, y: u32) -> f32 {
+    let mut value = 0;
+
+    for bit in 0..3 {
+        value = value << 2 | (((x ^ y) >> bit) & 1) << 1 | (y >> bit) & 1;
+    }
+
+    (value as f32 + 0.5) / 64.0
+}
+
+fn blue_noise(x: u32, y: u32) -> f32 {
+    static TEXTURE: OnceLock<Vec<f32>> = OnceLock::new();
+
+    let texture = TEXTURE.get_or_init(generate_blue_noise);
+    texture[(y as usize % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x as usize % BLUE_NOISE_SIZE]
+}
+
+/// Tileable threshold texture made with void-and-cluster, thresholds close to each other end up far apart
+fn generate_blue_noise() -> Vec<f32> {
+    const SIZE: usize = BLUE_NOISE_SIZE;
+    const SIGMA: f32 = 1.5;
+
+    let cells = SIZE * SIZE;
+
+    // Energy a filled cell adds to the cells around it, wrapping around the edges
+    let kernel = (0..cells)
+        .map(|cell| {
+            let dx = (cell % SIZE).min(SIZE - cell % SIZE) as f32;
+            let dy = (cell / SIZE).min(SIZE - cell / SIZE) as f32;
+
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect::<Vec<_>>();
+
+    let update = |energy: &mut [f32], cell: usize, sign: f32| {
+        let (cx, cy) = (cell % SIZE, cell / SIZE);
+
+        for (index, value) in energy.iter_mut().enumerate() {
+            let dx = (index % SIZE + SIZE - cx) % SIZE;
+            let dy = (index / SIZE + SIZE - cy) % SIZE;
+
+            *value += sign * kernel[dy * SIZE + dx];
+        }
+    };
+
+    let tightest_cluster = |energy: &[f32], filled: &[bool]| (0..cells)
+        .filter(|cell| filled[*cell])
+        .max_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+        .unwrap_or_default();
+
+    let largest_void = |energy: &[f32], filled: &[bool]| (0..cells)
+        .filter(|cell| !filled[*cell])
+        .min_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+        .unwrap_or_default();
+
+    // Starting pattern, a tenth of the cells picked with a fixed xorshift, so the texture is always the same
+    let mut filled = vec![false; cells];
+    let mut energy = vec![0.0; cells];
+    let mut state = 0x2545_F491_u32;
+
+    while filled.iter().filter(|filled| **filled).count() < cells / 10 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        let cell = state as usize % cells;
+
+        if !filled[cell] {
+            filled[cell] = true;
+            update(&mut energy, cell, 1.0);
+        }
+    }
+
+    // Spread the starting pattern out evenly
+    loop {
+        let cluster = tightest_cluster(&energy, &filled);
+        filled[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+
+        let void = largest_void(&energy, &filled);
+        filled[void] = true;
+        update(&mut energy, void, 1.0);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let initial_count = filled.iter().filter(|filled| **filled).count();
+    let mut ranks = vec![0; cells];
+
+    // Starting pattern is ranked by taking the tightest clusters out first
+    let (mut removed, mut removed_energy) = (filled.clone(), energy.clone());
+
+    for rank in (0..initial_count).rev() {
+        let cluster = tightest_cluster(&removed_energy, &removed);
+        removed[cluster] = false;
+        update(&mut removed_energy, cluster, -1.0);
+
+        ranks[cluster] = rank;
+    }
+
+    // Rest of the cells by filling the largest voids
+    for rank in initial_count..cells {
+        let void = largest_void(&energy, &filled);
+        filled[void] = true;
+        update(&mut energy, void, 1.0);
+
+        ranks[void] = rank;
+    }
+
+    ranks.into_iter()
+        .map(|rank| (rank as f32 + 0.5) / cells as f32)
+        .collect()
+}
+
+/// Reduces the image to colors the pixel format shows exactly, indexed formats need the palette
+pub fn quantize(image: &RgbImage, format: PixelFormat, palette: &[[u8; 3]], options: &ConversionOptions) -> RgbImage {
+    let quantizer = Quantizer::new(format, palette, options.gamma_aware);
+    let wanted = |pixel: &Rgb<u8>| quantizer.color_space(pixel.0);
+
+    match options.quantization {
+        Quantization::Truncate => image.clone(),
+        Quantization::Nearest => RgbImage::from_fn(image.width(), image.height(), |x, y| {
+            Rgb(quantizer.pick(wanted(image.get_pixel(x, y)), 0.5))
+        }),
+        Quantization::Bayer => RgbImage::from_fn(image.width(), image.height(), |x, y| {
+            Rgb(quantizer.pick(wanted(image.get_pixel(x, y)), bayer(x, y)))
+        }),
+        Quantization::BlueNoise => RgbImage::from_fn(image.width(), image.height(), |x, y| {
+            Rgb(quantizer.pick(wanted(image.get_pixel(x, y)), blue_noise(x, y)))
+        }),
+        Quantization::FloydSteinberg => {
+            let width = image.width() as usize;
+            let mut result = RgbImage::new(image.width(), image.height());
+
+            // Errors spread to the current and the next row, with a spare column on both sides
+            let mut current = vec![[0.0_f32; 3]; width + 2];
+            let mut next = vec![[0.0_f32; 3]; width + 2];
+
+            for y in 0..image.height() {
+                for x in 0..width {
+                    let mut color = wanted(image.get_pixel(x as u32, y));
+
+                    for (value, error) in color.iter_mut().zip(current[x + 1]) {
+                        *value = (*value + error).clamp(0.0, 255.0);
+                    }
+
+                    let picked = quantizer.pick(color, 0.5);
+                    let shown = quantizer.color_space(picked);
+
+                    for channel in 0..3 {
+                        let error = color[channel] - shown[channel];
+
+                        current[x + 2][channel] += error * 7.0 / 16.0;
+                        next[x][channel] += error * 3.0 / 16.0;
+                        next[x + 1][channel] += error * 5.0 / 16.0;
+                        next[x + 2][channel] += error / 16.0;
+                    }
+
+                    result.put_pixel(x as u32, y, Rgb(picked));
+                }
+
+                std::mem::swap(&mut current, &mut next);
+                next.fill([0.0; 3]);
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(64, 8, |x, _| {
+            let value = (x * 2) as u8;
+            Rgb([value, value, value])
+        })
+    }
+
+    fn average(image: &RgbImage) -> f64 {
+        image.pixels().map(|pixel| pixel[0] as f64).sum::<f64>() / (image.width() * image.height()) as f64
+    }
+
+    #[test]
+    fn dithering_keeps_average_brightness() {
+        let source = gradient();
+
+        for quantization in [Quantization::Bayer, Quantization::FloydSteinberg, Quantization::BlueNoise] {
+            let options = ConversionOptions {
+                quantization,
+                ..Default::default()
+            };
+
+            let result = quantize(&source, PixelFormat::L1, &[], &options);
+
+            assert!(result.pixels().all(|pixel| pixel[0] == 0 || pixel[0] == 255));
+            assert!((average(&result) - average(&source)).abs() < 4.0, "{quantization}: {} != {}", average(&result), average(&source));
+        }
+
+        // Rounding everything to the nearest level is what makes the bands
+        let nearest = quantize(&source, PixelFormat::L1, &[], &ConversionOptions {
+            quantization: Quantization::Nearest,
+            ..Default::default()
+        });
+        assert!(nearest.pixels().all(|pixel| pixel[0] == 0 || pixel[0] == 255));
+    }
+
+    #[test]
+    fn nearest_rounds_instead_of_truncating() {
+        let source = RgbImage::from_pixel(1, 1, Rgb([7, 3, 7]));
+
+        let result = quantize(&source, PixelFormat::Rgb565, &[], &ConversionOptions {
+            quantization: Quantization::Nearest,
+            ..Default::default()
+        });
+
+        assert_eq!(result.get_pixel(0, 0).0, [8, 4, 8]);
+    }
+}
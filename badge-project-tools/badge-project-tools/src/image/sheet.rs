@@ -0,0 +1,509 @@
+use crate::image::animated::{AnimatedFrame, DEFAULT_DURATION_US};
+use crate::image::fit::{parse_crop, CropRect};
+use crate::image::ConversionOptions;
+use anyhow::{bail, ensure, Context};
+use image::{imageops, RgbaImage};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt::Formatter;
+use std::fs;
+use std::path::{Path, PathBuf};
+use strum::{Display, EnumIs};
+
+/// Extensions of Aseprite and TexturePacker atlas files
+pub const ATLAS_EXTENSIONS: &[&str] = &["json"];
+
+/// How a sprite sheet is cut into frames
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Display, EnumIs)]
+pub enum SheetLayout {
+    /// Frames of the same size, read row by row
+    Grid {
+        columns: u32,
+        rows: u32,
+        /// Empty space around the whole grid
+        #[serde(default)]
+        margin_x: u32,
+        #[serde(default)]
+        margin_y: u32,
+        /// Empty space between neighbouring frames
+        #[serde(default)]
+        spacing_x: u32,
+        #[serde(default)]
+        spacing_y: u32
+    },
+    /// Aseprite or TexturePacker JSON with named regions
+    Atlas(PathBuf)
+}
+
+impl Default for SheetLayout {
+    fn default() -> Self {
+        Self::Grid {
+            columns: 1,
+            rows: 1,
+            margin_x: 0,
+            margin_y: 0,
+            spacing_x: 0,
+            spacing_y: 0,
+        }
+    }
+}
+
+/// Single image holding many frames, sliced when the archive is built
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SpriteSheet {
+    pub image: PathBuf,
+    pub layout: SheetLayout,
+    /// Regions used as frames in this order, counted from 0. Every region if empty
+    #[serde(default)]
+    pub frames: Vec<u32>
+}
+
+/// Where a trimmed region was in its frame before the empty border was cut off
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trim {
+    pub x: u32,
+    pub y: u32,
+    pub source_width: u32,
+    pub source_height: u32
+}
+
+/// Parses trims as `X,Y,WIDTH,HEIGHT`, the same way as crop rectangles
+pub fn parse_trim(value: &str) -> anyhow::Result<Trim> {
+    let CropRect { x, y, width, height } = parse_crop(value)?;
+
+    Ok(Trim { x, y, source_width: width, source_height: height })
+}
+
+/// Frame of a sprite sheet
+#[derive(Clone, Debug, PartialEq)]
+pub struct SheetRegion {
+    pub name: String,
+    /// Area of the sheet, turned like it is stored
+    pub rect: CropRect,
+    /// Stored turned 90° clockwise, TexturePacker does that to pack tighter
+    pub rotated: bool,
+    pub trim: Option<Trim>,
+    pub duration_us: i64
+}
+
+impl SheetRegion {
+    /// Size of the frame after turning it back and restoring the trimmed border
+    pub fn size(&self) -> (u32, u32) {
+        match (self.trim, self.rotated) {
+            (Some(trim), _) => (trim.source_width, trim.source_height),
+            (None, true) => (self.rect.height, self.rect.width),
+            (None, false) => (self.rect.width, self.rect.height)
+        }
+    }
+
+    /// Cuts the frame out of the sheet
+    pub fn slice(&self, sheet: &RgbaImage) -> RgbaImage {
+        let CropRect { x, y, width, height } = self.rect;
+        let area = imageops::crop_imm(sheet, x, y, width, height).to_image();
+
+        let area = if self.rotated {
+            imageops::rotate270(&area)
+        } else {
+            area
+        };
+
+        let Some(trim) = self.trim else {
+            return area;
+        };
+
+        let mut frame = RgbaImage::new(trim.source_width, trim.source_height);
+        imageops::replace(&mut frame, &area, trim.x as i64, trim.y as i64);
+
+        frame
+    }
+
+    /// Conversion that crops the frame out of the whole sheet, for images that point at the sheet.
+    /// Trimmed border is put back before the frame is scaled
+    pub fn crop_options(&self, options: &ConversionOptions) -> anyhow::Result<ConversionOptions> {
+        ensure!(!self.rotated, "Region '{}' is rotated, export the atlas without rotation to use it as an image", self.name);
+
+        Ok(ConversionOptions {
+            crop: Some(self.rect),
+            trim: self.trim,
+            ..options.clone()
+        })
+    }
+}
+
+/// Regions of a sheet with the named ranges of them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Atlas {
+    pub regions: Vec<SheetRegion>,
+    /// Aseprite tags, as region indices in playing order
+    pub tags: Vec<(String, Vec<u32>)>,
+    /// Sheet image the atlas was exported with, relative to the atlas
+    pub image: Option<PathBuf>
+}
+
+#[derive(Deserialize)]
+struct JsonRect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32
+}
+
+#[derive(Deserialize)]
+struct JsonSize {
+    w: u32,
+    h: u32
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonFrame {
+    #[serde(default)]
+    filename: String,
+    frame: JsonRect,
+    #[serde(default)]
+    rotated: bool,
+    #[serde(default)]
+    trimmed: bool,
+    sprite_source_size: Option<JsonRect>,
+    source_size: Option<JsonSize>,
+    /// Milliseconds, only Aseprite has it
+    duration: Option<i64>
+}
+
+/// Frames keyed by name, in the order of the file
+struct JsonFrameMap(Vec<(String, JsonFrame)>);
+
+impl<'de> Deserialize<'de> for JsonFrameMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MapVisitor;
+
+        impl<'de> Visitor<'de> for MapVisitor {
+            type Value = JsonFrameMap;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("a map of frames")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut frames = vec![];
+
+                while let Some(entry) = map.next_entry()? {
+                    frames.push(entry);
+                }
+
+                Ok(JsonFrameMap(frames))
+            }
+        }
+
+        deserializer.deserialize_map(MapVisitor)
+    }
+}
+
+/// Both tools export frames as a hash or as an array
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonFrames {
+    Array(Vec<JsonFrame>),
+    Hash(JsonFrameMap)
+}
+
+#[derive(Deserialize)]
+struct JsonTag {
+    name: String,
+    from: u32,
+    to: u32,
+    #[serde(default)]
+    direction: String
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct JsonMeta {
+    image: Option<PathBuf>,
+    frame_tags: Vec<JsonTag>
+}
+
+#[derive(Deserialize)]
+struct JsonAtlas {
+    frames: JsonFrames,
+    #[serde(default)]
+    meta: JsonMeta
+}
+
+impl JsonTag {
+    fn frames(&self) -> Vec<u32> {
+        let forward = (self.from..=self.to).collect::<Vec<_>>();
+        let backward = forward.iter().rev().copied().collect::<Vec<_>>();
+
+        // Ping-pong doesn't repeat the frames it turns at
+        let inner = |frames: &[u32]| frames[1..frames.len().saturating_sub(1).max(1)].to_vec();
+
+        match self.direction.as_str() {
+            "reverse" => backward,
+            "pingpong" => [forward, inner(&backward)].concat(),
+            "pingpong_reverse" => [backward, inner(&forward)].concat(),
+            _ => forward
+        }
+    }
+}
+
+/// Reads an Aseprite or TexturePacker JSON atlas, both the hash and the array variant
+pub fn parse_atlas(json: &str) -> anyhow::Result<Atlas> {
+    let atlas: JsonAtlas = serde_json::from_str(json)?;
+
+    let frames = match atlas.frames {
+        JsonFrames::Array(frames) => frames.into_iter()
+            .map(|frame| (frame.filename.clone(), frame))
+            .collect(),
+        JsonFrames::Hash(JsonFrameMap(frames)) => frames
+    };
+
+    let regions = frames.into_iter()
+        .map(|(name, frame)| {
+            // Rotated regions take the turned size on the sheet
+            let (width, height) = if frame.rotated {
+                (frame.frame.h, frame.frame.w)
+            } else {
+                (frame.frame.w, frame.frame.h)
+            };
+
+            let trim = match (frame.trimmed, frame.sprite_source_size, frame.source_size) {
+                (true, Some(placed), Some(source)) => Some(Trim {
+                    x: placed.x,
+                    y: placed.y,
+                    source_width: source.w,
+                    source_height: source.h,
+                }),
+                _ => None
+            };
+
+            SheetRegion {
+                name,
+                rect: CropRect {
+                    x: frame.frame.x,
+                    y: frame.frame.y,
+                    width,
+                    height,
+                },
+                rotated: frame.rotated,
+                trim,
+                duration_us: frame.duration.filter(|&duration| duration > 0).map(|duration| duration * 1000).unwrap_or(DEFAULT_DURATION_US),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let tags = atlas.meta.frame_tags.iter()
+        .filter(|tag| tag.from <= tag.to && (tag.to as usize) < regions.len())
+        .map(|tag| (tag.name.clone(), tag.frames()))
+        .collect();
+
+    Ok(Atlas {
+        regions,
+        tags,
+        image: atlas.meta.image,
+    })
+}
+
+/// Cells of a grid over the sheet of the given size
+pub fn grid_regions(layout: &SheetLayout, (sheet_width, sheet_height): (u32, u32)) -> anyhow::Result<Vec<SheetRegion>> {
+    let &SheetLayout::Grid { columns, rows, margin_x, margin_y, spacing_x, spacing_y } = layout else {
+        bail!("Layout isn't a grid");
+    };
+
+    ensure!(columns > 0 && rows > 0, "Grid needs at least one column and one row");
+
+    let cell_size = |size: u32, count: u32, margin: u32, spacing: u32| {
+        size.checked_sub(margin * 2 + spacing * (count - 1))
+            .map(|free| free / count)
+            .filter(|&cell| cell > 0)
+    };
+
+    let (Some(width), Some(height)) = (
+        cell_size(sheet_width, columns, margin_x, spacing_x),
+        cell_size(sheet_height, rows, margin_y, spacing_y)
+    ) else {
+        bail!("{columns}x{rows} grid with its margins and spacing doesn't fit on the {sheet_width}x{sheet_height} sheet");
+    };
+
+    Ok((0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (row, column)))
+        .map(|(row, column)| SheetRegion {
+            name: format!("{}", row * columns + column),
+            rect: CropRect {
+                x: margin_x + column * (width + spacing_x),
+                y: margin_y + row * (height + spacing_y),
+                width,
+                height,
+            },
+            rotated: false,
+            trim: None,
+            duration_us: DEFAULT_DURATION_US,
+        })
+        .collect())
+}
+
+impl SpriteSheet {
+    /// Regions of the sheet of the given size, atlas files are relative to the location
+    pub fn atlas(&self, location: impl AsRef<Path>, sheet_size: (u32, u32)) -> anyhow::Result<Atlas> {
+        let atlas = match &self.layout {
+            SheetLayout::Grid { .. } => Atlas {
+                regions: grid_regions(&self.layout, sheet_size)?,
+                ..Default::default()
+            },
+            SheetLayout::Atlas(path) => {
+                let path = location.as_ref().join(path);
+                let json = fs::read_to_string(&path).with_context(|| format!("Atlas {} can't be read", path.display()))?;
+
+                parse_atlas(&json)?
+            }
+        };
+
+        let (width, height) = sheet_size;
+        if let Some(outside) = atlas.regions.iter()
+            .find(|region| {
+                let CropRect { x, y, width: region_width, height: region_height } = region.rect;
+
+                x.checked_add(region_width).is_none_or(|right| right > width)
+                    || y.checked_add(region_height).is_none_or(|bottom| bottom > height)
+            }) {
+            bail!("Region '{}' is outside of the {width}x{height} sheet", outside.name);
+        }
+
+        Ok(atlas)
+    }
+
+    /// Regions picked as frames, in the order they are played
+    pub fn selected(&self, regions: &[SheetRegion]) -> anyhow::Result<Vec<SheetRegion>> {
+        if self.frames.is_empty() {
+            return Ok(regions.to_vec());
+        }
+
+        self.frames.iter()
+            .map(|&index| {
+                regions.get(index as usize)
+                    .cloned()
+                    .with_context(|| format!("Sheet has {} regions, there's no region {index}", regions.len()))
+            })
+            .collect()
+    }
+
+    /// Slices the picked frames out of the decoded sheet image
+    pub fn slice_frames(&self, data: &[u8], location: impl AsRef<Path>) -> anyhow::Result<Vec<AnimatedFrame>> {
+        let sheet = image::load_from_memory(data)?.to_rgba8();
+        let atlas = self.atlas(location, sheet.dimensions())?;
+        let frames = self.selected(&atlas.regions)?;
+
+        ensure!(!frames.is_empty(), "Sheet has no regions");
+
+        Ok(frames.iter()
+            .map(|region| AnimatedFrame {
+                image: region.slice(&sheet),
+                duration_us: region.duration_us,
+            })
+            .collect())
+    }
+
+    pub fn load(&self, location: impl AsRef<Path>) -> anyhow::Result<Vec<AnimatedFrame>> {
+        let location = location.as_ref();
+
+        self.slice_frames(&fs::read(location.join(&self.image))?, location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::fit::{fit_image, FitMode, ResizeFilter};
+    use image::{DynamicImage, Rgba};
+
+    #[test]
+    fn grid_skips_margins_and_spacing() {
+        let layout = SheetLayout::Grid {
+            columns: 3,
+            rows: 2,
+            margin_x: 1,
+            margin_y: 2,
+            spacing_x: 1,
+            spacing_y: 0,
+        };
+
+        // 1 + 3 * 4 + 2 * 1 + 1 wide, 2 + 2 * 5 + 2 high
+        let regions = grid_regions(&layout, (16, 14)).unwrap();
+        assert_eq!(regions.len(), 6);
+        assert_eq!(regions[4].rect, CropRect { x: 6, y: 7, width: 4, height: 5 });
+        assert!(grid_regions(&layout, (4, 14)).is_err());
+
+        let sheet = SpriteSheet {
+            frames: vec![5, 0],
+            layout,
+            ..Default::default()
+        };
+        let selected = sheet.selected(&regions).unwrap();
+        assert_eq!(selected.iter().map(|region| region.name.as_str()).collect::<Vec<_>>(), vec!["5", "0"]);
+    }
+
+    #[test]
+    fn atlas_keeps_order_and_restores_frames() {
+        let json = r#"{
+            "frames": {
+                "walk 9.aseprite": { "frame": { "x": 0, "y": 0, "w": 2, "h": 1 }, "duration": 80 },
+                "walk 10.aseprite": {
+                    "frame": { "x": 2, "y": 0, "w": 2, "h": 1 },
+                    "rotated": true,
+                    "trimmed": true,
+                    "spriteSourceSize": { "x": 1, "y": 0, "w": 2, "h": 1 },
+                    "sourceSize": { "w": 3, "h": 2 }
+                }
+            },
+            "meta": {
+                "image": "walk.png",
+                "frameTags": [{ "name": "loop", "from": 0, "to": 1, "direction": "reverse" }]
+            }
+        }"#;
+
+        let atlas = parse_atlas(json).unwrap();
+        assert_eq!(atlas.regions[0].name, "walk 9.aseprite");
+        assert_eq!(atlas.regions[0].duration_us, 80_000);
+        assert_eq!(atlas.regions[1].duration_us, DEFAULT_DURATION_US);
+        assert_eq!(atlas.tags, vec![("loop".to_string(), vec![1, 0])]);
+        assert_eq!(atlas.image, Some(PathBuf::from("walk.png")));
+
+        // The rotated region is stored 1 wide and 2 high, top pixel red
+        let mut sheet = RgbaImage::new(3, 2);
+        sheet.put_pixel(2, 0, Rgba([255, 0, 0, 255]));
+        sheet.put_pixel(2, 1, Rgba([0, 0, 255, 255]));
+
+        let frame = atlas.regions[1].slice(&sheet);
+        assert_eq!(frame.dimensions(), (3, 2));
+        assert_eq!(frame.get_pixel(0, 0).0[3], 0);
+        assert_eq!(frame.get_pixel(1, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(frame.get_pixel(2, 0), &Rgba([0, 0, 255, 255]));
+        assert!(atlas.regions[1].crop_options(&ConversionOptions::default()).is_err());
+    }
+
+    #[test]
+    fn trimmed_region_is_scaled_as_whole_frame() {
+        // 1x1 red pixel, trimmed out of the bottom right of a 2x2 frame
+        let region = SheetRegion {
+            name: "walk".to_string(),
+            rect: CropRect { x: 1, y: 0, width: 1, height: 1 },
+            rotated: false,
+            trim: Some(Trim { x: 1, y: 1, source_width: 2, source_height: 2 }),
+            duration_us: DEFAULT_DURATION_US,
+        };
+
+        let mut sheet = RgbaImage::new(2, 1);
+        sheet.put_pixel(1, 0, Rgba([255, 0, 0, 255]));
+
+        let options = region.crop_options(&ConversionOptions {
+            fit: FitMode::Stretch,
+            filter: ResizeFilter::Nearest,
+            ..Default::default()
+        }).unwrap();
+
+        let frame = fit_image(&DynamicImage::ImageRgba8(sheet), 4, 4, &options);
+        assert_eq!(frame.get_pixel(1, 1).0[3], 0);
+        assert_eq!(frame.get_pixel(2, 2), &Rgba([255, 0, 0, 255]));
+        assert_eq!(frame.get_pixel(3, 3), &Rgba([255, 0, 0, 255]));
+    }
+}
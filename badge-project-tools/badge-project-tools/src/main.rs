@@ -5,18 +5,31 @@
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 use crate::character::{process_character_cli, CharacterCli};
+use crate::character::analysis::{process_analyze_cli, AnalyzeCli};
+use crate::character::bundle::{process_bundle_cli, BundleCli};
+use crate::character::diff::{process_diff_archive_cli, DiffArchiveCli};
+use crate::character::sim::timeline::{process_simulate_cli, SimulateCli};
+use crate::character::unpack::{process_unpack_cli, UnpackCli};
+use crate::character::validation::{process_validate_cli, ValidateCli};
 use crate::gui::{start_gui, GuiCli};
 use crate::image::{process_image, ImageCli};
 use clap::Parser;
 
 pub mod image;
 pub mod character;
+pub mod target;
 mod gui;
 
 #[derive(clap::Subcommand, Debug)]
 enum CliCommand {
     Image(ImageCli),
     Char(CharacterCli),
+    Bundle(BundleCli),
+    Unpack(UnpackCli),
+    DiffArchive(DiffArchiveCli),
+    Validate(ValidateCli),
+    Simulate(SimulateCli),
+    Analyze(AnalyzeCli),
     Gui(GuiCli)
 }
 
@@ -36,6 +49,12 @@ fn main() -> anyhow::Result<()> {
     match cli.command {
         CliCommand::Image(img) => process_image(img),
         CliCommand::Char(char) => process_character_cli(char),
+        CliCommand::Bundle(bundle) => process_bundle_cli(bundle),
+        CliCommand::Unpack(unpack) => process_unpack_cli(unpack),
+        CliCommand::DiffArchive(diff) => process_diff_archive_cli(diff),
+        CliCommand::Validate(validate) => process_validate_cli(validate),
+        CliCommand::Simulate(simulate) => process_simulate_cli(simulate),
+        CliCommand::Analyze(analyze) => process_analyze_cli(analyze),
         CliCommand::Gui(_) => start_gui(),
     }
 }
@@ -0,0 +1,223 @@
+use crate::character::format::{find_codec, FormatCodec, CURRENT_FORMAT_VERSION};
+use crate::character::sim::allocator::{storage_size_from_sdkconfig, IMAGE_STORAGE_SIZE};
+use crate::image::{ByteOrder, PixelFormat};
+use crate::{bp_data_ACTION_DISPLAY_MAX_LEN, bp_data_ANIMATION_NAME_MAX_LEN, bp_data_IMAGE_NAME_MAX_LEN, bp_data_NAME_MAX_LEN, bp_data_SPECIES_MAX_LEN, bp_data_STATE_NAME_MAX_LEN};
+use anyhow::{bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Longest names the firmware accepts, without the terminating nul.
+/// The binary layout always has room for the limits of the format version, these can only be lower
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct NameLimits {
+    pub name: usize,
+    pub species: usize,
+    pub state_name: usize,
+    pub animation_name: usize,
+    pub image_name: usize,
+    pub action_display: usize
+}
+
+impl Default for NameLimits {
+    fn default() -> Self {
+        Self {
+            name: bp_data_NAME_MAX_LEN - 1,
+            species: bp_data_SPECIES_MAX_LEN - 1,
+            state_name: bp_data_STATE_NAME_MAX_LEN - 1,
+            animation_name: bp_data_ANIMATION_NAME_MAX_LEN - 1,
+            image_name: bp_data_IMAGE_NAME_MAX_LEN - 1,
+            action_display: bp_data_ACTION_DISPLAY_MAX_LEN - 1,
+        }
+    }
+}
+
+/// Everything about the badge hardware and firmware build that the tools need to know.
+/// Missing fields in profile files are taken from the stock badge
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TargetProfile {
+    pub name: String,
+    /// Resolution of the display
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: PixelFormat,
+    /// Pixel formats the firmware can show, the stock firmware draws everything as RGB565
+    pub pixel_formats: Vec<PixelFormat>,
+    /// Byte order of images handed to LVGL
+    pub image_byte_order: ByteOrder,
+    /// Byte order of animation frames, they're sent to the display as they are
+    pub frame_byte_order: ByteOrder,
+    /// Size of the static image storage in PSRAM in bytes
+    pub storage_size: u64,
+    pub name_limits: NameLimits,
+    /// Format version of character archives the firmware reads
+    pub format_version: u16
+}
+
+impl Default for TargetProfile {
+    fn default() -> Self {
+        Self {
+            name: "Stock badge".to_string(),
+            width: 320,
+            height: 480,
+            pixel_format: PixelFormat::default(),
+            pixel_formats: vec![PixelFormat::Rgb565],
+            image_byte_order: ByteOrder::Little,
+            frame_byte_order: ByteOrder::Big,
+            storage_size: IMAGE_STORAGE_SIZE,
+            name_limits: NameLimits::default(),
+            format_version: CURRENT_FORMAT_VERSION,
+        }
+    }
+}
+
+impl TargetProfile {
+    /// Reads TOML or JSON profile, picked by the extension of the file
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+
+        let profile: TargetProfile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            Some("json") => serde_json::from_str(&content)?,
+            _ => bail!("Target profile '{}' has to be a .toml or .json file", path.display())
+        };
+
+        profile.check()?;
+
+        Ok(profile)
+    }
+
+    /// Checks the profile again after command line options changed it
+    pub fn check(&self) -> anyhow::Result<()> {
+        ensure!(self.width > 0 && self.height > 0, "Display resolution of target '{}' can't be zero", self.name);
+        ensure!(
+            self.pixel_formats.contains(&self.pixel_format),
+            "Target '{}' can't show {} images, it supports {}",
+            self.name,
+            self.pixel_format,
+            self.pixel_formats.iter().map(PixelFormat::to_string).collect::<Vec<_>>().join(", ")
+        );
+
+        let layout = NameLimits::default();
+        let limits = &self.name_limits;
+
+        for (field, limit, max) in [
+            ("name", limits.name, layout.name),
+            ("species", limits.species, layout.species),
+            ("state_name", limits.state_name, layout.state_name),
+            ("animation_name", limits.animation_name, layout.animation_name),
+            ("image_name", limits.image_name, layout.image_name),
+            ("action_display", limits.action_display, layout.action_display),
+        ] {
+            ensure!(
+                limit > 0 && limit <= max,
+                "Name limit '{field}' of target '{}' has to be between 1 and {max}, the most the binary format can store",
+                self.name
+            );
+        }
+
+        self.codec()?;
+
+        Ok(())
+    }
+
+    pub fn codec(&self) -> anyhow::Result<&'static dyn FormatCodec> {
+        find_codec(self.format_version)
+    }
+}
+
+/// Target profile option shared by all commands
+#[derive(clap::Args, Debug)]
+pub struct TargetArgs {
+    #[arg(long, help = "Target profile TOML or JSON file describing the badge, defaults to the stock badge")]
+    target: Option<PathBuf>
+}
+
+impl TargetArgs {
+    pub fn profile(&self) -> anyhow::Result<TargetProfile> {
+        match &self.target {
+            Some(path) => TargetProfile::load(path),
+            None => Ok(TargetProfile::default())
+        }
+    }
+}
+
+/// Storage size options of the commands that simulate memory, they override the target profile
+#[derive(clap::Args, Debug)]
+pub struct StorageArgs {
+    #[arg(long, help = "Image storage size of the board in KB, same as CONFIG_IMAGE_STATIC_STORAGE_SIZE")]
+    storage_size: Option<u64>,
+    #[arg(long, help = "Takes the image storage size from sdkconfig of the board, overrides --storage-size")]
+    sdkconfig: Option<PathBuf>
+}
+
+impl StorageArgs {
+    pub fn apply(&self, profile: &mut TargetProfile) -> anyhow::Result<()> {
+        if let Some(path) = &self.sdkconfig {
+            profile.storage_size = storage_size_from_sdkconfig(path)?;
+        } else if let Some(size) = self.storage_size {
+            profile.storage_size = size * 1000;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_come_from_stock_badge() {
+        let profile: TargetProfile = toml::from_str(r#"
+            name = "Round badge"
+            width = 240
+            height = 240
+            frame_byte_order = "Little"
+
+            [name_limits]
+            state_name = 15
+        "#).unwrap();
+
+        assert_eq!(profile, TargetProfile {
+            name: "Round badge".to_string(),
+            width: 240,
+            height: 240,
+            frame_byte_order: ByteOrder::Little,
+            name_limits: NameLimits {
+                state_name: 15,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert!(profile.check().is_ok());
+
+        let too_long = TargetProfile {
+            name_limits: NameLimits {
+                image_name: 1000,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(too_long.check().is_err());
+    }
+
+    #[test]
+    fn pixel_format_has_to_be_supported() {
+        let mut stock = TargetProfile::default();
+        assert!(stock.check().is_ok());
+
+        stock.pixel_format = PixelFormat::L8;
+        assert!(stock.check().is_err());
+
+        // Name doesn't matter, only the listed formats
+        let custom: TargetProfile = toml::from_str(r#"
+            pixel_format = "L1"
+            pixel_formats = ["Rgb565", "L1"]
+        "#).unwrap();
+        assert!(custom.check().is_ok());
+    }
+}